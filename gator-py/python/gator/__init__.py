@@ -0,0 +1,39 @@
+"""Gator's parallel download engine for Python pipelines.
+
+Build and install with maturin, from gator-py/:
+
+    maturin develop --release
+
+    import gator
+
+    gator.download(url, "data.parquet", connections=8, on_progress=print)
+    await gator.download_async(url, "data.parquet", connections=8)
+"""
+
+import asyncio
+
+from ._gator import Cancel, DownloadCancelled, GatorError, download
+
+__all__ = ["Cancel", "DownloadCancelled", "GatorError", "download", "download_async"]
+
+
+async def download_async(url, path, *, connections=None, resume=False, on_progress=None):
+    """download() on a worker thread. Cancelling the task cancels the download
+    and waits for it to stop, leaving a part file that resume=True continues."""
+    cancel = Cancel()
+    loop = asyncio.get_running_loop()
+    future = loop.run_in_executor(
+        None,
+        lambda: download(
+            url, path, connections=connections, resume=resume, on_progress=on_progress, cancel=cancel
+        ),
+    )
+    try:
+        return await asyncio.shield(future)
+    except asyncio.CancelledError:
+        cancel.cancel()
+        try:
+            await future
+        except DownloadCancelled:
+            pass
+        raise
@@ -0,0 +1,206 @@
+// Python bindings for the download engine, built by maturin into the
+// `gator._gator` extension module. The `gator` package re-exports it and adds
+// the asyncio variant (python/gator/__init__.py).
+//
+// Downloads release the GIL while they run, so other Python threads keep
+// going; progress callbacks take it back for each call.
+
+use gator::{blocking, Cancelled, CancellationToken, DownloadOptions, ProgressObserver, Snapshot};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+create_exception!(gator, GatorError, PyException, "A download failed.");
+create_exception!(
+    gator,
+    DownloadCancelled,
+    GatorError,
+    "A download was cancelled. Run it again with resume=True to continue."
+);
+
+// Stops the downloads it is passed to; cancel() may be called from any thread
+#[pyclass(frozen, module = "gator")]
+struct Cancel(CancellationToken);
+
+#[pymethods]
+impl Cancel {
+    #[new]
+    fn new() -> Self {
+        Self(CancellationToken::new())
+    }
+
+    fn cancel(&self) {
+        self.0.cancel();
+    }
+
+    #[getter]
+    fn cancelled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+}
+
+// Calls on_progress(bytes, total, speed) from the download's threads. The
+// first exception it raises stops the download and is raised in its place.
+struct Callback {
+    on_progress: Py<PyAny>,
+    error: Arc<Mutex<Option<PyErr>>>,
+    cancel: CancellationToken,
+}
+
+impl ProgressObserver for Callback {
+    fn on_progress(&self, snapshot: &Snapshot) {
+        if self.cancel.is_cancelled() {
+            return;
+        }
+        let result = Python::attach(|py| {
+            self.on_progress
+                .call1(py, (snapshot.bytes, snapshot.total, snapshot.speed))
+                .map(drop)
+        });
+        if let Err(e) = result {
+            self.error.lock().unwrap().get_or_insert(e);
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// download(url, path, *, connections=None, resume=False, on_progress=None, cancel=None)
+/// --
+///
+/// Download url to path, blocking until done, and return where the file was
+/// saved. connections caps the parallel segment workers. on_progress(bytes,
+/// total, speed) is called at most every 100 ms from one of Gator's threads;
+/// total is None when the server did not say, and speed is in bytes per
+/// second. Raises DownloadCancelled when cancel fires, and GatorError when
+/// the download fails.
+#[pyfunction]
+#[pyo3(signature = (url, path, *, connections=None, resume=false, on_progress=None, cancel=None))]
+fn download(
+    py: Python<'_>,
+    url: String,
+    path: PathBuf,
+    connections: Option<usize>,
+    resume: bool,
+    on_progress: Option<Py<PyAny>>,
+    cancel: Option<Py<Cancel>>,
+) -> PyResult<PathBuf> {
+    // A child token, so a failing callback stops this download and not
+    // every other one sharing the handle
+    let cancel = cancel.map_or_else(CancellationToken::new, |c| c.get().0.child_token());
+    let error = Arc::new(Mutex::new(None));
+    let options = DownloadOptions {
+        resume,
+        connections: connections.map(|c| c.max(1)),
+        ..DownloadOptions::default()
+    };
+    if let Some(on_progress) = on_progress {
+        options.progress.observe(Arc::new(Callback {
+            on_progress,
+            error: error.clone(),
+            cancel: cancel.clone(),
+        }));
+    }
+
+    let result = py.detach(|| blocking::download_until_cancelled(&url, &path, options, cancel));
+    if let Some(e) = error.lock().unwrap().take() {
+        return Err(e);
+    }
+    result.map_err(|e| match e.downcast_ref::<Cancelled>() {
+        Some(_) => DownloadCancelled::new_err(e.to_string()),
+        None => GatorError::new_err(e.to_string()),
+    })
+}
+
+#[pymodule]
+fn _gator(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_class::<Cancel>()?;
+    m.add("GatorError", py.get_type::<GatorError>())?;
+    m.add("DownloadCancelled", py.get_type::<DownloadCancelled>())?;
+    m.add_function(wrap_pyfunction!(download, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gator::mock_server::{Behavior, MockServer};
+    use pyo3::types::PyDict;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("file.bin")
+    }
+
+    // Run `code` with the module imported as `gator` and `url` and `path` set
+    fn run(code: &str, url: &str, path: &std::path::Path) -> PyResult<()> {
+        Python::attach(|py| {
+            let module = PyModule::new(py, "gator")?;
+            _gator(&module)?;
+            let globals = PyDict::new(py);
+            globals.set_item("gator", module)?;
+            globals.set_item("url", url)?;
+            globals.set_item("path", path)?;
+            py.run(&std::ffi::CString::new(code).unwrap(), Some(&globals), None)
+        })
+    }
+
+    #[test]
+    fn downloads_with_progress_and_a_worker_cap() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::start(12 * 1024 * 1024, Behavior::default())).unwrap();
+        let path = temp_path("py-download");
+
+        run(
+            r#"
+seen = []
+saved = gator.download(url, path, connections=2, on_progress=lambda *s: seen.append(s))
+assert str(saved) == str(path), saved
+assert seen and seen[-1][0] == seen[-1][1] == 12 * 1024 * 1024, seen[-1:]
+"#,
+            &server.url("file.bin"),
+            &path,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), server.data());
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn exceptions_in_progress_callbacks_stop_the_download() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::start(12 * 1024 * 1024, Behavior::default())).unwrap();
+        let path = temp_path("py-callback-error");
+
+        run(
+            r#"
+def fail(done, total, speed):
+    raise ValueError("stop here")
+
+try:
+    gator.download(url, path, on_progress=fail)
+except ValueError as e:
+    assert str(e) == "stop here"
+else:
+    raise AssertionError("the download finished")
+
+cancel = gator.Cancel()
+cancel.cancel()
+try:
+    gator.download(url, path, cancel=cancel)
+except gator.DownloadCancelled:
+    pass
+else:
+    raise AssertionError("the download finished")
+assert issubclass(gator.DownloadCancelled, gator.GatorError)
+"#,
+            &server.url("file.bin"),
+            &path,
+        )
+        .unwrap();
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ * C interface to Gator's download engine. Build the shared library with
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * and link against target/release/libgator.so (gator.dll, libgator.dylib).
+ */
+
+#ifndef GATOR_H
+#define GATOR_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define GATOR_OK 0
+#define GATOR_ERROR 1
+#define GATOR_CANCELLED 2
+
+/* Bytes so far, total size (0 when unknown), speed in bytes per second, and
+ * the user data given to gator_download. Called from Gator's threads at most
+ * every 100 ms. */
+typedef void (*gator_progress_fn)(uint64_t bytes, uint64_t total, double speed, void *user_data);
+
+typedef struct GatorCancel GatorCancel;
+
+GatorCancel *gator_cancel_new(void);
+/* Cancel every download using this handle; safe to call from any thread */
+void gator_cancel(const GatorCancel *cancel);
+/* Only once no download uses the handle any more */
+void gator_cancel_free(GatorCancel *cancel);
+
+/* Download url to path, blocking until done. With resume non-zero, a partial
+ * file left by an earlier (e.g. cancelled) attempt is continued. progress and
+ * cancel may be NULL. Returns GATOR_OK, GATOR_ERROR or GATOR_CANCELLED. */
+int gator_download(const char *url, const char *path, int resume,
+                   gator_progress_fn progress, void *user_data,
+                   const GatorCancel *cancel);
+
+/* Why the last gator_download on this thread failed, or NULL. Valid until the
+ * next gator_download on the same thread. */
+const char *gator_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
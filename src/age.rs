@@ -0,0 +1,132 @@
+use crate::protocol::BoxError;
+use std::io::Write;
+use std::iter;
+use std::sync::{Arc, Mutex};
+
+// An age X25519 recipient, the `age1...` public key files are encrypted to
+#[derive(Debug, Clone)]
+pub struct Recipient(::age::x25519::Recipient);
+
+// clap value parser for `--encrypt age:RECIPIENT`
+pub fn parse(spec: &str) -> Result<Recipient, String> {
+    let Some(recipient) = spec.strip_prefix("age:") else {
+        return Err("Expected age:RECIPIENT, e.g. age:age1...".to_string());
+    };
+    recipient
+        .parse()
+        .map(Recipient)
+        .map_err(|_| format!("'{}' is not an age X25519 recipient (age1...)", recipient))
+}
+
+// Where the age stream writer puts its output, until the caller collects it
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Sink {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Encrypts a file as it streams past, in the age v1 format. The output can
+// be decrypted with `age -d -i KEY`.
+pub struct Encryptor {
+    // None once finished
+    writer: Option<::age::stream::StreamWriter<Sink>>,
+    sink: Sink,
+}
+
+impl Encryptor {
+    // An encryptor with a fresh file key, and the header that starts the file
+    pub fn new(recipient: &Recipient) -> Result<(Self, Vec<u8>), BoxError> {
+        let encryptor = ::age::Encryptor::with_recipients(iter::once(&recipient.0 as _))?;
+        let sink = Sink::default();
+        let writer = encryptor.wrap_output(sink.clone())?;
+        let header = sink.take();
+        Ok((Self { writer: Some(writer), sink }, header))
+    }
+
+    // Add plaintext; returns the chunks sealed so far, if any
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let writer = self.writer.as_mut().expect("an unfinished encryptor");
+        writer.write_all(plaintext).expect("writes to memory cannot fail");
+        self.sink.take()
+    }
+
+    // Seal the last chunk, which is empty only when the whole file is
+    pub fn finish(&mut self) -> Vec<u8> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish().expect("writes to memory cannot fail");
+        }
+        self.sink.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::age::x25519::Identity;
+
+    // The identity the checked-in fixture was encrypted to; not used for
+    // anything else
+    const IDENTITY: &str = include_str!("testdata/age-identity.txt");
+
+    fn identity() -> Identity {
+        let line = IDENTITY.lines().find(|l| l.starts_with("AGE-SECRET-KEY-")).unwrap();
+        line.parse().unwrap()
+    }
+
+    fn encrypt(recipient: &Recipient, pieces: &[&[u8]]) -> Vec<u8> {
+        let (mut encryptor, mut file) = Encryptor::new(recipient).unwrap();
+        for piece in pieces {
+            file.extend(encryptor.encrypt(piece));
+        }
+        file.extend(encryptor.finish());
+        file
+    }
+
+    #[test]
+    fn recipients_need_the_age_prefix_and_a_valid_checksum() {
+        let recipient = "age1w84zr8mjr33la70ms997h7lk4pc822ryfhcxpkexnp74zu0t5evst784tu";
+        assert!(parse(&format!("age:{}", recipient)).is_ok());
+        assert!(parse(recipient).is_err());
+        assert!(parse("age:age1w84zr8mjr33la70ms997h7lk4pc822ryfhcxpkexnp74zu0t5evst784tv").is_err());
+    }
+
+    #[test]
+    fn whole_files_decrypt_with_the_identity() {
+        let identity = identity();
+        let recipient = Recipient(identity.to_public());
+
+        // More than two chunks, written in pieces that straddle them
+        let plaintext: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+        let file = encrypt(&recipient, &[&plaintext[..1000], &plaintext[1000..70_000], &plaintext[70_000..]]);
+        assert!(file.starts_with(b"age-encryption.org/v1\n-> X25519 "));
+        assert_eq!(::age::decrypt(&identity, &file).unwrap(), plaintext);
+
+        let empty = encrypt(&recipient, &[]);
+        assert_eq!(::age::decrypt(&identity, &empty).unwrap(), Vec::<u8>::new());
+
+        let other = Identity::generate();
+        assert!(::age::decrypt(&other, &file).is_err());
+    }
+
+    #[test]
+    fn files_from_the_age_tool_decrypt_with_the_identity() {
+        // Made by rage 0.12, the Rust reference implementation:
+        //   printf 'hello from age\n' | rage -r age14j4z... -o hello.txt.age
+        let file = include_bytes!("testdata/hello.txt.age");
+        assert_eq!(::age::decrypt(&identity(), file).unwrap(), b"hello from age\n");
+    }
+}
@@ -0,0 +1,203 @@
+use crate::batch::BatchItem;
+use crate::digest::{Algorithm, Expected};
+use crate::listing::Entry;
+use crate::protocol::BoxError;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::path::{Component, PathBuf};
+
+// Items on the Internet Archive (https://archive.org/details/IDENTIFIER). The
+// metadata API lists an item's files with their sizes and checksums, and each
+// file downloads from /download/IDENTIFIER/NAME, where NAME may hold folders.
+const SITE: &str = "https://archive.org";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Item {
+    #[serde(skip)]
+    pub identifier: String,
+    #[serde(default)]
+    pub files: Vec<ItemFile>,
+}
+
+// One file as the metadata API describes it; numbers arrive as strings
+#[derive(Debug, Clone, Deserialize)]
+pub struct ItemFile {
+    pub name: String,
+    // "original" for uploads, "derivative" for what archive.org made from
+    // them (thumbnails, other formats), "metadata" for its own records
+    #[serde(default)]
+    pub source: String,
+    size: Option<String>,
+    mtime: Option<String>,
+    md5: Option<String>,
+    sha1: Option<String>,
+    // "true" for files only the uploader may download
+    private: Option<serde_json::Value>,
+}
+
+// The identifier in an archive.org URL: /details/ID, /download/ID/... or
+// /metadata/ID
+pub fn identifier_in_url(url: &str) -> Option<String> {
+    let url = Url::parse(url.trim()).ok()?;
+    let host = url.host_str()?;
+    if host != "archive.org" && !host.ends_with(".archive.org") {
+        return None;
+    }
+    let mut segments = url.path_segments()?;
+    match segments.next()? {
+        "details" | "download" | "metadata" => segments.next().filter(|s| !s.is_empty()).map(str::to_string),
+        _ => None,
+    }
+}
+
+// The identifier in `input`, an archive.org URL or the identifier itself
+pub fn identifier(input: &str) -> Option<String> {
+    let input = input.trim();
+    if input.contains("://") {
+        return identifier_in_url(input);
+    }
+    let bare = !input.is_empty() && input.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    bare.then(|| input.to_string())
+}
+
+pub fn metadata_url(identifier: &str) -> String {
+    format!("{}/metadata/{}", SITE, identifier)
+}
+
+// Fetch the item's file list from `url`, its metadata_url (possibly rewritten)
+pub async fn fetch(client: &Client, url: &str, identifier: &str) -> Result<Item, BoxError> {
+    let body = client.get(url).send().await?.error_for_status()?.text().await?;
+    let mut item: Item =
+        serde_json::from_str(&body).map_err(|e| format!("Unexpected metadata for '{}': {}", identifier, e))?;
+    // An unknown (or withdrawn) item comes back as `{}`
+    if item.files.is_empty() {
+        return Err(format!("archive.org has no item '{}', or it holds no files", identifier).into());
+    }
+    item.identifier = identifier.to_string();
+    Ok(item)
+}
+
+impl Item {
+    pub fn url(&self, file: &ItemFile) -> String {
+        let mut url = Url::parse(SITE).expect("a valid URL");
+        url.path_segments_mut()
+            .expect("an http URL")
+            .push("download")
+            .push(&self.identifier)
+            .extend(file.name.split('/'));
+        url.to_string()
+    }
+
+    // The files for `gator ls`
+    pub fn entries(&self) -> Vec<Entry> {
+        self.files
+            .iter()
+            .map(|file| Entry {
+                name: file.name.clone(),
+                url: self.url(file),
+                size: file.size(),
+                modified: file.mtime.as_deref().and_then(|t| t.parse().ok()).map(format_time),
+                is_dir: false,
+            })
+            .collect()
+    }
+
+    // `files` to download into IDENTIFIER/, keeping the item's folders. Names
+    // that would leave that folder are dropped.
+    pub fn batch_items<'a>(&self, files: impl IntoIterator<Item = &'a ItemFile>) -> Vec<BatchItem> {
+        files
+            .into_iter()
+            .filter_map(|file| {
+                let name = PathBuf::from(&file.name);
+                if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+                    return None;
+                }
+                Some(BatchItem {
+                    url: self.url(file),
+                    relative: PathBuf::from(&self.identifier).join(name),
+                    digests: file.digests(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl ItemFile {
+    pub fn size(&self) -> Option<u64> {
+        self.size.as_deref()?.parse().ok()
+    }
+
+    pub fn is_private(&self) -> bool {
+        match &self.private {
+            Some(serde_json::Value::Bool(private)) => *private,
+            Some(serde_json::Value::String(private)) => private == "true",
+            _ => false,
+        }
+    }
+
+    // The MD5 and SHA-1 archive.org publishes for the file
+    pub fn digests(&self) -> Vec<Expected> {
+        let published = [(Algorithm::Md5, &self.md5), (Algorithm::Sha1, &self.sha1)];
+        published
+            .into_iter()
+            .filter_map(|(algorithm, hex)| {
+                Expected::from_hex(algorithm, hex.as_deref()?, format!("archive.org {}", algorithm))
+            })
+            .collect()
+    }
+}
+
+// Unix seconds as "YYYY-MM-DD HH:MM" in UTC, as directory listings show times
+fn format_time(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs / 3600, secs % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_become_checked_batch_items() {
+        assert_eq!(identifier("https://archive.org/details/gov.nasa.apollo11/").as_deref(), Some("gov.nasa.apollo11"));
+        assert_eq!(identifier("https://ia800.us.archive.org/download/my_item/a.mp3").as_deref(), Some("my_item"));
+        assert_eq!(identifier("my_item").as_deref(), Some("my_item"));
+        assert_eq!(identifier("https://example.com/details/my_item"), None);
+        assert_eq!(identifier("../etc"), None);
+
+        let mut item: Item = serde_json::from_str(
+            r#"{"files": [
+                {"name": "disc 1/track 01.flac", "source": "original", "size": "1234", "mtime": "1700000000",
+                 "md5": "0cc175b9c0f1b6a831c399e269772661", "sha1": "86f7e437faa5a7fce15d1ddcb9eaeaea377667b8"},
+                {"name": "../escape.txt", "source": "original"},
+                {"name": "secret.pdf", "source": "original", "private": "true"}
+            ]}"#,
+        )
+        .unwrap();
+        item.identifier = "my_item".to_string();
+        assert!(item.files[2].is_private());
+        assert_eq!(item.entries()[0].modified.as_deref(), Some("2023-11-14 22:13"));
+
+        let items = item.batch_items(&item.files);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].url, "https://archive.org/download/my_item/disc%201/track%2001.flac");
+        assert_eq!(items[0].relative, PathBuf::from("my_item/disc 1/track 01.flac"));
+        let algorithms: Vec<Algorithm> = items[0].digests.iter().map(|d| d.algorithm).collect();
+        assert_eq!(algorithms, [Algorithm::Md5, Algorithm::Sha1]);
+
+        // The published checksums are of "a"
+        let mut running = crate::digest::Running::all(&items[0].digests);
+        running.update(b"a");
+        assert!(running.verify(&items[0].digests).is_ok());
+    }
+}
@@ -0,0 +1,524 @@
+use crate::config::{self, OAuthConfig};
+use gator::hooks::Hook;
+use gator::output::Output;
+use gator::pattern::host_matches;
+use gator::protocol::{BoxError, HostClients};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Tokens are refreshed this long before they expire, so a request never
+// goes out with one that lapses on the way
+const REFRESH_MARGIN: u64 = 5 * 60;
+
+// How often a long run checks whether a token is due for a refresh
+const CHECK_EVERY: Duration = Duration::from_secs(60);
+
+// An OAuth provider with every endpoint filled in
+#[derive(Debug, Clone)]
+struct Provider {
+    name: String,
+    client_id: String,
+    client_secret: Option<String>,
+    device_url: String,
+    token_url: String,
+    scopes: Vec<String>,
+    hosts: Vec<String>,
+}
+
+// Endpoints, scopes and hosts of the providers Gator knows, as (device_url,
+// token_url, scopes, hosts). Google only allows the device flow a few
+// scopes, so Drive files need `drive.file`.
+fn built_in(name: &str) -> Option<(&'static str, &'static str, &'static [&'static str], &'static [&'static str])> {
+    match name {
+        "google" => Some((
+            "https://oauth2.googleapis.com/device/code",
+            "https://oauth2.googleapis.com/token",
+            &["https://www.googleapis.com/auth/drive.file"],
+            &["www.googleapis.com"],
+        )),
+        "microsoft" => Some((
+            "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode",
+            "https://login.microsoftonline.com/common/oauth2/v2.0/token",
+            &["Files.Read.All", "offline_access"],
+            &["graph.microsoft.com"],
+        )),
+        _ => None,
+    }
+}
+
+impl Provider {
+    fn from_config(name: &str, oauth: &BTreeMap<String, OAuthConfig>) -> Result<Self, BoxError> {
+        let Some(settings) = oauth.get(name) else {
+            return Err(match built_in(name) {
+                Some(_) => format!("Add [oauth.{}] with the client_id of your app to the config file", name),
+                None => format!("OAuth provider '{}' is not defined in the config file", name),
+            }
+            .into());
+        };
+        if settings.client_id.is_empty() {
+            return Err(format!("[oauth.{}] needs a client_id", name).into());
+        }
+        let defaults = built_in(name);
+        let pick = |value: &Option<String>, default: Option<&str>, key: &str| {
+            value
+                .clone()
+                .or_else(|| default.map(str::to_string))
+                .ok_or_else(|| format!("[oauth.{}] needs {}", name, key))
+        };
+        let list = |value: &Option<Vec<String>>, default: Option<&[&str]>| {
+            value
+                .clone()
+                .or_else(|| default.map(|d| d.iter().map(|s| s.to_string()).collect()))
+                .unwrap_or_default()
+        };
+        let provider = Self {
+            name: name.to_string(),
+            client_id: settings.client_id.clone(),
+            client_secret: settings.client_secret.clone(),
+            device_url: pick(&settings.device_url, defaults.map(|d| d.0), "device_url")?,
+            token_url: pick(&settings.token_url, defaults.map(|d| d.1), "token_url")?,
+            scopes: list(&settings.scopes, defaults.map(|d| d.2)),
+            hosts: list(&settings.hosts, defaults.map(|d| d.3)),
+        };
+        if provider.hosts.is_empty() {
+            return Err(format!("[oauth.{}] needs the hosts to send its token to", name).into());
+        }
+        Ok(provider)
+    }
+
+    // Form fields that identify the client
+    fn client_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("client_id", self.client_id.clone())];
+        if let Some(secret) = &self.client_secret {
+            fields.push(("client_secret", secret.clone()));
+        }
+        fields
+    }
+}
+
+// A token as kept in the keyring
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: Option<String>,
+    // Seconds since the Unix epoch; None if the provider did not say
+    expires_at: Option<u64>,
+}
+
+impl Token {
+    fn due(&self, now: u64) -> bool {
+        self.refresh_token.is_some() && self.expires_at.is_some_and(|at| at <= now + REFRESH_MARGIN)
+    }
+}
+
+// A token endpoint's answer, or its error (RFC 6749 section 5)
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+impl TokenResponse {
+    fn into_token(self, previous_refresh: Option<String>) -> Option<Token> {
+        Some(Token {
+            access_token: self.access_token?,
+            // Providers that do not rotate refresh tokens leave it out
+            refresh_token: self.refresh_token.or(previous_refresh),
+            expires_at: self.expires_in.map(|secs| now() + secs),
+        })
+    }
+
+    fn error_message(&self) -> String {
+        match (&self.error, &self.error_description) {
+            (Some(error), Some(description)) => format!("{} ({})", description, error),
+            (Some(error), None) => error.clone(),
+            _ => "no access token in the response".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceResponse {
+    device_code: String,
+    user_code: String,
+    // Microsoft calls it verification_url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default)]
+    interval: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// `gator auth login`: the device authorization grant (RFC 8628). The user
+// signs in on any device with a browser while Gator polls for the token.
+pub async fn login(
+    clients: &HostClients,
+    name: &str,
+    oauth: &BTreeMap<String, OAuthConfig>,
+    out: Output,
+) -> Result<(), BoxError> {
+    let provider = Provider::from_config(name, oauth)?;
+    let mut fields = provider.client_fields();
+    fields.push(("scope", provider.scopes.join(" ")));
+    let client = clients.for_url(&provider.device_url);
+    let response = client.post(&provider.device_url).form(&fields).send().await?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "{} refused the sign-in request: {} {}",
+            provider.name,
+            response.status(),
+            response.text().await.unwrap_or_default().trim()
+        )
+        .into());
+    }
+    let device: DeviceResponse = serde_json::from_str(&response.text().await?)?;
+    match &device.verification_uri_complete {
+        Some(uri) => out.info(format!("To sign in to {}, open {}", provider.name, uri)),
+        None => out.info(format!(
+            "To sign in to {}, open {} and enter the code {}",
+            provider.name, device.verification_uri, device.user_code
+        )),
+    }
+    out.event(
+        "auth_code",
+        serde_json::json!({
+            "provider": provider.name,
+            "verification_uri": device.verification_uri,
+            "user_code": device.user_code,
+        }),
+    );
+
+    let mut interval = Duration::from_secs(device.interval.unwrap_or(5).max(1));
+    let deadline = std::time::Instant::now() + Duration::from_secs(device.expires_in);
+    let token = loop {
+        tokio::time::sleep(interval).await;
+        if std::time::Instant::now() >= deadline {
+            return Err("The code expired before sign-in finished; run `gator auth login` again".into());
+        }
+        let mut fields = provider.client_fields();
+        fields.push(("grant_type", "urn:ietf:params:oauth:grant-type:device_code".to_string()));
+        fields.push(("device_code", device.device_code.clone()));
+        let client = clients.for_url(&provider.token_url);
+        let response: TokenResponse =
+            serde_json::from_str(&client.post(&provider.token_url).form(&fields).send().await?.text().await?)?;
+        match response.error.as_deref() {
+            Some("authorization_pending") => continue,
+            Some("slow_down") => interval += Duration::from_secs(5),
+            Some("expired_token") => {
+                return Err("The code expired before sign-in finished; run `gator auth login` again".into())
+            }
+            Some("access_denied") => return Err(format!("Sign-in to {} was declined", provider.name).into()),
+            Some(_) => return Err(format!("Sign-in to {} failed: {}", provider.name, response.error_message()).into()),
+            None => {
+                let message = response.error_message();
+                break response
+                    .into_token(None)
+                    .ok_or_else(|| format!("Sign-in to {} failed: {}", provider.name, message))?;
+            }
+        }
+    };
+    let keyring = Keyring::detect();
+    keyring.store(&provider.name, &token)?;
+    out.info(format!("Signed in to {}; the token is kept in {}", provider.name, keyring));
+    Ok(())
+}
+
+// `gator auth logout`
+pub fn logout(name: &str, out: Output) -> Result<(), BoxError> {
+    let keyring = Keyring::detect();
+    match keyring.delete(name)? {
+        true => out.info(format!("Signed out of {}", name)),
+        false => out.info(format!("Not signed in to {}", name)),
+    }
+    Ok(())
+}
+
+// The tokens of the configured providers that have been signed in to, sent
+// as bearer tokens to their hosts and refreshed before they expire
+pub struct Tokens {
+    providers: Vec<(Provider, Mutex<Token>)>,
+    keyring: Keyring,
+}
+
+impl Tokens {
+    // None when no provider has been signed in to
+    pub fn load(oauth: &BTreeMap<String, OAuthConfig>) -> Result<Option<Arc<Self>>, BoxError> {
+        if oauth.is_empty() {
+            return Ok(None);
+        }
+        let keyring = Keyring::detect();
+        let mut providers = Vec::new();
+        for name in oauth.keys() {
+            let provider = Provider::from_config(name, oauth)?;
+            if let Some(token) = keyring.load(name)? {
+                providers.push((provider, Mutex::new(token)));
+            }
+        }
+        Ok((!providers.is_empty()).then(|| Arc::new(Self { providers, keyring })))
+    }
+
+    // Refresh the tokens that expire soon. A token that cannot be refreshed
+    // is kept, and its requests will likely be refused, so that is worth a
+    // warning rather than stopping downloads from other hosts.
+    pub async fn refresh_due(&self, clients: &HostClients, out: Output) {
+        for (provider, token) in &self.providers {
+            let current = token.lock().unwrap().clone();
+            if !current.due(now()) {
+                continue;
+            }
+            match refresh(clients, provider, &current).await {
+                Ok(fresh) => {
+                    if let Err(e) = self.keyring.store(&provider.name, &fresh) {
+                        out.info(format!("Warning: cannot save the refreshed {} token: {}", provider.name, e));
+                    }
+                    *token.lock().unwrap() = fresh;
+                }
+                Err(e) => out.info(format!(
+                    "Warning: cannot refresh the {} token ({}); sign in again with `gator auth login {}`",
+                    provider.name, e, provider.name
+                )),
+            }
+        }
+    }
+
+    // Keep refreshing tokens in the background for as long as the run lasts
+    pub fn keep_fresh(self: Arc<Self>, clients: HostClients, out: Output) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CHECK_EVERY).await;
+                self.refresh_due(&clients, out).await;
+            }
+        });
+    }
+}
+
+async fn refresh(clients: &HostClients, provider: &Provider, token: &Token) -> Result<Token, BoxError> {
+    let client = clients.for_url(&provider.token_url);
+    let mut fields = provider.client_fields();
+    fields.push(("grant_type", "refresh_token".to_string()));
+    fields.push(("refresh_token", token.refresh_token.clone().unwrap_or_default()));
+    let response: TokenResponse =
+        serde_json::from_str(&client.post(&provider.token_url).form(&fields).send().await?.text().await?)?;
+    let message = response.error_message();
+    response.into_token(token.refresh_token.clone()).ok_or_else(|| message.into())
+}
+
+// The token of the most specific provider whose hosts match
+impl Hook for Tokens {
+    fn request_headers(&self, url: &str) -> Result<Vec<(String, String)>, BoxError> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()));
+        let Some(host) = host else {
+            return Ok(Vec::new());
+        };
+        let best = self
+            .providers
+            .iter()
+            .filter_map(|(provider, token)| {
+                let pattern = provider
+                    .hosts
+                    .iter()
+                    .filter(|pattern| host_matches(&pattern.to_ascii_lowercase(), &host))
+                    .max_by_key(|pattern| pattern.trim_matches('*').len())?;
+                Some((pattern.trim_matches('*').len(), token))
+            })
+            .max_by_key(|(specificity, _)| *specificity);
+        Ok(best
+            .map(|(_, token)| {
+                let bearer = format!("Bearer {}", token.lock().unwrap().access_token);
+                vec![("Authorization".to_string(), bearer)]
+            })
+            .unwrap_or_default())
+    }
+}
+
+// Where tokens are kept: the Secret Service (GNOME Keyring, KWallet) through
+// `secret-tool`, the macOS keychain through `security`, or else a file only
+// the user can read next to the config file
+enum Keyring {
+    SecretTool,
+    Keychain,
+    File(PathBuf),
+}
+
+impl std::fmt::Display for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SecretTool => f.write_str("the system keyring"),
+            Self::Keychain => f.write_str("the keychain"),
+            Self::File(dir) => write!(f, "{} (no keyring was found)", dir.display()),
+        }
+    }
+}
+
+impl Keyring {
+    fn detect() -> Self {
+        if cfg!(target_os = "macos") {
+            return Self::Keychain;
+        }
+        let secret_tool = std::env::var_os("PATH")
+            .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join("secret-tool").is_file()));
+        match secret_tool {
+            true => Self::SecretTool,
+            false => Self::File(
+                config::default_path()
+                    .and_then(|p| p.parent().map(|dir| dir.join("tokens")))
+                    .unwrap_or_else(|| PathBuf::from("gator-tokens")),
+            ),
+        }
+    }
+
+    fn store(&self, name: &str, token: &Token) -> Result<(), BoxError> {
+        let secret = serde_json::to_string(token)?;
+        match self {
+            Self::SecretTool => {
+                let label = format!("Gator OAuth token ({})", name);
+                let mut child = Command::new("secret-tool")
+                    .args(["store", "--label", &label, "service", "gator", "provider", name])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                // The secret goes in on stdin, never on the command line
+                child.stdin.take().expect("piped stdin").write_all(secret.as_bytes())?;
+                check(child.wait_with_output()?, "secret-tool store")
+            }
+            // `security` only takes the password as an argument; -U updates
+            // an existing item
+            Self::Keychain => check(
+                Command::new("security")
+                    .args(["add-generic-password", "-U", "-s", "gator", "-a", name, "-w", &secret])
+                    .output()?,
+                "security add-generic-password",
+            ),
+            Self::File(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let mut file = gator::paths::create_private(&dir.join(format!("{}.json", name)))?;
+                Ok(file.write_all(secret.as_bytes())?)
+            }
+        }
+    }
+
+    fn load(&self, name: &str) -> Result<Option<Token>, BoxError> {
+        let secret = match self {
+            Self::SecretTool => Command::new("secret-tool")
+                .args(["lookup", "service", "gator", "provider", name])
+                .stderr(Stdio::null())
+                .output()?,
+            Self::Keychain => Command::new("security")
+                .args(["find-generic-password", "-s", "gator", "-a", name, "-w"])
+                .stderr(Stdio::null())
+                .output()?,
+            Self::File(dir) => {
+                return match std::fs::read_to_string(dir.join(format!("{}.json", name))) {
+                    Ok(secret) => Ok(Some(serde_json::from_str(&secret)?)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e.into()),
+                };
+            }
+        };
+        // Both tools fail when there is no such item
+        if !secret.status.success() || secret.stdout.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&secret.stdout)?))
+    }
+
+    // Whether there was a token to delete
+    fn delete(&self, name: &str) -> Result<bool, BoxError> {
+        if self.load(name)?.is_none() {
+            return Ok(false);
+        }
+        match self {
+            Self::SecretTool => check(
+                Command::new("secret-tool")
+                    .args(["clear", "service", "gator", "provider", name])
+                    .output()?,
+                "secret-tool clear",
+            )?,
+            Self::Keychain => check(
+                Command::new("security")
+                    .args(["delete-generic-password", "-s", "gator", "-a", name])
+                    .output()?,
+                "security delete-generic-password",
+            )?,
+            Self::File(dir) => std::fs::remove_file(dir.join(format!("{}.json", name)))?,
+        }
+        Ok(true)
+    }
+}
+
+fn check(output: std::process::Output, what: &str) -> Result<(), BoxError> {
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(format!("{} failed: {}", what, String::from_utf8_lossy(&output.stderr).trim()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_go_to_their_provider_hosts() {
+        let mut oauth = BTreeMap::new();
+        oauth.insert(
+            "microsoft".to_string(),
+            OAuthConfig {
+                client_id: "app".to_string(),
+                ..OAuthConfig::default()
+            },
+        );
+        oauth.insert(
+            "corp".to_string(),
+            OAuthConfig {
+                client_id: "app".to_string(),
+                device_url: Some("https://login.corp.example/device".to_string()),
+                token_url: Some("https://login.corp.example/token".to_string()),
+                hosts: Some(vec!["*.corp.example".to_string()]),
+                ..OAuthConfig::default()
+            },
+        );
+        let microsoft = Provider::from_config("microsoft", &oauth).unwrap();
+        assert_eq!(microsoft.hosts, ["graph.microsoft.com"]);
+        assert!(Provider::from_config("google", &oauth).is_err());
+
+        let token = |access: &str, expires_at| Token {
+            access_token: access.to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at,
+        };
+        let tokens = Tokens {
+            providers: vec![
+                (microsoft, Mutex::new(token("ms", Some(10_000)))),
+                (Provider::from_config("corp", &oauth).unwrap(), Mutex::new(token("corp", None))),
+            ],
+            keyring: Keyring::File(PathBuf::new()),
+        };
+        assert_eq!(
+            tokens.request_headers("https://graph.microsoft.com/v1.0/me/drive").unwrap(),
+            [("Authorization".to_string(), "Bearer ms".to_string())]
+        );
+        assert_eq!(tokens.request_headers("https://files.corp.example/a").unwrap()[0].1, "Bearer corp");
+        assert!(tokens.request_headers("https://example.com/a").unwrap().is_empty());
+
+        // Due a few minutes before it expires
+        assert!(!token("ms", Some(10_000)).due(10_000 - REFRESH_MARGIN - 1));
+        assert!(token("ms", Some(10_000)).due(10_000 - REFRESH_MARGIN));
+        assert!(!token("corp", None).due(u64::MAX / 2));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::digest;
+use crate::paths;
+use crate::urls;
+use clap::ValueEnum;
+use reqwest::Url;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Order {
+    /// In the order the files were found
+    Input,
+    /// Smallest files first
+    SizeAsc,
+    /// Largest files first
+    SizeDesc,
+}
+
+// One file of a multi-file download, with its path relative to the output directory
+#[derive(Debug, Clone)]
+pub struct BatchItem {
+    pub url: String,
+    pub relative: PathBuf,
+    // Checksums published for the file, if its source lists any
+    pub digests: Vec<digest::Expected>,
+}
+
+// Mirror a URL's path below the output directory, e.g. `/docs/a/` becomes
+// `docs/a/index.html`. Paths under `root` (a directory path such as `/pub/`)
+// are taken relative to it, so crawled files and their requisites line up.
+pub fn relative_path_for_url(url: &Url, root: &str) -> PathBuf {
+    relative_path_with(url, root, paths::sanitize_file_name)
+}
+
+// `relative_path_for_url` with the rules of one platform
+fn relative_path_with(url: &Url, root: &str, sanitize: fn(&str) -> String) -> PathBuf {
+    let mut relative = PathBuf::new();
+    let path = url.path().strip_prefix(root).unwrap_or(url.path());
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in &segments {
+        relative.push(sanitize(&urls::decode_segment(segment)));
+    }
+    if path.ends_with('/') || segments.is_empty() {
+        relative.push("index.html");
+    }
+    relative
+}
+
+// The relative paths handed out in one batch. Where the file system ignores
+// case (Windows, macOS), `A.txt` and `a.txt` are one file, so the later one
+// becomes `a (1).txt` rather than overwriting the first.
+pub struct UniquePaths {
+    case_insensitive: bool,
+    used: HashSet<String>,
+}
+
+impl UniquePaths {
+    pub fn new(case_insensitive: bool) -> Self {
+        Self {
+            case_insensitive,
+            used: HashSet::new(),
+        }
+    }
+
+    // `relative`, or the first ` (N)` variant of it not yet taken
+    pub fn claim(&mut self, relative: PathBuf) -> PathBuf {
+        let mut candidate = relative.clone();
+        let mut n = 0;
+        while !self.used.insert(self.key(&candidate)) {
+            n += 1;
+            let name = match (relative.file_stem(), relative.extension()) {
+                (Some(stem), Some(extension)) => {
+                    format!("{} ({}).{}", stem.to_string_lossy(), n, extension.to_string_lossy())
+                }
+                _ => format!("{} ({})", relative.file_name().unwrap_or_default().to_string_lossy(), n),
+            };
+            candidate = relative.with_file_name(name);
+        }
+        candidate
+    }
+
+    fn key(&self, relative: &Path) -> String {
+        let key = relative.to_string_lossy();
+        if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key.into_owned()
+        }
+    }
+}
+
+// Sort items by their probed size for `--order`. Files of unknown size go
+// last either way; ties keep their input order.
+pub fn sort_by_size(items: Vec<BatchItem>, sizes: Vec<Option<u64>>, order: Order) -> Vec<BatchItem> {
+    let mut sized: Vec<(Option<u64>, BatchItem)> = sizes.into_iter().zip(items).collect();
+    match order {
+        Order::Input => {}
+        Order::SizeAsc => sized.sort_by_key(|(size, _)| (size.is_none(), *size)),
+        Order::SizeDesc => sized.sort_by_key(|(size, _)| (size.is_none(), size.map(Reverse))),
+    }
+    sized.into_iter().map(|(_, item)| item).collect()
+}
+
+// The directory part of a URL's path, used as the root for relative layouts
+pub fn root_dir(url: &Url) -> String {
+    let path = url.path();
+    path[..=path.rfind('/').unwrap_or(0)].to_string()
+}
+
+// Items waiting to download, handed out round-robin by host so 50 files from
+// one origin cannot starve 5 from another, optionally with a cap on how many
+// files from one host are in flight at once
+pub struct HostQueue {
+    hosts: VecDeque<(String, VecDeque<BatchItem>)>,
+    in_flight: HashMap<String, usize>,
+    max_per_host: Option<usize>,
+    len: usize,
+}
+
+impl HostQueue {
+    pub fn new(max_per_host: Option<usize>) -> Self {
+        Self {
+            hosts: VecDeque::new(),
+            in_flight: HashMap::new(),
+            max_per_host,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: BatchItem) {
+        let host = host_key(&item.url);
+        match self.hosts.iter_mut().find(|(h, _)| *h == host) {
+            Some((_, items)) => items.push_back(item),
+            None => self.hosts.push_back((host, VecDeque::from([item]))),
+        }
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The next item of the first host in the rotation that is below its cap.
+    // None when the queue is empty or every waiting host is at its cap.
+    pub fn pop(&mut self) -> Option<BatchItem> {
+        let index = self.hosts.iter().position(|(host, _)| {
+            let busy = self.in_flight.get(host).copied().unwrap_or(0);
+            self.max_per_host.is_none_or(|max| busy < max)
+        })?;
+        let (host, mut items) = self.hosts.remove(index)?;
+        let item = items.pop_front()?;
+        *self.in_flight.entry(host.clone()).or_insert(0) += 1;
+        // The host goes to the back of the rotation
+        if !items.is_empty() {
+            self.hosts.push_back((host, items));
+        }
+        self.len -= 1;
+        Some(item)
+    }
+
+    // Take every waiting item, in the order `pop` would have handed them out
+    // without host caps
+    pub fn drain(&mut self) -> Vec<BatchItem> {
+        let mut items = Vec::with_capacity(self.len);
+        while let Some((host, mut waiting)) = self.hosts.pop_front() {
+            if let Some(item) = waiting.pop_front() {
+                items.push(item);
+            }
+            if !waiting.is_empty() {
+                self.hosts.push_back((host, waiting));
+            }
+        }
+        self.len = 0;
+        items
+    }
+
+    // Release the slot of an item returned by `pop`
+    pub fn finished(&mut self, item: &BatchItem) {
+        if let Some(busy) = self.in_flight.get_mut(&host_key(&item.url)) {
+            *busy = busy.saturating_sub(1);
+        }
+    }
+}
+
+fn host_key(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => format!(
+            "{}:{}",
+            url.host_str().unwrap_or(""),
+            url.port_or_known_default().unwrap_or(0)
+        ),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(url: &str) -> BatchItem {
+        BatchItem {
+            url: url.to_string(),
+            relative: PathBuf::from(url.rsplit('/').next().unwrap()),
+            digests: Vec::new(),
+        }
+    }
+
+    fn urls(items: &[BatchItem]) -> Vec<&str> {
+        items.iter().map(|item| item.url.as_str()).collect()
+    }
+
+    #[test]
+    fn hosts_take_turns_within_their_cap() {
+        let mut queue = HostQueue::new(Some(2));
+        for url in [
+            "https://a.example/1",
+            "https://a.example/2",
+            "https://a.example/3",
+            "https://a.example:8443/4",
+            "https://b.example/5",
+        ] {
+            queue.push(item(url));
+        }
+        assert_eq!(queue.len(), 5);
+
+        // Round-robin by host, where another port is another host
+        let mut running = Vec::new();
+        while let Some(item) = queue.pop() {
+            running.push(item);
+        }
+        assert_eq!(
+            urls(&running),
+            ["https://a.example/1", "https://a.example:8443/4", "https://b.example/5", "https://a.example/2"]
+        );
+        // a.example holds both of its slots, so its third file waits
+        assert_eq!(queue.len(), 1);
+        assert!(queue.pop().is_none());
+        queue.finished(&running[1]);
+        assert!(queue.pop().is_none());
+        queue.finished(&running[0]);
+        assert_eq!(queue.pop().map(|item| item.url), Some("https://a.example/3".to_string()));
+        assert!(queue.is_empty());
+
+        // Without a cap every item is handed out at once
+        let mut queue = HostQueue::new(None);
+        for item in running {
+            queue.push(item);
+        }
+        assert_eq!(std::iter::from_fn(|| queue.pop()).count(), 4);
+    }
+
+    #[test]
+    fn sizes_order_files_and_unknown_sizes_go_last() {
+        let items: Vec<BatchItem> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|name| item(&format!("https://x.example/{}", name)))
+            .collect();
+        let sizes = vec![Some(300), None, Some(100), Some(300), None];
+        let names = |order| -> Vec<String> {
+            sort_by_size(items.clone(), sizes.clone(), order)
+                .into_iter()
+                .map(|item| item.relative.display().to_string())
+                .collect()
+        };
+        assert_eq!(names(Order::Input), ["a", "b", "c", "d", "e"]);
+        assert_eq!(names(Order::SizeAsc), ["c", "a", "d", "b", "e"]);
+        assert_eq!(names(Order::SizeDesc), ["a", "d", "c", "b", "e"]);
+    }
+
+    #[test]
+    fn mirrored_paths_are_safe_on_windows() {
+        let windows = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            relative_path_with(&url, "/pub/", paths::sanitize_windows)
+        };
+        // Reserved device names, trailing dots and spaces, and characters
+        // Windows refuses, in directories and file names alike
+        assert_eq!(windows("https://x.example/pub/con/aux.txt"), Path::new("_con").join("_aux.txt"));
+        assert_eq!(
+            windows("https://x.example/pub/notes./v1.%20/readme.txt."),
+            Path::new("notes").join("v1").join("readme.txt")
+        );
+        assert_eq!(windows("https://x.example/pub/a%3Ab/what%3F.html"), Path::new("a_b").join("what_.html"));
+        assert_eq!(windows("https://x.example/pub/docs/"), Path::new("docs").join("index.html"));
+
+        // A deep mirror needs the long-path prefix below its base directory
+        let deep = windows(&format!("https://x.example/pub/{}/file.bin", ["segment"; 40].join("/")));
+        let full = format!(r"C:\mirror\{}", deep.to_string_lossy().replace('/', "\\"));
+        assert!(paths::windows_long_path(&full).unwrap().starts_with(r"\\?\C:\mirror\segment\"));
+        let short = format!(r"C:\mirror\{}", windows("https://x.example/pub/a.txt").display());
+        assert_eq!(paths::windows_long_path(&short), None);
+    }
+
+    #[test]
+    fn paths_differing_only_in_case_get_a_suffix_where_case_is_ignored() {
+        let relative = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            relative_path_with(&url, "/", paths::sanitize_windows)
+        };
+        let mut names = UniquePaths::new(true);
+        assert_eq!(names.claim(relative("https://x.example/docs/A.txt")), Path::new("docs").join("A.txt"));
+        assert_eq!(names.claim(relative("https://x.example/docs/a.txt")), Path::new("docs").join("a (1).txt"));
+        assert_eq!(names.claim(relative("https://x.example/Docs/a.TXT")), Path::new("Docs").join("a (2).TXT"));
+        assert_eq!(names.claim(relative("https://x.example/docs/b.txt")), Path::new("docs").join("b.txt"));
+        // Sanitizing can make two names one, too
+        assert_eq!(names.claim(relative("https://x.example/q%3F")), Path::new("q_"));
+        assert_eq!(names.claim(relative("https://x.example/q%2A")), Path::new("q_ (1)"));
+
+        let mut names = UniquePaths::new(false);
+        assert_eq!(names.claim(PathBuf::from("A.txt")), Path::new("A.txt"));
+        assert_eq!(names.claim(PathBuf::from("a.txt")), Path::new("a.txt"));
+        assert_eq!(names.claim(PathBuf::from("a.txt")), Path::new("a (1).txt"));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::download::{DownloadOptions, Downloader};
+use crate::protocol::BoxError;
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+
+// Download `url` to `path` without an async runtime of your own, for build
+// scripts and synchronous tools. Returns where the file was saved. Progress
+// observers registered on `options.progress` are called as usual, from the
+// download's threads.
+pub fn download(url: &str, path: impl AsRef<Path>, options: DownloadOptions) -> Result<PathBuf, BoxError> {
+    download_until_cancelled(url, path.as_ref(), options, CancellationToken::new())
+}
+
+// `download` that stops with a `Cancelled` error when `cancel` fires, e.g.
+// from a UI thread
+pub fn download_until_cancelled(
+    url: &str,
+    path: &Path,
+    options: DownloadOptions,
+    cancel: CancellationToken,
+) -> Result<PathBuf, BoxError> {
+    let url = url.to_string();
+    let path = path.to_path_buf();
+    // Like reqwest::blocking, the runtime gets a thread of its own, so this
+    // also works when called from async code (where blocking on a runtime
+    // from the caller's thread would panic)
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+        runtime.block_on(async {
+            let downloader = Downloader::new()?.with_options(options);
+            downloader.run(&url, &path, &cancel).await
+        })
+    })
+    .join()
+    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("file.bin")
+    }
+
+    #[test]
+    fn downloads_without_a_runtime() {
+        // The server needs one; the download does not use it
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::start(12 * 1024 * 1024, Behavior::default())).unwrap();
+
+        let path = temp_path("blocking");
+        let saved = download(&server.url("file.bin"), &path, DownloadOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&saved).unwrap(), server.data());
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn downloads_from_async_code() {
+        let server = MockServer::start(100_000, Behavior::default()).await.unwrap();
+
+        let path = temp_path("blocking-async");
+        let saved = download(&server.url("file.bin"), &path, DownloadOptions::default()).unwrap();
+        assert_eq!(std::fs::read(&saved).unwrap(), server.data());
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
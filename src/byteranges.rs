@@ -0,0 +1,122 @@
+use crate::protocol::{self, BoxError};
+use bytes::Bytes;
+
+// Answers to a request for several ranges at once (RFC 9110, section 14.6):
+// a `multipart/byteranges` body with a part per range, each carrying its own
+// Content-Range, or a single range when the server merged them into one.
+
+// A range the server sent: the offset of its first byte and its bytes
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub start: u64,
+    pub data: Bytes,
+}
+
+// The boundary of a `multipart/byteranges` Content-Type
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let (kind, params) = content_type.split_once(';')?;
+    if !kind.trim().eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"'))
+    })
+}
+
+// The parts of a multipart body. A part's length comes from its
+// Content-Range, so bytes that happen to look like the boundary cannot cut
+// it short.
+pub fn parse(body: &Bytes, boundary: &str) -> Result<Vec<Part>, BoxError> {
+    let delimiter = format!("--{}", boundary);
+    let mut pos = find(body, delimiter.as_bytes(), 0).ok_or("No multipart boundary in the response")?;
+    let mut parts = Vec::new();
+    loop {
+        pos += delimiter.len();
+        // The closing delimiter has two more dashes
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        let headers_end = find(body, b"\r\n\r\n", pos).ok_or("Multipart part without a blank line after its headers")?;
+        let headers = String::from_utf8_lossy(&body[pos..headers_end]);
+        let range = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim().eq_ignore_ascii_case("content-range").then(|| value.trim())
+            })
+            .ok_or("Multipart part without a Content-Range")?;
+        let (first, last) =
+            protocol::parse_content_range(range).ok_or_else(|| format!("Malformed Content-Range: {}", range))?;
+        let start = headers_end + 4;
+        let end = protocol::range_len(first, last)
+            .and_then(|len| usize::try_from(len).ok())
+            .and_then(|len| start.checked_add(len))
+            .filter(|&end| end <= body.len())
+            .ok_or_else(|| format!("Multipart part {} is cut short", range))?;
+        parts.push(Part {
+            start: first,
+            data: body.slice(start..end),
+        });
+        pos = find(body, delimiter.as_bytes(), end).ok_or("Multipart body without a closing boundary")?;
+    }
+}
+
+// The bytes of each of `ranges` (first and last byte), in order, out of the
+// parts the server sent. A part may hold several ranges that were merged.
+pub fn slice(parts: &[Part], ranges: &[(u64, u64)]) -> Result<Vec<Bytes>, BoxError> {
+    ranges
+        .iter()
+        .map(|&(first, last)| {
+            parts
+                .iter()
+                .find(|part| part.start <= first && first <= last && last - part.start < part.data.len() as u64)
+                .map(|part| part.data.slice((first - part.start) as usize..=(last - part.start) as usize))
+                .ok_or_else(|| format!("Server did not send bytes {}-{}", first, last).into())
+        })
+        .collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|at| from + at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_are_sliced_into_the_ranges_asked_for() {
+        let content_type = "multipart/byteranges; boundary=\"3d6b6a416f9b5\"";
+        assert_eq!(boundary(content_type), Some("3d6b6a416f9b5"));
+        assert_eq!(boundary("application/octet-stream"), None);
+
+        // The second part holds two ranges the server merged, and a byte of
+        // data that looks like the start of a boundary
+        let body = Bytes::from_static(
+            b"\r\n--3d6b6a416f9b5\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-4/100\r\n\r\nhello\r\n\
+              --3d6b6a416f9b5\r\nContent-Range: bytes 10-19/100\r\n\r\n--3d6b6a41\r\n--3d6b6a416f9b5--\r\n",
+        );
+        let parts = parse(&body, "3d6b6a416f9b5").unwrap();
+        assert_eq!(parts.len(), 2);
+        let ranges = slice(&parts, &[(0, 4), (10, 13), (14, 19)]).unwrap();
+        assert_eq!(ranges, [&b"hello"[..], b"--3d", b"6b6a41"]);
+        assert!(slice(&parts, &[(5, 9)]).is_err());
+
+        let cut = Bytes::from_static(b"--b\r\nContent-Range: bytes 0-9/10\r\n\r\nshort");
+        assert!(parse(&cut, "b").is_err());
+        // A range too long to count is refused, not wrapped around
+        let huge = Bytes::from_static(b"--b\r\nContent-Range: bytes 0-18446744073709551615/*\r\n\r\nx\r\n--b--\r\n");
+        assert!(parse(&huge, "b").is_err());
+        let far = [Part {
+            start: u64::MAX,
+            data: Bytes::from_static(b"x"),
+        }];
+        assert!(slice(&far, &[(u64::MAX, u64::MAX)]).is_ok());
+        assert!(slice(&far, &[(0, 1)]).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use crate::protocol::{BoxError, ByteStream, Probe, ProtocolHandler, Trailers};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// Faults to inject for `--chaos`, to reproduce bug reports from flaky networks
+// and full disks. Parsed from e.g. "delay=20%:2s,reset=5%,error=5%,disk-full=64M".
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    // Percentage of requests held back, and for how long
+    delay: Option<(u64, Duration)>,
+    // Percentage of requests whose response is cut off partway
+    reset: u64,
+    // Percentage of requests that fail before sending anything
+    error: u64,
+    // Writes that reach this offset fail as if the disk were full
+    disk_full: Option<u64>,
+    seed: u64,
+}
+
+// clap value parser for `--chaos`
+pub fn parse(spec: &str) -> Result<Chaos, String> {
+    let mut chaos = Chaos::default();
+    for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let (name, value) = item
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form name=value", item))?;
+        match name.trim() {
+            "delay" => {
+                let (percent, duration) = value
+                    .split_once(':')
+                    .ok_or_else(|| format!("delay needs PERCENT%:DURATION, e.g. 20%:2s, not '{}'", value))?;
+                chaos.delay = Some((parse_percent(percent)?, parse_duration(duration)?));
+            }
+            "reset" => chaos.reset = parse_percent(value)?,
+            "error" => chaos.error = parse_percent(value)?,
+            "disk-full" => chaos.disk_full = Some(parse_size(value)?),
+            other => {
+                return Err(format!(
+                    "unknown fault '{}' (expected delay, reset, error or disk-full)",
+                    other
+                ))
+            }
+        }
+    }
+    Ok(chaos)
+}
+
+impl Chaos {
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+
+    // A number in 0..100 that depends only on the seed, the request and the
+    // kind of fault, so a run can be repeated exactly however the workers
+    // happen to be scheduled
+    fn roll(&self, url: &str, start: u64, end: Option<u64>, fault: u64) -> u64 {
+        let mut hash = self.seed ^ fault.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        for byte in url.bytes() {
+            hash = mix(hash ^ u64::from(byte));
+        }
+        hash = mix(hash ^ start);
+        hash = mix(hash ^ end.map_or(u64::MAX, |e| e));
+        hash % 100
+    }
+}
+
+// SplitMix64's finalizer
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn parse_percent(value: &str) -> Result<u64, String> {
+    let percent = value
+        .trim()
+        .trim_end_matches('%')
+        .parse::<u64>()
+        .map_err(|_| format!("'{}' is not a percentage", value))?;
+    if percent > 100 {
+        return Err(format!("'{}' is more than 100%", value));
+    }
+    Ok(percent)
+}
+
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len()));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration like 500ms or 2s", value))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" | "" => Ok(Duration::from_secs(number)),
+        _ => Err(format!("'{}' is not a duration like 500ms or 2s", value)),
+    }
+}
+
+// Bytes, or with a K/M/G suffix
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("'{}' is not a size like 64M", value))
+}
+
+// The faults apply to the whole run, including the disk writes made far from
+// any protocol handler
+static ACTIVE: OnceLock<Chaos> = OnceLock::new();
+
+pub fn install(chaos: Chaos) {
+    let _ = ACTIVE.set(chaos);
+}
+
+// Fail a write of `len` bytes at `offset` if it would reach the disk-full offset
+pub fn check_write(offset: u64, len: usize) -> std::io::Result<()> {
+    match ACTIVE.get().and_then(|c| c.disk_full) {
+        Some(limit) if offset + len as u64 > limit => Err(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            format!("No space left on device (injected by --chaos at byte {})", limit),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Wraps a handler to delay, cut off or fail its data requests. Probes are
+// passed through untouched, so the download is planned as usual.
+pub struct ChaosHandler {
+    inner: Arc<dyn ProtocolHandler>,
+}
+
+impl ChaosHandler {
+    pub fn wrap(inner: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler> {
+        Arc::new(Self { inner })
+    }
+}
+
+impl ProtocolHandler for ChaosHandler {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Probe, BoxError>> {
+        self.inner.probe(url)
+    }
+
+    fn open<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        self.open_with_trailers(url, start, end, Trailers::default())
+    }
+
+    fn open_with_trailers<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        trailers: Trailers,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(inject(url, start, end, self.inner.open_with_trailers(url, start, end, trailers)))
+    }
+
+    fn open_prioritized<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        urgency: u8,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(inject(url, start, end, self.inner.open_prioritized(url, start, end, urgency)))
+    }
+
+    fn fetch_ranges<'a>(
+        &'a self,
+        url: &'a str,
+        ranges: &'a [(u64, u64)],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, BoxError>> {
+        let fetch = self.inner.fetch_ranges(url, ranges);
+        match ACTIVE.get() {
+            Some(chaos) => Box::pin(inject_ranges(chaos, url, ranges, fetch)),
+            None => fetch,
+        }
+    }
+
+    fn list<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<crate::listing::Entry>, BoxError>> {
+        self.inner.list(url)
+    }
+}
+
+// Delay or fail a request before it is sent
+async fn before_request(chaos: &Chaos, url: &str, start: u64, end: Option<u64>) -> Result<(), BoxError> {
+    if let Some((percent, delay)) = chaos.delay {
+        if chaos.roll(url, start, end, 1) < percent {
+            tokio::time::sleep(delay).await;
+        }
+    }
+    if chaos.roll(url, start, end, 2) < chaos.error {
+        return Err(format!("Injected error for bytes {} (--chaos)", span(start, end)).into());
+    }
+    Ok(())
+}
+
+fn span(start: u64, end: Option<u64>) -> String {
+    match end {
+        Some(end) => format!("{}-{}", start, end),
+        None => format!("{}-", start),
+    }
+}
+
+// The faults of `inject` for a multi-range request, rolled for the bytes from
+// its first range to its last. A multipart response is read whole, so a reset
+// loses all of it.
+async fn inject_ranges(
+    chaos: &Chaos,
+    url: &str,
+    ranges: &[(u64, u64)],
+    fetch: BoxFuture<'_, Result<Vec<Bytes>, BoxError>>,
+) -> Result<Vec<Bytes>, BoxError> {
+    let (Some(&(start, _)), Some(&(_, end))) = (ranges.first(), ranges.last()) else {
+        return fetch.await;
+    };
+    before_request(chaos, url, start, Some(end)).await?;
+    let fetched = fetch.await?;
+    if chaos.roll(url, start, Some(end), 3) < chaos.reset {
+        return Err(format!("Injected connection reset for bytes {} (--chaos)", span(start, Some(end))).into());
+    }
+    Ok(fetched)
+}
+
+// Delay or fail a data request before `open` sends it, or cut off its response
+async fn inject(
+    url: &str,
+    start: u64,
+    end: Option<u64>,
+    open: BoxFuture<'_, Result<ByteStream, BoxError>>,
+) -> Result<ByteStream, BoxError> {
+    let Some(chaos) = ACTIVE.get() else {
+        return open.await;
+    };
+    before_request(chaos, url, start, end).await?;
+    let stream = open.await?;
+    if chaos.roll(url, start, end, 3) >= chaos.reset {
+        return Ok(stream);
+    }
+
+    // Cut off somewhere in the first half of the response
+    let span = span(start, end);
+    let len = end.map_or(1024 * 1024, |end| end - start + 1);
+    let cut = len * chaos.roll(url, start, end, 4) / 200;
+    let mut sent = 0u64;
+    let cut_off = stream
+        .scan(false, move |done, chunk| {
+            if *done {
+                return futures::future::ready(None);
+            }
+            let item = match chunk {
+                Ok(chunk) if sent + chunk.len() as u64 > cut => {
+                    *done = true;
+                    Err(format!("Injected connection reset for bytes {} at byte {} (--chaos)", span, start + cut).into())
+                }
+                chunk => chunk,
+            };
+            if let Ok(chunk) = &item {
+                sent += chunk.len() as u64;
+            }
+            futures::future::ready(Some(item))
+        });
+    Ok(Box::pin(cut_off) as ByteStream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_are_parsed() {
+        let chaos = parse("delay=20%:500ms, reset=5%,error=1,disk-full=64M").unwrap();
+        assert_eq!(chaos.delay, Some((20, Duration::from_millis(500))));
+        assert_eq!(chaos.reset, 5);
+        assert_eq!(chaos.error, 1);
+        assert_eq!(chaos.disk_full, Some(64 << 20));
+        assert_eq!(parse("delay=100%:2s").unwrap().delay, Some((100, Duration::from_secs(2))));
+    }
+
+    #[test]
+    fn bad_specs_are_rejected() {
+        for spec in ["reset", "reset=101%", "delay=5%", "delay=5%:2h", "disk-full=lots", "drop=5%"] {
+            assert!(parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn rolls_depend_only_on_seed_and_request() {
+        let chaos = Chaos::default().with_seed(7);
+        let url = "https://example.com/file.iso";
+        let rolls: Vec<u64> = (0..64).map(|i| chaos.roll(url, i << 20, Some(((i + 1) << 20) - 1), 3)).collect();
+        let again: Vec<u64> = (0..64).map(|i| chaos.roll(url, i << 20, Some(((i + 1) << 20) - 1), 3)).collect();
+        assert_eq!(rolls, again);
+        assert!(rolls.iter().all(|&r| r < 100));
+
+        let other = Chaos::default().with_seed(8);
+        let reseeded: Vec<u64> = (0..64).map(|i| other.roll(url, i << 20, Some(((i + 1) << 20) - 1), 3)).collect();
+        assert_ne!(rolls, reseeded);
+    }
+
+    #[tokio::test]
+    async fn multi_range_requests_get_the_same_faults() {
+        let url = "https://example.com/file.iso";
+        let ranges = [(0, 99), (200, 299)];
+        let fetch = || -> BoxFuture<'static, Result<Vec<Bytes>, BoxError>> {
+            Box::pin(async { Ok(vec![Bytes::from(vec![0; 100]), Bytes::from(vec![1; 100])]) })
+        };
+
+        let calm = Chaos::default();
+        assert_eq!(inject_ranges(&calm, url, &ranges, fetch()).await.unwrap().len(), 2);
+        let failing = parse("error=100%").unwrap();
+        let error = inject_ranges(&failing, url, &ranges, fetch()).await.unwrap_err();
+        assert!(error.to_string().contains("bytes 0-299"), "{}", error);
+        let resetting = parse("reset=100%").unwrap();
+        let error = inject_ranges(&resetting, url, &ranges, fetch()).await.unwrap_err();
+        assert!(error.to_string().contains("connection reset"), "{}", error);
+        // Nothing to roll for
+        assert!(inject_ranges(&failing, url, &[], fetch()).await.is_ok());
+    }
+}
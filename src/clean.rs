@@ -0,0 +1,68 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// A part file left behind by a download that was never finished
+#[derive(Debug)]
+pub struct Stale {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub age: Duration,
+}
+
+// Part files below `dir` (including subdirectories, without following links)
+// that nothing has written to for at least `min_age`. A download in progress
+// keeps its part file's modification time current, so it is never listed.
+pub fn find_stale(dir: &Path, min_age: Duration) -> io::Result<Vec<Stale>> {
+    let now = SystemTime::now();
+    let mut stale = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            if !metadata.is_file() || !entry.file_name().to_string_lossy().ends_with(".part") {
+                continue;
+            }
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if age >= min_age {
+                stale.push(Stale {
+                    path: entry.path(),
+                    bytes: metadata.len(),
+                    age,
+                });
+            }
+        }
+    }
+    stale.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn only_old_part_files_are_stale() {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-clean", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(10 * 86400);
+        for name in ["old.iso.part", "sub/old.bin.abc.part", "old.iso"] {
+            fs::write(dir.join(name), b"data").unwrap();
+            File::options().write(true).open(dir.join(name)).unwrap().set_modified(old).unwrap();
+        }
+        fs::write(dir.join("fresh.iso.part"), b"data").unwrap();
+
+        let stale = find_stale(&dir, Duration::from_secs(7 * 86400)).unwrap();
+        let names: Vec<_> = stale.iter().map(|s| s.path.strip_prefix(&dir).unwrap().to_path_buf()).collect();
+        assert_eq!(names, [PathBuf::from("old.iso.part"), PathBuf::from("sub/old.bin.abc.part")]);
+        assert_eq!(stale[0].bytes, 4);
+        assert!(stale[0].age >= Duration::from_secs(10 * 86400 - 60));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
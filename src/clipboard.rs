@@ -0,0 +1,49 @@
+use std::error::Error;
+use tokio::process::Command;
+
+// Clipboard readers to try, in order. Each is (program, arguments).
+#[cfg(target_os = "macos")]
+const READERS: &[(&str, &[&str])] = &[("pbpaste", &[])];
+
+#[cfg(windows)]
+const READERS: &[(&str, &[&str])] = &[("powershell", &["-NoProfile", "-Command", "Get-Clipboard"])];
+
+#[cfg(not(any(target_os = "macos", windows)))]
+const READERS: &[(&str, &[&str])] = &[
+    ("wl-paste", &["--no-newline"]),
+    ("xclip", &["-selection", "clipboard", "-o"]),
+    ("xsel", &["--clipboard", "--output"]),
+];
+
+// Current text contents of the system clipboard. Going through the platform's
+// clipboard tool avoids linking a GUI toolkit into a command line downloader.
+pub async fn read() -> Result<String, Box<dyn Error + Send + Sync>> {
+    for (program, args) in READERS {
+        let Ok(output) = Command::new(program).args(*args).output().await else {
+            continue;
+        };
+        // An empty clipboard makes some tools exit non-zero
+        if output.status.success() || output.stdout.is_empty() {
+            return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+        }
+    }
+    let tools: Vec<&str> = READERS.iter().map(|(program, _)| *program).collect();
+    Err(format!("No clipboard tool found (tried {})", tools.join(", ")).into())
+}
+
+// Whitespace separated tokens that look like URLs, e.g. one or more links
+// copied from a browser or a chat message
+pub fn extract_urls(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .map(|token| token.trim_matches(|c| matches!(c, '"' | '\'' | '<' | '>' | '(' | ')')))
+        .filter(|token| {
+            token.starts_with("magnet:?")
+                || token.split_once("://").is_some_and(|(scheme, rest)| {
+                    !scheme.is_empty()
+                        && !rest.is_empty()
+                        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+                })
+        })
+        .map(str::to_string)
+        .collect()
+}
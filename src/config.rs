@@ -0,0 +1,549 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+// Settings read from `config.toml`. Everything is optional; a missing file
+// behaves like an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Policies applied to downloads started with `--tag <name>`
+    pub tags: BTreeMap<String, TagPolicy>,
+    // Mail server for `--notify-email`
+    pub smtp: Option<SmtpConfig>,
+    // Named flag bundles for `--preset <name>`
+    pub presets: BTreeMap<String, Vec<String>>,
+    // Per-host proxies, used when `--proxy` is not given
+    pub proxy: ProxyConfig,
+    // TLS client certificates keyed by host pattern
+    pub identities: BTreeMap<String, IdentityConfig>,
+    // File with tokens and passwords per host, decrypted with gpg when it
+    // ends in .gpg or .asc
+    pub credentials: Option<PathBuf>,
+    // OAuth providers for `gator auth login`, by name
+    pub oauth: BTreeMap<String, OAuthConfig>,
+    // URL rewrites from `[[rewrite]]` tables, applied in order
+    pub rewrite: Vec<RewriteRule>,
+}
+
+// What a tag changes about the downloads that carry it
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagPolicy {
+    // Directory to save into when `--dir` is not given (`~` is expanded)
+    pub dir: Option<PathBuf>,
+    // Extra request headers, as "Name: value"
+    pub headers: Vec<String>,
+    // WASM plugins to load (requires the wasm-plugins feature)
+    pub plugins: Vec<PathBuf>,
+    // Bytes a second for all downloads, as a number or a size such as "2M"
+    #[serde(deserialize_with = "size")]
+    pub limit_rate: Option<u64>,
+    // Files of a batch at once, segment workers per download, and files at
+    // once from one host
+    #[serde(deserialize_with = "positive")]
+    pub jobs: Option<usize>,
+    #[serde(deserialize_with = "positive")]
+    pub connections: Option<usize>,
+    #[serde(deserialize_with = "positive")]
+    pub max_concurrent_per_host: Option<usize>,
+}
+
+// A size in bytes, written as a number or with a K, M, G or T suffix
+fn size<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(0) => Err(serde::de::Error::custom("expected a size above 0")),
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => gator::quota::parse(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+fn positive<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<usize>, D::Error> {
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("expected a number above 0")),
+        n => Ok(Some(n)),
+    }
+}
+
+// Hosts to reach without a proxy, and host patterns mapped to a proxy URL,
+// "direct" or "auto"
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub direct: Vec<String>,
+    pub hosts: BTreeMap<String, String>,
+}
+
+impl ProxyConfig {
+    pub fn is_empty(&self) -> bool {
+        self.direct.is_empty() && self.hosts.is_empty()
+    }
+}
+
+// A client certificate to present: PEM `cert` and `key` files (the key in
+// PKCS#8 form), or a PKCS#12 bundle and its password
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub pkcs12: Option<PathBuf>,
+    pub password: Option<String>,
+}
+
+// A URL rewrite: the first match of the regular expression `match` is
+// replaced with `replace`, in which $1 or ${1} stands for a group
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    #[serde(rename = "match")]
+    pub pattern: String,
+    pub replace: String,
+}
+
+// An OAuth client registered with a provider. The endpoints, scopes and
+// hosts of the built-in providers (google, microsoft) may be left out.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // Device authorization endpoint (RFC 8628)
+    pub device_url: Option<String>,
+    pub token_url: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    // Host patterns the token is sent to
+    pub hosts: Option<Vec<String>>,
+}
+
+// `host` and `from` are required to send, but may be filled in one at a time
+// with `gator config set`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    // Defaults to the usual port for `security`
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Implicit TLS, port 465
+    Tls,
+    // Plain connection upgraded with STARTTLS, port 587
+    #[default]
+    Starttls,
+    // Unencrypted, port 25; only sensible for a local relay
+    None,
+}
+
+impl Config {
+    // Load `explicit` if given (it must exist), otherwise the default location
+    // if there is a file there
+    pub fn load(explicit: Option<&Path>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read config '{}': {}", path.display(), e))?;
+        validate(&path, &content)
+    }
+
+    // The policies for `tags`, in the order given
+    pub fn policies(&self, tags: &[String]) -> Result<Vec<&TagPolicy>, Box<dyn Error + Send + Sync>> {
+        tags.iter()
+            .map(|tag| {
+                self.tags
+                    .get(tag)
+                    .ok_or_else(|| format!("Tag '{}' is not defined in the config file", tag).into())
+            })
+            .collect()
+    }
+}
+
+// Parse `content`, reporting unknown keys and bad values with their line
+pub fn validate(path: &Path, content: &str) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    toml::from_str(content).map_err(|e| format!("Invalid config '{}': {}", path.display(), e).into())
+}
+
+// The file `gator config` works on: `--config` if given, else the default location
+pub fn resolve_path(explicit: Option<&Path>) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    match explicit {
+        Some(path) => Ok(path.to_path_buf()),
+        None => default_path().ok_or_else(|| "Cannot determine the config directory".into()),
+    }
+}
+
+// Starting point written by `gator config init`
+pub const TEMPLATE: &str = r#"# Gator configuration. Every setting is optional.
+
+# Tokens and passwords per host, kept out of this file; one ending in .gpg
+# or .asc is decrypted with gpg (through gpg-agent) at startup
+# credentials = "~/.config/gator/credentials.toml.gpg"
+
+# Policies for downloads started with `--tag <name>`
+# [tags.linux-isos]
+# dir = "~/isos"
+# headers = ["X-Requested-By: gator"]
+# plugins = []
+# limit_rate = "2M"              # bytes a second in all, like --limit-rate
+# jobs = 2                       # like --jobs
+# connections = 4                # like --connections
+# max_concurrent_per_host = 1    # like --max-concurrent-per-host
+
+# Flag bundles for `--preset <name>`, managed with `gator preset`
+# [presets]
+# isodl = ["--dir", "~/isos", "--continue"]
+
+# Proxies per host when `--proxy` is not given; the longest matching pattern
+# wins, and other hosts use HTTPS_PROXY / HTTP_PROXY
+# [proxy]
+# direct = ["localhost", "*.corp.example.com", "10.0.0.0/8"]
+# [proxy.hosts]
+# "*" = "http://proxy.corp.example.com:3128"
+# "*.partner.example" = "auto"   # a proxy URL, "direct" or "auto"
+
+# TLS client certificates for hosts that require them, by host pattern
+# [identities."*.internal.example.com"]
+# cert = "~/.pki/internal.crt"
+# key = "~/.pki/internal.key"
+# [identities."billing.example.com"]
+# pkcs12 = "~/.pki/billing.p12"
+# password = "secret"
+
+# URL rewrites applied in order before anything is fetched: the first match
+# of `match` (a regular expression) is replaced with `replace`, where $1 is
+# its first group. Share links from Google Drive, OneDrive, Dropbox, GitHub,
+# GitLab and Hugging Face are handled without a rule.
+# [[rewrite]]
+# match = '^https://git\.example\.com/(.+?)/-/blob/'
+# replace = 'https://git.example.com/$1/-/raw/'
+
+# OAuth clients for `gator auth login <name>`; tokens are then sent to the
+# provider's hosts. google and microsoft only need the client's ID (and
+# Google's secret); other providers also need device_url, token_url, scopes
+# and hosts
+# [oauth.google]
+# client_id = "1234-abc.apps.googleusercontent.com"
+# client_secret = "GOCSPX-..."
+
+# Mail server for `--notify-email`
+# [smtp]
+# host = "smtp.example.com"
+# security = "starttls"   # tls, starttls or none
+# username = "gator"
+# password = "secret"
+# from = "gator@example.com"
+"#;
+
+// Look up a dotted key such as `smtp.host` or `tags.work.dir`
+pub fn get_value(content: &str, key: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let doc: toml_edit::DocumentMut = content.parse()?;
+    let mut item = doc.as_item();
+    for part in key.split('.') {
+        match item.get(part) {
+            Some(next) => item = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(match item.as_str() {
+        // Strings print bare so they can be used in scripts
+        Some(s) => s.to_string(),
+        None => item.to_string().trim().to_string(),
+    }))
+}
+
+// Set a dotted key, keeping the rest of the file (comments included) as is.
+// `value` is read as TOML (number, boolean, array, quoted string) and falls back
+// to a plain string when that does not fit the setting. Returns the new content.
+pub fn set_value(
+    path: &Path,
+    content: &str,
+    key: &str,
+    value: &str,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut candidates = Vec::new();
+    if let Ok(parsed) = value.parse::<toml_edit::Value>() {
+        candidates.push(parsed);
+    }
+    if candidates.first().is_none_or(|v| !v.is_str()) {
+        candidates.push(toml_edit::Value::from(value));
+    }
+
+    let mut last_error = None;
+    for candidate in candidates {
+        match set_item(path, content, key, candidate) {
+            Ok(updated) => return Ok(updated),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| "Invalid value".into()))
+}
+
+// Set a dotted key to an already typed value, validating the result
+pub fn set_item(
+    path: &Path,
+    content: &str,
+    key: &str,
+    value: toml_edit::Value,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let parts: Vec<&str> = key.split('.').collect();
+    if parts.iter().any(|p| p.is_empty()) {
+        return Err(format!("Invalid key '{}'", key).into());
+    }
+
+    let mut doc: toml_edit::DocumentMut = content.parse()?;
+    // In a file without settings (like the template) the comments trail the
+    // document; keep them above the first table instead of below it
+    let header = if doc.is_empty() {
+        let trailing = doc.trailing().as_str().unwrap_or("").to_string();
+        doc.set_trailing("");
+        trailing
+    } else {
+        String::new()
+    };
+
+    let mut table = doc.as_table_mut();
+    for (depth, part) in parts[..parts.len() - 1].iter().enumerate() {
+        let entry = table.entry(part).or_insert_with(|| {
+            let mut new = toml_edit::Table::new();
+            // Only the innermost table needs its own `[a.b]` header
+            new.set_implicit(depth + 2 < parts.len());
+            toml_edit::Item::Table(new)
+        });
+        table = entry
+            .as_table_mut()
+            .ok_or_else(|| format!("'{}' in '{}' is not a table", part, key))?;
+    }
+    table.insert(parts[parts.len() - 1], toml_edit::value(value));
+    if !header.is_empty() {
+        // The innermost new table is the only one with a visible header
+        let mut item = doc.as_item_mut();
+        for part in &parts[..parts.len() - 1] {
+            item = &mut item[part];
+        }
+        if let Some(table) = item.as_table_mut() {
+            table.decor_mut().set_prefix(format!("{}\n\n", header.trim_end()));
+        }
+    }
+
+    let updated = doc.to_string();
+    validate(path, &updated)?;
+    Ok(updated)
+}
+
+// Remove a dotted key. Returns None when it was not set.
+pub fn remove_key(content: &str, key: &str) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let mut doc: toml_edit::DocumentMut = content.parse()?;
+    let (parent, last) = match key.rsplit_once('.') {
+        Some((parent, last)) => (Some(parent), last),
+        None => (None, key),
+    };
+    let mut table = doc.as_table_mut();
+    for part in parent.into_iter().flat_map(|p| p.split('.')) {
+        match table.get_mut(part).and_then(|item| item.as_table_mut()) {
+            Some(next) => table = next,
+            None => return Ok(None),
+        }
+    }
+    Ok(table.remove(last).map(|_| doc.to_string()))
+}
+
+// Replace every `--preset NAME` (or `--preset=NAME`) in a command line with the
+// arguments stored for it, right after the flag. Flags given later on the
+// command line still win, since each flag may override an earlier one.
+pub fn expand_presets(argv: &[OsString]) -> Result<Vec<OsString>, Box<dyn Error + Send + Sync>> {
+    let wants_preset = argv
+        .iter()
+        .any(|a| a == "--preset" || a.to_string_lossy().starts_with("--preset="));
+    if !wants_preset {
+        return Ok(argv.to_vec());
+    }
+    let config = Config::load(explicit_config_arg(argv).as_deref())?;
+
+    let mut expanded = Vec::with_capacity(argv.len());
+    let mut args = argv.iter();
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy();
+        // Everything after `--` is positional
+        if text == "--" {
+            expanded.push(arg.clone());
+            expanded.extend(args.by_ref().cloned());
+            break;
+        }
+        let name = if text == "--preset" {
+            expanded.push(arg.clone());
+            match args.next() {
+                Some(name) => {
+                    expanded.push(name.clone());
+                    name.to_string_lossy().into_owned()
+                }
+                // Let clap report the missing value
+                None => break,
+            }
+        } else if let Some(name) = text.strip_prefix("--preset=") {
+            expanded.push(arg.clone());
+            name.to_string()
+        } else {
+            expanded.push(arg.clone());
+            continue;
+        };
+        let preset = config
+            .presets
+            .get(&name)
+            .ok_or_else(|| format!("Preset '{}' is not defined in the config file", name))?;
+        expanded.extend(preset.iter().map(OsString::from));
+    }
+    Ok(expanded)
+}
+
+// `--config FILE` / `--config=FILE` from a raw command line, before clap has
+// run, falling back to `GATOR_CONFIG` like the parsed flag does
+fn explicit_config_arg(argv: &[OsString]) -> Option<PathBuf> {
+    let mut args = argv.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_string_lossy().strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("GATOR_CONFIG")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+// `$XDG_CONFIG_HOME/gator/config.toml`, `~/.config/gator/config.toml`, or
+// `%APPDATA%\gator\config.toml` on Windows
+pub fn default_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|home| home.join(".config")))
+    };
+    base.map(|dir| dir.join("gator").join("config.toml"))
+}
+
+// `$XDG_CACHE_HOME/gator`, `~/.cache/gator`, or `%LOCALAPPDATA%\gator` on Windows
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home_dir().map(|home| home.join(".cache")))
+    };
+    base.map(|dir| dir.join("gator"))
+}
+
+// Expand a leading `~/` to the home directory
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(var).filter(|h| !h.is_empty()).map(PathBuf::from)
+}
+
+// "Name: value" into a header pair
+pub fn parse_header(header: &str) -> Result<(String, String), Box<dyn Error + Send + Sync>> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("Invalid header '{}', expected 'Name: value'", header).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path() -> &'static Path {
+        Path::new("config.toml")
+    }
+
+    #[test]
+    fn dotted_keys_are_set_and_read_back() {
+        let content = set_value(path(), TEMPLATE, "tags.work.dir", "~/work").unwrap();
+        let content = set_value(path(), &content, "smtp.port", "2525").unwrap();
+        let content = set_value(path(), &content, "tags.work.headers", r#"["X-Team: a"]"#).unwrap();
+        let content = set_value(path(), &content, "tags.work.limit_rate", "2M").unwrap();
+        // The comments of the template are kept
+        assert!(content.contains("# Gator configuration. Every setting is optional."));
+        assert!(content.contains("[tags.work]\ndir = \"~/work\""));
+
+        assert_eq!(get_value(&content, "tags.work.dir").unwrap().as_deref(), Some("~/work"));
+        assert_eq!(get_value(&content, "smtp.port").unwrap().as_deref(), Some("2525"));
+        assert_eq!(get_value(&content, "tags.work.headers").unwrap().as_deref(), Some(r#"["X-Team: a"]"#));
+        assert_eq!(get_value(&content, "tags.other.dir").unwrap(), None);
+        assert_eq!(get_value(&content, "smtp.port.x").unwrap(), None);
+
+        let config = validate(path(), &content).unwrap();
+        let work = &config.policies(&["work".to_string()]).unwrap()[0];
+        assert_eq!(work.dir.as_deref(), Some(Path::new("~/work")));
+        assert_eq!(work.limit_rate, Some(2 << 20));
+        assert_eq!(config.smtp.as_ref().unwrap().port, Some(2525));
+        assert!(config.policies(&["other".to_string()]).is_err());
+
+        let removed = remove_key(&content, "tags.work.dir").unwrap().unwrap();
+        assert_eq!(get_value(&removed, "tags.work.dir").unwrap(), None);
+        assert_eq!(remove_key(&removed, "tags.work.dir").unwrap(), None);
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_refused() {
+        let error = set_value(path(), "", "smtp.port", "lots").unwrap_err().to_string();
+        assert!(error.contains("invalid type"), "{}", error);
+        assert!(set_value(path(), "", "tags.work.jobs", "0").is_err());
+        let error = validate(path(), "[tags.work]\njobs = 0\n").unwrap_err().to_string();
+        assert!(error.contains("expected a number above 0"), "{}", error);
+        let error = set_value(path(), "", "tags.work.limit_rate", "fast").unwrap_err().to_string();
+        assert!(error.contains("'fast' is not a size"), "{}", error);
+        assert!(set_value(path(), "", "tags.work.typo", "1").is_err());
+
+        let content = set_value(path(), "", "smtp.host", "mail.example.com").unwrap();
+        let error = set_value(path(), &content, "smtp.host.name", "x").unwrap_err().to_string();
+        assert_eq!(error, "'host' in 'smtp.host.name' is not a table");
+        assert!(set_value(path(), "", "tags..dir", "x").is_err());
+    }
+
+    #[test]
+    fn validation_errors_give_the_file_and_line() {
+        assert!(validate(path(), TEMPLATE).is_ok());
+        let content = "[tags.work]\ndir = \"~/work\"\n\n[smtp]\nhots = \"mail.example.com\"\n";
+        let error = validate(path(), content).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid config 'config.toml': "), "{}", error);
+        assert!(error.contains("line 5"), "{}", error);
+        assert!(error.contains("unknown field `hots`"), "{}", error);
+
+        let error = validate(path(), "[tags.work]\njobs = \"many\"\n").unwrap_err().to_string();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// The control file of a segmented download (`<file>.gator-ctl` next to the
+// part file) records which segments are on disk. Workers write into a preallocated
+// part file out of order, so after a crash or a failed segment its length
+// says nothing about what arrived.
+//
+// A header line, then one byte per segment, `0` or `1`:
+//
+//     gator-control 1 length=<bytes> offset=<first byte> segment=<bytes>
+//     1101000111
+//
+// Marking a segment rewrites a single byte, so the file is never half updated.
+const MAGIC: &str = "gator-control 1";
+
+// How the bytes from `offset` to `length` were cut into segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub length: u64,
+    pub offset: u64,
+    pub segment_size: u64,
+}
+
+impl Layout {
+    pub fn segments(&self) -> usize {
+        (self.length - self.offset).div_ceil(self.segment_size) as usize
+    }
+
+    fn header(&self) -> String {
+        format!(
+            "{} length={} offset={} segment={}\n",
+            MAGIC, self.length, self.offset, self.segment_size
+        )
+    }
+}
+
+pub struct Control {
+    file: Mutex<File>,
+    header_len: u64,
+}
+
+impl Control {
+    // Start (or replace) the control file at `path`, with the segments in
+    // `done` already on disk
+    pub fn create(path: &Path, layout: Layout, done: &[bool]) -> io::Result<Self> {
+        let header = layout.header();
+        let mut contents = header.clone().into_bytes();
+        contents.extend(done.iter().map(|&done| if done { b'1' } else { b'0' }));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all(&contents)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            header_len: header.len() as u64,
+        })
+    }
+
+    // Record that segment `index` is on disk
+    pub fn mark(&self, index: usize) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(self.header_len + index as u64))?;
+        file.write_all(b"1")
+    }
+
+    // Make the marks so far survive a crash of the machine
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.lock().unwrap().sync_data()
+    }
+}
+
+// Remove the control file at `path`, if there is one
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+// The layout and finished segments recorded at `path`, or None when there is
+// no control file or it is not one
+pub fn load(path: &Path) -> Option<(Layout, Vec<bool>)> {
+    let contents = fs::read(path).ok()?;
+    let newline = contents.iter().position(|&b| b == b'\n')?;
+    let header = std::str::from_utf8(&contents[..newline]).ok()?;
+    let fields = header.strip_prefix(MAGIC)?;
+    let field = |name: &str| -> Option<u64> {
+        fields
+            .split_whitespace()
+            .find_map(|f| f.strip_prefix(name)?.strip_prefix('='))?
+            .parse()
+            .ok()
+    };
+    let layout = Layout {
+        length: field("length")?,
+        offset: field("offset")?,
+        segment_size: field("segment")?,
+    };
+    if layout.segment_size == 0 || layout.offset >= layout.length {
+        return None;
+    }
+    let done: Vec<bool> = contents[newline + 1..]
+        .iter()
+        .map(|&b| match b {
+            b'1' => Some(true),
+            b'0' => Some(false),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    (done.len() == layout.segments()).then_some((layout, done))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marked_segments_survive_a_reload() {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-control", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.iso.gator-ctl");
+        let layout = Layout {
+            length: 10 * 1024 + 1,
+            offset: 0,
+            segment_size: 1024,
+        };
+        assert_eq!(layout.segments(), 11);
+
+        let mut done = vec![false; 11];
+        done[0] = true;
+        let control = Control::create(&path, layout, &done).unwrap();
+        control.mark(10).unwrap();
+        control.mark(3).unwrap();
+        drop(control);
+
+        let (loaded, done) = load(&path).unwrap();
+        assert_eq!(loaded, layout);
+        let marked: Vec<usize> = (0..done.len()).filter(|&i| done[i]).collect();
+        assert_eq!(marked, [0, 3, 10]);
+
+        // A bitmap that does not fit the layout is not trusted
+        fs::write(&path, format!("{}1", layout.header())).unwrap();
+        assert!(load(&path).is_none());
+        remove(&path);
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::batch::BatchItem;
+use crate::paths;
+use crate::pattern;
+use crate::protocol::Registry;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::path::PathBuf;
+
+pub struct CrawlOptions<'a> {
+    pub accept: &'a [String],
+    pub reject: &'a [String],
+    // How many levels of subdirectories to descend into (0 = only the given page)
+    pub depth: usize,
+}
+
+// Walk autoindex pages breadth-first starting at `root` and collect every file
+// whose name passes the accept/reject filters. Pages are listed through the
+// registry's handlers, so wrappers such as --wait pacing apply to them too.
+pub async fn collect(
+    registry: &Registry,
+    root: &str,
+    options: &CrawlOptions<'_>,
+) -> Result<Vec<BatchItem>, Box<dyn Error + Send + Sync>> {
+    let mut items = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([(root.to_string(), PathBuf::new(), 0)]);
+
+    while let Some((url, relative, level)) = queue.pop_front() {
+        if !visited.insert(url.clone()) {
+            continue;
+        }
+
+        for entry in registry.handler_for(&url)?.list(&url).await? {
+            let local = relative.join(paths::sanitize_file_name(&entry.name));
+            if entry.is_dir {
+                if level < options.depth {
+                    queue.push_back((entry.url, local, level + 1));
+                }
+                continue;
+            }
+
+            let accepted =
+                options.accept.is_empty() || pattern::matches_any(options.accept, &entry.name);
+            if accepted && !pattern::matches_any(options.reject, &entry.name) {
+                items.push(BatchItem {
+                    url: entry.url,
+                    relative: local,
+                    digests: Vec::new(),
+                });
+            }
+        }
+    }
+
+    Ok(items)
+}
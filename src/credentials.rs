@@ -0,0 +1,146 @@
+use crate::config::parse_header;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use gator::hooks::Hook;
+use gator::pattern::host_matches;
+use gator::protocol::BoxError;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Credentials for the hosts a run downloads from, read from the file the
+// config's `credentials` setting names. A file ending in .gpg or .asc is
+// decrypted with gpg, which asks gpg-agent for the key, so no token has to
+// sit in plain text on a shared machine.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    // Keyed by host pattern, as for proxies and identities
+    hosts: BTreeMap<String, HostCredentials>,
+}
+
+// What to send to the hosts matching one pattern: a bearer token, a user
+// name and password for Basic authentication, or headers of any kind
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HostCredentials {
+    token: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    // As "Name: value"
+    headers: Vec<String>,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> Result<Self, BoxError> {
+        let encrypted = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gpg") || e.eq_ignore_ascii_case("asc"));
+        let content = match encrypted {
+            true => decrypt(path)?,
+            false => std::fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read credentials '{}': {}", path.display(), e))?,
+        };
+        Self::parse(&content).map_err(|e| format!("Invalid credentials '{}': {}", path.display(), e).into())
+    }
+
+    fn parse(content: &str) -> Result<Self, BoxError> {
+        let credentials: Self = toml::from_str(content)?;
+        for (pattern, host) in &credentials.hosts {
+            if host.username.is_some() != host.password.is_some() {
+                return Err(format!("'{}' needs both username and password", pattern).into());
+            }
+            for header in &host.headers {
+                parse_header(header)?;
+            }
+        }
+        Ok(credentials)
+    }
+}
+
+// The plaintext of a gpg-encrypted file. gpg runs without a terminal, so the
+// key has to be unlocked in gpg-agent already (or have no passphrase).
+fn decrypt(path: &Path) -> Result<String, BoxError> {
+    let output = Command::new("gpg")
+        .args(["--batch", "--quiet", "--decrypt"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("Cannot run gpg to decrypt '{}': {}", path.display(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "gpg cannot decrypt '{}': {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    String::from_utf8(output.stdout).map_err(|_| format!("'{}' does not decrypt to text", path.display()).into())
+}
+
+// The credentials of the most specific (longest) pattern that matches, so
+// one host never gets the token meant for another
+impl Hook for Credentials {
+    fn request_headers(&self, url: &str) -> Result<Vec<(String, String)>, BoxError> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()));
+        let Some(host) = host else {
+            return Ok(Vec::new());
+        };
+        let Some((_, credentials)) = self
+            .hosts
+            .iter()
+            .filter(|(pattern, _)| host_matches(&pattern.to_ascii_lowercase(), &host))
+            .max_by_key(|(pattern, _)| pattern.trim_matches('*').len())
+        else {
+            return Ok(Vec::new());
+        };
+        let mut headers = Vec::new();
+        if let Some(token) = &credentials.token {
+            headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+        }
+        if let (Some(username), Some(password)) = (&credentials.username, &credentials.password) {
+            let basic = STANDARD.encode(format!("{}:{}", username, password));
+            headers.push(("Authorization".to_string(), format!("Basic {}", basic)));
+        }
+        for header in &credentials.headers {
+            headers.push(parse_header(header)?);
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_get_only_their_own_credentials() {
+        let credentials = Credentials::parse(
+            r#"
+            [hosts."example.com"]
+            token = "abc"
+            [hosts."files.example.com"]
+            username = "me"
+            password = "secret"
+            headers = ["X-Api-Key: 123"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            credentials.request_headers("https://www.example.com/a").unwrap(),
+            [("Authorization".to_string(), "Bearer abc".to_string())]
+        );
+        assert_eq!(
+            credentials.request_headers("https://files.example.com/a").unwrap(),
+            [
+                ("Authorization".to_string(), "Basic bWU6c2VjcmV0".to_string()),
+                ("X-Api-Key".to_string(), "123".to_string()),
+            ]
+        );
+        assert!(credentials.request_headers("https://example.org/a").unwrap().is_empty());
+        assert!(Credentials::parse("[hosts.\"a.com\"]\nusername = \"me\"").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::protocol::{BoxError, ByteStream, Probe, ProtocolHandler};
+use base64::alphabet::STANDARD;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::Engine;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+// The media type of a data: URL that names none (RFC 2397)
+const DEFAULT_TYPE: &str = "text/plain;charset=US-ASCII";
+
+// Inline content, `data:[<media type>][;base64],<data>`, so a small payload
+// can go wherever a URL does. Nothing is fetched: the bytes are in the URL.
+pub struct DataHandler;
+
+// The media type and the decoded bytes of a data: URL
+pub fn parse(url: &str) -> Result<(String, Vec<u8>), BoxError> {
+    let rest = url
+        .get(..5)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("data:"))
+        .map(|_| &url[5..])
+        .ok_or_else(|| format!("'{}' is not a data: URL", url))?;
+    let (header, data) = rest
+        .split_once(',')
+        .ok_or("A data: URL needs a comma between the media type and the data")?;
+    let (media_type, base64) = match header.rsplit_once(';') {
+        Some((media_type, flag)) if flag.trim().eq_ignore_ascii_case("base64") => (media_type, true),
+        _ => (header, false),
+    };
+    let media_type = match media_type.trim() {
+        "" => DEFAULT_TYPE.to_string(),
+        // Parameters without a type, e.g. "data:;charset=utf-8,..."
+        t if t.starts_with(';') => format!("text/plain{}", t),
+        t => String::from_utf8_lossy(&percent_decode(t)).into_owned(),
+    };
+    let bytes = percent_decode(data);
+    if !base64 {
+        return Ok((media_type, bytes));
+    }
+    // Line breaks and missing padding are common in hand-written payloads
+    let engine = GeneralPurpose::new(
+        &STANDARD,
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+    let encoded: Vec<u8> = bytes.into_iter().filter(|b| !b.is_ascii_whitespace()).collect();
+    let decoded = engine
+        .decode(encoded)
+        .map_err(|e| format!("Invalid base64 in data: URL: {}", e))?;
+    Ok((media_type, decoded))
+}
+
+// Percent-decode into raw bytes; malformed escapes are kept literally
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+impl ProtocolHandler for DataHandler {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Probe, BoxError>> {
+        Box::pin(async move {
+            let (media_type, bytes) = parse(url)?;
+            Ok(Probe {
+                status: "inline data".to_string(),
+                length: Some(bytes.len() as u64),
+                content_type: Some(media_type),
+                content_encoding: None,
+                accepts_ranges: true,
+                headers: Vec::new(),
+                public_key_pin: None,
+            })
+        })
+    }
+
+    fn open<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(async move {
+            let (_, bytes) = parse(url)?;
+            let len = bytes.len() as u64;
+            let last = end.map_or(len, |end| end.saturating_add(1).min(len));
+            if start > last || (start == len && len > 0) {
+                return Err(format!("Range {}- is past the end of {} bytes of inline data", start, len).into());
+            }
+            let chunk = Bytes::from(bytes).slice(start as usize..last as usize);
+            Ok(Box::pin(futures::stream::iter([Ok(chunk)])) as ByteStream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_and_percent_encoded_payloads_decode() {
+        assert_eq!(parse("data:,Hello%2C%20World").unwrap(), (DEFAULT_TYPE.to_string(), b"Hello, World".to_vec()));
+        assert_eq!(
+            parse("data:text/plain;base64,SGVsbG8s\nIFdvcmxk").unwrap(),
+            ("text/plain".to_string(), b"Hello, World".to_vec())
+        );
+        assert_eq!(parse("DATA:application/json,{}").unwrap().0, "application/json");
+        assert_eq!(parse("data:;charset=utf-8,x").unwrap().0, "text/plain;charset=utf-8");
+        assert!(parse("data:text/plain;base64,not*base64").is_err());
+        assert!(parse("data:text/plain").is_err());
+    }
+
+    #[tokio::test]
+    async fn ranges_are_slices_of_the_payload() {
+        use futures::StreamExt;
+        let url = "data:application/octet-stream;base64,AAECAwQFBgc=";
+        let probe = DataHandler.probe(url).await.unwrap();
+        assert_eq!(probe.length, Some(8));
+        let mut stream = DataHandler.open(url, 2, Some(4)).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().as_ref(), &[2, 3, 4]);
+        assert!(DataHandler.open(url, 9, None).await.is_err());
+    }
+}
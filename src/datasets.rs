@@ -0,0 +1,341 @@
+use crate::batch::BatchItem;
+use crate::digest::{Algorithm, Expected};
+use crate::listing::Entry;
+use crate::protocol::{BoxError, HostClients, Registry};
+use crate::urls;
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::path::{Component, PathBuf};
+
+// Research data repositories. A DOI, or the page of a Zenodo or Figshare
+// record, names a set of files that the repository's API lists with their
+// sizes and MD5 checksums.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Zenodo(String),
+    Figshare(String),
+    // Any other DOI, looked up to find where it leads
+    Doi(String),
+}
+
+// A record's files, saved into a folder named after it (e.g. zenodo.1234)
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    // For messages, e.g. "Zenodo MD5 mismatch"
+    repository: &'static str,
+    pub title: Option<String>,
+    pub files: Vec<RecordFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordFile {
+    pub name: String,
+    pub url: String,
+    pub size: Option<u64>,
+    md5: Option<String>,
+}
+
+// What `input` names: a DOI (10.5281/zenodo.1234, doi:10.5281/..., or a
+// doi.org URL), or a Zenodo or Figshare record page
+pub fn source(input: &str) -> Option<Source> {
+    let input = input.trim();
+    let doi = match Url::parse(input) {
+        // `doi:10.1234/x` parses as a URL with no host
+        Ok(url) if url.has_host() => match url.host_str()? {
+            "doi.org" | "dx.doi.org" => urls::decode_segment(url.path().trim_start_matches('/')),
+            "zenodo.org" => return zenodo_id(&url).map(Source::Zenodo),
+            host if host == "figshare.com" || host.ends_with(".figshare.com") => {
+                return figshare_id(&url).map(Source::Figshare)
+            }
+            _ => return None,
+        },
+        _ => input.to_string(),
+    };
+    let doi = match doi.get(..4) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("doi:") => doi[4..].to_string(),
+        _ => doi,
+    };
+    if !doi.starts_with("10.") || !doi.contains('/') {
+        return None;
+    }
+    let lower = doi.to_ascii_lowercase();
+    if let Some(id) = lower.strip_prefix("10.5281/zenodo.") {
+        return digits(id).map(Source::Zenodo);
+    }
+    if let Some(rest) = lower.strip_prefix("10.6084/m9.figshare.") {
+        // A version may follow, as in 10.6084/m9.figshare.1234.v2
+        return digits(rest.split('.').next()?).map(Source::Figshare);
+    }
+    Some(Source::Doi(doi))
+}
+
+// zenodo.org/records/ID (or the older /record/ID)
+fn zenodo_id(url: &Url) -> Option<String> {
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    match segments.as_slice() {
+        ["records" | "record", id, ..] => digits(id),
+        _ => None,
+    }
+}
+
+// figshare.com/articles/TYPE/TITLE/ID[/VERSION]
+fn figshare_id(url: &Url) -> Option<String> {
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    if segments.first() != Some(&"articles") {
+        return None;
+    }
+    segments[1..].iter().find_map(|s| digits(s))
+}
+
+fn digits(s: &str) -> Option<String> {
+    (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.to_string())
+}
+
+// The files of the record `source` names. Every API URL goes through the
+// registry's rewrites first.
+pub async fn fetch(registry: &Registry, clients: &HostClients, source: &Source) -> Result<Record, BoxError> {
+    let record = match source {
+        Source::Zenodo(id) => fetch_zenodo(registry, clients, id).await?,
+        Source::Figshare(id) => fetch_figshare(registry, clients, id).await?,
+        Source::Doi(doi) => {
+            let target = resolve_doi(registry, clients, doi).await?;
+            match self::source(&target) {
+                Some(Source::Zenodo(id)) => fetch_zenodo(registry, clients, &id).await?,
+                Some(Source::Figshare(id)) => fetch_figshare(registry, clients, &id).await?,
+                _ => return Err(format!("DOI {} leads to {}, which is not a Zenodo or Figshare record", doi, target).into()),
+            }
+        }
+    };
+    if record.files.is_empty() {
+        return Err(format!("{} has no files that can be downloaded; they may be restricted", record.name).into());
+    }
+    Ok(record)
+}
+
+async fn fetch_zenodo(registry: &Registry, clients: &HostClients, id: &str) -> Result<Record, BoxError> {
+    let url = format!("https://zenodo.org/api/records/{}", id);
+    Ok(zenodo_record(id, get_json(registry, clients, &url).await?))
+}
+
+async fn fetch_figshare(registry: &Registry, clients: &HostClients, id: &str) -> Result<Record, BoxError> {
+    let url = format!("https://api.figshare.com/v2/articles/{}", id);
+    Ok(figshare_record(id, get_json(registry, clients, &url).await?))
+}
+
+async fn get_json<T: DeserializeOwned>(registry: &Registry, clients: &HostClients, url: &str) -> Result<T, BoxError> {
+    let url = registry.resolve_url(url)?;
+    let body = clients.for_url(&url).get(&url).send().await?.error_for_status()?.text().await?;
+    serde_json::from_str(&body).map_err(|e| format!("Unexpected answer from {}: {}", url, e).into())
+}
+
+#[derive(Deserialize)]
+struct Handle {
+    #[serde(default)]
+    values: Vec<HandleValue>,
+}
+
+#[derive(Deserialize)]
+struct HandleValue {
+    #[serde(rename = "type")]
+    kind: String,
+    data: HandleData,
+}
+
+#[derive(Deserialize)]
+struct HandleData {
+    value: serde_json::Value,
+}
+
+// Where a DOI leads, from the doi.org handle API rather than by following
+// redirects to a landing page
+async fn resolve_doi(registry: &Registry, clients: &HostClients, doi: &str) -> Result<String, BoxError> {
+    let url = format!("https://doi.org/api/handles/{}", doi);
+    let handle: Handle = get_json(registry, clients, &url)
+        .await
+        .map_err(|e| format!("Cannot look up DOI {}: {}", doi, e))?;
+    handle
+        .values
+        .into_iter()
+        .find(|v| v.kind == "URL")
+        .and_then(|v| v.data.value.as_str().map(str::to_string))
+        .ok_or_else(|| format!("DOI {} does not lead to a URL", doi).into())
+}
+
+#[derive(Deserialize)]
+struct ZenodoRecord {
+    #[serde(default)]
+    files: Vec<ZenodoFile>,
+    metadata: Option<ZenodoMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ZenodoMetadata {
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ZenodoFile {
+    key: String,
+    size: Option<u64>,
+    // "md5:<hex>"
+    checksum: Option<String>,
+    links: Option<ZenodoLinks>,
+}
+
+#[derive(Deserialize)]
+struct ZenodoLinks {
+    #[serde(rename = "self")]
+    content: Option<String>,
+}
+
+fn zenodo_record(id: &str, record: ZenodoRecord) -> Record {
+    let files = record
+        .files
+        .into_iter()
+        .map(|file| RecordFile {
+            url: file.links.and_then(|l| l.content).unwrap_or_else(|| {
+                let mut url = Url::parse("https://zenodo.org").expect("a valid URL");
+                url.path_segments_mut().expect("an http URL").extend(["records", id, "files", &file.key]);
+                url.to_string()
+            }),
+            size: file.size,
+            md5: file.checksum.and_then(|c| c.strip_prefix("md5:").map(str::to_string)),
+            name: file.key,
+        })
+        .collect();
+    Record {
+        name: format!("zenodo.{}", id),
+        repository: "Zenodo",
+        title: record.metadata.and_then(|m| m.title),
+        files,
+    }
+}
+
+#[derive(Deserialize)]
+struct FigshareArticle {
+    title: Option<String>,
+    #[serde(default)]
+    files: Vec<FigshareFile>,
+}
+
+#[derive(Deserialize)]
+struct FigshareFile {
+    name: String,
+    size: Option<u64>,
+    download_url: String,
+    computed_md5: Option<String>,
+    // Files stored elsewhere, with only a link on Figshare
+    #[serde(default)]
+    is_link_only: bool,
+}
+
+fn figshare_record(id: &str, article: FigshareArticle) -> Record {
+    let files = article
+        .files
+        .into_iter()
+        .filter(|file| !file.is_link_only)
+        .map(|file| RecordFile {
+            name: file.name,
+            url: file.download_url,
+            size: file.size,
+            md5: file.computed_md5.filter(|md5| !md5.is_empty()),
+        })
+        .collect();
+    Record {
+        name: format!("figshare.{}", id),
+        repository: "Figshare",
+        title: article.title,
+        files,
+    }
+}
+
+impl Record {
+    // The files for `gator ls`
+    pub fn entries(&self) -> Vec<Entry> {
+        self.files
+            .iter()
+            .map(|file| Entry {
+                name: file.name.clone(),
+                url: file.url.clone(),
+                size: file.size,
+                modified: None,
+                is_dir: false,
+            })
+            .collect()
+    }
+
+    // `files` to download into the record's folder, checked against their
+    // MD5. Names that would leave the folder are dropped.
+    pub fn batch_items<'a>(&self, files: impl IntoIterator<Item = &'a RecordFile>) -> Vec<BatchItem> {
+        files
+            .into_iter()
+            .filter_map(|file| {
+                let name = PathBuf::from(&file.name);
+                if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+                    return None;
+                }
+                let source = format!("{} MD5", self.repository);
+                let digest = file.md5.as_deref().and_then(|md5| Expected::from_hex(Algorithm::Md5, md5, source));
+                Some(BatchItem {
+                    url: file.url.clone(),
+                    relative: PathBuf::from(&self.name).join(name),
+                    digests: digest.into_iter().collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_found_and_listed() {
+        let zenodo = Some(Source::Zenodo("1234".to_string()));
+        assert_eq!(source("10.5281/zenodo.1234"), zenodo);
+        assert_eq!(source("doi:10.5281/ZENODO.1234"), zenodo);
+        assert_eq!(source("https://doi.org/10.5281/zenodo.1234"), zenodo);
+        assert_eq!(source("https://zenodo.org/records/1234?preview=1"), zenodo);
+        assert_eq!(source("https://zenodo.org/record/1234"), zenodo);
+        let figshare = Some(Source::Figshare("5678".to_string()));
+        assert_eq!(source("10.6084/m9.figshare.5678.v2"), figshare);
+        assert_eq!(source("https://figshare.com/articles/dataset/Some_data/5678/2"), figshare);
+        assert_eq!(source("https://doi.org/10.1000%2Fxyz"), Some(Source::Doi("10.1000/xyz".to_string())));
+        assert_eq!(source("https://example.com/10.1000/xyz"), None);
+        assert_eq!(source("data.csv"), None);
+
+        let record = zenodo_record(
+            "1234",
+            serde_json::from_str(
+                r#"{"metadata": {"title": "Readings"}, "files": [
+                    {"key": "data.csv", "size": 1, "checksum": "md5:0cc175b9c0f1b6a831c399e269772661",
+                     "links": {"self": "https://zenodo.org/api/records/1234/files/data.csv/content"}},
+                    {"key": "../up.txt", "size": 2}
+                ]}"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(record.title.as_deref(), Some("Readings"));
+        assert_eq!(record.files[1].url, "https://zenodo.org/records/1234/files/..%2Fup.txt");
+        let items = record.batch_items(&record.files);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].relative, PathBuf::from("zenodo.1234/data.csv"));
+        assert_eq!(items[0].digests[0].source, "Zenodo MD5");
+
+        let record = figshare_record(
+            "5678",
+            serde_json::from_str(
+                r#"{"title": "Scans", "files": [
+                    {"name": "a.tif", "size": 3, "download_url": "https://ndownloader.figshare.com/files/1", "computed_md5": "abcd"},
+                    {"name": "elsewhere", "download_url": "https://example.com/x", "computed_md5": "", "is_link_only": true}
+                ]}"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(record.files.len(), 1);
+        assert_eq!(record.entries()[0].size, Some(3));
+    }
+}
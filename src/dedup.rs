@@ -0,0 +1,257 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DedupMode {
+    /// Replace duplicates with hard links to the first copy
+    Hardlink,
+    /// Replace duplicates with copy-on-write clones (Linux btrfs/XFS and similar)
+    Reflink,
+}
+
+// SHA-256 of each finished download, taken in the pass that checks its
+// digests, so --dedup does not read every file again
+#[derive(Debug, Default)]
+pub struct KnownHashes(Mutex<HashMap<PathBuf, [u8; 32]>>);
+
+impl KnownHashes {
+    pub fn record(&self, path: &Path, hash: [u8; 32]) {
+        self.0.lock().unwrap().insert(path.to_path_buf(), hash);
+    }
+
+    pub fn get(&self, path: &Path) -> Option<[u8; 32]> {
+        self.0.lock().unwrap().get(path).copied()
+    }
+
+    // For a file changed since it was downloaded, e.g. by --convert-links
+    pub fn forget(&self, path: &Path) {
+        self.0.lock().unwrap().remove(path);
+    }
+}
+
+// Remembers the content hash of every file in a run and collapses later files
+// with identical content onto the first copy
+pub struct Deduper {
+    mode: DedupMode,
+    dry_run: bool,
+    seen: HashMap<[u8; 32], PathBuf>,
+    duplicates: usize,
+    saved_bytes: u64,
+}
+
+impl Deduper {
+    pub fn new(mode: DedupMode, dry_run: bool) -> Self {
+        Self {
+            mode,
+            dry_run,
+            seen: HashMap::new(),
+            duplicates: 0,
+            saved_bytes: 0,
+        }
+    }
+
+    // Hash `path` (unless its hash is `known`) and, if an identical file was
+    // seen before, link it to that copy. Returns the original when `path` was
+    // a duplicate.
+    pub fn add(&mut self, path: &Path, known: Option<[u8; 32]>) -> io::Result<Option<PathBuf>> {
+        let (hash, len) = match known {
+            Some(hash) => (hash, fs::metadata(path)?.len()),
+            None => hash_file(path)?,
+        };
+
+        let Some(original) = self.seen.get(&hash).cloned() else {
+            self.seen.insert(hash, path.to_path_buf());
+            return Ok(None);
+        };
+        if original == path {
+            return Ok(None);
+        }
+
+        if !self.dry_run {
+            replace_with_link(self.mode, &original, path)?;
+        }
+        self.duplicates += 1;
+        self.saved_bytes += len;
+        Ok(Some(original))
+    }
+
+    pub fn summary(&self) -> String {
+        let verb = if self.dry_run { "would save" } else { "saved" };
+        format!(
+            "Deduplication: {} duplicate files, {} {}",
+            self.duplicates,
+            verb,
+            indicatif::HumanBytes(self.saved_bytes)
+        )
+    }
+}
+
+// SHA-256 and length of a file
+pub fn hash_file(path: &Path) -> io::Result<([u8; 32], u64)> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    let mut len = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+    Ok((hasher.finalize().into(), len))
+}
+
+// Build the link next to the duplicate and rename it over, so the duplicate
+// is never missing if linking fails halfway
+fn replace_with_link(mode: DedupMode, original: &Path, duplicate: &Path) -> io::Result<()> {
+    let tmp = tmp_path(duplicate);
+
+    let result = match mode {
+        DedupMode::Hardlink => fs::hard_link(original, &tmp),
+        DedupMode::Reflink => reflink(original, &tmp),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to {} {} to {}: {}",
+                if mode == DedupMode::Hardlink { "hard link" } else { "reflink" },
+                duplicate.display(),
+                original.display(),
+                e
+            ),
+        ));
+    }
+    fs::rename(&tmp, duplicate)
+}
+
+// `file.iso` is linked as `file.iso.gator-dedup` first
+fn tmp_path(duplicate: &Path) -> PathBuf {
+    let mut tmp_name = duplicate.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".gator-dedup");
+    duplicate.with_file_name(tmp_name)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(original: &Path, target: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // FICLONE from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x40049409;
+
+    let src = fs::File::open(original)?;
+    let dst = fs::File::create(target)?;
+    // SAFETY: both descriptors are valid for the duration of the call
+    let rc = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_original: &Path, _target: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[cfg(unix)]
+    fn inode(path: &Path) -> u64 {
+        std::os::unix::fs::MetadataExt::ino(&fs::metadata(path).unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn duplicates_become_hard_links_to_the_first_copy() {
+        let dir = temp_dir("dedup");
+        let (a, b, c) = (dir.join("a.bin"), dir.join("b.bin"), dir.join("c.bin"));
+        fs::write(&a, b"same bytes").unwrap();
+        fs::write(&b, b"other").unwrap();
+        fs::write(&c, b"same bytes").unwrap();
+
+        let mut deduper = Deduper::new(DedupMode::Hardlink, false);
+        assert_eq!(deduper.add(&a, None).unwrap(), None);
+        assert_eq!(deduper.add(&b, None).unwrap(), None);
+        assert_eq!(deduper.add(&c, None).unwrap(), Some(a.clone()));
+        // Adding the original again is not a duplicate of itself
+        assert_eq!(deduper.add(&a, None).unwrap(), None);
+
+        assert_eq!(inode(&a), inode(&c));
+        assert_ne!(inode(&a), inode(&b));
+        assert_eq!(fs::read(&c).unwrap(), b"same bytes");
+        assert!(!tmp_path(&c).exists());
+        assert_eq!(deduper.summary(), "Deduplication: 1 duplicate files, saved 10 B");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dry_runs_count_without_linking() {
+        let dir = temp_dir("dedup-dry-run");
+        let (a, b) = (dir.join("a.bin"), dir.join("b.bin"));
+        fs::write(&a, vec![7u8; 2048]).unwrap();
+        fs::write(&b, vec![7u8; 2048]).unwrap();
+
+        let mut deduper = Deduper::new(DedupMode::Hardlink, true);
+        deduper.add(&a, None).unwrap();
+        assert_eq!(deduper.add(&b, None).unwrap(), Some(a.clone()));
+        assert_ne!(inode(&a), inode(&b));
+        assert_eq!(deduper.summary(), "Deduplication: 1 duplicate files, would save 2.00 KiB");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn links_are_made_beside_the_duplicate_and_cleaned_up_on_failure() {
+        assert_eq!(tmp_path(Path::new("dir/file.iso")), Path::new("dir/file.iso.gator-dedup"));
+
+        let dir = temp_dir("dedup-failure");
+        let duplicate = dir.join("b.bin");
+        fs::write(&duplicate, b"keep me").unwrap();
+        let error = replace_with_link(DedupMode::Hardlink, &dir.join("missing.bin"), &duplicate).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to hard link"), "{}", error);
+        assert_eq!(fs::read(&duplicate).unwrap(), b"keep me");
+        assert!(!tmp_path(&duplicate).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn known_hashes_are_used_instead_of_reading_the_file() {
+        let dir = temp_dir("dedup-known");
+        let (a, b) = (dir.join("a.bin"), dir.join("b.bin"));
+        fs::write(&a, b"first").unwrap();
+        fs::write(&b, b"second").unwrap();
+        let hashes = KnownHashes::default();
+        hashes.record(&a, [1; 32]);
+        hashes.record(&b, [1; 32]);
+
+        // Recorded as equal, so they are treated as equal without a read
+        let mut deduper = Deduper::new(DedupMode::Hardlink, true);
+        deduper.add(&a, hashes.get(&a)).unwrap();
+        assert_eq!(deduper.add(&b, hashes.get(&b)).unwrap(), Some(a.clone()));
+        hashes.forget(&b);
+        assert_eq!(hashes.get(&b), None);
+        assert_eq!(hashes.get(&a), Some([1; 32]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
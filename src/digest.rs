@@ -0,0 +1,303 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha2::digest::DynDigest;
+use sha2::{Sha256, Sha512};
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    // Only from published checksums; servers sending the obsolete "SHA" are ignored
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    // Names as registered for the `Digest` field; matched case-insensitively
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "sha-256" => Some(Self::Sha256),
+            "sha-512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Md5 => Hasher::Dyn(Box::new(Md5::default())),
+            Self::Sha1 => Hasher::Sha1(Box::new(ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY))),
+            Self::Sha256 => Hasher::Dyn(Box::new(Sha256::default())),
+            Self::Sha512 => Hasher::Dyn(Box::new(Sha512::default())),
+        }
+    }
+}
+
+// SHA-1 comes from ring, the one place it is available
+enum Hasher {
+    Dyn(Box<dyn DynDigest + Send>),
+    Sha1(Box<ring::digest::Context>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Dyn(hasher) => hasher.update(data),
+            Self::Sha1(context) => context.update(data),
+        }
+    }
+
+    fn finalize(self) -> Box<[u8]> {
+        match self {
+            Self::Dyn(hasher) => hasher.finalize(),
+            Self::Sha1(context) => context.finish().as_ref().into(),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Md5 => "MD5",
+            Self::Sha1 => "SHA-1",
+            Self::Sha256 => "SHA-256",
+            Self::Sha512 => "SHA-512",
+        })
+    }
+}
+
+// A digest of the whole body sent by the server
+#[derive(Debug, Clone)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    pub value: Vec<u8>,
+    // Where it came from, e.g. "Content-MD5 trailer"
+    pub source: String,
+}
+
+impl Expected {
+    // A checksum published as hex, e.g. in an archive.org item's metadata
+    pub fn from_hex(algorithm: Algorithm, hex: &str, source: String) -> Option<Self> {
+        let hex = hex.trim();
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+        let value = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Self {
+            algorithm,
+            value,
+            source,
+        })
+    }
+}
+
+// The digests in `Content-MD5`, RFC 3230 `Digest` and RFC 9530 `Repr-Digest`
+// fields. `kind` says
+// whether the fields are headers or trailers, for messages. Algorithms we
+// cannot compute (e.g. the obsolete SHA-1 "SHA") and undecodable values are
+// skipped rather than failing the download.
+pub fn collect(fields: &[(String, String)], kind: &str) -> Vec<Expected> {
+    let mut expected = Vec::new();
+    for (name, value) in fields {
+        if name.eq_ignore_ascii_case("content-md5") {
+            if let Ok(digest) = STANDARD.decode(value.trim()) {
+                expected.push(Expected {
+                    algorithm: Algorithm::Md5,
+                    value: digest,
+                    source: format!("Content-MD5 {}", kind),
+                });
+            }
+        } else if name.eq_ignore_ascii_case("digest") {
+            // "SHA-256=<base64>, MD5=<base64>"; base64 padding also uses '='
+            for item in value.split(',') {
+                let Some((algorithm, digest)) = item.split_once('=') else {
+                    continue;
+                };
+                let (Some(algorithm), Ok(digest)) =
+                    (Algorithm::from_name(algorithm), STANDARD.decode(digest.trim()))
+                else {
+                    continue;
+                };
+                expected.push(Expected {
+                    algorithm,
+                    value: digest,
+                    source: format!("Digest {} ({})", kind, algorithm),
+                });
+            }
+        } else if name.eq_ignore_ascii_case("repr-digest") {
+            // A structured field dictionary: "sha-256=:<base64>:, sha-512=:<base64>:"
+            for item in value.split(',') {
+                let Some((algorithm, digest)) = item.split_once('=') else {
+                    continue;
+                };
+                let Some(digest) = digest.trim().strip_prefix(':').and_then(|d| d.strip_suffix(':'))
+                else {
+                    continue;
+                };
+                let (Some(algorithm), Ok(digest)) =
+                    (Algorithm::from_name(algorithm), STANDARD.decode(digest))
+                else {
+                    continue;
+                };
+                expected.push(Expected {
+                    algorithm,
+                    value: digest,
+                    source: format!("Repr-Digest {} ({})", kind, algorithm),
+                });
+            }
+        }
+    }
+    expected
+}
+
+// Hash `path` once with every algorithm needed and compare with `expected`.
+// With `sha256`, the same pass also returns the file's SHA-256 (for --dedup).
+pub fn verify(
+    path: &Path,
+    expected: &[Expected],
+    sha256: bool,
+) -> Result<Option<[u8; 32]>, Box<dyn Error + Send + Sync>> {
+    let extra = sha256.then_some(Algorithm::Sha256);
+    let mut running = Running::for_algorithms(expected.iter().map(|e| e.algorithm).chain(extra));
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        running.update(&buf[..n]);
+    }
+    running.verify(expected)
+}
+
+// Digests computed as the bytes go by, for a file whose plaintext never
+// reaches the disk (see --encrypt)
+pub struct Running {
+    hashers: Vec<(Algorithm, Hasher)>,
+}
+
+impl Running {
+    // Every algorithm a server may send a digest in, and those `published`
+    pub fn all(published: &[Expected]) -> Self {
+        let sent = [Algorithm::Md5, Algorithm::Sha256, Algorithm::Sha512];
+        Self::for_algorithms(sent.into_iter().chain(published.iter().map(|e| e.algorithm)))
+    }
+
+    fn for_algorithms(algorithms: impl IntoIterator<Item = Algorithm>) -> Self {
+        let mut hashers: Vec<(Algorithm, Hasher)> = Vec::new();
+        for algorithm in algorithms {
+            if !hashers.iter().any(|(a, _)| *a == algorithm) {
+                hashers.push((algorithm, algorithm.hasher()));
+            }
+        }
+        Self { hashers }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in self.hashers.iter_mut() {
+            hasher.update(data);
+        }
+    }
+
+    // Compare with `expected`, whose algorithms must all have been computed.
+    // Returns the SHA-256, when it was one of them.
+    pub fn verify(self, expected: &[Expected]) -> Result<Option<[u8; 32]>, Box<dyn Error + Send + Sync>> {
+        let actual: Vec<(Algorithm, Box<[u8]>)> = self
+            .hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+            .collect();
+
+        for e in expected {
+            let (_, digest) = actual
+                .iter()
+                .find(|(a, _)| *a == e.algorithm)
+                .expect("a hasher for every expected algorithm");
+            if digest.as_ref() != e.value.as_slice() {
+                return Err(format!(
+                    "{} mismatch: expected {}, got {}",
+                    e.source,
+                    STANDARD.encode(&e.value),
+                    STANDARD.encode(digest)
+                )
+                .into());
+            }
+        }
+        Ok(actual
+            .iter()
+            .find(|(a, _)| *a == Algorithm::Sha256)
+            .and_then(|(_, digest)| digest.as_ref().try_into().ok()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Digests of "hello"
+    const MD5: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+    const SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+    const SHA512: &str = "m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==";
+
+    fn fields(fields: &[(&str, String)]) -> Vec<(String, String)> {
+        fields.iter().map(|(name, value)| (name.to_string(), value.clone())).collect()
+    }
+
+    fn hello(expected: &[Expected]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut running = Running::all(expected);
+        running.update(b"hel");
+        running.update(b"lo");
+        running.verify(expected).map(drop)
+    }
+
+    #[test]
+    fn digests_are_collected_from_every_field_and_checked() {
+        let expected = collect(
+            &fields(&[
+                ("Content-MD5", MD5.to_string()),
+                // RFC 3230, with an obsolete SHA-1 and an unknown algorithm skipped
+                ("DIGEST", format!("SHA=qvTGHdzF6KLavt4PO0gs2a6pQ00=, Sha-256={}, crc32c=AAAAAA==", SHA256)),
+                // RFC 9530
+                ("repr-digest", format!("sha-512=:{}:, unixsum=:MTIz:", SHA512)),
+                ("Content-Type", "text/plain".to_string()),
+            ]),
+            "header",
+        );
+        let sources: Vec<&str> = expected.iter().map(|e| e.source.as_str()).collect();
+        assert_eq!(
+            sources,
+            ["Content-MD5 header", "Digest header (SHA-256)", "Repr-Digest header (SHA-512)"]
+        );
+        assert!(hello(&expected).is_ok());
+        assert!(hello(&[]).is_ok());
+
+        // Undecodable values and a Repr-Digest without its colons are skipped too
+        let skipped = collect(
+            &fields(&[
+                ("Content-MD5", "not base64!".to_string()),
+                ("Repr-Digest", format!("sha-256={}", SHA256)),
+            ]),
+            "trailer",
+        );
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn a_mismatch_names_the_field_and_both_digests() {
+        let expected = collect(&fields(&[("Digest", format!("MD5={}", SHA256))]), "trailer");
+        let error = hello(&expected).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!("Digest trailer (MD5) mismatch: expected {}, got {}", SHA256, MD5)
+        );
+    }
+}
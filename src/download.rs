@@ -0,0 +1,2464 @@
+use crate::age;
+use crate::chaos;
+use crate::control::{self, Control, Layout};
+use crate::digest;
+use crate::dedup;
+use crate::encoding;
+use crate::expect::{self, Expectations};
+use crate::limits;
+use crate::mime;
+use crate::output::{self, Level, Output};
+use crate::paths;
+use crate::permissions;
+use crate::portal;
+use crate::progress::{Event, Progress, ProgressObserver, Snapshot};
+use crate::protocol::{self, BoxError, ProtocolHandler, RangeNotSatisfiable, Registry, StatusError, WrongRange};
+use crate::sniff;
+use crate::speed::SpeedMeter;
+use crate::urls;
+use console::style;
+use futures::StreamExt;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+use reqwest::Client;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio_util::sync::CancellationToken;
+
+// How one file is downloaded; the defaults are those of the command line
+#[derive(Clone)]
+pub struct DownloadOptions {
+    // Checked against the probe before anything is written
+    pub expectations: Expectations,
+    // Continue a partial file at the destination instead of refusing to touch it
+    pub resume: bool,
+    // Discard a file at the destination and start over
+    pub restart: bool,
+    // Decode a gzip or deflate Content-Encoding instead of saving the encoded bytes
+    pub decompress: bool,
+    // Encrypt the file to this age recipient as it downloads, saving FILE.age
+    pub encrypt: Option<age::Recipient>,
+    // Check the file against digests the server sends
+    pub auto_digest: bool,
+    // Fail unless the server sends a digest to check against
+    pub verify_digest: bool,
+    // Checksums published for the file, checked whatever the server sends
+    pub digests: Vec<digest::Expected>,
+    // Save the response headers and trailers next to the file as FILE.meta.json
+    pub write_metadata: bool,
+    // Append an extension derived from the Content-Type to a destination without one
+    pub auto_extension: bool,
+    // Fail, rather than warn, when the file does not look like its extension promises
+    pub check_type: bool,
+    // KiB before the resume point to fetch again and compare with the file (0 disables)
+    pub verify_overlap: u64,
+    // Octal permission bits and owner for the finished file
+    pub chmod: Option<u32>,
+    pub chown: Option<permissions::Owner>,
+    // Where to record the SHA-256 of finished files for --dedup
+    pub dedup_hashes: Option<Arc<dedup::KnownHashes>>,
+    // Keep the part file here instead of next to the destination (created if missing)
+    pub tmp_dir: Option<PathBuf>,
+    // URL that tells an open connection from a captive portal, asked when an
+    // HTML page comes back in place of the file (None never asks)
+    pub portal_check: Option<String>,
+    // Fetch segments close to file order, so the start of the part file can be
+    // played while the rest downloads
+    pub sequential: bool,
+    // How far back the current speed on the progress bar looks, roughly
+    pub speed_window: Duration,
+    // Segment workers at most, for one download (None allows max_workers())
+    pub connections: Option<usize>,
+    // Bytes in each segment of a new segmented download; a resumed one keeps
+    // the size it was started with
+    pub segment_size: u64,
+    // Segments a worker asks for in one request, as a multipart/byteranges
+    // response held in memory (1 asks for each on its own)
+    pub multi_range: usize,
+    // Write handles on the part file that the segment workers share
+    pub file_handles: usize,
+    // Received chunks are gathered up to this many bytes before each write
+    pub chunk_buffer: usize,
+    // Times a failed segment is fetched again, with growing pauses, before
+    // the download fails
+    pub segment_retries: u32,
+    pub progress: Progress,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            expectations: Expectations::default(),
+            resume: false,
+            restart: false,
+            decompress: false,
+            encrypt: None,
+            auto_digest: true,
+            verify_digest: false,
+            digests: Vec::new(),
+            write_metadata: false,
+            auto_extension: false,
+            check_type: false,
+            verify_overlap: 64,
+            chmod: None,
+            chown: None,
+            dedup_hashes: None,
+            tmp_dir: None,
+            portal_check: None,
+            sequential: false,
+            speed_window: Duration::from_secs(5),
+            connections: None,
+            segment_size: SEGMENT_SIZE,
+            multi_range: 1,
+            file_handles: 4,
+            chunk_buffer: 256 * 1024,
+            segment_retries: 5,
+            progress: Progress::default(),
+        }
+    }
+}
+
+// Downloads single files with the built-in protocol handlers, for programs
+// embedding Gator. Nothing is printed; follow a download through `progress()`.
+pub struct Downloader {
+    registry: Registry,
+    options: DownloadOptions,
+}
+
+impl Downloader {
+    pub fn new() -> Result<Self, BoxError> {
+        let client = Client::builder().tcp_nodelay(true).build()?;
+        Ok(Self::with_registry(Registry::with_defaults(Arc::new(client), Vec::new())))
+    }
+
+    // Use these handlers and hooks, e.g. for a client with its own proxy or TLS setup
+    pub fn with_registry(registry: Registry) -> Self {
+        Self {
+            registry,
+            options: DownloadOptions::default(),
+        }
+    }
+
+    pub fn with_options(self, options: DownloadOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    // Where downloads report progress and events; subscribe before `run`
+    pub fn progress(&self) -> &Progress {
+        &self.options.progress
+    }
+
+    // Download `url` to `path`, returning where the file was saved (the path
+    // may have gained an extension with `auto_extension`). Cancelling `cancel`
+    // stops the transfer with a `Cancelled` error once every worker has
+    // flushed and closed its file; the part file is left ready for `resume`.
+    pub async fn run(&self, url: &str, path: &Path, cancel: &CancellationToken) -> Result<PathBuf, BoxError> {
+        self.run_with(url, path, &self.options, cancel).await
+    }
+
+    // A download of `url` set up step by step, starting from this downloader's options
+    pub fn request(&self, url: &str) -> DownloadRequest<'_> {
+        DownloadRequest {
+            downloader: self,
+            url: url.to_string(),
+            output: None,
+            options: self.options.clone(),
+            callbacks: Vec::new(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    async fn run_with(
+        &self,
+        url: &str,
+        path: &Path,
+        options: &DownloadOptions,
+        cancel: &CancellationToken,
+    ) -> Result<PathBuf, BoxError> {
+        let url = self.registry.resolve_url(url)?;
+        let out = Output::new(Level::Silent, false, true, false);
+        let result = download_file(&self.registry, &url, path, options, cancel, out).await;
+        if let Err(e) = &result {
+            let event = match e.downcast_ref::<Cancelled>() {
+                Some(cancelled) => Event::Cancelled {
+                    path: cancelled.part_path.clone(),
+                    kept: cancelled.kept,
+                },
+                None => Event::Failed { error: e.to_string() },
+            };
+            options.progress.event(event);
+        }
+        result
+    }
+}
+
+// One download, for embedders that only need to set a few things:
+//
+//     let saved = downloader
+//         .request("https://example.com/file.iso")
+//         .output("file.iso")
+//         .connections(8)
+//         .on_progress(|s| println!("{} bytes", s.bytes))
+//         .send()
+//         .await?;
+//
+// Whatever is not set keeps the downloader's options.
+pub struct DownloadRequest<'a> {
+    downloader: &'a Downloader,
+    url: String,
+    output: Option<PathBuf>,
+    options: DownloadOptions,
+    callbacks: Vec<Arc<dyn ProgressObserver>>,
+    cancel: CancellationToken,
+}
+
+impl DownloadRequest<'_> {
+    // Where to save the file; by default the last segment of the URL's path,
+    // in the current directory, as the command line names it
+    pub fn output(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            output: Some(path.into()),
+            ..self
+        }
+    }
+
+    // Segment workers at most (at least one)
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.options.connections = Some(connections.max(1));
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.options.resume = resume;
+        self
+    }
+
+    // Change any other option
+    pub fn options(mut self, change: impl FnOnce(&mut DownloadOptions)) -> Self {
+        change(&mut self.options);
+        self
+    }
+
+    // Called with every snapshot of this download, at most every 100 ms. A
+    // request with callbacks reports to them, not to the downloader's
+    // `progress()`.
+    pub fn on_progress(mut self, callback: impl Fn(&Snapshot) + Send + Sync + 'static) -> Self {
+        self.callbacks.push(Arc::new(Callback(callback)));
+        self
+    }
+
+    // Stop with a `Cancelled` error when `cancel` fires
+    pub fn cancel_with(self, cancel: CancellationToken) -> Self {
+        Self { cancel, ..self }
+    }
+
+    // Download, returning where the file was saved
+    pub async fn send(mut self) -> Result<PathBuf, BoxError> {
+        let path = self.output.take().unwrap_or_else(|| PathBuf::from(urls::file_name(&self.url)));
+        if !self.callbacks.is_empty() {
+            self.options.progress = Progress::new();
+            for callback in self.callbacks.drain(..) {
+                self.options.progress.observe(callback);
+            }
+        }
+        self.downloader.run_with(&self.url, &path, &self.options, &self.cancel).await
+    }
+}
+
+struct Callback<F>(F);
+
+impl<F: Fn(&Snapshot) + Send + Sync> ProgressObserver for Callback<F> {
+    fn on_progress(&self, snapshot: &Snapshot) {
+        (self.0)(snapshot)
+    }
+}
+
+// The error of a download whose cancellation token fired. The bytes that had
+// arrived in order, from the start of the file, are kept in the part file
+// (when one was created) so a download with `resume` continues after them.
+#[derive(Debug)]
+pub struct Cancelled {
+    pub part_path: Option<PathBuf>,
+    pub kept: u64,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.part_path {
+            Some(path) => write!(f, "Download cancelled; kept {} bytes in '{}'", self.kept, path.display()),
+            None => write!(f, "Download cancelled"),
+        }
+    }
+}
+
+impl Error for Cancelled {}
+
+// Bytes in a segment of the work-stealing scheduler, unless a download asks
+// for other sizes (1MB)
+const SEGMENT_SIZE: u64 = 1024 * 1024;
+
+// Segment range for work-stealing scheduler
+#[derive(Debug, Clone)]
+struct Segment {
+    index: usize,
+    start: u64,
+    end: u64,
+    // Failed fetches of this segment so far
+    attempts: u32,
+}
+
+// The segments of a download still to fetch. A failed segment goes back on the
+// queue, so it stays open until every segment is on disk (or the download
+// fails) rather than until the last one has been handed out.
+struct SegmentQueue {
+    tx: std::sync::Mutex<Option<mpsc::UnboundedSender<Segment>>>,
+    rx: Mutex<mpsc::UnboundedReceiver<Segment>>,
+    // Segments handed out or waiting that are not on disk yet
+    outstanding: AtomicUsize,
+}
+
+impl SegmentQueue {
+    fn new(segments: Vec<Segment>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let outstanding = AtomicUsize::new(segments.len());
+        for segment in segments {
+            let _ = tx.send(segment);
+        }
+        Self {
+            tx: std::sync::Mutex::new(Some(tx)),
+            rx: Mutex::new(rx),
+            outstanding,
+        }
+    }
+
+    // A segment is on disk; the last one lets idle workers stop
+    fn finished(&self) {
+        if self.outstanding.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.close();
+        }
+    }
+
+    fn close(&self) {
+        self.tx.lock().unwrap().take();
+    }
+
+    // Queue `segment` again once `delay` has passed, unless the download
+    // stops first
+    fn retry(self: &Arc<Self>, segment: Segment, delay: Duration, cancel: &CancellationToken) {
+        let queue = self.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = tokio::time::sleep(delay) => {
+                    if let Some(tx) = queue.tx.lock().unwrap().as_ref() {
+                        let _ = tx.send(segment);
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Whether fetching a segment again may help: a timeout, a reset or an
+// overloaded server may pass, but a file that changed, a refusal or an answer
+// with the wrong bytes will not
+fn worth_retrying(error: &BoxError) -> bool {
+    if error.is::<RangeNotSatisfiable>() || error.is::<WrongRange>() {
+        return false;
+    }
+    match error.downcast_ref::<StatusError>() {
+        Some(e) => !e.status.starts_with('4') || e.status.starts_with("408") || e.status.starts_with("429"),
+        None => true,
+    }
+}
+
+// Pause before fetching a segment again after its `attempts`th failure:
+// 500ms, doubling each time, up to 30s
+fn retry_delay(attempts: u32) -> Duration {
+    (Duration::from_millis(500) * 2u32.saturating_pow(attempts.saturating_sub(1).min(6))).min(Duration::from_secs(30))
+}
+
+// Tracks how many bytes of each segment have actually been written to disk.
+// Progress is derived from this instead of raw chunk counts, so a segment that
+// gets re-fetched continues from its committed offset rather than double counting.
+struct SegmentCoverage {
+    committed: Vec<AtomicU64>,
+    total: AtomicU64,
+}
+
+impl SegmentCoverage {
+    fn new(num_segments: usize) -> Self {
+        Self {
+            committed: (0..num_segments).map(|_| AtomicU64::new(0)).collect(),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    // Record `len` bytes written at the current end of segment `index`.
+    // Returns the new total number of committed bytes.
+    fn commit(&self, index: usize, len: u64) -> u64 {
+        self.committed[index].fetch_add(len, Ordering::Relaxed);
+        self.total.fetch_add(len, Ordering::Relaxed) + len
+    }
+
+    fn committed(&self, index: usize) -> u64 {
+        self.committed[index].load(Ordering::Relaxed)
+    }
+
+    fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // Bytes written without a gap from the start of the first segment, when
+    // every segment but the last is `segment_size` bytes of `len`
+    fn contiguous(&self, segment_size: u64, len: u64) -> u64 {
+        let mut offset = 0;
+        for committed in &self.committed {
+            let segment_len = segment_size.min(len - offset);
+            let committed = committed.load(Ordering::Relaxed);
+            if committed < segment_len {
+                return offset + committed;
+            }
+            offset += segment_len;
+        }
+        offset
+    }
+
+    // Bytes in the segments written in full, wherever they are, when every
+    // segment but the last is `segment_size` bytes of `len`
+    fn finished(&self, segment_size: u64, len: u64) -> u64 {
+        let mut offset = 0;
+        let mut finished = 0;
+        for committed in &self.committed {
+            let segment_len = segment_size.min(len - offset);
+            if committed.load(Ordering::Relaxed) == segment_len {
+                finished += segment_len;
+            }
+            offset += segment_len;
+        }
+        finished
+    }
+}
+
+// RFC 9218 urgency for a segment `rank` segments after the first one not yet
+// on disk: the next few ranges in file order come first (2, above the default
+// of 3), and ranges further ahead wait more (down to 6) without being starved
+fn segment_urgency(rank: usize) -> u8 {
+    2 + (rank / 4).min(4) as u8
+}
+
+// Lets workers in sequential mode wait for the segments before theirs
+#[derive(Default)]
+struct Turns {
+    // A segment finished, so the first unfinished one may have moved on
+    advanced: Notify,
+    // A worker stopped with an error, so the segments it held never will finish
+    failed: AtomicBool,
+}
+
+// Marks the turns failed, closes the queue and stops the other workers when a
+// worker stops at an error (dropped armed): the download has failed, so they
+// neither wait for its segments nor go on fetching the ones still queued
+struct FailOnDrop(Option<(Arc<Turns>, Arc<SegmentQueue>, CancellationToken)>);
+
+impl Drop for FailOnDrop {
+    fn drop(&mut self) {
+        if let Some((turns, queue, cancel)) = self.0.take() {
+            turns.failed.store(true, Ordering::Relaxed);
+            turns.advanced.notify_waiters();
+            queue.close();
+            cancel.cancel();
+        }
+    }
+}
+
+// Write handles on one part file, shared by its segment workers so a download
+// holds a few descriptors however many workers it has. Writes are positional
+// (pwrite), so workers on the same handle neither share a cursor nor wait for
+// each other, and a write is in the file once it returns.
+struct FilePool(Vec<Arc<fs::File>>);
+
+impl FilePool {
+    fn open(path: &str, count: usize) -> std::io::Result<Self> {
+        let files = (0..count.max(1))
+            .map(|_| fs::OpenOptions::new().write(true).open(path).map(Arc::new))
+            .collect::<Result<_, _>>()?;
+        Ok(Self(files))
+    }
+
+    async fn write_at(&self, worker: usize, offset: u64, data: bytes::Bytes) -> Result<(), BoxError> {
+        let file = self.0[worker % self.0.len()].clone();
+        tokio::task::spawn_blocking(move || write_all_at(&file, &data, offset)).await??;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+// seek_write also moves the handle's cursor, but nothing here reads it
+#[cfg(windows)]
+fn write_all_at(file: &fs::File, mut data: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                data = &data[written..];
+                offset += written as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Encrypts a download on its way to disk for --encrypt, hashing the
+// plaintext first, since the file on disk can no longer be checked against
+// digests from the server
+struct Sealer {
+    encryptor: age::Encryptor,
+    plaintext: digest::Running,
+    // The start of the file, written before the first chunk
+    header: Vec<u8>,
+}
+
+impl Sealer {
+    fn new(recipient: &age::Recipient, published: &[digest::Expected]) -> Result<Self, BoxError> {
+        let (encryptor, header) = age::Encryptor::new(recipient)?;
+        Ok(Self {
+            encryptor,
+            plaintext: digest::Running::all(published),
+            header,
+        })
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> bytes::Bytes {
+        self.plaintext.update(plaintext);
+        bytes::Bytes::from(self.encryptor.encrypt(plaintext))
+    }
+}
+
+// Gathers the small chunks a response arrives in into fewer, larger writes.
+// Bytes wait at most MAX_WAIT, so a slow transfer still shows progress.
+struct Coalescer {
+    buffer: bytes::BytesMut,
+    limit: usize,
+    since: Option<Instant>,
+}
+
+impl Coalescer {
+    const MAX_WAIT: Duration = Duration::from_millis(100);
+
+    fn new(limit: usize) -> Self {
+        Self {
+            buffer: bytes::BytesMut::new(),
+            limit,
+            since: None,
+        }
+    }
+
+    // Add a chunk; returns the bytes to write when they are due, which they
+    // always are at the `last` chunk
+    fn push(&mut self, chunk: bytes::Bytes, last: bool) -> Option<bytes::Bytes> {
+        // A chunk as large as the buffer is written as it is, without a copy
+        if self.buffer.is_empty() && (last || chunk.len() >= self.limit) {
+            return Some(chunk);
+        }
+        self.buffer.extend_from_slice(&chunk);
+        let since = *self.since.get_or_insert_with(Instant::now);
+        if last || self.buffer.len() >= self.limit || since.elapsed() >= Self::MAX_WAIT {
+            return self.take();
+        }
+        None
+    }
+
+    // Whatever is gathered, if anything
+    fn take(&mut self) -> Option<bytes::Bytes> {
+        self.since = None;
+        (!self.buffer.is_empty()).then(|| self.buffer.split().freeze())
+    }
+}
+
+// Segment workers a download may run at once
+pub fn max_workers() -> usize {
+    std::cmp::max(16, num_cpus::get() * 4)
+}
+
+pub async fn download_file(
+    registry: &Registry,
+    url: &str,
+    final_path: &Path,
+    options: &DownloadOptions,
+    cancel: &CancellationToken,
+    out: Output,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let handler = registry.handler_for(url)?;
+    out.detail(format!("Fetching {}...", url));
+
+    let expectations = &options.expectations;
+    let mut probe = cancel
+        .run_until_cancelled(registry.probe(url))
+        .await
+        .ok_or(Cancelled {
+            part_path: None,
+            kept: 0,
+        })?
+        .map_err(|e| expectations.check_error(e))?;
+    registry.inspect_probe(url, &mut probe)?;
+
+    out.detail(format!("Request sent... {}", probe.status));
+    options.progress.event(Event::Probed {
+        url: url.to_string(),
+        status: probe.status.clone(),
+        length: probe.length,
+        content_type: probe.content_type.clone(),
+        accepts_ranges: probe.accepts_ranges,
+    });
+    out.event(
+        "probe",
+        serde_json::json!({
+            "url": url,
+            "status": probe.status,
+            "length": probe.length,
+            "content_type": probe.content_type,
+            "content_encoding": probe.content_encoding,
+            "accepts_ranges": probe.accepts_ranges,
+        }),
+    );
+
+    // Long destinations on Windows need the verbatim prefix to be opened at all
+    let mut final_path = paths::long_path(final_path);
+    if options.auto_extension && final_path.extension().is_none() {
+        if let Some(extension) = probe.content_type.as_deref().and_then(mime::extension_for) {
+            final_path = paths::with_extension(&final_path, extension);
+            out.detail(format!("Saving as {} (from Content-Type)", final_path.display()));
+        }
+    }
+    // Only ciphertext is ever written, the part file included, so the
+    // plaintext's name is kept for checks of its type
+    let plain_path = final_path.clone();
+    if options.encrypt.is_some() {
+        final_path = paths::with_extension(&final_path, "age");
+    }
+
+    // Bytes land in a part file (next to the destination, or in --tmp-dir) and
+    // are moved into place on success, so a half-written file never carries
+    // the final name
+    if let Some(tmp_dir) = &options.tmp_dir {
+        fs::create_dir_all(tmp_dir)
+            .map_err(|e| format!("Cannot create temporary directory '{}': {}", tmp_dir.display(), e))?;
+    }
+    let part_path = paths::part_path_in(&final_path, options.tmp_dir.as_deref());
+    let file_name = part_path.to_string_lossy().into_owned();
+    let control_path = paths::control_path(&part_path);
+    let mut starting_pos = 0;
+    // Segments of an earlier run that are on disk, when it left a control file
+    let mut segments = None;
+
+    if part_path.exists() || final_path.exists() {
+        if options.restart {
+            out.detail("Existing file found, discarding it and starting over...");
+        } else if options.resume {
+            // A file left behind without a part file (e.g. by an older version)
+            // becomes the part file so it can be resumed and renamed as usual
+            if !part_path.exists() {
+                paths::move_file(&final_path, &part_path)?;
+            }
+            let existing_file = File::open(&part_path).await?;
+            starting_pos = existing_file.metadata().await?.len();
+            // A segmented download that was killed or failed leaves its part
+            // file at full length with holes, and a control file saying
+            // which segments are on disk
+            segments = control::load(&control_path)
+                .filter(|(layout, _)| layout.length == starting_pos && probe.length == Some(layout.length));
+            if let Some((layout, done)) = &segments {
+                let missing = done.iter().filter(|done| !**done).count();
+                out.detail(format!(
+                    "Existing file found, {} of its {} segments are missing; resuming them...",
+                    missing,
+                    done.len()
+                ));
+                starting_pos = layout.offset;
+            } else {
+                out.detail(format!(
+                    "Existing file found, attempting to resume download from byte {}...",
+                    starting_pos
+                ));
+            }
+        } else {
+            // Never guess: a same-named file may have nothing to do with this URL
+            return Err(format!(
+                "'{}' already exists; pass --continue to resume it or --no-continue to start over",
+                final_path.display()
+            )
+            .into());
+        }
+    } else {
+        out.detail("Starting new download...");
+    }
+
+    let content_length = probe.length;
+    let content_type = probe.content_type.as_deref().unwrap_or("unknown");
+
+    match content_length {
+        Some(len) => {
+            out.detail(format!("Length: {} bytes", len));
+            out.detail(format!("Type: {}", content_type));
+        }
+        None => out.detail("Length: unknown"),
+    }
+    if let Some(pin) = &probe.public_key_pin {
+        out.detail(format!("Public key: {}", pin));
+    }
+
+    // Content-Length counts encoded bytes. Saved as-is they match what lands on
+    // disk; decoded, the output size is unknown until the end and offsets into
+    // the file no longer correspond to ranges of the response.
+    let encoding = probe
+        .content_encoding
+        .clone()
+        .filter(|e| encoding::is_encoded(Some(e)));
+    let mut decoder = None;
+    if let Some(encoding) = &encoding {
+        if options.decompress {
+            decoder = encoding::Decoder::for_encoding(encoding);
+            if decoder.is_none() {
+                out.detail(format!("Encoding: {} (cannot decode, saving encoded bytes)", encoding));
+            } else {
+                out.detail(format!("Encoding: {} (decoding while downloading)", encoding));
+                if starting_pos > 0 || segments.is_some() {
+                    out.detail("A decoded download cannot be resumed, starting over...");
+                    starting_pos = 0;
+                    segments = None;
+                }
+            }
+        } else {
+            out.detail(format!("Encoding: {} (saving encoded bytes)", encoding));
+        }
+    }
+
+    if options.verify_digest && decoder.is_some() {
+        return Err("--verify-digest cannot check a decoded download; drop --decompress".into());
+    }
+    // The file key of an earlier run is gone, so there is nothing to resume
+    let mut sealer = None;
+    if let Some(recipient) = &options.encrypt {
+        sealer = Some(Sealer::new(recipient, &options.digests)?);
+        out.detail("Encrypting while downloading");
+        if starting_pos > 0 || segments.is_some() {
+            out.detail("An encrypted download cannot be resumed, starting over...");
+            starting_pos = 0;
+            segments = None;
+        }
+    }
+
+    expectations.check(&probe)?;
+    if !expectations.is_empty() {
+        out.detail("Response meets all expectations");
+    }
+    // A portal's login page must not be saved, or appended to a partial file.
+    // Only requests that go over the network can be intercepted.
+    if let Some(check_url) = options.portal_check.as_ref().filter(|_| url.starts_with("http")) {
+        if portal::suspect(&final_path, &probe, starting_pos > 0) {
+            if let Some(portal) = portal::detect(check_url).await {
+                return Err(portal.into());
+            }
+        }
+    }
+    // Batch jobs otherwise "succeed" at saving a login page as dataset.tar.gz
+    let type_mismatch = sniff::check_content_type(&plain_path, probe.content_type.as_deref());
+    if let Some(problem) = &type_mismatch {
+        suspicious(url, problem, options, out)?;
+    }
+
+    let accepts_ranges = probe.accepts_ranges;
+
+    // A part file longer than the server's copy cannot be the start of it
+    if let Some(total_len) = content_length.filter(|len| starting_pos > *len) {
+        out.detail(format!(
+            "File on disk ({} bytes) is longer than the server's ({} bytes), restarting download...",
+            starting_pos, total_len
+        ));
+        starting_pos = 0;
+    }
+
+    // Without validators there is no way to know the partial file came from this
+    // resource, so compare the tail we already have against the server's copy
+    if starting_pos > 0 && accepts_ranges && options.verify_overlap > 0 && probe.validator().is_none() {
+        let overlap = std::cmp::min(options.verify_overlap * 1024, starting_pos);
+        let matches = cancel
+            .run_until_cancelled(verify_overlap(handler.as_ref(), url, &file_name, starting_pos, overlap))
+            .await
+            .ok_or_else(|| Cancelled {
+                part_path: Some(part_path.clone()),
+                kept: starting_pos,
+            })??;
+        if !matches {
+            out.detail(format!(
+                "Last {} bytes on disk do not match the server, restarting download...",
+                overlap
+            ));
+            starting_pos = 0;
+            segments = None;
+        } else {
+            out.detail(format!("Verified last {} bytes before resume point", overlap));
+        }
+    }
+
+    // Only segmented downloads can fill in the missing segments
+    if segments.is_some() && !accepts_ranges {
+        out.detail("The server no longer accepts ranges, restarting download...");
+        starting_pos = 0;
+        segments = None;
+    }
+    if segments.is_none() {
+        control::remove(&control_path);
+        if starting_pos == 0 {
+            paths::create_private(&part_path)?;
+        }
+    }
+    // Nothing is left to fetch, and a range past the end would get a 416
+    let complete = starting_pos > 0 && content_length == Some(starting_pos);
+    if complete {
+        out.detail("File on disk is already complete");
+    }
+
+    // Trailers only arrive on a single streamed response
+    let decoding = decoder.is_some();
+    let encrypting = sealer.is_some();
+    let trailers = if complete {
+        Vec::new()
+    } else if decoding || encrypting {
+        download_single_chunk(
+            handler,
+            url,
+            &file_name,
+            starting_pos,
+            content_length.unwrap_or(0),
+            decoder,
+            sealer.as_mut(),
+            out,
+            options,
+            cancel,
+        )
+        .await?
+    } else {
+        match content_length {
+            Some(total_len) if accepts_ranges && total_len > 10 * 1024 * 1024 && starting_pos < total_len => {
+                download_with_work_stealing(
+                    handler,
+                    url,
+                    &file_name,
+                    starting_pos,
+                    total_len,
+                    segments.take(),
+                    out,
+                    options,
+                    cancel,
+                )
+                .await?;
+                Vec::new()
+            }
+            // Without a length to compare against, a 416 is how the server
+            // says the file on disk is as long as its copy, or longer
+            _ => loop {
+                let result = match content_length {
+                    Some(total_len) if total_len - starting_pos <= SMALL_FILE => {
+                        download_small(handler.clone(), url, &file_name, starting_pos, total_len, options, cancel).await
+                    }
+                    _ => {
+                        download_single_chunk(
+                            handler.clone(),
+                            url,
+                            &file_name,
+                            starting_pos,
+                            content_length.unwrap_or(0),
+                            None,
+                            None,
+                            out,
+                            options,
+                            cancel,
+                        )
+                        .await
+                    }
+                };
+                let error = match result {
+                    Err(e) if starting_pos > 0 => e,
+                    result => break result?,
+                };
+                match error.downcast_ref::<RangeNotSatisfiable>() {
+                    Some(RangeNotSatisfiable { length: Some(len) }) if *len == starting_pos => {
+                        out.detail("File on disk is already complete");
+                        break Vec::new();
+                    }
+                    Some(unsatisfiable) => {
+                        out.detail(format!(
+                            "{} ({} bytes on disk), restarting download...",
+                            unsatisfiable, starting_pos
+                        ));
+                        starting_pos = 0;
+                        paths::create_private(&part_path)?;
+                    }
+                    None => return Err(error),
+                }
+            },
+        }
+    };
+    for (name, value) in &trailers {
+        out.detail(format!("Trailer: {}: {}", name, value));
+    }
+    // One warning per file is enough
+    if type_mismatch.is_none() {
+        if let Some(problem) = sniff::check_file(&final_path, &part_path)? {
+            if options.check_type {
+                let _ = fs::remove_file(&part_path);
+            }
+            suspicious(url, &problem, options, out)?;
+        }
+    }
+
+    let mut expected = options.digests.clone();
+    if options.auto_digest {
+        // A trailer on a resumed download only covers the bytes sent this time
+        let mut sent = digest::collect(&probe.headers, "header");
+        if starting_pos == 0 {
+            sent.extend(digest::collect(&trailers, "trailer"));
+        }
+        if sent.is_empty() && options.verify_digest {
+            let _ = fs::remove_file(&part_path);
+            return Err(
+                "--verify-digest: the server sent no Content-MD5, Digest or Repr-Digest; the download was discarded"
+                    .into(),
+            );
+        }
+        expected.extend(sent);
+    }
+    // --dedup takes the SHA-256 of the plain file on disk from the same pass
+    let record = options.dedup_hashes.as_ref().filter(|_| !decoding && !encrypting);
+    let mut sha256 = None;
+    if !expected.is_empty() || record.is_some() {
+        if decoding {
+            // The server hashed the encoded bytes, which were never written
+            out.detail("Cannot verify the digest of a decoded download, skipping");
+        } else if let Some(sealer) = sealer {
+            // Only the plaintext hashed on its way to the encryptor can match
+            if let Err(e) = sealer.plaintext.verify(&expected) {
+                let _ = fs::remove_file(&part_path);
+                return Err(format!("{}; the download was discarded", e).into());
+            }
+            for e in &expected {
+                out.detail(format!("Verified {}", e.source));
+            }
+        } else {
+            match digest::verify(&part_path, &expected, record.is_some()) {
+                Ok(hash) => sha256 = hash,
+                Err(e) => {
+                    let _ = fs::remove_file(&part_path);
+                    return Err(format!("{}; the download was discarded", e).into());
+                }
+            }
+            for e in &expected {
+                out.detail(format!("Verified {}", e.source));
+            }
+        }
+    }
+
+    paths::finalize(&part_path, &final_path)?;
+    permissions::apply(&final_path, options.chmod, options.chown)?;
+    if let (Some(hashes), Some(sha256)) = (record, sha256) {
+        hashes.record(&final_path, sha256);
+    }
+
+    let bytes = fs::metadata(&final_path)?.len();
+    // Both sizes matter when the file on disk is still encoded
+    let decoded_bytes = match encoding.as_deref() {
+        _ if encrypting => None,
+        Some(_) if decoding => Some(bytes),
+        Some(e) if e.eq_ignore_ascii_case("gzip") || e.eq_ignore_ascii_case("x-gzip") => {
+            encoding::gzip_decoded_size(&final_path).ok().map(u64::from)
+        }
+        _ => None,
+    };
+    if options.write_metadata {
+        let metadata = serde_json::json!({
+            "url": url,
+            "status": probe.status,
+            "headers": fields_json(&probe.headers),
+            "trailers": fields_json(&trailers),
+        });
+        fs::write(
+            paths::metadata_path(&final_path),
+            serde_json::to_string_pretty(&metadata)?,
+        )?;
+    }
+    out.detail("Download complete!");
+    if let (Some(encoding), Some(decoded)) = (&encoding, decoded_bytes) {
+        out.detail(format!(
+            "Sizes: {} bytes encoded ({}), {} bytes decoded",
+            content_length.map_or_else(|| "?".to_string(), |l| l.to_string()),
+            encoding,
+            decoded
+        ));
+    }
+    out.event(
+        "complete",
+        serde_json::json!({
+            "url": url,
+            "path": final_path,
+            "bytes": bytes,
+            "encoded_bytes": encoding.as_ref().and(content_length),
+            "decoded_bytes": decoded_bytes,
+            "resumed_from": starting_pos,
+            "trailers": fields_json(&trailers),
+        }),
+    );
+    options.progress.event(Event::Finished {
+        path: final_path.clone(),
+        bytes,
+    });
+    Ok(final_path)
+}
+
+// A response that does not look like the file it is saved as: an expectation
+// failure with --check-type, otherwise a warning
+fn suspicious(
+    url: &str,
+    problem: &str,
+    options: &DownloadOptions,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if options.check_type {
+        return Err(expect::Failed(problem.to_string()).into());
+    }
+    options.progress.event(Event::Warning {
+        message: problem.to_string(),
+    });
+    out.info(format!("Warning: {}", problem));
+    out.event("warning", serde_json::json!({ "url": url, "message": problem }));
+    Ok(())
+}
+
+// Header or trailer fields as a JSON object; repeated names are joined with ", "
+fn fields_json(fields: &[(String, String)]) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (name, value) in fields {
+        match map.get_mut(name) {
+            Some(serde_json::Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            _ => {
+                map.insert(name.clone(), value.clone().into());
+            }
+        }
+    }
+    serde_json::Value::Object(map)
+}
+
+// Fetch `overlap` bytes ending at `starting_pos` and check they match what is on disk
+async fn verify_overlap(
+    handler: &dyn ProtocolHandler,
+    url: &str,
+    file_name: &str,
+    starting_pos: u64,
+    overlap: u64,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let start = starting_pos - overlap;
+    let mut stream = handler.open(url, start, Some(starting_pos - 1)).await?;
+    let mut remote = Vec::with_capacity(overlap as usize);
+    while let Some(chunk) = stream.next().await {
+        remote.extend_from_slice(&chunk?);
+    }
+
+    let mut file = File::open(file_name).await?;
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let mut local = vec![0u8; overlap as usize];
+    file.read_exact(&mut local).await?;
+
+    Ok(remote == local)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_with_work_stealing(
+    handler: Arc<dyn ProtocolHandler>,
+    url: &str,
+    file_name: &str,
+    starting_pos: u64,
+    total_len: u64,
+    // The layout and finished segments of an earlier run, from its control file
+    resumed: Option<(Layout, Vec<bool>)>,
+    out: Output,
+    options: &DownloadOptions,
+    cancel: &CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // With `sequential`, how far past the first unfinished segment a worker may start
+    const READ_AHEAD: u64 = 4;
+    let progress = &options.progress;
+    let remaining_bytes = total_len - starting_pos;
+    let layout = match &resumed {
+        Some((layout, _)) => *layout,
+        None => Layout {
+            length: total_len,
+            offset: starting_pos,
+            segment_size: options.segment_size.max(1),
+        },
+    };
+    let segment_size = layout.segment_size;
+    let num_segments = layout.segments() as u64;
+    let done = resumed.map_or_else(|| vec![false; num_segments as usize], |(_, done)| done);
+    let coverage = Arc::new(SegmentCoverage::new(num_segments as usize));
+
+    // Create work queue for dynamic segment distribution; segments an earlier
+    // run finished count as written already
+    let mut segments = Vec::new();
+    let mut current_pos = starting_pos;
+    for i in 0..num_segments {
+        let start = current_pos;
+        let end = if i == num_segments - 1 {
+            total_len - 1
+        } else {
+            current_pos + segment_size - 1
+        };
+        current_pos = end + 1;
+        if done[i as usize] {
+            coverage.commit(i as usize, end - start + 1);
+            continue;
+        }
+
+        segments.push(Segment {
+            index: i as usize,
+            start,
+            end,
+            attempts: 0,
+        });
+    }
+    let queued = segments.len() as u64;
+    let control_path = paths::control_path(Path::new(file_name));
+    // An earlier run fetched every segment but stopped before the file was renamed
+    if queued == 0 {
+        out.detail("Every segment is already on disk");
+        control::remove(&control_path);
+        return Ok(());
+    }
+
+    if queued < num_segments {
+        out.detail(format!(
+            "Downloading the {} of {} segments of {} not yet on disk using work-stealing scheduler",
+            queued,
+            num_segments,
+            HumanBytes(segment_size)
+        ));
+    } else {
+        out.detail(format!(
+            "Downloading in {} segments of {} each using work-stealing scheduler",
+            num_segments,
+            HumanBytes(segment_size)
+        ));
+    }
+
+    // Share receiver for work-stealing (mutex contention is minimal since workers do async I/O)
+    let queue = Arc::new(SegmentQueue::new(segments));
+
+    // Pre-allocate file to reduce fragmentation (this also truncates a discarded
+    // file). A file being filled in already has its length.
+    if starting_pos == 0 && queued == num_segments {
+        let file = fs::File::create(file_name)?;
+        file.set_len(total_len)?;
+    }
+    let control = Arc::new(
+        Control::create(&control_path, layout, &done)
+            .map_err(|e| format!("Cannot write control file '{}': {}", control_path.display(), e))?,
+    );
+
+    let resumed_from = starting_pos + coverage.total();
+    let bytes_downloaded = Arc::new(AtomicU64::new(0));
+    let turns = Arc::new(Turns::default());
+    let pb = create_progress_bar(
+        out,
+        &progress_label(file_name, out),
+        Some(total_len),
+        Arc::new(SpeedMeter::new(options.speed_window, resumed_from)),
+        bytes_downloaded.clone(),
+    );
+
+    // Worker pool size: `connections`, or max(16, CPU * 4), but no more than
+    // there are segments, nor with `sequential` than may be fetched at once.
+    // Extra workers would only hold connections that per-IP limits count against.
+    let connections = options.connections.unwrap_or_else(max_workers).max(1);
+    let mut worker_count = connections.min(queued as usize);
+    if options.sequential {
+        worker_count = worker_count.min(READ_AHEAD as usize);
+    }
+
+    // Fewer workers rather than "Too many open files" halfway through
+    let wanted = worker_count;
+    let (worker_count, handles, _descriptors) = limits::reserve(worker_count, options.file_handles.min(worker_count))?;
+    if worker_count < wanted {
+        out.detail(format!("The open-file limit leaves room for {} of {} workers", worker_count, wanted));
+    }
+    let files = Arc::new(FilePool::open(file_name, handles)?);
+    out.detail(format!(
+        "Spawning {} workers for parallel download, writing through {} file handles",
+        worker_count,
+        files.0.len()
+    ));
+    if options.sequential {
+        out.detail(format!("Sequential: starting segments at most {} ahead of the first unfinished one", READ_AHEAD));
+    }
+    // Workers also stop if this future is dropped mid-transfer, so none of
+    // them outlives the download
+    let cancel = cancel.child_token();
+    let _stop_workers = cancel.clone().drop_guard();
+    progress.start(
+        Path::new(file_name),
+        Some(total_len),
+        resumed_from,
+        queued as usize,
+        worker_count,
+    );
+
+    // Segments a worker asks for in one request; with `sequential` they are
+    // started one at a time, in turn
+    let multi_range = if options.sequential { 1 } else { options.multi_range.max(1) };
+    if multi_range > 1 {
+        out.detail(format!("Asking for up to {} segments per request", multi_range));
+    }
+    // Set once the server fails to answer for several ranges at once
+    let single_ranges = Arc::new(AtomicBool::new(false));
+
+    let mut handles = Vec::new();
+    for worker in 0..worker_count {
+        let handler = handler.clone();
+        let progress = progress.clone();
+        let url = url.to_string();
+        let files = files.clone();
+        let queue = queue.clone();
+        let pb = pb.clone();
+        let bytes_downloaded = bytes_downloaded.clone();
+        let coverage = coverage.clone();
+        let cancel = cancel.clone();
+        let turns = turns.clone();
+        let sequential = options.sequential;
+        let chunk_buffer = options.chunk_buffer;
+        let single_ranges = single_ranges.clone();
+        let control = control.clone();
+        let retries = options.segment_retries;
+
+        let handle = tokio::spawn(async move {
+            let mut fail_on_drop = FailOnDrop(Some((turns.clone(), queue.clone(), cancel.clone())));
+
+            'segments: while !cancel.is_cancelled() {
+                // Pull the next segment from the queue (work-stealing), and with
+                // `multi_range` more of those already waiting
+                let mut batch = Vec::new();
+                {
+                    let mut rx_guard = queue.rx.lock().await;
+                    // Segments being fetched may still fail and come back
+                    let segment = tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => break,
+                        segment = rx_guard.recv() => segment,
+                    };
+                    let Some(segment) = segment else {
+                        break;
+                    };
+                    batch.push(segment);
+                    while batch.len() < multi_range {
+                        match rx_guard.try_recv() {
+                            Ok(segment) => batch.push(segment),
+                            Err(_) => break,
+                        }
+                    }
+                }
+                // Continue from whatever part of a segment is already on disk;
+                // one that failed after its last byte is done
+                for segment in &batch {
+                    if segment.start + coverage.committed(segment.index) > segment.end {
+                        control.mark(segment.index)?;
+                        queue.finished();
+                    }
+                }
+                batch.retain(|segment| segment.start + coverage.committed(segment.index) <= segment.end);
+
+                if batch.len() > 1 && !single_ranges.load(Ordering::Relaxed) {
+                    let ranges: Vec<(u64, u64)> = batch
+                        .iter()
+                        .map(|segment| (segment.start + coverage.committed(segment.index), segment.end))
+                        .collect();
+                    for (segment, &(offset, end)) in batch.iter().zip(&ranges) {
+                        progress.segment_started(worker, segment.index, offset, Some(end));
+                    }
+                    let fetched = tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => break,
+                        fetched = handler.fetch_ranges(&url, &ranges) => fetched,
+                    };
+                    match fetched {
+                        Ok(fetched) => {
+                            for ((segment, &(offset, _)), data) in batch.iter().zip(&ranges).zip(fetched) {
+                                let len = data.len() as u64;
+                                chaos::check_write(offset, data.len())?;
+                                files.write_at(worker, offset, data).await?;
+                                bytes_downloaded.fetch_add(len, Ordering::Relaxed);
+                                pb.set_position(starting_pos + coverage.commit(segment.index, len));
+                                progress.advance(worker, len);
+                                control.mark(segment.index)?;
+                                progress.segment_finished(worker, segment.index);
+                                queue.finished();
+                            }
+                            turns.advanced.notify_waiters();
+                            continue;
+                        }
+                        // These segments are fetched one by one below, and so
+                        // is everything after them
+                        Err(e) => {
+                            if !single_ranges.swap(true, Ordering::Relaxed) {
+                                out.detail(format!("{}; asking for one segment per request", e));
+                            }
+                        }
+                    }
+                }
+
+                'batch: for mut segment in batch {
+                    // Segments are queued in order, so the ones before this are
+                    // already being fetched; wait until they are nearly done
+                    if sequential {
+                        loop {
+                            let advanced = turns.advanced.notified();
+                            tokio::pin!(advanced);
+                            advanced.as_mut().enable();
+                            let first_unfinished = coverage.contiguous(segment_size, remaining_bytes) / segment_size;
+                            if (segment.index as u64) < first_unfinished + READ_AHEAD || turns.failed.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            tokio::select! {
+                                biased;
+                                _ = cancel.cancelled() => break 'segments,
+                                _ = advanced => {}
+                            }
+                        }
+                    }
+
+                    loop {
+                        let offset = segment.start + coverage.committed(segment.index);
+                        progress.segment_started(worker, segment.index, offset, Some(segment.end));
+                        // Ranges sharing an HTTP/2 connection may arrive in file order
+                        let first_unfinished = coverage.contiguous(segment_size, remaining_bytes) / segment_size;
+                        let urgency = segment_urgency(segment.index.saturating_sub(first_unfinished as usize));
+                        // What went wrong with the request or response, if anything;
+                        // the bytes written before it stay committed
+                        let failed: Option<BoxError> = 'fetch: {
+                            let mut stream = tokio::select! {
+                                biased;
+                                _ = cancel.cancelled() => break 'segments,
+                                stream = handler.open_prioritized(&url, offset, Some(segment.end), urgency) => match stream {
+                                    Ok(stream) => stream,
+                                    Err(e) => break 'fetch Some(e),
+                                },
+                            };
+
+                            // Anything past the segment end would overwrite the next segment
+                            let expected = segment.end - offset + 1;
+                            let mut received = 0;
+                            let mut written = 0;
+                            let mut pending = Coalescer::new(chunk_buffer);
+                            loop {
+                                // A chunk being written is finished first, so what was
+                                // committed is on disk when the worker stops
+                                let chunk = tokio::select! {
+                                    biased;
+                                    _ = cancel.cancelled() => break 'segments,
+                                    chunk = stream.next() => chunk,
+                                };
+                                let chunk = match chunk {
+                                    Some(Ok(chunk)) => chunk,
+                                    Some(Err(e)) => break 'fetch Some(e),
+                                    None => break,
+                                };
+                                received += chunk.len() as u64;
+                                if received > expected {
+                                    return Err(format!(
+                                        "Segment {} ({}-{}): server sent more than the {} bytes requested",
+                                        segment.index, offset, segment.end, expected
+                                    )
+                                    .into());
+                                }
+                                let Some(data) = pending.push(chunk, received == expected) else {
+                                    continue;
+                                };
+                                let len = data.len() as u64;
+                                let at = offset + written;
+                                chaos::check_write(at, data.len())?;
+                                // Write directly to the data's offset in the file
+                                files.write_at(worker, at, data).await?;
+                                written += len;
+                                bytes_downloaded.fetch_add(len, Ordering::Relaxed);
+                                // Only count bytes once they have been written at their offset
+                                pb.set_position(starting_pos + coverage.commit(segment.index, len));
+                                progress.advance(worker, len);
+                            }
+                            if received < expected {
+                                break 'fetch Some(format!("received {} of {} bytes", received, expected).into());
+                            }
+                            None
+                        };
+                        let Some(e) = failed else {
+                            break;
+                        };
+
+                        let error = format!("Segment {} ({}-{}): {}", segment.index, offset, segment.end, e);
+                        segment.attempts += 1;
+                        if !worth_retrying(&e) || segment.attempts > retries {
+                            if segment.attempts > 1 {
+                                return Err(format!("{} (gave up after {} retries)", error, segment.attempts - 1).into());
+                            }
+                            return Err(error.into());
+                        }
+                        let delay = retry_delay(segment.attempts);
+                        out.detail(format!(
+                            "{}; retry {} of {} in {:?}",
+                            error,
+                            segment.attempts,
+                            retries,
+                            delay
+                        ));
+                        // Another worker picks it up once the pause is over. With
+                        // `sequential` the others may all be waiting for this
+                        // segment, so the worker holding it fetches it again.
+                        if !sequential {
+                            queue.retry(segment, delay, &cancel);
+                            continue 'batch;
+                        }
+                        tokio::select! {
+                            biased;
+                            _ = cancel.cancelled() => break 'segments,
+                            _ = tokio::time::sleep(delay) => {}
+                        }
+                    }
+                    control.mark(segment.index)?;
+                    progress.segment_finished(worker, segment.index);
+                    queue.finished();
+                    turns.advanced.notify_waiters();
+                }
+            }
+            progress.worker_done(worker);
+            fail_on_drop.0 = None;
+
+            Ok::<(), Box<dyn Error + Send + Sync>>(())
+        });
+
+        handles.push(handle);
+    }
+
+    let results = futures::future::join_all(handles).await;
+    // Every worker has stopped with its writes done; close the handles
+    drop(files);
+
+    // The part file keeps its length and the control file the segments on
+    // disk, so resuming fetches only the rest. Segments cut off halfway are
+    // not marked and start over. A worker that failed stopped the others too,
+    // so its error comes before the cancellation it caused.
+    let failed = results.into_iter().find_map(|result| match result {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e),
+        Err(e) => Some(Box::new(e) as BoxError),
+    });
+    if let Some(e) = failed {
+        pb.abandon();
+        progress.finish();
+        control.sync()?;
+        return Err(e);
+    }
+    if cancel.is_cancelled() && coverage.total() != remaining_bytes {
+        pb.abandon();
+        progress.finish();
+        control.sync()?;
+        return Err(Cancelled {
+            part_path: Some(PathBuf::from(file_name)),
+            kept: starting_pos + coverage.finished(segment_size, remaining_bytes),
+        }
+        .into());
+    }
+
+    pb.set_position(starting_pos + coverage.total());
+    // A segment whose response ended early leaves a hole of preallocated zeros
+    if coverage.total() != remaining_bytes {
+        pb.abandon();
+        return Err(format!(
+            "Download incomplete: received {} of {} bytes",
+            coverage.total(),
+            remaining_bytes
+        )
+        .into());
+    }
+    drop(control);
+    control::remove(&control_path);
+    finish_progress_bar(&pb, out);
+    progress.finish();
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn download_single_chunk(
+    handler: Arc<dyn ProtocolHandler>,
+    url: &str,
+    file_name: &str,
+    starting_pos: u64,
+    total_len: u64,
+    mut decoder: Option<encoding::Decoder>,
+    mut sealer: Option<&mut Sealer>,
+    out: Output,
+    options: &DownloadOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<(String, String)>, Box<dyn Error + Send + Sync>> {
+    let progress = &options.progress;
+    let bytes_downloaded = Arc::new(AtomicU64::new(0));
+    let meter = Arc::new(SpeedMeter::new(options.speed_window, starting_pos));
+    let pb = if total_len > 0 {
+        create_progress_bar(
+            out,
+            &progress_label(file_name, out),
+            Some(total_len),
+            meter,
+            bytes_downloaded.clone(),
+        )
+    } else {
+        create_progress_bar(
+            out,
+            &progress_label(file_name, out),
+            None,
+            meter,
+            bytes_downloaded.clone(),
+        )
+    };
+
+    let trailers = protocol::Trailers::default();
+    let total = (total_len > 0).then_some(total_len);
+    progress.start(Path::new(file_name), total, starting_pos, 1, 1);
+    progress.segment_started(0, 0, starting_pos, total.map(|t| t - 1));
+    let cancelled = || Cancelled {
+        part_path: Some(PathBuf::from(file_name)),
+        kept: starting_pos,
+    };
+    let mut stream = cancel
+        .run_until_cancelled(handler.open_with_trailers(url, starting_pos, None, trailers.clone()))
+        .await
+        .ok_or_else(cancelled)??;
+
+    let mut file = if starting_pos > 0 {
+        OpenOptions::new()
+            .write(true)
+            .append(true)
+            .open(file_name)
+            .await?
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)
+            .await?
+    };
+
+    // Progress and the length check below count bytes received, which are
+    // encoded bytes when decoding, once they are written
+    let mut written = starting_pos;
+    if let Some(sealer) = sealer.as_deref_mut() {
+        let header = std::mem::take(&mut sealer.header);
+        file.write_all(&header).await?;
+        written += header.len() as u64;
+    }
+    let mut pending = Coalescer::new(options.chunk_buffer);
+    loop {
+        let chunk = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => {
+                // Drop the preallocated tail so the part file resumes after
+                // the last byte written
+                file.flush().await?;
+                file.set_len(written).await?;
+                pb.abandon();
+                progress.worker_done(0);
+                progress.finish();
+                return Err(Cancelled {
+                    kept: written,
+                    ..cancelled()
+                }
+                .into());
+            }
+            chunk = stream.next() => chunk,
+        };
+        let (batch, done) = match chunk {
+            Some(chunk) => (pending.push(chunk?, false), false),
+            None => (pending.take(), true),
+        };
+        if let Some(batch) = batch {
+            let batch_len = batch.len() as u64;
+            let data = match decoder.as_mut() {
+                Some(decoder) => bytes::Bytes::from(decoder.decode(&batch)?),
+                None => batch,
+            };
+            let data = match sealer.as_deref_mut() {
+                Some(sealer) => sealer.seal(&data),
+                None => data,
+            };
+            chaos::check_write(written, data.len())?;
+            file.write_all(&data).await?;
+            written += data.len() as u64;
+            bytes_downloaded.fetch_add(batch_len, Ordering::Relaxed);
+            pb.inc(batch_len);
+            progress.advance(0, batch_len);
+        }
+        if done {
+            break;
+        }
+    }
+    if let Some(decoder) = decoder {
+        let rest = decoder
+            .finish()
+            .map_err(|e| format!("Failed to decode response body: {}", e))?;
+        let rest = match sealer.as_deref_mut() {
+            Some(sealer) => sealer.seal(&rest),
+            None => bytes::Bytes::from(rest),
+        };
+        chaos::check_write(written, rest.len())?;
+        file.write_all(&rest).await?;
+        written += rest.len() as u64;
+    }
+    if let Some(sealer) = sealer {
+        let rest = sealer.encryptor.finish();
+        chaos::check_write(written, rest.len())?;
+        file.write_all(&rest).await?;
+    }
+    file.flush().await?;
+
+    // A server that closes the connection early ends the stream without an error
+    if total_len > 0 {
+        let received = starting_pos + bytes_downloaded.load(Ordering::Relaxed);
+        if received < total_len {
+            pb.abandon();
+            return Err(format!(
+                "Connection closed after {} of {} bytes; run again with --continue to resume",
+                received, total_len
+            )
+            .into());
+        }
+        if received > total_len {
+            pb.abandon();
+            return Err(format!(
+                "Server sent {} bytes but advertised {}",
+                received, total_len
+            )
+            .into());
+        }
+    }
+
+    finish_progress_bar(&pb, out);
+    progress.segment_finished(0, 0);
+    progress.worker_done(0);
+    progress.finish();
+    let trailers = trailers.lock().unwrap().clone();
+    Ok(trailers)
+}
+
+// Files of at most this many bytes (or with that many left to resume) come
+// in one response that is held in memory and written at once
+const SMALL_FILE: u64 = 64 * 1024;
+
+// A file too small for segments, a progress bar or preallocation. The body
+// must be exactly the rest of the file before anything is written, so a
+// short or long response leaves the part file as it was.
+async fn download_small(
+    handler: Arc<dyn ProtocolHandler>,
+    url: &str,
+    file_name: &str,
+    starting_pos: u64,
+    total_len: u64,
+    options: &DownloadOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<(String, String)>, BoxError> {
+    let progress = &options.progress;
+    progress.start(Path::new(file_name), Some(total_len), starting_pos, 1, 1);
+    progress.segment_started(0, 0, starting_pos, total_len.checked_sub(1));
+    let trailers = protocol::Trailers::default();
+    let fetch = async {
+        let mut stream = handler.open_with_trailers(url, starting_pos, None, trailers.clone()).await?;
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            body.extend_from_slice(&chunk?);
+            if starting_pos + body.len() as u64 > total_len {
+                return Err(format!("Server sent more than the {} bytes it advertised", total_len).into());
+            }
+        }
+        Ok::<_, BoxError>(body)
+    };
+    let body = cancel.run_until_cancelled(fetch).await.ok_or_else(|| Cancelled {
+        part_path: Some(PathBuf::from(file_name)),
+        kept: starting_pos,
+    })??;
+    let received = starting_pos + body.len() as u64;
+    if received < total_len {
+        return Err(format!(
+            "Connection closed after {} of {} bytes; run again with --continue to resume",
+            received, total_len
+        )
+        .into());
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .append(starting_pos > 0)
+        .truncate(starting_pos == 0)
+        .open(file_name)
+        .await?;
+    chaos::check_write(starting_pos, body.len())?;
+    file.write_all(&body).await?;
+    file.flush().await?;
+    progress.advance(0, body.len() as u64);
+    progress.segment_finished(0, 0);
+    progress.worker_done(0);
+    progress.finish();
+    let trailers = trailers.lock().unwrap().clone();
+    Ok(trailers)
+}
+
+// "Downloading", or the file's name when several bars are on screen
+fn progress_label(file_name: &str, out: Output) -> String {
+    if out.details() {
+        return "Downloading".to_string();
+    }
+    let name = Path::new(file_name).file_name().unwrap_or_default().to_string_lossy();
+    name.strip_suffix(".part").unwrap_or(&name).to_string()
+}
+
+// The bar of a resumed download: `#` for the part that was already on disk,
+// then `=>` for the bytes of this session. Only indicatif's own bar can take
+// up the width left on the line, so this one is a fixed 40 columns.
+fn resumed_bar(resumed: u64, pos: u64, len: u64) -> String {
+    const WIDTH: usize = 40;
+    let cells = |bytes: u64| match len {
+        0 => WIDTH,
+        len => (bytes.min(len) as f64 / len as f64 * WIDTH as f64) as usize,
+    };
+    let (old, done) = (cells(resumed), cells(pos));
+    let (head, rest) = match done < WIDTH {
+        true => (">", WIDTH - done - 1),
+        false => ("", 0),
+    };
+    format!(
+        "{}{}{}",
+        style("#".repeat(old)).dim().for_stderr(),
+        style(format!("{}{}", "=".repeat(done.saturating_sub(old)), head)).cyan().for_stderr(),
+        style(" ".repeat(rest)).blue().for_stderr()
+    )
+}
+
+// A finished bar stays on screen for a single download; in a batch it makes
+// room for the next file
+fn finish_progress_bar(pb: &ProgressBar, out: Output) {
+    if out.details() {
+        pb.finish_with_message("Download complete!");
+    } else {
+        pb.finish_and_clear();
+    }
+}
+
+fn create_progress_bar(
+    out: Output,
+    msg: &str,
+    length: Option<u64>,
+    meter: Arc<SpeedMeter>,
+    _bytes_downloaded: Arc<AtomicU64>,
+) -> ProgressBar {
+    let bar = match !out.show_progress() || out.plain() {
+        true => ProgressBar::hidden(),
+        false => output::bars().add(match length {
+            Some(len) => ProgressBar::new(len),
+            None => ProgressBar::new_spinner(),
+        }),
+    };
+
+    bar.set_message(msg.to_string());
+    // Positions are in the whole file, so a resumed download starts part way
+    bar.set_position(meter.resumed());
+
+    match length.is_some() {
+        true => {
+            let (current, average, eta) = (meter.clone(), meter.clone(), meter.clone());
+            let resumed = meter.resumed();
+            let mut template = "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {percent}% {speed} (avg {average}) {bytes}/{total_bytes} eta: {eta}".to_string();
+            if resumed > 0 {
+                template = template.replace("{wide_bar:.cyan/blue}", "{resumed_bar}");
+            }
+            bar.set_style(ProgressStyle::default_bar()
+                .template(&template)
+                .unwrap()
+                .with_key("speed", move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                    let bytes_per_sec = current.update(state.pos(), Instant::now());
+                    write!(w, "{}", format_speed(bytes_per_sec)).unwrap();
+                })
+                .with_key("average", move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                    write!(w, "{}", format_speed(average.average(state.pos(), Instant::now()))).unwrap();
+                })
+                .with_key("resumed_bar", move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                    write!(w, "{}", resumed_bar(resumed, state.pos(), state.len().unwrap_or(0))).unwrap();
+                })
+                .with_key("eta", move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+                    match eta.eta(state.pos(), state.len().unwrap_or(0)) {
+                        Some(left) => write!(w, "{:#}", HumanDuration(left)).unwrap(),
+                        None => write!(w, "-").unwrap(),
+                    }
+                })
+                .progress_chars("=> "));
+        }
+        false => {
+            bar.set_style(ProgressStyle::default_spinner());
+        }
+    };
+
+    if out.show_progress() && out.plain() {
+        bar.set_length(length.unwrap_or(0));
+        spawn_plain_reporter(bar.clone(), meter);
+    }
+
+    bar
+}
+
+fn format_speed(bytes_per_sec: f64) -> String {
+    format!("{:.2} MB/s", bytes_per_sec / (1024.0 * 1024.0))
+}
+
+// Stand-in for the animated bar in plain mode: one timestamped line every few seconds
+fn spawn_plain_reporter(bar: ProgressBar, meter: Arc<SpeedMeter>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(5));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let finished = bar.is_finished();
+
+            let pos = bar.position();
+            let now = Instant::now();
+            let speed = format!(
+                "{}/s (avg {}/s)",
+                HumanBytes(meter.update(pos, now) as u64),
+                HumanBytes(meter.average(pos, now) as u64)
+            );
+            let resumed = match meter.resumed() {
+                0 => String::new(),
+                resumed => format!(", {} resumed", HumanBytes(resumed)),
+            };
+            let line = match bar.length() {
+                Some(len) if len > 0 => format!(
+                    "[{}] {} {:.1}% ({} of {}{}) {}{}",
+                    output::timestamp(),
+                    bar.message(),
+                    pos as f64 * 100.0 / len as f64,
+                    HumanBytes(pos),
+                    HumanBytes(len),
+                    resumed,
+                    speed,
+                    meter.eta(pos, len).map(|left| format!(", {} left", HumanDuration(left))).unwrap_or_default()
+                ),
+                _ => format!(
+                    "[{}] {} {} {}",
+                    output::timestamp(),
+                    bar.message(),
+                    HumanBytes(pos),
+                    speed
+                ),
+            };
+            eprintln!("{}", line);
+
+            if finished {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use crate::progress::{ProgressObserver, Snapshot, WorkerState};
+
+    fn downloader() -> Downloader {
+        let client = Client::builder().no_proxy().build().unwrap();
+        Downloader::with_registry(Registry::with_defaults(Arc::new(client), Vec::new()))
+    }
+
+    #[test]
+    fn small_chunks_are_gathered_into_one_write() {
+        let chunk = |len: usize| bytes::Bytes::from(vec![7u8; len]);
+        let mut pending = Coalescer::new(10);
+        assert_eq!(pending.push(chunk(4), false), None);
+        assert_eq!(pending.push(chunk(4), false), None);
+        assert_eq!(pending.push(chunk(4), false).map(|b| b.len()), Some(12));
+        // Large chunks skip the buffer, and the last one takes what is left
+        assert_eq!(pending.push(chunk(16), false).map(|b| b.len()), Some(16));
+        assert_eq!(pending.push(chunk(3), false), None);
+        assert_eq!(pending.push(chunk(2), true).map(|b| b.len()), Some(5));
+        assert_eq!(pending.take(), None);
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("file.bin")
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        snapshots: std::sync::Mutex<Vec<Snapshot>>,
+        events: std::sync::Mutex<Vec<Event>>,
+    }
+
+    impl ProgressObserver for Recorder {
+        fn on_progress(&self, snapshot: &Snapshot) {
+            self.snapshots.lock().unwrap().push(snapshot.clone());
+        }
+
+        fn on_event(&self, event: &Event) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn segmented_downloads_report_every_segment() {
+        let server = MockServer::start(12 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let downloader = downloader();
+        let recorder = Arc::new(Recorder::default());
+        downloader.progress().observe(recorder.clone());
+        let mut events = downloader.progress().events();
+        let watch = downloader.progress().watch();
+
+        let path = temp_path("progress-segments");
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let last = watch.borrow().clone();
+        assert_eq!(last.bytes, server.data().len() as u64);
+        assert_eq!(last.total, Some(server.data().len() as u64));
+        assert_eq!(last.segments_total, 12);
+        assert_eq!(last.segments_done, 12);
+        assert!(last.workers.iter().all(|w| *w == WorkerState::Done));
+        let snapshots = recorder.snapshots.lock().unwrap();
+        assert!(snapshots.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+        assert_eq!(snapshots.last().unwrap().bytes, last.bytes);
+
+        let mut streamed = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            streamed.push(event);
+        }
+        let observed = recorder.events.lock().unwrap();
+        assert_eq!(streamed.len(), observed.len());
+        assert!(matches!(streamed.first(), Some(Event::Probed { .. })));
+        // One worker per segment, however many cores there are
+        assert!(matches!(streamed[1], Event::Started { segments: 12, workers: 12, resumed_from: 0, .. }));
+        assert!(matches!(streamed.last(), Some(Event::Finished { bytes, .. }) if *bytes == last.bytes));
+        let started = streamed.iter().filter(|e| matches!(e, Event::SegmentStarted { .. })).count();
+        let finished = streamed.iter().filter(|e| matches!(e, Event::SegmentFinished { .. })).count();
+        assert_eq!((started, finished), (12, 12));
+    }
+
+    #[tokio::test]
+    async fn requests_set_their_own_connections_and_progress_callback() {
+        let server = MockServer::start(12 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let downloader = downloader();
+        let mut events = downloader.progress().events();
+        let seen = Arc::new(std::sync::Mutex::new(Snapshot::default()));
+        let last = seen.clone();
+
+        let path = temp_path("request");
+        let saved = downloader
+            .request(&server.url("file.bin"))
+            .output(&path)
+            .connections(3)
+            .on_progress(move |s| *last.lock().unwrap() = s.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let last = seen.lock().unwrap().clone();
+        assert_eq!(last.bytes, server.data().len() as u64);
+        assert_eq!(last.workers.len(), 3);
+        // The callback took the place of the downloader's progress
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn workers_sharing_one_file_handle_write_every_segment_in_place() {
+        let server = MockServer::start(12 * 1024 * 1024 + 123, Behavior::default()).await.unwrap();
+        let options = DownloadOptions {
+            file_handles: 1,
+            ..DownloadOptions::default()
+        };
+        let path = temp_path("one-handle");
+        let saved = downloader()
+            .with_options(options)
+            .run(&server.url("file.bin"), &path, &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn segments_are_batched_into_multi_range_requests() {
+        let options = DownloadOptions {
+            multi_range: 4,
+            connections: Some(2),
+            ..DownloadOptions::default()
+        };
+        for no_multi_range in [false, true] {
+            let behavior = Behavior {
+                no_multi_range,
+                ..Behavior::default()
+            };
+            let server = MockServer::start(12 * 1024 * 1024 + 5, behavior).await.unwrap();
+            let path = temp_path(&format!("multi-range-{}", no_multi_range));
+            let saved = downloader()
+                .with_options(options.clone())
+                .run(&server.url("file.bin"), &path, &CancellationToken::new())
+                .await
+                .unwrap();
+            assert_eq!(fs::read(&saved).unwrap(), server.data());
+            let _ = fs::remove_dir_all(path.parent().unwrap());
+
+            let gets: Vec<String> = server.requests().into_iter().filter(|r| r.starts_with("get ")).collect();
+            let batched = gets.iter().filter(|r| r.contains(",")).count();
+            if no_multi_range {
+                // A failed batch per worker at most, then a request per segment
+                assert!(batched <= 2);
+                assert_eq!(gets.len(), 13 + batched);
+            } else {
+                assert!(batched > 0 && gets.len() <= 6, "{} requests", gets.len());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn segment_size_sets_how_many_ranges_are_requested() {
+        let server = MockServer::start(12 * 1024 * 1024 + 5, Behavior::default()).await.unwrap();
+        let path = temp_path("segment-size");
+        let downloader = downloader().with_options(DownloadOptions {
+            segment_size: 4 * 1024 * 1024,
+            connections: Some(2),
+            ..DownloadOptions::default()
+        });
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let gets = server.requests().iter().filter(|r| r.starts_with("get ")).count();
+        assert_eq!(gets, 4);
+        assert_eq!(downloader.progress().watch().borrow().workers.len(), 2);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn sequential_downloads_stay_close_to_file_order() {
+        let server = MockServer::start(16 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let options = DownloadOptions {
+            sequential: true,
+            ..DownloadOptions::default()
+        };
+        let downloader = downloader().with_options(options);
+        let recorder = Arc::new(Recorder::default());
+        downloader.progress().observe(recorder.clone());
+        let path = temp_path("sequential");
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        // No segment starts before every segment four or more before it is done
+        let mut finished = std::collections::HashSet::new();
+        for event in recorder.events.lock().unwrap().iter() {
+            match event {
+                Event::Started { workers, .. } => assert_eq!(*workers, 4),
+                Event::SegmentStarted { segment, .. } => {
+                    assert!((0..segment.saturating_sub(3)).all(|s| finished.contains(&s)), "{}", segment);
+                }
+                Event::SegmentFinished { segment, .. } => {
+                    finished.insert(*segment);
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(finished.len(), 16);
+    }
+
+    #[test]
+    fn resumed_part_of_the_bar_is_drawn_apart() {
+        let bar = |resumed, pos| console::strip_ansi_codes(&resumed_bar(resumed, pos, 100)).into_owned();
+        assert_eq!(bar(25, 50), format!("{}{}>{}", "#".repeat(10), "=".repeat(10), " ".repeat(19)));
+        assert_eq!(bar(25, 25), format!("{}>{}", "#".repeat(10), " ".repeat(29)));
+        assert_eq!(bar(25, 100), format!("{}{}", "#".repeat(10), "=".repeat(30)));
+    }
+
+    #[tokio::test]
+    async fn segment_requests_carry_an_urgency() {
+        let server = MockServer::start(12 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let path = temp_path("urgency");
+        downloader().run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let ranges: Vec<String> = server.requests().into_iter().filter(|r| r.contains("range:")).collect();
+        assert_eq!(ranges.len(), 12);
+        let first = ranges.iter().find(|r| r.contains("bytes=0-")).unwrap();
+        assert!(first.contains("priority: u=2"), "{}", first);
+        assert!(ranges.iter().all(|r| r.contains("priority: u=")));
+        assert_eq!(segment_urgency(0), 2);
+        assert_eq!(segment_urgency(100), 6);
+    }
+
+    #[tokio::test]
+    async fn single_connection_downloads_report_one_segment() {
+        let behavior = Behavior {
+            no_ranges: true,
+            ..Behavior::default()
+        };
+        let server = MockServer::start(100_000, behavior).await.unwrap();
+        let downloader = downloader();
+        let watch = downloader.progress().watch();
+
+        let path = temp_path("progress-single");
+        downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let last = watch.borrow().clone();
+        assert_eq!(last.bytes, 100_000);
+        assert_eq!((last.segments_done, last.segments_total), (1, 1));
+        assert_eq!(last.workers, [WorkerState::Done]);
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_as_events() {
+        let behavior = Behavior {
+            bad_content_range: true,
+            ..Behavior::default()
+        };
+        let server = MockServer::start(12 * 1024 * 1024, behavior).await.unwrap();
+        let downloader = downloader();
+        let mut events = downloader.progress().events();
+
+        let path = temp_path("progress-failure");
+        let result = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await;
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let error = result.unwrap_err().to_string();
+        let mut last = None;
+        while let Ok(event) = events.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(Event::Failed { error: e }) if e == error));
+    }
+
+    #[tokio::test]
+    async fn cancelling_keeps_a_resumable_part_file() {
+        let behavior = Behavior {
+            slow: Some(std::time::Duration::from_millis(20)),
+            ..Behavior::default()
+        };
+        let server = MockServer::start(12 * 1024 * 1024, behavior).await.unwrap();
+        let url = server.url("file.bin");
+        // Two workers, each taking ~320ms a segment, are a few segments in
+        let downloader = downloader().with_options(DownloadOptions {
+            connections: Some(2),
+            ..DownloadOptions::default()
+        });
+        let watch = downloader.progress().watch();
+        let mut events = downloader.progress().events();
+        let cancel = CancellationToken::new();
+        let timer = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(800)).await;
+            timer.cancel();
+        });
+
+        let path = temp_path("cancel-resume");
+        let error = downloader.run(&url, &path, &cancel).await.unwrap_err();
+        let cancelled = error.downcast_ref::<Cancelled>().unwrap();
+        let part_path = cancelled.part_path.clone().unwrap();
+        // Finished segments stay where they are, and the control file says which
+        let on_disk = fs::read(&part_path).unwrap();
+        assert_eq!(on_disk.len(), server.data().len());
+        let (layout, done) = control::load(&paths::control_path(&part_path)).unwrap();
+        let segment = layout.segment_size as usize;
+        let finished: Vec<usize> = (0..done.len()).filter(|&i| done[i]).collect();
+        assert!(!finished.is_empty() && finished.len() < done.len());
+        assert_eq!(cancelled.kept, (finished.len() * segment) as u64);
+        for i in &finished {
+            let range = i * segment..(i + 1) * segment;
+            assert_eq!(on_disk[range.clone()], server.data()[range]);
+        }
+        assert!(!path.exists());
+        // Every worker returned before `run` did
+        assert!(watch.borrow().workers.iter().all(|w| *w == WorkerState::Done));
+        let mut last = None;
+        while let Ok(event) = events.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(Event::Cancelled { kept, .. }) if kept == cancelled.kept));
+
+        let gets = || server.requests().iter().filter(|r| r.starts_with("get ")).count();
+        let before = gets();
+        let resumed = downloader.with_options(DownloadOptions {
+            resume: true,
+            ..DownloadOptions::default()
+        });
+        let saved = resumed.run(&url, &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        assert_eq!(gets() - before, done.len() - finished.len());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_resources_without_validators_have_the_overlap_checked() {
+        let downloader = downloader().with_options(DownloadOptions {
+            resume: true,
+            ..DownloadOptions::default()
+        });
+        // The overlap before the resume point, here 100000
+        let overlap = |server: &MockServer| server.requests().iter().any(|head| head.contains("range: bytes=34464-99999"));
+
+        let server = MockServer::start(200_000, Behavior { no_etag: true, ..Behavior::default() }).await.unwrap();
+        let path = temp_path("overlap-unvalidated");
+        let mut part = server.data()[..100_000].to_vec();
+        part[99_999] ^= 0xff;
+        fs::write(paths::part_path(&path), &part).unwrap();
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert!(overlap(&server));
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        // An ETag identifies the version, so the tail is not fetched again
+        let server = MockServer::start(200_000, Behavior::default()).await.unwrap();
+        let path = temp_path("overlap-validated");
+        fs::write(paths::part_path(&path), &server.data()[..100_000]).unwrap();
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert!(!overlap(&server));
+        assert!(server.requests().iter().any(|head| head.contains("range: bytes=100000-")));
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn complete_or_longer_files_on_disk_are_not_resumed_past_the_end() {
+        let server = MockServer::start(64 * 1024, Behavior::default()).await.unwrap();
+        let url = server.url("file.bin");
+        let downloader = downloader().with_options(DownloadOptions {
+            resume: true,
+            ..DownloadOptions::default()
+        });
+
+        let path = temp_path("resume-complete");
+        fs::write(paths::part_path(&path), server.data()).unwrap();
+        let saved = downloader.run(&url, &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        assert!(server.requests().iter().all(|head| !head.contains("range: bytes=65536-")));
+
+        let mut longer = server.data().to_vec();
+        longer.extend_from_slice(b"trailing junk");
+        fs::write(&path, longer).unwrap();
+        let saved = downloader.run(&url, &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn edge_sizes_download_and_resume_intact() {
+        let small = SMALL_FILE as usize;
+        let threshold = 10 * 1024 * 1024;
+        for size in [0, 1, 2, 1023, 1024, small, small + 1, threshold, threshold + 1] {
+            let server = MockServer::start(size, Behavior::default()).await.unwrap();
+            let url = server.url("file.bin");
+            let path = temp_path(&format!("edge-{}", size));
+            let saved = downloader().run(&url, &path, &CancellationToken::new()).await.unwrap();
+            assert_eq!(fs::read(&saved).unwrap(), server.data(), "{} bytes", size);
+            if size <= small {
+                // The probe, then one GET
+                assert_eq!(server.requests().len(), 2, "{} bytes", size);
+            }
+
+            // One byte short, so the resumed request is a 1-byte range
+            if size > 0 {
+                fs::write(&path, &server.data()[..size - 1]).unwrap();
+                let resumed = downloader().with_options(DownloadOptions {
+                    resume: true,
+                    ..DownloadOptions::default()
+                });
+                let saved = resumed.run(&url, &path, &CancellationToken::new()).await.unwrap();
+                assert_eq!(fs::read(&saved).unwrap(), server.data(), "{} bytes resumed", size);
+            }
+            let _ = fs::remove_dir_all(path.parent().unwrap());
+        }
+
+        // A small file cut short leaves nothing behind to resume from
+        let behavior = Behavior {
+            reset_after: Some(500),
+            ..Behavior::default()
+        };
+        let server = MockServer::start(1000, behavior).await.unwrap();
+        let path = temp_path("edge-reset");
+        assert!(downloader().run(&server.url("file.bin"), &path, &CancellationToken::new()).await.is_err());
+        assert_eq!(fs::metadata(paths::part_path(&path)).unwrap().len(), 0);
+        let resumed = downloader().with_options(DownloadOptions {
+            resume: true,
+            ..DownloadOptions::default()
+        });
+        let saved = resumed.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_segmented_downloads_resume_only_the_missing_segments() {
+        // The first GET is cut short, failing one segment while the rest arrive
+        let behavior = Behavior {
+            reset_after: Some(100_000),
+            ..Behavior::default()
+        };
+        let server = MockServer::start(12 * 1024 * 1024 + 123, behavior).await.unwrap();
+        let url = server.url("file.bin");
+        let path = temp_path("control-resume");
+        let part_path = paths::part_path(&path);
+        let control_path = paths::control_path(&part_path);
+        let once = downloader().with_options(DownloadOptions {
+            segment_retries: 0,
+            ..DownloadOptions::default()
+        });
+        assert!(once.run(&url, &path, &CancellationToken::new()).await.is_err());
+        assert_eq!(fs::metadata(&part_path).unwrap().len(), server.data().len() as u64);
+        // The failure stops the other workers, so segments still queued or
+        // cut off are missing along with the one that failed
+        let (layout, done) = control::load(&control_path).unwrap();
+        let missing = done.iter().filter(|done| !**done).count();
+        assert!(missing >= 1);
+        let part = fs::read(&part_path).unwrap();
+        for (i, _) in done.iter().enumerate().filter(|(_, done)| **done) {
+            let start = i * layout.segment_size as usize;
+            let end = (start + layout.segment_size as usize).min(part.len());
+            assert_eq!(part[start..end], server.data()[start..end]);
+        }
+
+        let gets = || server.requests().iter().filter(|r| r.starts_with("get ")).count();
+        let before = gets();
+        let resumed = downloader().with_options(DownloadOptions {
+            resume: true,
+            ..DownloadOptions::default()
+        });
+        let saved = resumed.run(&url, &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        assert_eq!(gets() - before, missing);
+        assert!(!control_path.exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_segments_are_fetched_again_until_they_run_out_of_retries() {
+        let size = 12 * 1024 * 1024 + 7;
+        let server = MockServer::start(size, Behavior { unavailable: 3, ..Behavior::default() }).await.unwrap();
+        let path = temp_path("segment-retries");
+        let saved = downloader().run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        let gets = server.requests().iter().filter(|r| r.starts_with("get ")).count();
+        assert_eq!(gets, 13 + 3);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let server = MockServer::start(size, Behavior { unavailable: 100, ..Behavior::default() }).await.unwrap();
+        let path = temp_path("segment-retries-exhausted");
+        let downloader = downloader().with_options(DownloadOptions {
+            segment_retries: 1,
+            connections: Some(1),
+            ..DownloadOptions::default()
+        });
+        let error = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap_err();
+        assert!(error.to_string().contains("gave up after 1 retries"), "{}", error);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn encrypted_downloads_never_write_the_plaintext() {
+        let server = MockServer::start(200_000, Behavior::default()).await.unwrap();
+        let path = temp_path("encrypt");
+        let recipient = "age:age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+        let downloader = downloader().with_options(DownloadOptions {
+            encrypt: Some(age::parse(recipient).unwrap()),
+            ..DownloadOptions::default()
+        });
+
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(saved, paths::with_extension(&path, "age"));
+        assert!(!path.exists());
+        let encrypted = fs::read(&saved).unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/v1\n"));
+        // Four chunks, each with its tag
+        assert!(encrypted.len() > 200_000 + 4 * 16);
+        assert!(!encrypted.windows(64).any(|w| w == &server.data()[1000..1064]));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn finished_files_record_their_sha256_for_dedup() {
+        use sha2::{Digest, Sha256};
+        let server = MockServer::start(3 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let path = temp_path("dedup-hash");
+        let hashes = Arc::new(dedup::KnownHashes::default());
+        let downloader = downloader().with_options(DownloadOptions {
+            dedup_hashes: Some(hashes.clone()),
+            ..DownloadOptions::default()
+        });
+
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        let expected: [u8; 32] = Sha256::digest(server.data()).into();
+        assert_eq!(hashes.get(&saved), Some(expected));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn part_files_can_live_in_a_temporary_directory() {
+        let server = MockServer::start(3 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let path = temp_path("tmp-dir");
+        let tmp_dir = path.parent().unwrap().join("scratch");
+        let downloader = downloader().with_options(DownloadOptions {
+            tmp_dir: Some(tmp_dir.clone()),
+            ..DownloadOptions::default()
+        });
+
+        let saved = downloader.run(&server.url("file.bin"), &path, &CancellationToken::new()).await.unwrap();
+        assert_eq!(fs::read(&saved).unwrap(), server.data());
+        assert_eq!(fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        assert!(!paths::part_path(&path).exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancelled_downloads_do_not_start() {
+        let server = MockServer::start(1000, Behavior::default()).await.unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let path = temp_path("cancel-early");
+        let error = downloader().run(&server.url("file.bin"), &path, &cancel).await.unwrap_err();
+        assert!(error.downcast_ref::<Cancelled>().is_some());
+        assert!(server.requests().is_empty());
+        assert!(!paths::part_path(&path).exists());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,59 @@
+use flate2::write::{GzDecoder, ZlibDecoder};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Streaming decoder for a `Content-Encoding` applied by the server. Offsets in
+// the decoded output do not correspond to offsets in the response, which is
+// why decoded downloads can neither be split into ranges nor resumed.
+pub enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    // HTTP's "deflate" is zlib-wrapped deflate
+    Deflate(ZlibDecoder<Vec<u8>>),
+}
+
+impl Decoder {
+    // None for identity and for encodings we cannot decode (e.g. br)
+    pub fn for_encoding(encoding: &str) -> Option<Self> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip(GzDecoder::new(Vec::new()))),
+            "deflate" => Some(Self::Deflate(ZlibDecoder::new(Vec::new()))),
+            _ => None,
+        }
+    }
+
+    // Feed encoded bytes and take whatever decoded output is ready
+    pub fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(d) => {
+                d.write_all(chunk)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+            Self::Deflate(d) => {
+                d.write_all(chunk)?;
+                Ok(std::mem::take(d.get_mut()))
+            }
+        }
+    }
+
+    // Flush the rest of the output, failing if the encoded stream is incomplete
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(d) => d.finish(),
+            Self::Deflate(d) => d.finish(),
+        }
+    }
+}
+
+// An encoding that changes the bytes on the wire
+pub fn is_encoded(encoding: Option<&str>) -> bool {
+    encoding.is_some_and(|e| !e.trim().eq_ignore_ascii_case("identity"))
+}
+
+// The decoded size recorded in a gzip trailer (ISIZE, modulo 4 GiB)
+pub fn gzip_decoded_size(path: &Path) -> io::Result<u32> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::End(-4))?;
+    let mut isize = [0u8; 4];
+    file.read_exact(&mut isize)?;
+    Ok(u32::from_le_bytes(isize))
+}
@@ -0,0 +1,206 @@
+use crate::pattern;
+use crate::protocol::{Probe, StatusError};
+use std::error::Error;
+use std::fmt;
+
+// Exit status when an expectation is not met, so CI can tell it apart from
+// network or disk errors (exit status 1)
+pub const EXIT_CODE: i32 = 3;
+
+// Checks on the server's response, made before anything is written to disk
+#[derive(Debug, Clone, Default)]
+pub struct Expectations {
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    pub length: Option<u64>,
+    pub min_length: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Failed(pub String);
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expectation failed: {}", self.0)
+    }
+}
+
+impl Error for Failed {}
+
+// The exit status for a run that ended with `error`
+pub fn exit_code(error: &(dyn Error + Send + Sync + 'static)) -> i32 {
+    if error.is::<Failed>() {
+        EXIT_CODE
+    } else {
+        1
+    }
+}
+
+// clap value parser for `--expect-header`: "Name: value", where the value may
+// use `*` and `?` wildcards
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("'{}' is not of the form 'Name: value'", s)),
+    }
+}
+
+impl Expectations {
+    pub fn is_empty(&self) -> bool {
+        self.status.is_none()
+            && self.headers.is_empty()
+            && self.length.is_none()
+            && self.min_length.is_none()
+    }
+
+    // Turn a failed probe into an expectation failure when a status was
+    // expected, since "expected 200, got 404" is exactly what was asked about
+    pub fn check_error(&self, error: Box<dyn Error + Send + Sync>) -> Box<dyn Error + Send + Sync> {
+        match (self.status, error.downcast_ref::<StatusError>()) {
+            (Some(expected), Some(e)) => {
+                Failed(format!("expected status {}, got {}", expected, e.status)).into()
+            }
+            _ => error,
+        }
+    }
+
+    pub fn check(&self, probe: &Probe) -> Result<(), Failed> {
+        if let Some(expected) = self.status {
+            let actual = probe.status.split_whitespace().next().and_then(|c| c.parse::<u16>().ok());
+            if actual != Some(expected) {
+                return Err(Failed(format!(
+                    "expected status {}, got {}",
+                    expected, probe.status
+                )));
+            }
+        }
+
+        for (name, expected) in &self.headers {
+            let actual = probe
+                .headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str());
+            match actual {
+                Some(value) if pattern::matches(expected, value) => {}
+                Some(value) => {
+                    return Err(Failed(format!(
+                        "expected header {}: {}, got {}",
+                        name, expected, value
+                    )))
+                }
+                None => return Err(Failed(format!("expected header {} is missing", name))),
+            }
+        }
+
+        if let Some(expected) = self.length {
+            if probe.length != Some(expected) {
+                return Err(Failed(format!(
+                    "expected Content-Length {}, got {}",
+                    expected,
+                    describe_length(probe.length)
+                )));
+            }
+        }
+        if let Some(min) = self.min_length {
+            if probe.length.is_none_or(|len| len < min) {
+                return Err(Failed(format!(
+                    "expected Content-Length of at least {}, got {}",
+                    min,
+                    describe_length(probe.length)
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn describe_length(length: Option<u64>) -> String {
+    length.map_or_else(|| "none".to_string(), |len| len.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(status: &str, length: Option<u64>, content_type: &str) -> Probe {
+        Probe {
+            status: status.to_string(),
+            length,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            ..Probe::default()
+        }
+    }
+
+    fn failure(expectations: &Expectations, probe: &Probe) -> String {
+        expectations.check(probe).unwrap_err().0
+    }
+
+    #[test]
+    fn headers_parse_as_name_and_pattern() {
+        assert_eq!(
+            parse_header(" content-type : application/*"),
+            Ok(("content-type".to_string(), "application/*".to_string()))
+        );
+        assert_eq!(parse_header("X-Empty:"), Ok(("X-Empty".to_string(), String::new())));
+        assert!(parse_header("no colon").is_err());
+        assert!(parse_header(": value").is_err());
+    }
+
+    #[test]
+    fn responses_are_checked_for_status_type_and_size() {
+        let iso = probe("200 OK", Some(5000), "application/x-iso9660-image");
+        assert!(Expectations::default().is_empty());
+        assert!(Expectations::default().check(&iso).is_ok());
+
+        let expectations = Expectations {
+            status: Some(200),
+            headers: vec![parse_header("content-type: application/*").unwrap()],
+            length: Some(5000),
+            min_length: Some(4096),
+        };
+        assert!(!expectations.is_empty());
+        assert!(expectations.check(&iso).is_ok());
+
+        let status = Expectations { status: Some(200), ..Expectations::default() };
+        assert_eq!(failure(&status, &probe("206 Partial Content", None, "")), "expected status 200, got 206 Partial Content");
+
+        let html = probe("200 OK", Some(5000), "text/html; charset=utf-8");
+        assert_eq!(
+            failure(&expectations, &html),
+            "expected header content-type: application/*, got text/html; charset=utf-8"
+        );
+        let missing = Expectations {
+            headers: vec![parse_header("ETag: *").unwrap()],
+            ..Expectations::default()
+        };
+        assert_eq!(failure(&missing, &iso), "expected header ETag is missing");
+
+        let length = Expectations { length: Some(4999), ..Expectations::default() };
+        assert_eq!(failure(&length, &iso), "expected Content-Length 4999, got 5000");
+        let min_length = Expectations { min_length: Some(4096), ..Expectations::default() };
+        assert!(min_length.check(&probe("200 OK", Some(4096), "")).is_ok());
+        assert_eq!(
+            failure(&min_length, &probe("200 OK", None, "")),
+            "expected Content-Length of at least 4096, got none"
+        );
+    }
+
+    #[test]
+    fn unmet_expectations_exit_with_their_own_code() {
+        let expectations = Expectations { status: Some(200), ..Expectations::default() };
+        let not_found: Box<dyn Error + Send + Sync> = Box::new(StatusError { status: "404 Not Found".to_string() });
+        let error = expectations.check_error(not_found);
+        assert_eq!(error.to_string(), "Expectation failed: expected status 200, got 404 Not Found");
+        assert_eq!(exit_code(error.as_ref()), EXIT_CODE);
+        assert_ne!(EXIT_CODE, 1);
+
+        // Without an expected status a failed probe stays an ordinary error
+        let not_found: Box<dyn Error + Send + Sync> = Box::new(StatusError { status: "404 Not Found".to_string() });
+        let error = Expectations::default().check_error(not_found);
+        assert_eq!(exit_code(error.as_ref()), 1);
+        assert_eq!(exit_code(&Failed("expected header ETag is missing".to_string())), EXIT_CODE);
+    }
+}
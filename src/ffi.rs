@@ -0,0 +1,218 @@
+// A C interface to the download engine, for programs that load Gator as a
+// shared library (see include/gator.h). Built with the `ffi` feature:
+//
+//     cargo rustc --release --lib --features ffi --crate-type cdylib
+//
+// Downloads block the calling thread; cancel one from another thread with a
+// cancel handle.
+
+use crate::blocking;
+use crate::download::{Cancelled, DownloadOptions};
+use crate::progress::{ProgressObserver, Snapshot};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+
+pub const GATOR_OK: c_int = 0;
+pub const GATOR_ERROR: c_int = 1;
+pub const GATOR_CANCELLED: c_int = 2;
+
+// bytes, total (0 when unknown), speed in bytes per second, user data
+pub type GatorProgressFn = extern "C" fn(u64, u64, f64, *mut c_void);
+
+pub struct GatorCancel(CancellationToken);
+
+thread_local! {
+    // The message of the last failed call on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // An interior NUL would cut the message short; C strings cannot hold one
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// The callback and its user data, called from the download's threads
+struct Callback {
+    progress: GatorProgressFn,
+    user_data: *mut c_void,
+}
+
+// The caller promises the user data may be used from other threads
+unsafe impl Send for Callback {}
+unsafe impl Sync for Callback {}
+
+impl ProgressObserver for Callback {
+    fn on_progress(&self, snapshot: &Snapshot) {
+        (self.progress)(snapshot.bytes, snapshot.total.unwrap_or(0), snapshot.speed, self.user_data);
+    }
+}
+
+/// Create a handle for cancelling downloads. Free it with `gator_cancel_free`.
+#[no_mangle]
+pub extern "C" fn gator_cancel_new() -> *mut GatorCancel {
+    Box::into_raw(Box::new(GatorCancel(CancellationToken::new())))
+}
+
+/// Cancel every download started with `cancel`, from any thread.
+///
+/// # Safety
+///
+/// `cancel` must come from `gator_cancel_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn gator_cancel(cancel: *const GatorCancel) {
+    if let Some(cancel) = cancel.as_ref() {
+        cancel.0.cancel();
+    }
+}
+
+/// # Safety
+///
+/// `cancel` must come from `gator_cancel_new`, and no download may still be
+/// using it. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn gator_cancel_free(cancel: *mut GatorCancel) {
+    if !cancel.is_null() {
+        drop(Box::from_raw(cancel));
+    }
+}
+
+/// Download `url` to `path`, returning `GATOR_OK`, `GATOR_ERROR` or
+/// `GATOR_CANCELLED`. With `resume` non-zero, a partial file left by an
+/// earlier attempt is continued. `progress` (may be NULL) is called with
+/// `user_data` at most every 100 ms, from another thread. `cancel` may be NULL.
+///
+/// # Safety
+///
+/// `url` and `path` must be NUL-terminated UTF-8 strings. `cancel` must be
+/// NULL or a live handle from `gator_cancel_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gator_download(
+    url: *const c_char,
+    path: *const c_char,
+    resume: c_int,
+    progress: Option<GatorProgressFn>,
+    user_data: *mut c_void,
+    cancel: *const GatorCancel,
+) -> c_int {
+    let (url, path) = match (string(url), string(path)) {
+        (Some(url), Some(path)) => (url, path),
+        _ => {
+            set_last_error("url and path must be non-NULL UTF-8 strings".to_string());
+            return GATOR_ERROR;
+        }
+    };
+    let options = DownloadOptions {
+        resume: resume != 0,
+        ..DownloadOptions::default()
+    };
+    if let Some(progress) = progress {
+        options.progress.observe(Arc::new(Callback { progress, user_data }));
+    }
+    let cancel = cancel.as_ref().map_or_else(CancellationToken::new, |c| c.0.clone());
+
+    // A panic must not unwind into C
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        blocking::download_until_cancelled(url, Path::new(path), options, cancel)
+    }));
+    match result {
+        Ok(Ok(_)) => GATOR_OK,
+        Ok(Err(e)) => {
+            let code = match e.downcast_ref::<Cancelled>() {
+                Some(_) => GATOR_CANCELLED,
+                None => GATOR_ERROR,
+            };
+            set_last_error(e.to_string());
+            code
+        }
+        Err(_) => {
+            set_last_error("internal error (panic) in the download".to_string());
+            GATOR_ERROR
+        }
+    }
+}
+
+/// The message of the last failed `gator_download` on this thread, or NULL.
+/// Valid until the next call of `gator_download` on the same thread.
+#[no_mangle]
+pub extern "C" fn gator_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |m| m.as_ptr()))
+}
+
+unsafe fn string<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    extern "C" fn record(bytes: u64, _total: u64, _speed: f64, user_data: *mut c_void) {
+        let last = unsafe { &*(user_data as *const AtomicU64) };
+        last.store(bytes, Ordering::SeqCst);
+    }
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn downloads_through_the_c_interface() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::start(12 * 1024 * 1024, Behavior::default())).unwrap();
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-ffi", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.bin");
+
+        let last = AtomicU64::new(0);
+        let url = c(&server.url("file.bin"));
+        let c_path = c(path.to_str().unwrap());
+        let code = unsafe {
+            gator_download(
+                url.as_ptr(),
+                c_path.as_ptr(),
+                0,
+                Some(record),
+                &last as *const AtomicU64 as *mut c_void,
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(code, GATOR_OK);
+        assert_eq!(std::fs::read(&path).unwrap(), server.data());
+        assert_eq!(last.load(Ordering::SeqCst), server.data().len() as u64);
+
+        // Fails again: the file exists and resume is off
+        let code = unsafe {
+            gator_download(url.as_ptr(), c_path.as_ptr(), 0, None, std::ptr::null_mut(), std::ptr::null())
+        };
+        assert_eq!(code, GATOR_ERROR);
+        let message = unsafe { CStr::from_ptr(gator_last_error()) }.to_str().unwrap();
+        assert!(message.contains("already exists"), "{}", message);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cancelled_downloads_report_it() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.block_on(MockServer::start(1000, Behavior::default())).unwrap();
+        let path = std::env::temp_dir().join(format!("gator-test-{}-ffi-cancel.bin", std::process::id()));
+
+        let cancel = gator_cancel_new();
+        unsafe { gator_cancel(cancel) };
+        let url = c(&server.url("file.bin"));
+        let c_path = c(path.to_str().unwrap());
+        let code = unsafe { gator_download(url.as_ptr(), c_path.as_ptr(), 0, None, std::ptr::null_mut(), cancel) };
+        unsafe { gator_cancel_free(cancel) };
+        assert_eq!(code, GATOR_CANCELLED);
+        assert!(!path.exists());
+    }
+}
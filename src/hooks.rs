@@ -0,0 +1,29 @@
+use crate::protocol::{BoxError, Probe};
+
+// Extension points consulted around every download, independent of the
+// protocol handler doing the transfer. All methods default to "no change".
+pub trait Hook: Send + Sync {
+    // Rewrite a URL before anything is requested (e.g. vanity link -> artifact URL)
+    fn resolve_url(&self, _url: &str) -> Result<Option<String>, BoxError> {
+        Ok(None)
+    }
+
+    // Extra request headers to send for `url`
+    fn request_headers(&self, _url: &str) -> Result<Vec<(String, String)>, BoxError> {
+        Ok(Vec::new())
+    }
+
+    // Adjust probe results before the download is planned
+    fn inspect_probe(&self, _url: &str, _probe: &mut Probe) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+// Fixed headers sent with every request, e.g. from a tag policy
+pub struct StaticHeaders(pub Vec<(String, String)>);
+
+impl Hook for StaticHeaders {
+    fn request_headers(&self, _url: &str) -> Result<Vec<(String, String)>, BoxError> {
+        Ok(self.0.clone())
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::{Component, Path};
+
+// Tag/attribute pairs that reference resources a page needs to render
+const REQUISITES: &[(&str, &str)] = &[
+    ("link", "href"),
+    ("script", "src"),
+    ("img", "src"),
+    ("source", "src"),
+    ("video", "poster"),
+    ("embed", "src"),
+];
+
+// Raw (unresolved) URLs of stylesheets, scripts, images and other page requisites
+pub fn requisite_links(html: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    for_each_tag(html, |name, tag| {
+        for (tag_name, attr) in REQUISITES {
+            if name.eq_ignore_ascii_case(tag_name) {
+                if let Some(value) = attribute(tag, attr) {
+                    links.push(value);
+                }
+            }
+        }
+    });
+    links
+}
+
+// Replace the value of every href/src attribute for which `rewrite` returns Some
+pub fn rewrite_links(html: &str, rewrite: impl Fn(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(html.len());
+    let mut last = 0;
+
+    for (start, end) in attribute_values(html) {
+        let raw = &html[start..end];
+        if let Some(replacement) = rewrite(&raw.replace("&amp;", "&")) {
+            result.push_str(&html[last..start]);
+            result.push_str(&replacement.replace('&', "&amp;").replace('"', "&quot;"));
+            last = end;
+        }
+    }
+
+    result.push_str(&html[last..]);
+    result
+}
+
+// Path of `target` relative to the directory containing `from`, with `/`
+// separators as used in links
+pub fn relative_link(from: &Path, target: &Path) -> String {
+    let from_dir: Vec<Component> = from
+        .parent()
+        .map(|p| p.components().collect())
+        .unwrap_or_default();
+    let target: Vec<Component> = target.components().collect();
+
+    let common = from_dir
+        .iter()
+        .zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<String> = vec!["..".to_string(); from_dir.len() - common];
+    parts.extend(
+        target[common..]
+            .iter()
+            .map(|c| c.as_os_str().to_string_lossy().replace('%', "%25").replace(' ', "%20")),
+    );
+    parts.join("/")
+}
+
+// A crude but sufficient heuristic for "this downloaded file is a web page"
+pub fn looks_like_html(path: &Path, head: &[u8]) -> bool {
+    let by_extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm"));
+    let text = String::from_utf8_lossy(&head[..head.len().min(1024)]).to_ascii_lowercase();
+    by_extension || text.contains("<!doctype html") || text.contains("<html")
+}
+
+// Call `f(name, tag_contents)` for every opening tag
+fn for_each_tag(html: &str, mut f: impl FnMut(&str, &str)) {
+    let mut rest = html;
+    while let Some(pos) = rest.find('<') {
+        rest = &rest[pos + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        let name = tag.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+        if !name.starts_with('/') && !name.starts_with('!') {
+            f(name, tag);
+        }
+        rest = &rest[end + 1..];
+    }
+}
+
+// Byte ranges of every quoted href/src attribute value
+fn attribute_values(html: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let lower = html.to_ascii_lowercase();
+
+    for attr in ["href=", "src="] {
+        let mut offset = 0;
+        while let Some(pos) = lower[offset..].find(attr) {
+            let at = offset + pos;
+            offset = at + attr.len();
+            // Must be a whole attribute name, e.g. not `data-src=`
+            let preceded_by_space = at > 0 && lower.as_bytes()[at - 1].is_ascii_whitespace();
+            let quote = html[offset..].chars().next();
+            if let (true, Some(q @ ('"' | '\''))) = (preceded_by_space, quote) {
+                if let Some(len) = html[offset + 1..].find(q) {
+                    ranges.push((offset + 1, offset + 1 + len));
+                }
+            }
+        }
+    }
+
+    ranges.sort_unstable();
+    ranges
+}
+
+pub fn attribute(tag: &str, name: &str) -> Option<String> {
+    let needle = format!("{}=", name);
+    let mut offset = 0;
+    // A whole attribute name, as in `attribute_values`
+    let pos = loop {
+        let at = offset + find_ascii_ci(&tag[offset..], &needle)?;
+        if at == 0 || tag.as_bytes()[at - 1].is_ascii_whitespace() {
+            break at;
+        }
+        offset = at + needle.len();
+    };
+    let value = tag[pos + needle.len()..].trim_start();
+    let (quote, value) = match value.chars().next()? {
+        q @ ('"' | '\'') => (Some(q), &value[1..]),
+        _ => (None, value),
+    };
+    let end = match quote {
+        Some(q) => value.find(q)?,
+        None => value.find(|c: char| c.is_whitespace()).unwrap_or(value.len()),
+    };
+    Some(value[..end].replace("&amp;", "&"))
+}
+
+pub fn find_ascii_ci(haystack: &str, needle: &str) -> Option<usize> {
+    let needle = needle.as_bytes();
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_whole_href_and_src_attributes_are_links() {
+        let html = r#"<link rel="stylesheet" href="a.css"><img data-src="lazy.png" src='b.png'>
+<script src="c.js?v=1&amp;x=2"></script><a href="page.html">page</a><video poster="d.jpg"></video>"#;
+        assert_eq!(requisite_links(html), ["a.css", "b.png", "c.js?v=1&x=2", "d.jpg"]);
+        assert_eq!(attribute(r#"img data-src="lazy.png""#, "src"), None);
+
+        // Rewrites see decoded values and escape what they put back
+        let rewritten = rewrite_links(html, |link| match link {
+            "c.js?v=1&x=2" => Some("local/c.js?v=1&x=2".to_string()),
+            "lazy.png" => Some("wrong.png".to_string()),
+            _ => None,
+        });
+        assert!(rewritten.contains(r#"<script src="local/c.js?v=1&amp;x=2">"#));
+        assert!(rewritten.contains(r#"data-src="lazy.png""#));
+        assert_eq!(rewrite_links(html, |_| None), html);
+        assert_eq!(rewrite_links(html, |link| Some(link.to_string())), html);
+    }
+
+    #[test]
+    fn relative_links_climb_out_of_the_page_directory() {
+        let link = |from: &str, target: &str| relative_link(Path::new(from), Path::new(target));
+        assert_eq!(link("site/index.html", "site/style.css"), "style.css");
+        assert_eq!(link("site/docs/page.html", "site/docs/img/logo.png"), "img/logo.png");
+        assert_eq!(link("site/docs/page.html", "site/assets/app.js"), "../assets/app.js");
+        assert_eq!(link("site/a/b/page.html", "site/index.html"), "../../index.html");
+        assert_eq!(link("site/index.html", "site/my files/100% done.pdf"), "my%20files/100%25%20done.pdf");
+    }
+}
@@ -0,0 +1,123 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn of(addr: &SocketAddr) -> Self {
+        if addr.is_ipv4() {
+            Family::V4
+        } else {
+            Family::V6
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Family::V4 => Family::V6,
+            Family::V6 => Family::V4,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HostFamilies {
+    // The host has both IPv4 and IPv6 addresses
+    dual: bool,
+    // The family of the first address, which is tried first
+    preferred: Option<Family>,
+    // Only connect over this family, after the other one failed
+    only: Option<Family>,
+}
+
+// Which IP families each host resolved to, and the family to stick to for
+// hosts where the other one failed. Used as the clients' DNS resolver, so a
+// host pinned to a family only ever gets addresses of that family, for the
+// rest of the run. Hosts given as IP addresses are not resolved and have no
+// other family to fall back on.
+#[derive(Debug, Clone, Default)]
+pub struct Families(Arc<Mutex<HashMap<String, HostFamilies>>>);
+
+impl Families {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The family `host` is pinned to, if any
+    pub fn pinned(&self, host: &str) -> Option<Family> {
+        let hosts = self.0.lock().unwrap();
+        hosts.get(host).and_then(|h| h.only)
+    }
+
+    // After connecting to `host` failed or stalled while it was pinned to
+    // `pinned` (or not pinned, so over its preferred family), pin it to the
+    // other family. False when there is no other family to try. Concurrent
+    // requests that failed the same way flip it only once.
+    pub fn fall_back(&self, host: &str, pinned: Option<Family>) -> bool {
+        let mut hosts = self.0.lock().unwrap();
+        let Some(state) = hosts.get_mut(host).filter(|h| h.dual) else {
+            return false;
+        };
+        if state.only == pinned {
+            state.only = pinned.or(state.preferred).map(Family::other);
+        }
+        true
+    }
+
+    // Note what `host` resolved to and keep only the addresses it may use
+    fn filter(&self, host: &str, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let mut hosts = self.0.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_default();
+        state.preferred = addrs.first().map(Family::of);
+        state.dual = addrs.iter().any(|a| a.is_ipv4()) && addrs.iter().any(|a| a.is_ipv6());
+        match state.only {
+            Some(family) if state.dual => addrs.into_iter().filter(|a| Family::of(a) == family).collect(),
+            _ => addrs,
+        }
+    }
+}
+
+impl Resolve for Families {
+    fn resolve(&self, name: Name) -> Resolving {
+        let families = self.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_ascii_lowercase();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            Ok(Box::new(families.filter(&host, addrs).into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_families_are_swapped_once() {
+        let families = Families::new();
+        let v6: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        assert_eq!(families.filter("dual.example", vec![v6, v4]), vec![v6, v4]);
+        assert_eq!(families.pinned("dual.example"), None);
+
+        // Two segments failed over IPv6, which was tried first, at the same time
+        assert!(families.fall_back("dual.example", None));
+        assert!(families.fall_back("dual.example", None));
+        assert_eq!(families.pinned("dual.example"), Some(Family::V4));
+        assert_eq!(families.filter("dual.example", vec![v6, v4]), vec![v4]);
+
+        // Then IPv4 failed too
+        assert!(families.fall_back("dual.example", Some(Family::V4)));
+        assert_eq!(families.pinned("dual.example"), Some(Family::V6));
+
+        families.filter("v4only.example", vec![v4]);
+        assert!(!families.fall_back("v4only.example", Some(Family::V4)));
+        assert!(!families.fall_back("unknown.example", None));
+    }
+}
@@ -0,0 +1,255 @@
+use gator::listing::{xml_blocks, xml_text};
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+
+// Subfolders of a watch folder that finished job files are moved into
+pub const DONE_DIR: &str = "done";
+pub const FAILED_DIR: &str = "failed";
+
+// One download described by a job file
+#[derive(Debug, Clone)]
+pub struct JobItem {
+    pub url: String,
+    // File name to save as; derived from the URL when missing
+    pub file_name: Option<String>,
+    // Subdirectory of the download directory, always relative
+    pub dir: PathBuf,
+}
+
+// `.gator` job files are JSON:
+// { "urls": ["https://..."], "dir": "isos" }
+#[derive(Deserialize)]
+struct GatorJob {
+    urls: Vec<String>,
+    #[serde(default)]
+    dir: Option<PathBuf>,
+}
+
+// Whether `path` is a job file this watcher understands (including ones it
+// will reject with an explanation, like torrents)
+pub fn is_job_file(path: &Path) -> bool {
+    matches!(
+        extension(path).as_deref(),
+        Some("gator" | "txt" | "metalink" | "meta4" | "torrent")
+    )
+}
+
+pub fn parse(path: &Path) -> Result<Vec<JobItem>, Box<dyn Error + Send + Sync>> {
+    let ext = extension(path).unwrap_or_default();
+    if ext == "torrent" {
+        return Err("BitTorrent is not supported".into());
+    }
+
+    let content = std::fs::read_to_string(path)?;
+    let items = match ext.as_str() {
+        "gator" => parse_gator(&content)?,
+        "metalink" | "meta4" => parse_metalink(&content),
+        _ => parse_url_list(&content),
+    };
+    if items.is_empty() {
+        return Err("job file contains no URLs".into());
+    }
+    Ok(items)
+}
+
+fn parse_gator(content: &str) -> Result<Vec<JobItem>, Box<dyn Error + Send + Sync>> {
+    let job: GatorJob = serde_json::from_str(content)?;
+    let dir = job.dir.unwrap_or_default();
+    // A job may only place files below the download directory
+    if dir.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(format!("'dir' must be a relative path without '..': {}", dir.display()).into());
+    }
+    Ok(job
+        .urls
+        .into_iter()
+        .map(|url| JobItem {
+            url,
+            file_name: None,
+            dir: dir.clone(),
+        })
+        .collect())
+}
+
+// One URL per line; blank lines and `#` comments are ignored
+fn parse_url_list(content: &str) -> Vec<JobItem> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|url| JobItem {
+            url: url.to_string(),
+            file_name: None,
+            dir: PathBuf::new(),
+        })
+        .collect()
+}
+
+// Metalink 3 and 4: each <file name="..."> lists mirrors as <url> elements.
+// The first mirror is used.
+fn parse_metalink(content: &str) -> Vec<JobItem> {
+    let mut items = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("<file ") {
+        rest = &rest[start..];
+        let tag_end = rest.find('>').unwrap_or(rest.len());
+        let name = gator::html::attribute(&rest[..tag_end], "name");
+        let Some(block) = xml_blocks(rest, "file").into_iter().next() else {
+            break;
+        };
+        if let Some(url) = xml_text(block, "url") {
+            // Names may contain directories, but never escape the download directory
+            let path = PathBuf::from(name.unwrap_or_default());
+            let safe = path.components().all(|c| matches!(c, Component::Normal(_)));
+            let (dir, file_name) = match (safe, path.file_name()) {
+                (true, Some(file_name)) => (
+                    path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                    Some(file_name.to_string_lossy().into_owned()),
+                ),
+                _ => (PathBuf::new(), None),
+            };
+            items.push(JobItem {
+                url,
+                file_name,
+                dir,
+            });
+        }
+        rest = &rest[tag_end..];
+    }
+    items
+}
+
+// Move a processed job file into `<watch folder>/<subdir>/`
+pub fn move_to(job: &Path, subdir: &str) -> std::io::Result<PathBuf> {
+    let folder = job.parent().unwrap_or(Path::new(".")).join(subdir);
+    std::fs::create_dir_all(&folder)?;
+    let target = folder.join(job.file_name().unwrap_or_default());
+    std::fs::rename(job, &target)?;
+    Ok(target)
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn parse_file(dir: &Path, name: &str, content: &str) -> Result<Vec<JobItem>, Box<dyn Error + Send + Sync>> {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        parse(&path)
+    }
+
+    #[test]
+    fn gator_jobs_give_every_url_the_same_relative_dir() {
+        let dir = temp_dir("jobs-gator");
+        let items = parse_file(
+            &dir,
+            "isos.gator",
+            r#"{ "urls": ["https://example.com/a.iso", "https://example.com/b.iso"], "dir": "isos/linux" }"#,
+        )
+        .unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].url, "https://example.com/b.iso");
+        assert!(items.iter().all(|i| i.dir == Path::new("isos/linux") && i.file_name.is_none()));
+
+        let items = parse_file(&dir, "plain.gator", r#"{ "urls": ["https://example.com/a"] }"#).unwrap();
+        assert_eq!(items[0].dir, PathBuf::new());
+
+        assert!(parse_file(&dir, "up.gator", r#"{ "urls": ["https://example.com/a"], "dir": "../x" }"#).is_err());
+        assert!(parse_file(&dir, "abs.gator", r#"{ "urls": ["https://example.com/a"], "dir": "/tmp" }"#).is_err());
+        assert!(parse_file(&dir, "empty.gator", r#"{ "urls": [] }"#).is_err());
+        assert!(parse_file(&dir, "broken.gator", "{ urls").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn url_lists_skip_blank_lines_and_comments() {
+        let dir = temp_dir("jobs-txt");
+        let items = parse_file(
+            &dir,
+            "list.txt",
+            "# nightly builds\nhttps://example.com/a.tar.gz\n\n   https://example.com/b.tar.gz  \n",
+        )
+        .unwrap();
+        let urls: Vec<_> = items.iter().map(|i| i.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/a.tar.gz", "https://example.com/b.tar.gz"]);
+        assert!(parse_file(&dir, "comments.txt", "# nothing yet\n\n").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn metalinks_use_the_first_mirror_and_safe_names() {
+        let dir = temp_dir("jobs-metalink");
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="images/disk.img">
+    <size>1024</size>
+    <url priority="1">https://one.example.com/disk.img?a=1&amp;b=2</url>
+    <url priority="2">https://two.example.com/disk.img</url>
+  </file>
+  <file name="../escape.bin">
+    <url>https://example.com/escape.bin</url>
+  </file>
+  <file name="no-mirrors.bin">
+  </file>
+</metalink>"#;
+        let items = parse_file(&dir, "set.meta4", content).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].url, "https://one.example.com/disk.img?a=1&b=2");
+        assert_eq!(items[0].file_name.as_deref(), Some("disk.img"));
+        assert_eq!(items[0].dir, Path::new("images"));
+        // Names that would leave the download directory fall back to the URL's
+        assert_eq!(items[1].url, "https://example.com/escape.bin");
+        assert_eq!(items[1].file_name, None);
+        assert_eq!(items[1].dir, PathBuf::new());
+
+        assert_eq!(parse_file(&dir, "set.metalink", content).unwrap().len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn torrents_and_unknown_files_are_told_apart() {
+        let dir = temp_dir("jobs-kinds");
+        assert!(is_job_file(Path::new("a.GATOR")));
+        assert!(is_job_file(Path::new("a.torrent")));
+        assert!(!is_job_file(Path::new("a.json")));
+        assert!(!is_job_file(Path::new("done")));
+        let err = parse_file(&dir, "a.torrent", "d8:announce").unwrap_err();
+        assert_eq!(err.to_string(), "BitTorrent is not supported");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn processed_jobs_move_into_done_or_failed() {
+        let dir = temp_dir("jobs-move");
+        let ok = dir.join("ok.txt");
+        let bad = dir.join("bad.gator");
+        std::fs::write(&ok, "https://example.com/a\n").unwrap();
+        std::fs::write(&bad, "not json").unwrap();
+
+        let moved = move_to(&ok, DONE_DIR).unwrap();
+        assert_eq!(moved, dir.join("done/ok.txt"));
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), "https://example.com/a\n");
+        assert!(!ok.exists());
+
+        assert_eq!(move_to(&bad, FAILED_DIR).unwrap(), dir.join("failed/bad.gator"));
+        assert!(!bad.exists());
+
+        // A job of the same name finished later replaces the earlier one
+        std::fs::write(&ok, "https://example.com/b\n").unwrap();
+        let moved = move_to(&ok, DONE_DIR).unwrap();
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), "https://example.com/b\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
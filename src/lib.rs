@@ -0,0 +1,67 @@
+// Gator's download engine, protocol handlers and batch helpers, shared by
+// the `gator` binary and programs that embed it. `Downloader` is the entry
+// point for downloading a file; `progress` has the types for following one.
+
+pub mod age;
+pub mod archive_org;
+pub mod batch;
+pub mod blocking;
+pub mod byteranges;
+pub mod chaos;
+pub mod clean;
+pub mod control;
+pub mod crawl;
+pub mod data_url;
+pub mod datasets;
+pub mod dedup;
+pub mod digest;
+pub mod download;
+mod encoding;
+pub mod expect;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod hooks;
+pub mod html;
+pub mod ip_family;
+pub mod limits;
+pub mod listing;
+mod mime;
+pub mod metered;
+pub mod mock_server;
+pub mod output;
+pub mod pacing;
+pub mod parquet;
+pub mod paths;
+pub mod pattern;
+pub mod peek;
+pub mod permissions;
+#[cfg(feature = "wasm-plugins")]
+pub mod plugins;
+pub mod portal;
+pub mod preview;
+pub mod probe_cache;
+pub mod progress;
+pub mod protocol;
+pub mod proxy;
+pub mod quota;
+pub mod reader;
+pub mod recompress;
+pub mod rewrite;
+pub mod self_test;
+pub mod share_links;
+pub mod sitemap;
+pub mod speed;
+mod sniff;
+pub mod system_proxy;
+pub mod tar;
+pub mod throttle;
+pub mod timings;
+pub mod tls;
+pub mod urls;
+pub mod zip;
+
+pub use download::{Cancelled, DownloadOptions, DownloadRequest, Downloader};
+pub use progress::{Event, Progress, ProgressObserver, Snapshot, WorkerState};
+pub use reader::ParallelHttpReader;
+// So embedders can cancel a download without depending on tokio-util themselves
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,137 @@
+use std::sync::Mutex;
+
+// Descriptors a run needs besides those of its downloads: standard streams,
+// the config file, DNS lookups, the terminal and the like
+pub const BASE_OPEN_FILES: u64 = 64;
+
+// Descriptors the downloads running now have set aside, process-wide
+static RESERVED: Mutex<u64> = Mutex::new(0);
+
+// Descriptors set aside for one download, given back when dropped
+#[derive(Debug)]
+pub struct Reservation(u64);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *RESERVED.lock().unwrap() -= self.0;
+    }
+}
+
+// Set aside descriptors for a download that wants `workers` connections and
+// `files` file handles, with fewer of each when the open-file limit leaves
+// too few. Returns how many workers and handles it may use.
+pub fn reserve(workers: usize, files: usize) -> Result<(usize, usize, Reservation), String> {
+    let mut reserved = RESERVED.lock().unwrap();
+    let (workers, files) = match open_files_limit() {
+        None => (workers, files),
+        Some(limit) => {
+            let available = limit.saturating_sub(BASE_OPEN_FILES + *reserved);
+            fit(available, workers, files).ok_or_else(|| {
+                format!(
+                    "Only {} of {} open files are left for this download; lower --jobs, or raise `ulimit -n`",
+                    available, limit
+                )
+            })?
+        }
+    };
+    let count = (workers + files) as u64;
+    *reserved += count;
+    Ok((workers, files, Reservation(count)))
+}
+
+// Workers and file handles that fit in `available` descriptors: all of them
+// if they can, otherwise a quarter of the room for files and the rest for
+// workers. None unless at least one of each fits.
+fn fit(available: u64, workers: usize, files: usize) -> Option<(usize, usize)> {
+    if available >= (workers + files) as u64 {
+        return Some((workers, files));
+    }
+    if available < 2 {
+        return None;
+    }
+    let files = files.min((available as usize / 4).max(1));
+    Some((workers.min(available as usize - files), files))
+}
+
+// The soft limit on open files, when there is one
+#[cfg(unix)]
+// rlim_t is not u64 on every platform
+#[allow(clippy::unnecessary_cast)]
+fn open_files_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it is given
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn open_files_limit() -> Option<u64> {
+    None
+}
+
+// Make sure the process may have `needed` files and sockets open at once,
+// raising the soft RLIMIT_NOFILE as far as the hard limit allows. Err with the
+// limit in force when that is still too low.
+#[cfg(unix)]
+// rlim_t is not u64 on every platform
+#[allow(clippy::unnecessary_cast)]
+pub fn ensure_open_files(needed: u64) -> Result<(), u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it is given
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Ok(());
+    }
+    let current = limit.rlim_cur as u64;
+    if limit.rlim_cur == libc::RLIM_INFINITY || current >= needed {
+        return Ok(());
+    }
+    let raised = match limit.rlim_max {
+        libc::RLIM_INFINITY => needed,
+        max => needed.min(max as u64),
+    };
+    let wanted = libc::rlimit {
+        rlim_cur: raised as libc::rlim_t,
+        rlim_max: limit.rlim_max,
+    };
+    // SAFETY: setrlimit only reads the struct it is given
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &wanted) } != 0 {
+        return Err(current);
+    }
+    match raised >= needed {
+        true => Ok(()),
+        false => Err(raised),
+    }
+}
+
+// Windows has no per-process descriptor limit to speak of
+#[cfg(not(unix))]
+pub fn ensure_open_files(_needed: u64) -> Result<(), u64> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_fit_in_the_descriptors_left() {
+        assert_eq!(fit(100, 16, 4), Some((16, 4)));
+        assert_eq!(fit(12, 16, 4), Some((9, 3)));
+        assert_eq!(fit(2, 16, 4), Some((1, 1)));
+        assert_eq!(fit(1, 16, 4), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modest_needs_fit_the_limit() {
+        assert_eq!(ensure_open_files(BASE_OPEN_FILES), Ok(()));
+    }
+}
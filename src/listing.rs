@@ -0,0 +1,469 @@
+use crate::html::{attribute, find_ascii_ci};
+use crate::urls;
+use reqwest::{Client, Method, Url};
+use serde::Serialize;
+use std::error::Error;
+
+// One entry of a remote directory listing
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub name: String,
+    pub url: String,
+    pub size: Option<u64>,
+    pub modified: Option<String>,
+    pub is_dir: bool,
+}
+
+// Enumerate the entries below `url`. S3 bucket listings and WebDAV collections
+// are recognised by their XML responses; anything else is treated as an
+// apache/nginx style autoindex HTML page.
+pub async fn list(
+    client: &Client,
+    url: &str,
+    webdav: bool,
+) -> Result<Vec<Entry>, Box<dyn Error + Send + Sync>> {
+    let base = Url::parse(url)?;
+
+    if webdav {
+        let response = client
+            .request(Method::from_bytes(b"PROPFIND")?, base.clone())
+            .header("Depth", "1")
+            .send()
+            .await?;
+        if response.status().as_u16() != 207 {
+            return Err(format!("PROPFIND returned {}", response.status()).into());
+        }
+        let body = response.text().await?;
+        return Ok(parse_webdav(&base, &body));
+    }
+
+    let response = client.get(base.clone()).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("Server returned error: {}", response.status()).into());
+    }
+    // Redirects (e.g. `/dir` to `/dir/`) change what relative links resolve against
+    let base = response.url().clone();
+    let body = response.text().await?;
+
+    if body.contains("<ListBucketResult") {
+        Ok(parse_s3(&base, &body))
+    } else {
+        Ok(parse_autoindex(&base, &body))
+    }
+}
+
+// Autoindex pages put each entry's link first, followed by the modification
+// date and size as plain text (in <pre> or table cells) before the next link
+pub fn parse_autoindex(base: &Url, html: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut rest = html;
+
+    while let Some(pos) = find_ascii_ci(rest, "<a ") {
+        rest = &rest[pos + 3..];
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let Some(href) = attribute(&rest[..tag_end], "href") else {
+            continue;
+        };
+
+        // The text between this link and the next one holds date and size
+        let after = match rest[tag_end..].find("</a>") {
+            Some(close) => &rest[tag_end + close + 4..],
+            None => &rest[tag_end..],
+        };
+        let detail_end = find_ascii_ci(after, "<a ").unwrap_or(after.len());
+        let details = strip_tags(&after[..detail_end]);
+
+        let Some(url) = child_url(base, &href) else {
+            continue;
+        };
+        let is_dir = url.path().ends_with('/');
+        let (modified, size) = parse_details(&details);
+
+        entries.push(Entry {
+            name: last_segment(&url),
+            url: url.to_string(),
+            size: if is_dir { None } else { size },
+            modified,
+            is_dir,
+        });
+    }
+
+    entries
+}
+
+// `ListObjectsV2` / `ListObjects` XML from S3 and compatible stores
+pub fn parse_s3(base: &Url, xml: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+
+    for block in xml_blocks(xml, "CommonPrefixes") {
+        if let Some(prefix) = xml_text(block, "Prefix") {
+            entries.push(Entry {
+                name: prefix.trim_end_matches('/').rsplit('/').next().unwrap_or("").to_string(),
+                url: s3_object_url(base, &prefix),
+                size: None,
+                modified: None,
+                is_dir: true,
+            });
+        }
+    }
+
+    for block in xml_blocks(xml, "Contents") {
+        let Some(key) = xml_text(block, "Key") else {
+            continue;
+        };
+        if key.ends_with('/') {
+            continue;
+        }
+        entries.push(Entry {
+            name: key.rsplit('/').next().unwrap_or(&key).to_string(),
+            url: s3_object_url(base, &key),
+            size: xml_text(block, "Size").and_then(|s| s.parse().ok()),
+            modified: xml_text(block, "LastModified"),
+            is_dir: false,
+        });
+    }
+
+    entries
+}
+
+// Depth-1 PROPFIND multistatus; the first response is the collection itself
+pub fn parse_webdav(base: &Url, xml: &str) -> Vec<Entry> {
+    xml_blocks(xml, "response")
+        .into_iter()
+        .filter_map(|block| {
+            let href = xml_text(block, "href")?;
+            let url = base.join(&href).ok()?;
+            if url.path() == base.path() {
+                return None;
+            }
+            let is_dir = block.contains("collection");
+            Some(Entry {
+                name: last_segment(&url),
+                size: xml_text(block, "getcontentlength").and_then(|s| s.parse().ok()),
+                modified: xml_text(block, "getlastmodified"),
+                url: url.to_string(),
+                is_dir,
+            })
+        })
+        .collect()
+}
+
+// Print a listing as aligned columns
+pub fn print_table(entries: &[Entry]) {
+    for entry in entries {
+        let size = match entry.size {
+            Some(size) => indicatif::HumanBytes(size).to_string(),
+            None => "-".to_string(),
+        };
+        let suffix = if entry.is_dir { "/" } else { "" };
+        println!(
+            "{:>12}  {:<24}  {}{}",
+            size,
+            entry.modified.as_deref().unwrap_or("-"),
+            entry.name,
+            suffix
+        );
+    }
+}
+
+// Resolve an href and keep it only if it points strictly below the listing
+fn child_url(base: &Url, href: &str) -> Option<Url> {
+    if href.starts_with('?') || href.starts_with('#') || href.starts_with("mailto:") {
+        return None;
+    }
+    let mut url = base.join(href).ok()?;
+    url.set_fragment(None);
+    if url.query().is_some() {
+        return None;
+    }
+    let same_origin = url.scheme() == base.scheme()
+        && url.host_str() == base.host_str()
+        && url.port_or_known_default() == base.port_or_known_default();
+    // A listing served as e.g. `/pub/index.html` lists the `/pub/` directory
+    let dir = &base.path()[..=base.path().rfind('/').unwrap_or(0)];
+    let below = url.path().len() > dir.len() && url.path().starts_with(dir);
+    (same_origin && below).then_some(url)
+}
+
+fn last_segment(url: &Url) -> String {
+    let segment = url.path().trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    urls::decode_segment(segment)
+}
+
+// Keys are relative to the bucket root, which is the listing URL's path for
+// both virtual-hosted (`/`) and path-style (`/bucket/`) requests
+fn s3_object_url(base: &Url, key: &str) -> String {
+    let mut url = base.clone();
+    url.set_query(None);
+    let encoded: Vec<String> = key.split('/').map(encode_path_segment).collect();
+    url.set_path(&format!(
+        "{}/{}",
+        base.path().trim_end_matches('/'),
+        encoded.join("/")
+    ));
+    url.to_string()
+}
+
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Pull a date (with optional time) and a size token out of the text that
+// follows a link, e.g. "2024-01-31 12:00  4.2M" or "31-Jan-2024 12:00  4404019"
+fn parse_details(text: &str) -> (Option<String>, Option<u64>) {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let mut modified = None;
+    let mut size = None;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        if modified.is_none() && looks_like_date(token) {
+            let mut date = token.to_string();
+            if let Some(time) = tokens.get(i + 1).filter(|t| looks_like_time(t)) {
+                date = format!("{} {}", date, time);
+                i += 1;
+            }
+            modified = Some(date);
+        } else if size.is_none() {
+            size = parse_size(token);
+        }
+        i += 1;
+    }
+
+    (modified, size)
+}
+
+fn looks_like_date(token: &str) -> bool {
+    let parts: Vec<&str> = token.split('-').collect();
+    parts.len() == 3 && parts.iter().all(|p| !p.is_empty()) && token.chars().any(|c| c.is_ascii_digit())
+}
+
+fn looks_like_time(token: &str) -> bool {
+    token.contains(':') && token.chars().all(|c| c.is_ascii_digit() || c == ':')
+}
+
+// Autoindex sizes are either exact byte counts or rounded with a K/M/G/T suffix
+fn parse_size(token: &str) -> Option<u64> {
+    let (number, multiplier) = match token.chars().last()? {
+        'K' | 'k' => (&token[..token.len() - 1], 1u64 << 10),
+        'M' => (&token[..token.len() - 1], 1 << 20),
+        'G' => (&token[..token.len() - 1], 1 << 30),
+        'T' => (&token[..token.len() - 1], 1 << 40),
+        _ => (token, 1),
+    };
+    if multiplier == 1 {
+        number.parse().ok()
+    } else {
+        number.parse::<f64>().ok().map(|n| (n * multiplier as f64) as u64)
+    }
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&nbsp;", " ")
+}
+
+// Contents of every `<tag>...</tag>` element, ignoring any namespace prefix
+pub fn xml_blocks<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let mut blocks = Vec::new();
+    let mut rest = xml;
+    while let Some((_, content_start)) = find_open_tag(rest, tag) {
+        let content = &rest[content_start..];
+        let Some(end) = find_close_tag(content, tag) else {
+            break;
+        };
+        blocks.push(&content[..end]);
+        rest = &rest[content_start + end..];
+    }
+    blocks
+}
+
+pub fn xml_text(xml: &str, tag: &str) -> Option<String> {
+    let (_, content_start) = find_open_tag(xml, tag)?;
+    let content = &xml[content_start..];
+    let end = find_close_tag(content, tag)?;
+    Some(
+        content[..end]
+            .trim()
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            // Last, so `&amp;lt;` stays `&lt;`
+            .replace("&amp;", "&"),
+    )
+}
+
+// Returns (offset of '<', offset just past '>') for the first `<tag>` or `<ns:tag ...>`
+fn find_open_tag(xml: &str, tag: &str) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    while let Some(pos) = xml[offset..].find('<') {
+        let start = offset + pos;
+        let end = start + xml[start..].find('>')?;
+        let name = xml[start + 1..end]
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        let local = name.rsplit(':').next().unwrap_or(name);
+        if local.eq_ignore_ascii_case(tag) && !xml[start + 1..end].ends_with('/') {
+            return Some((start, end + 1));
+        }
+        offset = end + 1;
+    }
+    None
+}
+
+fn find_close_tag(xml: &str, tag: &str) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(pos) = xml[offset..].find("</") {
+        let start = offset + pos;
+        let end = start + xml[start..].find('>')?;
+        let name = xml[start + 2..end].trim();
+        let local = name.rsplit(':').next().unwrap_or(name);
+        if local.eq_ignore_ascii_case(tag) {
+            return Some(start);
+        }
+        offset = end + 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Name, URL, size, modification time and whether it is a directory
+    type Row<'a> = (&'a str, &'a str, Option<u64>, Option<&'a str>, bool);
+
+    fn rows(entries: &[Entry]) -> Vec<Row<'_>> {
+        entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.url.as_str(), e.size, e.modified.as_deref(), e.is_dir))
+            .collect()
+    }
+
+    #[test]
+    fn autoindex_pages_list_the_links_below_them() {
+        let base = Url::parse("https://example.com/pub/linux/").unwrap();
+        // Apache, with sort links, a parent link and sizes in their own cells
+        let apache = r#"<html><body><h1>Index of /pub/linux</h1><table>
+<tr><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+<tr><td valign="top"><img src="/icons/back.gif" alt="[PARENTDIR]"></td><td><a href="/pub/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td></tr>
+<tr><td valign="top"><img src="/icons/folder.gif" alt="[DIR]"></td><td><a href="docs/">docs/</a></td><td align="right">2024-02-01 08:30  </td><td align="right">  - </td></tr>
+<tr><td valign="top"><img src="/icons/compressed.gif" alt="[   ]"></td><td><a href="linux-6.1.tar.xz">linux-6.1.tar.xz</a></td><td align="right">2024-01-31 12:00  </td><td align="right">128M</td></tr>
+<tr><td valign="top"><img src="/icons/text.gif" alt="[TXT]"></td><td><a href="release%20notes.txt">release notes.txt</a></td><td align="right">2023-12-24 18:05  </td><td align="right">4.5K</td></tr>
+</table></body></html>"#;
+        let entries = parse_autoindex(&base, apache);
+        assert_eq!(
+            rows(&entries),
+            [
+                ("docs", "https://example.com/pub/linux/docs/", None, Some("2024-02-01 08:30"), true),
+                ("linux-6.1.tar.xz", "https://example.com/pub/linux/linux-6.1.tar.xz", Some(128 << 20), Some("2024-01-31 12:00"), false),
+                ("release notes.txt", "https://example.com/pub/linux/release%20notes.txt", Some(4608), Some("2023-12-24 18:05"), false),
+            ]
+        );
+
+        // nginx, with exact sizes in a <pre> block
+        let nginx = r#"<html><head><title>Index of /pub/linux/</title></head><body>
+<h1>Index of /pub/linux/</h1><hr><pre><a href="../">../</a>
+<a href="archive/">archive/</a>                                           31-Jan-2024 12:00                   -
+<a href="data.csv">data.csv</a>                                           01-Feb-2024 09:15              4404019
+</pre><hr></body></html>"#;
+        let entries = parse_autoindex(&base, nginx);
+        assert_eq!(
+            rows(&entries),
+            [
+                ("archive", "https://example.com/pub/linux/archive/", None, Some("31-Jan-2024 12:00"), true),
+                ("data.csv", "https://example.com/pub/linux/data.csv", Some(4404019), Some("01-Feb-2024 09:15"), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn s3_listings_give_prefixes_as_directories() {
+        let base = Url::parse("https://s3.example.com/bucket/?list-type=2&prefix=data/&delimiter=/").unwrap();
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/">
+  <Name>bucket</Name><Prefix>data/</Prefix><Delimiter>/</Delimiter><KeyCount>4</KeyCount>
+  <Contents><Key>data/</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified><Size>0</Size></Contents>
+  <Contents><Key>data/read me &amp; notes.txt</Key><LastModified>2024-01-31T12:00:00.000Z</LastModified><ETag>"abc"</ETag><Size>1234</Size></Contents>
+  <CommonPrefixes><Prefix>data/2024/</Prefix></CommonPrefixes>
+  <CommonPrefixes><Prefix>data/raw/</Prefix></CommonPrefixes>
+</ListBucketResult>"#;
+        let entries = parse_s3(&base, xml);
+        assert_eq!(
+            rows(&entries),
+            [
+                ("2024", "https://s3.example.com/bucket/data/2024/", None, None, true),
+                ("raw", "https://s3.example.com/bucket/data/raw/", None, None, true),
+                (
+                    "read me & notes.txt",
+                    "https://s3.example.com/bucket/data/read%20me%20%26%20notes.txt",
+                    Some(1234),
+                    Some("2024-01-31T12:00:00.000Z"),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn webdav_multistatus_skips_the_collection_itself() {
+        let base = Url::parse("https://dav.example.com/dav/share/").unwrap();
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/dav/share/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype>
+<D:getlastmodified>Wed, 31 Jan 2024 12:00:00 GMT</D:getlastmodified></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>
+<D:response><D:href>/dav/share/photos/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype>
+</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>
+<D:response><D:href>https://dav.example.com/dav/share/report%202024.pdf</D:href><D:propstat><D:prop><D:resourcetype/>
+<D:getcontentlength>52428</D:getcontentlength><D:getlastmodified>Thu, 01 Feb 2024 09:15:00 GMT</D:getlastmodified>
+</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>
+</D:multistatus>"#;
+        let entries = parse_webdav(&base, xml);
+        assert_eq!(
+            rows(&entries),
+            [
+                ("photos", "https://dav.example.com/dav/share/photos/", None, None, true),
+                (
+                    "report 2024.pdf",
+                    "https://dav.example.com/dav/share/report%202024.pdf",
+                    Some(52428),
+                    Some("Thu, 01 Feb 2024 09:15:00 GMT"),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn xml_text_decodes_entities_once() {
+        assert_eq!(xml_text("<loc> a?x=1&amp;y=2 </loc>", "loc").unwrap(), "a?x=1&y=2");
+        // An escaped entity is text, not a second entity
+        assert_eq!(xml_text("<Key>a&amp;lt;b&amp;quot;</Key>", "Key").unwrap(), "a&lt;b&quot;");
+        assert_eq!(xml_text("<Key>&lt;&gt;&quot;&apos;</Key>", "Key").unwrap(), "<>\"'");
+    }
+}
@@ -1,390 +1,2358 @@
-use clap::Parser;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use reqwest::header::CONTENT_LENGTH;
-use reqwest::{Client, StatusCode};
+use clap::builder::BoolishValueParser;
+use clap::{CommandFactory, Parser, Subcommand};
+use futures::StreamExt;
+use gator::download::{self, DownloadOptions};
+use gator::output::{self, Level, Output};
+use gator::protocol::{HostClients, Registry};
+#[cfg(feature = "wasm-plugins")]
+use gator::plugins;
+use gator::{
+    age, archive_org, batch, chaos, clean, control, crawl, datasets, dedup, digest, expect, hooks, html,
+    ip_family, limits, listing, metered, pacing, paths, pattern, peek, permissions, portal, preview,
+    probe_cache, proxy, quota, recompress, rewrite, self_test, share_links, sitemap, speed,
+    system_proxy, tar, throttle, timings, tls, urls, zip,
+    ParallelHttpReader,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::Client;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
-use tokio::fs::{File, OpenOptions};
-use tokio::sync::{mpsc, Mutex};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use std::fs;
-use num_cpus;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+mod auth;
+mod clipboard;
+mod config;
+mod credentials;
+mod jobs;
+mod notify;
+mod update;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+// Set from --quota; every download of the run counts against it
+static QUOTA: std::sync::OnceLock<quota::Quota> = std::sync::OnceLock::new();
+
+// Set from --serve; follows the download to serve it as it arrives
+static PREVIEW: std::sync::OnceLock<Arc<preview::Preview>> = std::sync::OnceLock::new();
+
+// Set with --dedup; downloads record the SHA-256 they check their digests with
+static DEDUP_HASHES: std::sync::OnceLock<Arc<dedup::KnownHashes>> = std::sync::OnceLock::new();
+
 #[derive(Parser, Debug)]
 #[command(name = "gator")]
 #[command(author, version, about = "A blazingly fast HTTP downloader", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+// A later flag replaces an earlier one, so options can override a --preset
+#[command(args_override_self = true)]
 struct Args {
-    #[arg(required = true)]
-    url: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
-    #[arg(short, long)]
+    /// URLs to download; several are downloaded as a batch, --jobs at once
+    #[arg(required = true, value_name = "URL")]
+    urls: Vec<String>,
+
+    #[arg(short, long, conflicts_with_all = ["recursive", "sitemap", "archive_item", "dataset"])]
     output: Option<String>,
 
-    #[arg(short, long, default_value = "false")]
+    /// Only print errors
+    #[arg(short, long, global = true, env = "GATOR_QUIET", value_parser = BoolishValueParser::new())]
     quiet: bool,
+
+    /// Print nothing at all, not even errors
+    #[arg(short, long, global = true, env = "GATOR_SILENT", value_parser = BoolishValueParser::new())]
+    silent: bool,
+
+    /// Hide the progress bar but keep other messages
+    #[arg(long, global = true, env = "GATOR_NO_PROGRESS", value_parser = BoolishValueParser::new())]
+    no_progress: bool,
+
+    /// Print machine-readable JSON lines on stdout; human-readable text goes to stderr
+    #[arg(long, global = true, env = "GATOR_JSON", value_parser = BoolishValueParser::new())]
+    json: bool,
+
+    /// No colors or animation; report progress as timestamped lines (for logs and CI)
+    #[arg(long, global = true, env = "GATOR_PLAIN", value_parser = BoolishValueParser::new())]
+    plain: bool,
+
+    /// How far back the current speed on the progress bar looks, e.g. 10s (default 5s)
+    #[arg(long, value_name = "DURATION", value_parser = speed::parse_window, global = true, env = "GATOR_SPEED_WINDOW")]
+    speed_window: Option<std::time::Duration>,
+
+    /// Resume a partially downloaded file that already exists at the output path (env: GATOR_CONTINUE)
+    #[arg(short = 'c', long = "continue", overrides_with = "no_continue")]
+    resume: bool,
+
+    /// Discard any existing file at the output path and start from scratch
+    #[arg(long, overrides_with = "resume")]
+    no_continue: bool,
+
+    /// Directory to save into (created if missing)
+    #[arg(short, long, value_name = "DIR", env = "GATOR_DIR")]
+    dir: Option<PathBuf>,
+
+    /// Keep part files of downloads in progress here, e.g. on a fast scratch disk, and move them into place when done
+    #[arg(long, value_name = "DIR", env = "GATOR_TMP_DIR")]
+    tmp_dir: Option<PathBuf>,
+
+    /// Treat the URL as a directory index page and download the files it lists
+    #[arg(short, long, conflicts_with = "sitemap")]
+    recursive: bool,
+
+    /// Treat the URL as a sitemap.xml (or sitemap index) and download every page it lists
+    #[arg(long)]
+    sitemap: bool,
+
+    /// Treat the URL as an archive.org item (its page, or just the identifier) and download its files, checked against their published MD5 and SHA-1
+    #[arg(long, conflicts_with_all = ["recursive", "sitemap"])]
+    archive_item: bool,
+
+    /// Treat the URL as a DOI, or a Zenodo or Figshare record page, and download the record's files, checked against their published MD5
+    #[arg(long, conflicts_with_all = ["recursive", "sitemap", "archive_item"])]
+    dataset: bool,
+
+    /// With --archive-item, skip the files archive.org derived from the uploads (thumbnails, other formats, its own metadata)
+    #[arg(long, requires = "archive_item")]
+    originals: bool,
+
+    /// With --sitemap, only download pages whose lastmod is on or after this date
+    #[arg(long, value_name = "YYYY-MM-DD", requires = "sitemap", value_parser = sitemap::parse_date)]
+    since: Option<String>,
+
+    /// How many levels of subdirectories to descend into with --recursive
+    #[arg(long, value_name = "N", default_value_t = 5, requires = "recursive")]
+    depth: usize,
+
+    /// Only download files whose name (full URL with --sitemap, path in the item with --archive-item or --dataset) matches one of these patterns
+    #[arg(short = 'A', long, value_name = "PATTERNS", value_delimiter = ',')]
+    accept: Vec<String>,
+
+    /// Skip files whose name (full URL with --sitemap, path in the item with --archive-item or --dataset) matches one of these patterns
+    #[arg(short = 'R', long, value_name = "PATTERNS", value_delimiter = ',')]
+    reject: Vec<String>,
+
+    /// Order of the files in a batch; the size orders probe every file first
+    #[arg(long, value_enum, default_value_t = batch::Order::Input, global = true)]
+    order: batch::Order,
+
+    /// Reuse probe results (size, ETag, range support) from earlier runs younger than SECS seconds
+    #[arg(long, value_name = "SECS", global = true)]
+    probe_cache: Option<u64>,
+
+    /// In batches, download at most N files from the same host at once
+    #[arg(long, value_name = "N", global = true,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_concurrent_per_host: Option<usize>,
+
+    /// Send this User-Agent; given more than once, requests take turns with each (repeatable)
+    #[arg(long, value_name = "AGENT", global = true)]
+    user_agent: Vec<String>,
+
+    /// Pause before each request to a host, e.g. 2s, or a random pause in a range such as 500ms-3s
+    #[arg(long, value_name = "DURATION", value_parser = pacing::parse_wait, global = true, env = "GATOR_WAIT")]
+    wait: Option<(std::time::Duration, std::time::Duration)>,
+
+    /// Send at most N requests a minute to each host
+    #[arg(long, value_name = "N", global = true, env = "GATOR_MAX_PER_MINUTE",
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_per_minute: Option<usize>,
+
+    /// Start at most RATE requests a second to each host, e.g. 5 or 0.5
+    #[arg(long, value_name = "RATE", value_parser = pacing::parse_rate, global = true, env = "GATOR_MAX_REQUESTS_PER_SECOND")]
+    max_requests_per_second: Option<f64>,
+
+    /// Requests to a host that may start back to back under --max-requests-per-second
+    #[arg(long, value_name = "N", default_value_t = 1, global = true, requires = "max_requests_per_second",
+          value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..))]
+    request_burst: u32,
+
+    /// Download at most SIZE a second in all, across workers and files (e.g. 2M)
+    #[arg(long, value_name = "SIZE", value_parser = quota::parse, global = true, env = "GATOR_LIMIT_RATE")]
+    limit_rate: Option<u64>,
+
+    /// Download at most SIZE a second over each connection (e.g. 500K)
+    #[arg(long, value_name = "SIZE", value_parser = quota::parse, global = true)]
+    limit_rate_per_connection: Option<u64>,
+
+    /// Treat URLs whose query parameters differ only in order as the same file in batch downloads
+    #[arg(long)]
+    sort_query: bool,
+
+    /// Stop starting new downloads once this much has been transferred (e.g. 20G); per day when watching a folder or the clipboard
+    #[arg(long, value_name = "SIZE", value_parser = quota::parse, global = true, env = "GATOR_QUOTA")]
+    quota: Option<u64>,
+
+    /// Hold back downloads that have not started while the system reports a metered connection
+    #[arg(long, global = true, env = "GATOR_PAUSE_ON_METERED", value_parser = BoolishValueParser::new())]
+    pause_on_metered: bool,
+
+    /// How many files of a batch to download at once (default 4)
+    #[arg(short = 'j', long, visible_alias = "max-concurrent-downloads", value_name = "N", global = true,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    jobs: Option<usize>,
+
+    /// Write handles each download's segment workers share on its part file
+    #[arg(long, value_name = "N", default_value_t = 4, global = true,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    file_handles: usize,
+
+    /// Segment workers for each download (default max(16, CPUs x 4))
+    #[arg(long, value_name = "N", global = true, env = "GATOR_CONNECTIONS",
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    connections: Option<usize>,
+
+    /// Split large downloads into segments of SIZE (e.g. 4M; default 1M)
+    #[arg(long, value_name = "SIZE", value_parser = quota::parse, global = true)]
+    segment_size: Option<u64>,
+
+    /// Ask for up to N segments in one request, from servers that answer with multipart/byteranges
+    #[arg(long, value_name = "N", default_value_t = 1, global = true,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    multi_range: usize,
+
+    /// Times a failed segment is fetched again, after growing pauses, before the download fails
+    #[arg(long, value_name = "N", default_value_t = 5, global = true)]
+    segment_retries: u32,
+
+    /// Gather received data into writes of up to SIZE (e.g. 1M; default 256K)
+    #[arg(long, value_name = "SIZE", value_parser = quota::parse, global = true)]
+    chunk_buffer: Option<u64>,
+
+    /// Fetch segments close to file order, so a player can start on the part file while the rest downloads
+    #[arg(long)]
+    sequential: bool,
+
+    /// Serve the file over HTTP at this address while it downloads, e.g. 127.0.0.1:8080, so a player can stream it
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["recursive", "sitemap", "page_requisites", "convert_links"])]
+    serve: Option<std::net::SocketAddr>,
+
+    /// Also download the stylesheets, scripts and images that downloaded pages reference
+    #[arg(short = 'p', long)]
+    page_requisites: bool,
+
+    /// After downloading, rewrite links in saved pages to point at the local copies
+    #[arg(short = 'k', long)]
+    convert_links: bool,
+
+    /// Store files with identical content once, linking duplicates to the first copy
+    #[arg(long, value_name = "MODE")]
+    dedup: Option<dedup::DedupMode>,
+
+    /// Only report how much --dedup would save, without touching any files
+    #[arg(long, requires = "dedup")]
+    dedup_dry_run: bool,
+
+    /// Load a WASM plugin that can resolve URLs, add headers and adjust metadata (repeatable)
+    #[cfg(feature = "wasm-plugins")]
+    #[arg(long, value_name = "FILE")]
+    plugin: Vec<PathBuf>,
+
+    /// Insert the flags saved under this name with `gator preset add` (repeatable)
+    #[arg(long, value_name = "NAME")]
+    preset: Vec<String>,
+
+    /// Apply the policy of a tag defined in the config file (repeatable)
+    #[arg(long, value_name = "NAME")]
+    tag: Vec<String>,
+
+    /// Email a summary to this address when the run finishes (needs [smtp] in the config)
+    #[arg(long, value_name = "ADDRESS", global = true, env = "GATOR_NOTIFY_EMAIL")]
+    notify_email: Option<String>,
+
+    /// Read settings from this file instead of the default config location
+    #[arg(long, value_name = "FILE", global = true, env = "GATOR_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Only accept servers whose certificate has one of these public keys: sha256//<base64>, several joined with ';'
+    #[arg(long, value_name = "PINS", value_parser = tls::Pins::parse, global = true, env = "GATOR_PINNEDPUBKEY")]
+    pinnedpubkey: Option<tls::Pins>,
+
+    /// Print the certificate chain each HTTPS server presents, with validity dates and names
+    #[arg(long, global = true)]
+    show_cert: bool,
+
+    /// Refuse servers that do not staple a good, verified OCSP response for their certificate
+    #[arg(long, global = true)]
+    cert_status: bool,
+
+    /// Report how long DNS, connecting, TLS and the first byte took, and how long the transfer took
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Send requests through this HTTP(S) proxy URL, or 'auto' for the proxy the system's PAC file, WPAD or settings pick for each URL
+    #[arg(long, value_name = "URL", global = true, env = "GATOR_PROXY")]
+    proxy: Option<String>,
+
+    /// Decode a gzip or deflate Content-Encoding applied by the server instead of saving the encoded bytes
+    #[arg(long)]
+    decompress: bool,
+
+    /// Compress each downloaded file again, e.g. gzip:9, replacing it with FILE.gz (gzip files are decoded first)
+    #[arg(long, value_name = "FORMAT[:LEVEL]", value_parser = recompress::parse,
+          conflicts_with_all = ["serve", "page_requisites", "convert_links"])]
+    recompress: Option<recompress::Target>,
+
+    /// Encrypt each file to an age recipient as it downloads, e.g. age:age1..., saving FILE.age and no plaintext
+    #[arg(long, value_name = "age:RECIPIENT", value_parser = age::parse,
+          conflicts_with_all = ["serve", "recompress", "page_requisites", "convert_links"])]
+    encrypt: Option<age::Recipient>,
+
+    /// Fail unless the server sends a Content-MD5, Digest or Repr-Digest to check the file against
+    #[arg(long, conflicts_with = "no_auto_digest")]
+    verify_digest: bool,
+
+    /// Do not check the file against digests sent by the server
+    #[arg(long)]
+    no_auto_digest: bool,
+
+    /// Save the response headers and trailers next to the file as FILE.meta.json
+    #[arg(long)]
+    write_metadata: bool,
+
+    /// Append an extension derived from the Content-Type to file names that have none
+    #[arg(long)]
+    auto_extension: bool,
+
+    /// Fail with exit code 3 when the Content-Type or first bytes do not match the file extension
+    #[arg(long)]
+    check_type: bool,
+
+    /// Fail with exit code 3, before writing anything, unless the response status is CODE
+    #[arg(long, value_name = "CODE")]
+    expect_status: Option<u16>,
+
+    /// Fail with exit code 3 unless the response has this header; the value may use * and ? (repeatable)
+    #[arg(long, value_name = "NAME: VALUE", value_parser = expect::parse_header)]
+    expect_header: Vec<(String, String)>,
+
+    /// Fail with exit code 3 unless Content-Length is exactly BYTES
+    #[arg(long, value_name = "BYTES")]
+    expect_length: Option<u64>,
+
+    /// Fail with exit code 3 unless Content-Length is at least BYTES
+    #[arg(long, value_name = "BYTES")]
+    expect_min_length: Option<u64>,
+
+    /// Set the permissions of the finished file, as an octal mode (e.g. 0644)
+    #[arg(long, value_name = "MODE", value_parser = permissions::parse_mode, env = "GATOR_CHMOD")]
+    chmod: Option<u32>,
+
+    /// Set the owner of the finished file as USER[:GROUP] (usually requires root)
+    #[arg(long, value_name = "USER:GROUP", value_parser = permissions::parse_owner, env = "GATOR_CHOWN")]
+    chown: Option<permissions::Owner>,
+
+    /// Inject faults to reproduce bug reports, e.g. delay=20%:2s,reset=5%,error=5%,disk-full=64M
+    #[arg(long, value_name = "SPEC", value_parser = chaos::parse, hide = true)]
+    chaos: Option<chaos::Chaos>,
+
+    /// Seed for --chaos; the same seed injects the same faults into the same requests
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "chaos", hide = true)]
+    chaos_seed: u64,
+
+    /// KiB before the resume point to re-download and compare with the local file when the server sends no ETag or Last-Modified (0 disables)
+    #[arg(long, value_name = "KIB", default_value_t = 64, env = "GATOR_VERIFY_OVERLAP")]
+    verify_overlap: u64,
+}
+
+impl Args {
+    // Files of a batch at once: --jobs, a tag's `jobs`, or 4
+    fn jobs(&self) -> usize {
+        self.jobs.unwrap_or(4)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the files in a remote directory (autoindex page, S3 prefix, WebDAV collection, archive.org item, or DOI or Zenodo/Figshare record)
+    Ls {
+        url: String,
+
+        /// Print only the absolute URLs of files, one per line
+        #[arg(long, conflicts_with = "json")]
+        urls: bool,
+
+        /// Query a WebDAV collection with PROPFIND instead of reading an index page
+        #[arg(long)]
+        webdav: bool,
+    },
+
+    /// Watch the clipboard and download URLs as they are copied
+    WatchClipboard {
+        /// Only download URLs matching one of these patterns (e.g. '*.iso'); all URLs by default
+        #[arg(short = 'A', long, value_name = "PATTERNS", value_delimiter = ',')]
+        accept: Vec<String>,
+
+        /// Ask for confirmation before starting each download
+        #[arg(long)]
+        confirm: bool,
+
+        /// Directory to save into (created if missing)
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Seconds between clipboard checks
+        #[arg(long, value_name = "SECS", default_value_t = 1)]
+        interval: u64,
+    },
+
+    /// Replace this binary with the latest release, after verifying its checksum
+    SelfUpdate {
+        /// Release channel to follow
+        #[arg(long, value_enum, default_value = "stable")]
+        channel: update::Channel,
+
+        /// Only report whether an update is available
+        #[arg(long)]
+        check: bool,
+
+        /// Reinstall even if the latest release is the running version
+        #[arg(long)]
+        force: bool,
+
+        /// Releases API to query instead of the project's GitHub releases
+        #[arg(long, value_name = "URL", default_value = update::DEFAULT_RELEASE_URL)]
+        release_url: String,
+    },
+
+    /// Print a shell completion script (e.g. `gator completions bash > /etc/bash_completion.d/gator`)
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the man page in roff format (e.g. `gator manpage > gator.1`)
+    Manpage,
+
+    /// List or extract files in a remote ZIP archive, fetching only what is needed
+    Zip {
+        #[command(subcommand)]
+        action: ZipAction,
+    },
+
+    /// List or extract files in a remote tar or tar.gz archive
+    Tar {
+        #[command(subcommand)]
+        action: TarAction,
+    },
+
+    /// Show what a remote file holds without downloading it: a Parquet schema, a ZIP listing, or the first lines of a CSV or other text file
+    Peek {
+        url: String,
+
+        /// Lines to show of a text file
+        #[arg(short = 'n', long, value_name = "N", default_value_t = 10)]
+        lines: usize,
+    },
+
+    /// Delete part files left by downloads that were never finished
+    Clean {
+        /// Directories to search, with their subdirectories [default: --tmp-dir, else --dir, else the current directory]
+        dirs: Vec<PathBuf>,
+
+        /// Only delete part files nothing has written to for this many days
+        #[arg(long, value_name = "DAYS", default_value_t = 7)]
+        older_than: u64,
+
+        /// List the files that would be deleted, without deleting them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Download from a built-in local server that misbehaves in known ways, to check this build
+    #[command(hide = true)]
+    SelfTest,
+
+    /// Sign in to an OAuth provider from the config file, so downloads from its hosts carry a token
+    Auth {
+        #[command(subcommand)]
+        action: AuthAction,
+    },
+
+    /// Manage the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// Save and manage named bundles of flags, used with `--preset NAME`
+    Preset {
+        #[command(subcommand)]
+        action: PresetAction,
+    },
+
+    /// Watch a folder for job files (.gator, .txt URL lists, .metalink) and run them
+    WatchFolder {
+        folder: PathBuf,
+
+        /// Directory to save into (created if missing; defaults to the current directory)
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Seconds between folder scans
+        #[arg(long, value_name = "SECS", default_value_t = 2)]
+        interval: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the path of the config file
+    Path,
+
+    /// Write a commented starter config file
+    Init {
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Print a setting, e.g. `smtp.host` or `tags.work.dir`
+    Get { key: String },
+
+    /// Change a setting, e.g. `gator config set tags.work.dir ~/work`
+    Set { key: String, value: String },
+
+    /// Open the config file in $VISUAL or $EDITOR, then check it
+    Edit,
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthAction {
+    /// Sign in with a code entered in any browser and keep the token in the system keyring, e.g. `gator auth login google`
+    Login { provider: String },
+
+    /// Forget the token of a provider
+    Logout { provider: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum PresetAction {
+    /// Save flags under a name, e.g. `gator preset add isodl --dir ~/isos --continue`.
+    /// Put `--` before the flags to include output flags such as -q.
+    Add {
+        name: String,
+
+        /// The flags to store
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        flags: Vec<String>,
+    },
+
+    /// Show all presets
+    List,
+
+    /// Delete a preset
+    Remove { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ZipAction {
+    /// List the files in the archive, from its central directory
+    Ls { url: String },
+
+    /// Extract files from the archive without downloading the rest of it
+    Get {
+        url: String,
+
+        /// Names or wildcard patterns (e.g. 'data/*.csv') of the files to extract
+        #[arg(required = true)]
+        members: Vec<String>,
+
+        /// Directory to extract into, keeping the archive's folders (created if missing)
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Overwrite files that already exist
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-// Segment range for work-stealing scheduler
-#[derive(Debug, Clone)]
-struct Segment {
-    start: u64,
-    end: u64,
+#[derive(Subcommand, Debug)]
+enum TarAction {
+    /// List the files in the archive (read once, then kept in the cache until it changes)
+    Ls { url: String },
+
+    /// Extract files from the archive, fetching only their data when it is uncompressed
+    Get {
+        url: String,
+
+        /// Names or wildcard patterns (e.g. 'data/*.csv') of the files to extract
+        #[arg(required = true)]
+        members: Vec<String>,
+
+        /// Directory to extract into, keeping the archive's folders (created if missing)
+        #[arg(short, long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Overwrite files that already exist
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-fn create_optimized_client() -> Result<Client, Box<dyn Error + Send + Sync>> {
+fn create_optimized_client(
+    proxy: Option<&str>,
+    rules: &config::ProxyConfig,
+    identity: Option<reqwest::Identity>,
+    families: &ip_family::Families,
+) -> Result<Client, Box<dyn Error + Send + Sync>> {
     // Disable Nagle's algorithm for lower latency
-    // reqwest uses async DNS by default, so no custom resolver needed
-    let client = Client::builder()
+    // Keeps the server certificate with each response for --pinnedpubkey
+    // Names resolve through `families`, which drops the IP family that failed
+    // for a host; the connect timeout (TCP and TLS) turns a connection that
+    // stalls on a broken dual-stack network into a failure to fall back from
+    let mut builder = Client::builder()
         .tcp_nodelay(true)
-        .build()?;
-    Ok(client)
+        .tls_info(true)
+        .dns_resolver(Arc::new(families.clone()))
+        .connect_timeout(std::time::Duration::from_secs(15));
+    if let Some(identity) = identity {
+        builder = builder.identity(identity);
+    }
+    // Without --proxy, reqwest still honors HTTP_PROXY / HTTPS_PROXY / NO_PROXY
+    // and the proxy set in the Windows or macOS settings, but not a PAC file
+    if proxy == Some("auto") {
+        let system = system_proxy::SystemProxy::new();
+        builder = builder.proxy(reqwest::Proxy::custom(move |url| system.proxy_for(url)));
+    } else if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| format!("Invalid proxy '{}': {}", proxy, e))?;
+        builder = builder.proxy(proxy);
+    } else if !rules.is_empty() {
+        let rules = proxy::ProxyRules::new(&rules.direct, &rules.hosts)?;
+        builder = builder.proxy(reqwest::Proxy::custom(move |url| rules.proxy_for(url)));
+    }
+    Ok(builder.build()?)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let client = Arc::new(create_optimized_client()?);
-    let args = Args::parse();
+// The client certificate configured for hosts matching `pattern`
+fn load_identity(
+    pattern: &str,
+    identity: &config::IdentityConfig,
+) -> Result<reqwest::Identity, Box<dyn Error + Send + Sync>> {
+    let read = |path: &Path| {
+        let path = config::expand_home(path);
+        fs::read(&path).map_err(|e| format!("Cannot read '{}' for identity '{}': {}", path.display(), pattern, e))
+    };
+    let loaded = match identity {
+        config::IdentityConfig {
+            pkcs12: Some(bundle),
+            cert: None,
+            key: None,
+            password,
+        } => reqwest::Identity::from_pkcs12_der(&read(bundle)?, password.as_deref().unwrap_or("")),
+        config::IdentityConfig {
+            cert: Some(cert),
+            key: Some(key),
+            pkcs12: None,
+            ..
+        } => reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?),
+        _ => return Err(format!("Identity '{}' needs either cert and key, or pkcs12", pattern).into()),
+    };
+    loaded.map_err(|e| format!("Invalid client certificate for identity '{}': {}", pattern, e).into())
+}
+
+fn main() {
+    // Before the runtime starts its threads, since outside Linux the umask
+    // can only be read by setting it for a moment
+    paths::save_umask();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the async runtime")
+        .block_on(async_main());
+}
 
-    println!("Fetching {}...", args.url);
+async fn async_main() {
+    let argv: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let args = match config::expand_presets(&argv) {
+        Ok(argv) => Args::parse_from(argv),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Applied by hand so that --no-continue on the command line beats the
+    // variable instead of conflicting with it
+    let mut args = args;
+    if !args.resume && !args.no_continue {
+        args.resume = std::env::var("GATOR_CONTINUE").is_ok_and(|v| {
+            matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "y" | "on")
+        });
+    }
 
-    let file_name = if let Some(output_dest) = args.output {
-        output_dest
+    let level = if args.silent {
+        Level::Silent
+    } else if args.quiet {
+        Level::Quiet
     } else {
-        args.url
-            .split('/')
-            .last()
-            .unwrap_or("downloaded_file")
-            .to_string()
+        Level::Normal
     };
+    let plain = output::configure_terminal(args.plain);
+    let out = Output::new(level, !args.no_progress, plain, args.json);
 
-    let file_path = Path::new(&file_name);
-    let mut starting_pos = 0;
+    // `gator config` must keep working when the file is broken, to fix it
+    let config = match &args.command {
+        Some(Command::Config { .. } | Command::Preset { .. }) => config::Config::default(),
+        _ => match config::Config::load(args.config.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                out.error(e);
+                std::process::exit(1);
+            }
+        },
+    };
+    // Fail before downloading anything rather than after a long unattended run
+    let notify = match (args.notify_email.clone(), config.smtp.clone()) {
+        (Some(to), Some(smtp)) if !smtp.host.is_empty() && !smtp.from.is_empty() => {
+            Some((to, smtp))
+        }
+        (Some(_), _) => {
+            out.error("--notify-email needs [smtp] with `host` and `from` in the config file");
+            std::process::exit(1);
+        }
+        (None, _) => None,
+    };
 
-    if file_path.exists() {
-        let existing_file = File::open(&file_path).await?;
-        starting_pos = existing_file.metadata().await?.len();
-        println!(
-            "Existing file found, attempting to resume download from byte {}...",
-            starting_pos
-        );
-    } else {
-        println!("Starting new download...");
+    let started = output::timestamp();
+    let clock = std::time::Instant::now();
+    let result = run(args, &config, out).await;
+
+    if let Some((to, smtp)) = notify {
+        let summary = notify::Summary {
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+            started,
+            finished: output::timestamp(),
+            elapsed_secs: clock.elapsed().as_secs_f64(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        match notify::send(&smtp, &to, &summary).await {
+            Ok(()) => out.info(format!("Sent summary to {}", to)),
+            Err(e) => out.error(format!("Failed to send notification email: {}", e)),
+        }
     }
 
-    let head_response = client.head(&args.url).send().await?;
+    if let Err(e) = result {
+        out.error(&e);
+        std::process::exit(expect::exit_code(e.as_ref()));
+    }
+}
 
-    if !args.quiet {
-        println!("HTTP request sent... {}", head_response.status());
+async fn run(
+    mut args: Args,
+    config: &config::Config,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let policies = config.policies(&args.tag)?;
+    if args.dedup.is_some() {
+        let _ = DEDUP_HASHES.set(Arc::default());
+    }
+    if let Some(limit) = args.quota {
+        let quota = match args.command {
+            Some(Command::WatchFolder { .. } | Command::WatchClipboard { .. }) => quota::Quota::daily(limit),
+            _ => quota::Quota::new(limit),
+        };
+        let _ = QUOTA.set(quota);
+    }
+
+    let families = ip_family::Families::new();
+    let client = Arc::new(create_optimized_client(args.proxy.as_deref(), &config.proxy, None, &families)?);
+    let mut clients = HostClients::new(client);
+    for (pattern, identity) in &config.identities {
+        let identity = load_identity(pattern, identity)?;
+        let client = create_optimized_client(args.proxy.as_deref(), &config.proxy, Some(identity), &families)?;
+        clients.add(pattern, Arc::new(client));
+    }
+    clients.fall_back_across(families);
+    if let Some(pins) = args.pinnedpubkey.clone() {
+        clients.pin(pins);
+    }
+    let rules = config
+        .rewrite
+        .iter()
+        .map(|r| rewrite::Rule::new(&r.pattern, &r.replace))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut hooks: Vec<Arc<dyn hooks::Hook>> = vec![Arc::new(rewrite::Rewrites(rules)), Arc::new(share_links::ShareLinks)];
+    if let Some(path) = &config.credentials {
+        let path = config::expand_home(path);
+        hooks.push(Arc::new(credentials::Credentials::load(&path)?));
+        out.detail(format!("Loaded credentials from {}", path.display()));
+    }
+    if !matches!(args.command, Some(Command::Auth { .. })) {
+        if let Some(tokens) = auth::Tokens::load(&config.oauth)? {
+            tokens.refresh_due(&clients, out).await;
+            tokens.clone().keep_fresh(clients.clone(), out);
+            hooks.push(tokens);
+        }
+    }
+    let mut plugin_paths: Vec<PathBuf> = Vec::new();
+    #[cfg(feature = "wasm-plugins")]
+    plugin_paths.extend(args.plugin.iter().cloned());
+    for policy in &policies {
+        // An explicit --dir beats the tag's directory; the first tag with one wins
+        if args.dir.is_none() {
+            args.dir = policy.dir.as_deref().map(config::expand_home);
+        }
+        if !policy.headers.is_empty() {
+            let headers = policy
+                .headers
+                .iter()
+                .map(|h| config::parse_header(h))
+                .collect::<Result<Vec<_>, _>>()?;
+            hooks.push(Arc::new(hooks::StaticHeaders(headers)));
+        }
+        plugin_paths.extend(policy.plugins.iter().map(|p| config::expand_home(p)));
+        // Likewise for limits: the command line first, then the first tag setting one
+        args.limit_rate = args.limit_rate.or(policy.limit_rate);
+        args.jobs = args.jobs.or(policy.jobs);
+        args.connections = args.connections.or(policy.connections);
+        args.max_concurrent_per_host = args.max_concurrent_per_host.or(policy.max_concurrent_per_host);
     }
 
-    if !head_response.status().is_success() {
-        return Err(format!("Server returned error: {}", head_response.status()).into());
+    // Each download at once may hold a connection per worker and its file handles
+    let per_download = (batch_connections(&args) + args.file_handles) as u64;
+    let needed = args.jobs() as u64 * per_download + limits::BASE_OPEN_FILES;
+    if let Err(limit) = limits::ensure_open_files(needed) {
+        out.info(format!(
+            "Warning: with --jobs {}, Gator may need {} open files, but the limit is {}; downloads will use fewer workers (raise `ulimit -n` to avoid that)",
+            args.jobs(), needed, limit
+        ));
+    }
+    for path in plugin_paths {
+        hooks.push(load_plugin(&path)?);
+    }
+    let mut pacer = pacing::Pacer::new(args.user_agent.clone(), args.wait, args.max_per_minute);
+    if let Some(rate) = args.max_requests_per_second {
+        pacer = pacer.with_request_rate(rate, args.request_burst);
+    }
+    let pacer = Arc::new(pacer);
+    if !pacer.is_empty() {
+        hooks.push(pacer.clone());
+    }
+    let mut registry = Registry::with_clients(clients.clone(), hooks);
+    if !pacer.is_empty() {
+        registry.wrap_handlers(|inner| pacing::PacedHandler::wrap(&pacer, inner));
+    }
+    if args.limit_rate.is_some() || args.limit_rate_per_connection.is_some() {
+        let shared = args.limit_rate.map(|rate| Arc::new(throttle::Bucket::new(rate)));
+        registry.wrap_handlers(|inner| {
+            throttle::ThrottledHandler::wrap(shared.clone(), args.limit_rate_per_connection, inner)
+        });
+    }
+    // A cached probe has only some of the headers to check
+    let unstored = args.expect_header.iter().find(|(name, _)| !probe_cache::is_stored_header(name));
+    if let (Some(_), Some((name, _))) = (args.probe_cache, unstored) {
+        out.info(format!(
+            "Not using --probe-cache: it does not keep the {} header --expect-header checks",
+            name
+        ));
+    }
+    if let Some(ttl) = args.probe_cache.filter(|_| unstored.is_none()) {
+        let file = config::cache_dir()
+            .ok_or("Cannot determine the cache directory")?
+            .join("probes.jsonl");
+        registry.set_probe_cache(probe_cache::ProbeCache::open(
+            &file,
+            std::time::Duration::from_secs(ttl),
+        )?);
+    } else if args.order != batch::Order::Input {
+        // Files are probed for sorting and then again when downloaded
+        registry.set_probe_cache(probe_cache::ProbeCache::in_memory());
     }
 
-    let headers = head_response.headers();
-    let content_length = headers
-        .get(CONTENT_LENGTH)
-        .and_then(|ct_len| ct_len.to_str().ok())
-        .and_then(|ct_len| ct_len.parse::<u64>().ok());
+    if let Some(chaos) = args.chaos.clone() {
+        chaos::install(chaos.with_seed(args.chaos_seed));
+        registry.wrap_handlers(chaos::ChaosHandler::wrap);
+    }
 
-    let content_type = headers
-        .get("content-type")
-        .and_then(|ct| ct.to_str().ok())
-        .unwrap_or("unknown");
+    if let Some(command) = &args.command {
+        return run_command(command, &clients, &registry, &args, config, out).await;
+    }
 
-    match content_length {
-        Some(len) => {
-            if !args.quiet {
-                println!("Length: {} bytes", len);
-                println!("Type: {}", content_type);
+    let base_dir = match &args.dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => PathBuf::new(),
+    };
+    if args.urls.len() > 1 {
+        let items = url_batch(&registry, &args)?;
+        return download_batch(&registry, items, "/", &base_dir, &args, out).await;
+    }
+
+    // clap enforces the URL whenever no subcommand is given
+    let requested = args.urls.first().cloned().unwrap_or_default();
+    let url = registry.resolve_url(&requested)?;
+
+    if args.recursive {
+        out.info(format!("Crawling {}...", url));
+        let options = crawl::CrawlOptions {
+            accept: &args.accept,
+            reject: &args.reject,
+            depth: args.depth,
+        };
+        let items = crawl::collect(&registry, &url, &options).await?;
+        let root = batch::root_dir(&reqwest::Url::parse(&url)?);
+        return download_batch(&registry, items, &root, &base_dir, &args, out).await;
+    }
+
+    if args.sitemap {
+        out.info(format!("Reading sitemap {}...", url));
+        let items = sitemap::collect(clients.for_url(&url), &url)
+            .await?
+            .into_iter()
+            .filter(|e| args.since.as_deref().is_none_or(|d| sitemap::modified_since(e, d)))
+            .filter(|e| args.accept.is_empty() || pattern::matches_any(&args.accept, &e.url))
+            .filter(|e| !pattern::matches_any(&args.reject, &e.url))
+            .filter_map(|e| {
+                let parsed = reqwest::Url::parse(&e.url).ok()?;
+                Some(batch::BatchItem {
+                    relative: batch::relative_path_for_url(&parsed, "/"),
+                    url: e.url,
+                    digests: Vec::new(),
+                })
+            })
+            .collect();
+        return download_batch(&registry, items, "/", &base_dir, &args, out).await;
+    }
+
+    if args.archive_item {
+        let identifier = archive_org::identifier(&requested)
+            .ok_or_else(|| format!("'{}' is not an archive.org item or identifier", requested))?;
+        out.info(format!("Reading archive.org item {}...", identifier));
+        let metadata_url = registry.resolve_url(&archive_org::metadata_url(&identifier))?;
+        let item = archive_org::fetch(clients.for_url(&metadata_url), &metadata_url, &identifier).await?;
+        let (private, files): (Vec<_>, Vec<_>) = item
+            .files
+            .iter()
+            .filter(|f| !args.originals || f.source == "original")
+            .filter(|f| args.accept.is_empty() || pattern::matches_any(&args.accept, &f.name))
+            .filter(|f| !pattern::matches_any(&args.reject, &f.name))
+            .partition(|f| f.is_private());
+        if !private.is_empty() {
+            out.info(format!("Skipping {} private files", private.len()));
+        }
+        let items = item.batch_items(files);
+        return download_batch(&registry, items, "/", &base_dir, &args, out).await;
+    }
+
+    if args.dataset {
+        let source = datasets::source(&requested)
+            .ok_or_else(|| format!("'{}' is not a DOI, Zenodo record or Figshare record", requested))?;
+        out.info(format!("Looking up {}...", requested));
+        let record = datasets::fetch(&registry, &clients, &source).await?;
+        if let Some(title) = &record.title {
+            out.info(format!("{}: {}", record.name, title));
+        }
+        let files = record
+            .files
+            .iter()
+            .filter(|f| args.accept.is_empty() || pattern::matches_any(&args.accept, &f.name))
+            .filter(|f| !pattern::matches_any(&args.reject, &f.name));
+        let items = record.batch_items(files);
+        return download_batch(&registry, items, "/", &base_dir, &args, out).await;
+    }
+
+    let file_name = match &args.output {
+        Some(output_dest) => output_dest.clone(),
+        None => urls::file_name(&url),
+    };
+
+    // A single page with its requisites is a small mirror of its own
+    if args.page_requisites || args.convert_links {
+        let root = batch::root_dir(&reqwest::Url::parse(&url)?);
+        // Batch items are resolved again on download, so hand over the original
+        let items = vec![batch::BatchItem {
+            url: requested,
+            relative: PathBuf::from(file_name),
+            digests: Vec::new(),
+        }];
+        return download_batch(&registry, items, &root, &base_dir, &args, out).await;
+    }
+
+    let final_path = base_dir.join(file_name);
+    let Some(addr) = args.serve else {
+        download_file(&registry, &url, &final_path, &args, out).await?;
+        return Ok(());
+    };
+    let preview = Arc::new(preview::Preview::new());
+    let addr = preview::listen(addr, preview.clone())
+        .await
+        .map_err(|e| format!("Cannot serve at {}: {}", addr, e))?;
+    let _ = PREVIEW.set(preview);
+    out.info(format!("Serving {} at http://{}/ while it downloads", final_path.display(), addr));
+    download_file(&registry, &url, &final_path, &args, out).await?;
+    // The player may still be reading
+    out.info("Download finished; still serving until interrupted");
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+#[cfg(feature = "wasm-plugins")]
+fn load_plugin(path: &Path) -> Result<Arc<dyn hooks::Hook>, Box<dyn Error + Send + Sync>> {
+    Ok(Arc::new(plugins::WasmPlugin::load(path)?))
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+fn load_plugin(path: &Path) -> Result<Arc<dyn hooks::Hook>, Box<dyn Error + Send + Sync>> {
+    Err(format!(
+        "Cannot load plugin '{}': gator was built without the wasm-plugins feature",
+        path.display()
+    )
+    .into())
+}
+
+// The engine's options for one file, from the command line
+fn download_options(args: &Args) -> DownloadOptions {
+    DownloadOptions {
+        expectations: expect::Expectations {
+            status: args.expect_status,
+            headers: args.expect_header.clone(),
+            length: args.expect_length,
+            min_length: args.expect_min_length,
+        },
+        resume: args.resume,
+        restart: args.no_continue,
+        decompress: args.decompress,
+        encrypt: args.encrypt.clone(),
+        auto_digest: !args.no_auto_digest,
+        verify_digest: args.verify_digest,
+        write_metadata: args.write_metadata,
+        // -o is used exactly as given
+        auto_extension: args.auto_extension && args.output.is_none(),
+        check_type: args.check_type,
+        verify_overlap: args.verify_overlap,
+        chmod: args.chmod,
+        chown: args.chown,
+        dedup_hashes: DEDUP_HASHES.get().cloned(),
+        tmp_dir: args.tmp_dir.clone(),
+        portal_check: portal::check_url(),
+        sequential: args.sequential,
+        speed_window: args.speed_window.unwrap_or(DownloadOptions::default().speed_window),
+        connections: args.connections,
+        segment_size: args.segment_size.unwrap_or(DownloadOptions::default().segment_size),
+        multi_range: args.multi_range,
+        segment_retries: args.segment_retries,
+        file_handles: args.file_handles,
+        chunk_buffer: args
+            .chunk_buffer
+            .map_or(DownloadOptions::default().chunk_buffer, |size| size as usize),
+        ..DownloadOptions::default()
+    }
+}
+
+async fn download_file(
+    registry: &Registry,
+    url: &str,
+    final_path: &Path,
+    args: &Args,
+    out: Output,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    download_checked(registry, url, final_path, args, Vec::new(), args.connections, out).await
+}
+
+// download_file, also checking the file against checksums its source publishes
+async fn download_checked(
+    registry: &Registry,
+    url: &str,
+    final_path: &Path,
+    args: &Args,
+    digests: Vec<digest::Expected>,
+    // Segment workers for this file, which in a batch share the default
+    connections: Option<usize>,
+    out: Output,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    if args.pause_on_metered {
+        wait_while_metered(out).await;
+    }
+    if args.show_cert || args.cert_status {
+        check_certificates(url, args, out).await?;
+    }
+    let phases = match args.timings {
+        true => Some(measure_phases(url, out).await),
+        false => None,
+    };
+    let options = DownloadOptions {
+        digests,
+        connections,
+        ..download_options(args)
+    };
+    if let Some(quota) = QUOTA.get() {
+        if quota.exhausted() {
+            return Err(quota::QuotaExceeded { limit: quota.limit() }.into());
+        }
+        options.progress.observe(quota.meter());
+    }
+    if let Some(preview) = PREVIEW.get() {
+        options.progress.observe(preview.clone());
+    }
+    let stopwatch = Arc::new(timings::Stopwatch::start());
+    if args.timings {
+        options.progress.observe(stopwatch.clone());
+    }
+    // Ctrl-C ends the whole process, so the token is never cancelled
+    let cancel = gator::CancellationToken::new();
+    loop {
+        match download::download_file(registry, url, final_path, &options, &cancel, out).await {
+            Err(e) if e.is::<portal::CaptivePortal>() => {
+                let check_url = options.portal_check.as_deref().unwrap_or(portal::CHECK_URL);
+                wait_for_sign_in(check_url, out).await;
+            }
+            result => {
+                if args.timings {
+                    print_timings(url, phases.flatten(), stopwatch.split(), out);
+                }
+                return match (result, args.recompress) {
+                    (Ok(path), Some(target)) => recompress_file(path, target, out).await,
+                    (result, _) => result,
+                };
             }
         }
+    }
+}
+
+// Compress a finished download again for --recompress, on a blocking thread
+async fn recompress_file(
+    path: PathBuf,
+    target: recompress::Target,
+    out: Output,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let source = path.clone();
+    let done = tokio::task::spawn_blocking(move || recompress::recompress(&source, target))
+        .await?
+        .map_err(|e| format!("Cannot recompress {}: {}", path.display(), e))?;
+    // The file may have been rewritten in place
+    if let Some(hashes) = DEDUP_HASHES.get() {
+        hashes.forget(&path);
+    }
+    out.info(format!(
+        "Recompressed {} to {} ({} -> {})",
+        path.display(),
+        done.path.display(),
+        indicatif::HumanBytes(done.before),
+        indicatif::HumanBytes(done.after)
+    ));
+    out.event(
+        "recompressed",
+        serde_json::json!({
+            "path": path,
+            "to": done.path,
+            "before": done.before,
+            "after": done.after,
+        }),
+    );
+    Ok(done.path)
+}
+
+// Time the phases of a request to the download's server for --timings, on a
+// connection of its own. Not being able to is worth a note, not a failure.
+async fn measure_phases(url: &str, out: Output) -> Option<timings::Phases> {
+    let owned = url.to_string();
+    let measured = tokio::task::spawn_blocking(move || timings::measure(&owned)).await;
+    match measured.map_err(Into::into).and_then(|result| result) {
+        Ok(phases) => Some(phases),
+        Err(e) => {
+            out.detail(format!("Cannot time the connection to {}: {}", url, e));
+            None
+        }
+    }
+}
+
+fn print_timings(url: &str, phases: Option<timings::Phases>, split: Option<timings::Split>, out: Output) {
+    out.info(format!("Timings for {}:", url));
+    if let Some(phases) = &phases {
+        out.info(format!("  DNS lookup      {}", timings::format(phases.dns)));
+        out.info(format!("  Connect         {}", timings::format(phases.connect)));
+        if let Some(tls) = phases.tls {
+            out.info(format!("  TLS handshake   {}", timings::format(tls)));
+        }
+        out.info(format!(
+            "  First byte      {} ({} since the lookup)",
+            timings::format(phases.first_byte),
+            timings::format(phases.total())
+        ));
+    }
+    if let Some(split) = &split {
+        out.info(format!("  Probe           {}", timings::format(split.probe)));
+        let speed = match split.transfer.as_secs_f64() {
+            secs if secs > 0.0 => format!(" ({}/s)", indicatif::HumanBytes((split.bytes as f64 / secs) as u64)),
+            _ => String::new(),
+        };
+        out.info(format!("  Transfer        {}{}", timings::format(split.transfer), speed));
+        out.info(format!("  Total           {}", timings::format(split.total)));
+    }
+    // Seconds, like curl's time_* variables
+    let secs = |d: std::time::Duration| d.as_secs_f64();
+    out.event(
+        "timings",
+        serde_json::json!({
+            "url": url,
+            "dns": phases.as_ref().map(|p| secs(p.dns)),
+            "connect": phases.as_ref().map(|p| secs(p.connect)),
+            "tls": phases.as_ref().and_then(|p| p.tls).map(secs),
+            "first_byte": phases.as_ref().map(|p| secs(p.first_byte)),
+            "probe": split.as_ref().map(|s| secs(s.probe)),
+            "transfer": split.as_ref().map(|s| secs(s.transfer)),
+            "total": split.as_ref().map(|s| secs(s.total)),
+            "bytes": split.as_ref().map(|s| s.bytes),
+        }),
+    );
+}
+
+// Look at the handshake with the download's server, once per host and run:
+// print the chain for --show-cert, and check the stapled OCSP response for
+// --cert-status
+async fn check_certificates(url: &str, args: &Args, out: Output) -> Result<(), Box<dyn Error + Send + Sync>> {
+    type Seen = Vec<(String, Arc<tls::Handshake>)>;
+    static HANDSHAKES: tokio::sync::Mutex<Seen> = tokio::sync::Mutex::const_new(Vec::new());
+    let parsed = reqwest::Url::parse(url)?;
+    if parsed.scheme() != "https" {
+        if args.cert_status {
+            return Err(format!("'{}' is not HTTPS, so it has no certificate status (--cert-status)", url).into());
+        }
+        return Ok(());
+    }
+    let host = parsed.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let server = format!("{}:{}", host, port);
+
+    let mut handshakes = HANDSHAKES.lock().await;
+    let handshake = match handshakes.iter().find(|(s, _)| *s == server) {
+        Some((_, handshake)) => handshake.clone(),
         None => {
-            if !args.quiet {
-                println!("Length: unknown");
+            let handshake = Arc::new(tokio::task::spawn_blocking(move || tls::handshake(&host, port)).await??);
+            if args.show_cert {
+                print_certificates(&server, &handshake, out);
+            }
+            handshakes.push((server.clone(), handshake.clone()));
+            handshake
+        }
+    };
+    drop(handshakes);
+
+    if args.cert_status {
+        match handshake.ocsp {
+            Some(tls::Stapled::Good) => {}
+            None => return Err(format!("{} stapled no OCSP response (--cert-status)", server).into()),
+            Some(tls::Stapled::Invalid) => {
+                return Err(format!("{} stapled an OCSP response that does not check out (--cert-status)", server).into())
+            }
+            Some(status) => {
+                return Err(format!("OCSP says the certificate of {} is {} (--cert-status)", server, status).into())
             }
         }
     }
+    Ok(())
+}
 
-    let accepts_ranges = headers
-        .get("accept-ranges")
-        .and_then(|h| h.to_str().ok())
-        .map(|s| s == "bytes")
-        .unwrap_or(false);
+fn print_certificates(server: &str, handshake: &tls::Handshake, out: Output) {
+    let trust = match &handshake.untrusted {
+        None => "trusted".to_string(),
+        Some(reason) => format!("NOT trusted: {}", reason),
+    };
+    out.info(format!("Certificate chain of {} ({}):", server, trust));
+    for (depth, cert) in handshake.chain.iter().enumerate() {
+        out.info(format!("  {} {}", depth, cert.subject));
+        out.info(format!("      issuer  {}", cert.issuer));
+        out.info(format!("      valid   {} to {}", cert.not_before, cert.not_after));
+        if !cert.names.is_empty() {
+            out.info(format!("      names   {}", cert.names.join(", ")));
+        }
+    }
+    match handshake.ocsp {
+        Some(status) => out.info(format!("  OCSP: {}", status)),
+        None => out.info("  OCSP: none stapled"),
+    }
+    out.event(
+        "certificates",
+        serde_json::json!({ "server": server, "handshake": handshake }),
+    );
+}
 
-    if let Some(total_len) = content_length {
-        if accepts_ranges && total_len > 10 * 1024 * 1024 && starting_pos < total_len {
-            download_with_work_stealing(
-                client,
-                &args.url,
-                &file_name,
-                starting_pos,
-                total_len,
-                args.quiet,
-            )
-            .await?;
-        } else {
-            download_single_chunk(
-                client,
-                &args.url,
-                &file_name,
-                starting_pos,
-                total_len,
-                args.quiet,
-            )
-            .await?;
+// Hold the download (and so the queue) until the captive portal lets requests
+// through, checking again every so often. Downloads that run into the portal
+// together wait on the first, which alone tells the user.
+async fn wait_for_sign_in(check_url: &str, out: Output) {
+    const RECHECK: std::time::Duration = std::time::Duration::from_secs(10);
+    static WAITING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let _waiting = WAITING.lock().await;
+    let mut waited = false;
+    while let Some(portal) = portal::detect(check_url).await {
+        if !waited {
+            out.info(format!("{}; waiting until you have signed in...", portal));
+            out.event("captive_portal", serde_json::json!({ "waiting": true, "login": portal.login }));
+            waited = true;
         }
-    } else {
-        download_single_chunk(client, &args.url, &file_name, starting_pos, 0, args.quiet).await?;
+        tokio::time::sleep(RECHECK).await;
     }
+    if waited {
+        out.info("The network lets requests through again; continuing");
+        out.event("captive_portal", serde_json::json!({ "waiting": false }));
+    }
+}
 
-    println!("Download complete!");
-    Ok(())
+// Return once the connection is not (known to be) metered, checking again
+// every so often
+async fn wait_while_metered(out: Output) {
+    const RECHECK: std::time::Duration = std::time::Duration::from_secs(30);
+    let mut waited = false;
+    while tokio::task::spawn_blocking(metered::is_metered).await.ok().flatten() == Some(true) {
+        if !waited {
+            out.info("The connection is metered; waiting for an unmetered network before starting...");
+            out.event("metered", serde_json::json!({ "waiting": true }));
+            waited = true;
+        }
+        tokio::time::sleep(RECHECK).await;
+    }
+    if waited {
+        out.info("The connection is no longer metered; continuing");
+        out.event("metered", serde_json::json!({ "waiting": false }));
+    }
 }
 
-async fn download_with_work_stealing(
-    client: Arc<Client>,
-    url: &str,
-    file_name: &str,
-    starting_pos: u64,
-    total_len: u64,
-    quiet: bool,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    const SEGMENT_SIZE: u64 = 1 * 1024 * 1024; // 1MB segments
-    let remaining_bytes = total_len - starting_pos;
-    let num_segments = ((remaining_bytes as f64) / (SEGMENT_SIZE as f64)).ceil() as u64;
-
-    if !quiet {
-        println!(
-            "Downloading in {} segments of ~{}MB each using work-stealing scheduler",
-            num_segments,
-            SEGMENT_SIZE / 1024 / 1024
-        );
+// Several URLs from the command line, each saved under its own file name
+fn url_batch(registry: &Registry, args: &Args) -> Result<Vec<batch::BatchItem>, Box<dyn Error + Send + Sync>> {
+    let single = [
+        ("--output", args.output.is_some()),
+        ("--recursive", args.recursive),
+        ("--sitemap", args.sitemap),
+        ("--archive-item", args.archive_item),
+        ("--dataset", args.dataset),
+        ("--page-requisites", args.page_requisites),
+        ("--convert-links", args.convert_links),
+        ("--serve", args.serve.is_some()),
+    ];
+    if let Some((flag, _)) = single.iter().find(|(_, set)| *set) {
+        return Err(format!("{} takes a single URL", flag).into());
+    }
+    let mut names: HashMap<PathBuf, &str> = HashMap::new();
+    let mut items = Vec::with_capacity(args.urls.len());
+    for requested in &args.urls {
+        let relative = PathBuf::from(urls::file_name(&registry.resolve_url(requested)?));
+        // The same URL twice is skipped as a duplicate; two files with one name are not
+        if let Some(other) = names.insert(relative.clone(), requested) {
+            if urls::normalize(other) != urls::normalize(requested) {
+                return Err(format!(
+                    "{} and {} would both be saved as {}",
+                    other,
+                    requested,
+                    relative.display()
+                )
+                .into());
+            }
+        }
+        // Batch items are resolved again on download, so hand over the original
+        items.push(batch::BatchItem {
+            url: requested.clone(),
+            relative,
+            digests: Vec::new(),
+        });
     }
+    Ok(items)
+}
 
-    // Create work queue for dynamic segment distribution
-    let (tx, rx) = mpsc::unbounded_channel::<Segment>();
+// Segment workers for each file of a batch: --connections, or an even share of
+// the default so --jobs files at once do not each open as many as one would
+fn batch_connections(args: &Args) -> usize {
+    args.connections.unwrap_or_else(|| (download::max_workers() / args.jobs()).max(1))
+}
 
-    let mut current_pos = starting_pos;
-    for i in 0..num_segments {
-        let start = current_pos;
-        let end = if i == num_segments - 1 {
-            total_len - 1
+// Download a set of files below `base_dir`, up to --jobs at once and taking
+// hosts in turn, keeping their relative layout. `root` is the URL directory
+// that relative paths start from.
+async fn download_batch(
+    registry: &Registry,
+    items: Vec<batch::BatchItem>,
+    root: &str,
+    base_dir: &Path,
+    args: &Args,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    out.info(format!("Found {} matching files", items.len()));
+
+    // Listings and sitemaps often name one file several ways
+    let canonical = |url: &str| {
+        let url = urls::normalize(url);
+        if args.sort_query {
+            urls::sort_query(&url)
+        } else {
+            url
+        }
+    };
+    let mut seen: HashSet<String> = HashSet::new();
+    // Different URLs may still map to one file, e.g. `A.txt` and `a.txt`
+    let mut names = batch::UniquePaths::new(paths::CASE_INSENSITIVE);
+    let mut unique = Vec::with_capacity(items.len());
+    let mut duplicates = 0;
+    for mut item in items {
+        item.url = canonical(&item.url);
+        if seen.insert(item.url.clone()) {
+            item.relative = names.claim(item.relative);
+            unique.push(item);
         } else {
-            current_pos + SEGMENT_SIZE - 1
+            duplicates += 1;
+        }
+    }
+    if duplicates > 0 {
+        out.info(format!("Skipped {} duplicate URLs", duplicates));
+    }
+    if args.order != batch::Order::Input {
+        out.info(format!("Probing {} files for their size...", unique.len()));
+        let sizes = probe_sizes(registry, &unique, args.jobs()).await;
+        unique = batch::sort_by_size(unique, sizes, args.order);
+    }
+    let mut queue = batch::HostQueue::new(args.max_concurrent_per_host);
+    for item in unique {
+        queue.push(item);
+    }
+    // Where each successfully downloaded URL ended up, for --convert-links
+    let mut saved: HashMap<String, PathBuf> = HashMap::new();
+    let mut pages = Vec::new();
+    let mut failed = 0;
+    let mut downloaded = Vec::new();
+
+    // Up to --jobs files at once, taking hosts in turn; pages finishing may
+    // queue more (requisites)
+    let item_out = if args.jobs() > 1 { out.batch_item() } else { out };
+    let total_bar = create_batch_progress_bar(out, queue.len() as u64);
+    let mut running = futures::stream::FuturesUnordered::new();
+    loop {
+        // Past the quota, the files in flight finish and no more start
+        while running.len() < args.jobs() && !QUOTA.get().is_some_and(|q| q.exhausted()) {
+            let Some(item) = queue.pop() else {
+                break;
+            };
+            running.push(async move {
+                let result = download_batch_item(registry, &item, base_dir, args, item_out).await;
+                (item, result)
+            });
+        }
+        let Some((item, result)) = running.next().await else {
+            break;
         };
+        queue.finished(&item);
+        total_bar.inc(1);
+
+        // One bad file should not stop the rest of the mirror
+        let final_path = match result {
+            Ok(path) => path,
+            Err(e) => {
+                out.error(format!("{}: {}", item.url, e));
+                failed += 1;
+                continue;
+            }
+        };
+        if !item_out.details() {
+            out.info(format!("Saved {}", final_path.display()));
+        }
+        saved.insert(item.url.clone(), final_path.clone());
+        downloaded.push(final_path.clone());
 
-        tx.send(Segment { start, end })?;
-        current_pos = end + 1;
+        if !args.page_requisites && !args.convert_links {
+            continue;
+        }
+        let content = fs::read(&final_path)?;
+        if !html::looks_like_html(&final_path, &content) {
+            continue;
+        }
+
+        if args.page_requisites {
+            let page_url = reqwest::Url::parse(&item.url)?;
+            let html = String::from_utf8_lossy(&content);
+            for link in html::requisite_links(&html) {
+                let Ok(url) = page_url.join(&link) else {
+                    continue;
+                };
+                if url.host_str() != page_url.host_str() {
+                    continue;
+                }
+                let url = canonical(url.as_str());
+                if !seen.insert(url.clone()) {
+                    continue;
+                }
+                let Ok(parsed) = reqwest::Url::parse(&url) else {
+                    continue;
+                };
+                queue.push(batch::BatchItem {
+                    relative: names.claim(batch::relative_path_for_url(&parsed, root)),
+                    url,
+                    digests: Vec::new(),
+                });
+                total_bar.inc_length(1);
+            }
+        }
+        pages.push((item.url, final_path));
+    }
+    total_bar.finish_and_clear();
+    let skipped = queue.drain();
+    if let (Some(quota), false) = (QUOTA.get(), skipped.is_empty()) {
+        for item in &skipped {
+            out.detail(format!("Skipped {}", item.url));
+            out.event("skipped", serde_json::json!({ "url": item.url, "reason": "quota" }));
+        }
+        out.info(format!(
+            "Download quota of {} reached after {}; skipped {} file(s)",
+            indicatif::HumanBytes(quota.limit()),
+            indicatif::HumanBytes(quota.used()),
+            skipped.len()
+        ));
     }
-    drop(tx);
 
-    // Share receiver for work-stealing (mutex contention is minimal since workers do async I/O)
-    let rx = Arc::new(Mutex::new(rx));
+    if args.convert_links {
+        for (page_url, page_path) in &pages {
+            convert_links(page_url, page_path, &saved, args.sort_query)?;
+            if let Some(hashes) = DEDUP_HASHES.get() {
+                hashes.forget(page_path);
+            }
+        }
+        out.info(format!("Converted links in {} pages", pages.len()));
+    }
 
-    // Pre-allocate file to reduce fragmentation
-    let file_path = Path::new(file_name);
-    if !file_path.exists() {
-        let file = fs::File::create(file_name)?;
-        file.set_len(total_len)?;
+    // Runs last so pages rewritten by --convert-links are compared as saved
+    if let Some(mode) = args.dedup {
+        let mut deduper = dedup::Deduper::new(mode, args.dedup_dry_run);
+        for path in &downloaded {
+            let known = DEDUP_HASHES.get().and_then(|hashes| hashes.get(path));
+            if let Some(original) = deduper.add(path, known)? {
+                out.info(format!(
+                    "{} has the same content as {}",
+                    path.display(),
+                    original.display()
+                ));
+            }
+        }
+        out.info(deduper.summary());
     }
 
-    let bytes_downloaded = Arc::new(AtomicU64::new(0));
-    let pb = create_progress_bar(
-        quiet,
-        "Downloading",
-        Some(remaining_bytes),
-        None,
-        bytes_downloaded.clone(),
+    let total = saved.len() + failed;
+    out.event(
+        "summary",
+        serde_json::json!({
+            "total": total,
+            "succeeded": saved.len(),
+            "failed": failed,
+            "duplicates": duplicates,
+            "skipped": skipped.len(),
+        }),
     );
+    if failed > 0 {
+        return Err(format!("{} of {} downloads failed", failed, total).into());
+    }
+    Ok(())
+}
 
-    // Worker pool size: max(16, CPU * 4)
-    let worker_count = std::cmp::max(16, num_cpus::get() * 4);
-
-    if !quiet {
-        println!("Spawning {} workers for parallel download", worker_count);
-    }
-
-    let mut handles = Vec::new();
-    for _ in 0..worker_count {
-        let client_clone = client.clone();
-        let url = url.to_string();
-        let file_name = file_name.to_string();
-        let rx = rx.clone();
-        let pb = pb.clone();
-        let bytes_downloaded = bytes_downloaded.clone();
-
-        let handle = tokio::spawn(async move {
-            // Each worker has its own file handle for parallel writes
-            let mut file = OpenOptions::new()
-                .write(true)
-                .read(false)
-                .open(&file_name)
-                .await?;
-
-            loop {
-                // Pull next segment from queue (work-stealing)
-                let segment = {
-                    let mut rx_guard = rx.lock().await;
-                    rx_guard.recv().await
-                };
+// The advertised size of every item, None where it is unknown or the probe failed
+async fn probe_sizes(
+    registry: &Registry,
+    items: &[batch::BatchItem],
+    concurrency: usize,
+) -> Vec<Option<u64>> {
+    futures::stream::iter(items)
+        .map(|item| async move {
+            let url = registry.resolve_url(&item.url).ok()?;
+            let mut probe = registry.probe(&url).await.ok()?;
+            registry.inspect_probe(&url, &mut probe).ok()?;
+            probe.length
+        })
+        .buffered(concurrency)
+        .collect()
+        .await
+}
 
-                let segment = match segment {
-                    Some(seg) => seg,
-                    None => break,
-                };
+async fn download_batch_item(
+    registry: &Registry,
+    item: &batch::BatchItem,
+    base_dir: &Path,
+    args: &Args,
+    out: Output,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let final_path = base_dir.join(&item.relative);
+    if let Some(parent) = final_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let url = registry.resolve_url(&item.url)?;
+    download_checked(registry, &url, &final_path, args, item.digests.clone(), Some(batch_connections(args)), out).await
+}
+
+// Point links at downloaded files to their local copies, and make every other
+// relative link absolute so it keeps working from the mirror
+fn convert_links(
+    page_url: &str,
+    page_path: &Path,
+    saved: &HashMap<String, PathBuf>,
+    sort_query: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let base = reqwest::Url::parse(page_url)?;
+    let html = fs::read_to_string(page_path)?;
 
-                let range_header = format!("bytes={}-{}", segment.start, segment.end);
-                let mut response = client_clone
-                    .get(&url)
-                    .header("Range", range_header)
-                    .send()
-                    .await?;
+    let converted = html::rewrite_links(&html, |link| {
+        if link.starts_with('#') || link.starts_with("data:") || link.starts_with("javascript:") {
+            return None;
+        }
+        let mut url = base.join(link).ok()?;
+        let fragment = url.fragment().map(|f| format!("#{}", f));
+        url.set_fragment(None);
+        // Saved under the same canonical form as the batch used
+        let key = match sort_query {
+            true => urls::sort_query(url.as_str()),
+            false => url.to_string(),
+        };
+        match saved.get(&key) {
+            Some(local) => {
+                Some(html::relative_link(page_path, local) + fragment.as_deref().unwrap_or(""))
+            }
+            None if link.contains("://") => None,
+            None => Some(url.to_string() + fragment.as_deref().unwrap_or("")),
+        }
+    });
 
-                if !response.status().is_success() && response.status() != StatusCode::PARTIAL_CONTENT {
-                    return Err(format!("Segment download failed: {}", response.status()).into());
+    fs::write(page_path, converted)?;
+    Ok(())
+}
+
+
+async fn run_command(
+    command: &Command,
+    clients: &HostClients,
+    registry: &Registry,
+    args: &Args,
+    config: &config::Config,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match command {
+        Command::Ls { url, urls, webdav } => {
+            let entries = if let Some(identifier) = archive_org::identifier_in_url(url).filter(|_| !*webdav) {
+                let metadata_url = registry.resolve_url(&archive_org::metadata_url(&identifier))?;
+                archive_org::fetch(clients.for_url(&metadata_url), &metadata_url, &identifier).await?.entries()
+            } else if let Some(source) = datasets::source(url).filter(|_| !*webdav) {
+                datasets::fetch(registry, clients, &source).await?.entries()
+            } else if *webdav {
+                listing::list(clients.for_url(url), url, true).await?
+            } else {
+                registry.handler_for(url)?.list(url).await?
+            };
+            if out.json() {
+                println!("{}", serde_json::to_string(&entries)?);
+            } else if *urls {
+                for entry in entries.iter().filter(|e| !e.is_dir) {
+                    println!("{}", entry.url);
+                }
+            } else {
+                listing::print_table(&entries);
+            }
+        }
+        Command::WatchClipboard {
+            accept,
+            confirm,
+            dir,
+            interval,
+        } => {
+            let base_dir = match dir {
+                Some(dir) => {
+                    fs::create_dir_all(dir)?;
+                    dir.clone()
                 }
+                None => PathBuf::new(),
+            };
+            let interval = std::time::Duration::from_secs((*interval).max(1));
+            watch_clipboard(registry, accept, *confirm, &base_dir, interval, args, out).await?;
+        }
+        Command::SelfUpdate {
+            channel,
+            check,
+            force,
+            release_url,
+        } => {
+            self_update(clients.for_url(release_url), registry, *channel, *check, *force, release_url, args, out).await?;
+        }
+        Command::Completions { shell } => {
+            // Rendered to memory first: `generate` panics if stdout is closed early
+            let mut script = Vec::new();
+            clap_complete::generate(*shell, &mut Args::command(), "gator", &mut script);
+            std::io::Write::write_all(&mut std::io::stdout(), &script)?;
+        }
+        Command::Manpage => {
+            clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout())?;
+        }
+        Command::Zip { action } => run_zip_action(action, registry, out).await?,
+        Command::Tar { action } => run_tar_action(action, registry, out).await?,
+        Command::Peek { url, lines } => run_peek(url, *lines, registry, out).await?,
+        Command::Clean {
+            dirs,
+            older_than,
+            dry_run,
+        } => {
+            let default_dir = [args.tmp_dir.clone().or(args.dir.clone()).unwrap_or_else(|| PathBuf::from("."))];
+            let dirs = if dirs.is_empty() { &default_dir[..] } else { &dirs[..] };
+            run_clean(dirs, *older_than, *dry_run, out)?;
+        }
+        Command::SelfTest => self_test::run(out).await?,
+        Command::Auth { action } => match action {
+            AuthAction::Login { provider } => auth::login(clients, provider, &config.oauth, out).await?,
+            AuthAction::Logout { provider } => auth::logout(provider, out)?,
+        },
+        Command::Config { action } => {
+            let path = config::resolve_path(args.config.as_deref())?;
+            run_config_action(action, &path, out)?;
+        }
+        Command::Preset { action } => {
+            let path = config::resolve_path(args.config.as_deref())?;
+            run_preset_action(action, &path, out)?;
+        }
+        Command::WatchFolder {
+            folder,
+            dir,
+            interval,
+        } => {
+            if !folder.is_dir() {
+                return Err(format!("'{}' is not a directory", folder.display()).into());
+            }
+            let base_dir = match dir {
+                Some(dir) => {
+                    fs::create_dir_all(dir)?;
+                    dir.clone()
+                }
+                None => PathBuf::new(),
+            };
+            let interval = std::time::Duration::from_secs((*interval).max(1));
+            watch_folder(registry, folder, &base_dir, interval, args, out).await?;
+        }
+    }
+    Ok(())
+}
 
-                // Write directly to correct file offset
-                file.seek(std::io::SeekFrom::Start(segment.start)).await?;
+// Poll the clipboard and download each new matching URL in turn until interrupted.
+// A failed download is reported and the watch carries on.
+async fn watch_clipboard(
+    registry: &Registry,
+    accept: &[String],
+    confirm: bool,
+    base_dir: &Path,
+    interval: std::time::Duration,
+    args: &Args,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Whatever is on the clipboard already was copied before we started
+    let mut last = clipboard::read().await?;
+    let mut seen: HashSet<String> = HashSet::new();
+    out.info("Watching the clipboard for URLs (Ctrl+C to stop)...");
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let text = match clipboard::read().await {
+            Ok(text) => text,
+            Err(e) => {
+                out.error(e);
+                continue;
+            }
+        };
+        if text == last {
+            continue;
+        }
+        last = text;
 
-                while let Some(chunk) = response.chunk().await? {
-                    file.write_all(&chunk).await?;
-                    let chunk_len = chunk.len() as u64;
-                    bytes_downloaded.fetch_add(chunk_len, Ordering::Relaxed);
-                    pb.inc(chunk_len);
+        for url in clipboard::extract_urls(&last) {
+            if !accept.is_empty() && !pattern::matches_any(accept, &url) {
+                continue;
+            }
+            if !seen.insert(url.clone()) {
+                continue;
+            }
+            if confirm && !confirm_download(&url).await? {
+                continue;
+            }
+            let result = match registry.resolve_url(&url) {
+                Ok(resolved) => {
+                    let file_name = urls::file_name(&resolved);
+                    download_file(registry, &resolved, &base_dir.join(file_name), args, out).await
                 }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                out.error(format!("{}: {}", url, e));
             }
+        }
+    }
+}
 
-            Ok::<(), Box<dyn Error + Send + Sync>>(())
-        });
+// The config file's text, or nothing if it does not exist yet
+fn read_config_file(path: &Path) -> Result<String, Box<dyn Error + Send + Sync>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(format!("Failed to read config '{}': {}", path.display(), e).into()),
+    }
+}
 
-        handles.push(handle);
+fn write_config_file(path: &Path, content: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    // Write beside the file and rename, so a crash never leaves it half written
+    let tmp = paths::part_path(path);
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
 
-    let results = futures::future::join_all(handles).await;
+fn run_config_action(
+    action: &ConfigAction,
+    path: &Path,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let read = || read_config_file(path);
+    let write = |content: &str| write_config_file(path, content);
 
-    for result in results {
-        match result {
-            Ok(Ok(_)) => {}
-            Ok(Err(e)) => return Err(e),
-            Err(e) => return Err(Box::new(e)),
+    match action {
+        ConfigAction::Path if out.json() => {
+            out.event("config_path", serde_json::json!({ "path": path }));
+        }
+        ConfigAction::Path => println!("{}", path.display()),
+        ConfigAction::Init { force } => {
+            if path.exists() && !force {
+                return Err(format!(
+                    "'{}' already exists (use --force to overwrite)",
+                    path.display()
+                )
+                .into());
+            }
+            write(config::TEMPLATE)?;
+            out.info(format!("Wrote {}", path.display()));
+        }
+        ConfigAction::Get { key } => match config::get_value(&read()?, key)? {
+            Some(value) if out.json() => {
+                out.event("config_value", serde_json::json!({ "key": key, "value": value }));
+            }
+            Some(value) => println!("{}", value),
+            None => return Err(format!("'{}' is not set", key).into()),
+        },
+        ConfigAction::Set { key, value } => {
+            let updated = config::set_value(path, &read()?, key, value)?;
+            write(&updated)?;
+        }
+        ConfigAction::Edit => {
+            if !path.exists() {
+                write(config::TEMPLATE)?;
+            }
+            let editor = std::env::var("VISUAL")
+                .or_else(|_| std::env::var("EDITOR"))
+                .unwrap_or_else(|_| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+            // The editor setting may carry arguments, e.g. "code --wait"
+            let mut words = editor.split_whitespace();
+            let program = words.next().ok_or("$EDITOR is empty")?;
+            let status = std::process::Command::new(program)
+                .args(words)
+                .arg(path)
+                .status()
+                .map_err(|e| format!("Failed to start editor '{}': {}", editor, e))?;
+            if !status.success() {
+                return Err(format!("Editor exited with {}", status).into());
+            }
+            config::validate(path, &read()?)?;
+            out.info(format!("{} is valid", path.display()));
         }
     }
-
-    pb.finish_with_message("Download complete!");
     Ok(())
 }
 
-async fn download_single_chunk(
-    client: Arc<Client>,
-    url: &str,
-    file_name: &str,
-    starting_pos: u64,
-    total_len: u64,
-    quiet: bool,
+async fn run_zip_action(
+    action: &ZipAction,
+    registry: &Registry,
+    out: Output,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let bytes_downloaded = Arc::new(AtomicU64::new(0));
-    let pb = if total_len > 0 {
-        create_progress_bar(
-            quiet,
-            "Downloading",
-            Some(total_len - starting_pos),
-            None,
-            bytes_downloaded.clone(),
-        )
-    } else {
-        create_progress_bar(
-            quiet,
-            "Downloading",
-            None,
-            None,
-            bytes_downloaded.clone(),
-        )
+    let url = match action {
+        ZipAction::Ls { url } | ZipAction::Get { url, .. } => url,
     };
+    let mut reader = ParallelHttpReader::open(registry, url).await?;
+    let members = zip::list(&mut reader).await?;
 
-    let mut request = client.get(url);
+    let ZipAction::Get {
+        members: wanted,
+        dir,
+        force,
+        ..
+    } = action
+    else {
+        if out.json() {
+            println!("{}", serde_json::to_string(&members)?);
+        } else {
+            print_zip_members(&members);
+        }
+        return Ok(());
+    };
 
-    if starting_pos > 0 {
-        request = request.header("Range", format!("bytes={}-", starting_pos));
+    if let Some(pattern) = wanted
+        .iter()
+        .find(|p| !members.iter().any(|m| pattern::matches(p, &m.name)))
+    {
+        return Err(format!("No file in the archive matches '{}'", pattern).into());
+    }
+    let base_dir = dir.clone().unwrap_or_default();
+    let (mut extracted, mut fetched) = (0, 0);
+    for member in members
+        .iter()
+        .filter(|m| !m.is_dir && pattern::matches_any(wanted, &m.name))
+    {
+        let dest = base_dir.join(zip::relative_path(member));
+        if dest.exists() && !force {
+            return Err(format!("'{}' already exists (use --force to overwrite)", dest.display()).into());
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let bytes = zip::extract(&mut reader, member, &dest).await?;
+        out.detail(format!("Extracted {} ({})", dest.display(), indicatif::HumanBytes(bytes)));
+        out.event(
+            "extracted",
+            serde_json::json!({ "url": url, "member": member.name, "path": dest, "bytes": bytes }),
+        );
+        extracted += 1;
+        fetched += member.compressed_size;
     }
+    out.info(format!(
+        "Extracted {} file(s), fetching about {} of the {} archive",
+        extracted,
+        indicatif::HumanBytes(fetched),
+        indicatif::HumanBytes(reader.len())
+    ));
+    Ok(())
+}
 
-    let mut response = request.send().await?;
+fn print_zip_members(members: &[zip::Member]) {
+    for member in members {
+        let size = match member.is_dir {
+            true => "-".to_string(),
+            false => indicatif::HumanBytes(member.size).to_string(),
+        };
+        println!("{:>12}  {:<24}  {}", size, member.modified, member.name);
+    }
+}
 
-    if !response.status().is_success() && response.status() != StatusCode::PARTIAL_CONTENT {
-        return Err(format!("Server returned error: {}", response.status()).into());
+async fn run_tar_action(
+    action: &TarAction,
+    registry: &Registry,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let url = match action {
+        TarAction::Ls { url } | TarAction::Get { url, .. } => url,
+    };
+    let cached = config::cache_dir().map(|dir| tar::Index::file_in(&dir.join("tar-index"), url));
+    let index = tar::index(registry, url, cached.as_deref()).await?;
+
+    let TarAction::Get {
+        members: wanted,
+        dir,
+        force,
+        ..
+    } = action
+    else {
+        if out.json() {
+            println!("{}", serde_json::to_string(&index.members)?);
+        } else {
+            for member in &index.members {
+                let size = match member.kind {
+                    tar::Kind::File => indicatif::HumanBytes(member.size).to_string(),
+                    _ => "-".to_string(),
+                };
+                println!("{:>12}  {:<24}  {}", size, member.modified, member.name);
+            }
+        }
+        return Ok(());
+    };
+
+    if let Some(pattern) = wanted
+        .iter()
+        .find(|p| !index.members.iter().any(|m| pattern::matches(p, &m.name)))
+    {
+        return Err(format!("No file in the archive matches '{}'", pattern).into());
     }
+    let base_dir = dir.clone().unwrap_or_default();
+    let mut files = Vec::new();
+    // Links, directories and devices are not recreated
+    for member in index
+        .members
+        .iter()
+        .filter(|m| m.kind == tar::Kind::File && pattern::matches_any(wanted, &m.name))
+    {
+        let dest = base_dir.join(tar::relative_path(member));
+        if dest.exists() && !force {
+            return Err(format!("'{}' already exists (use --force to overwrite)", dest.display()).into());
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        files.push((member.clone(), dest));
+    }
+    let extracted = tar::extract(registry, url, &index, files).await?;
+    for (member, dest, bytes) in &extracted {
+        out.detail(format!("Extracted {} ({})", dest.display(), indicatif::HumanBytes(*bytes)));
+        out.event(
+            "extracted",
+            serde_json::json!({ "url": url, "member": member.name, "path": dest, "bytes": bytes }),
+        );
+    }
+    out.info(format!("Extracted {} file(s)", extracted.len()));
+    Ok(())
+}
 
-    // Pre-allocate file if we know the size
-    if total_len > 0 && starting_pos == 0 {
-        let file = fs::File::create(file_name)?;
-        file.set_len(total_len)?;
+async fn run_peek(
+    url: &str,
+    lines: usize,
+    registry: &Registry,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let peeked = peek::peek(registry, url, lines).await?;
+    if out.json() {
+        println!("{}", serde_json::to_string(&peeked)?);
+        return Ok(());
+    }
+    match peeked {
+        peek::Peek::Parquet(schema) => {
+            println!("Parquet, {} rows in {} row group(s)", schema.num_rows, schema.row_groups);
+            if let Some(created_by) = &schema.created_by {
+                println!("Written by {}", created_by);
+            }
+            let types: Vec<String> = schema
+                .columns
+                .iter()
+                .map(|c| match &c.logical_type {
+                    Some(logical) => format!("{} ({})", c.physical_type, logical),
+                    None => c.physical_type.clone(),
+                })
+                .collect();
+            let name_width = schema.columns.iter().map(|c| c.name.len()).max().unwrap_or(0);
+            let type_width = types.iter().map(|t| t.len()).max().unwrap_or(0);
+            for (column, kind) in schema.columns.iter().zip(&types) {
+                println!("  {:<name_width$}  {:<type_width$}  {}", column.name, kind, column.repetition);
+            }
+        }
+        peek::Peek::Zip { members } => print_zip_members(&members),
+        peek::Peek::Text { lines } => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
     }
+    Ok(())
+}
 
-    let mut file = if starting_pos > 0 {
-        OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(file_name)
-            .await?
-    } else {
-        OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(file_name)
-            .await?
-    };
+fn run_clean(
+    dirs: &[PathBuf],
+    older_than: u64,
+    dry_run: bool,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let min_age = std::time::Duration::from_secs(older_than * 86400);
+    let (mut count, mut freed) = (0, 0);
+    for dir in dirs {
+        let stale = clean::find_stale(dir, min_age)
+            .map_err(|e| format!("Cannot search '{}': {}", dir.display(), e))?;
+        for file in stale {
+            let days = file.age.as_secs() / 86400;
+            let description = format!(
+                "{} ({}, {} days old)",
+                file.path.display(),
+                indicatif::HumanBytes(file.bytes),
+                days
+            );
+            if dry_run {
+                out.info(format!("Would delete {}", description));
+            } else {
+                fs::remove_file(&file.path)?;
+                control::remove(&paths::control_path(&file.path));
+                out.detail(format!("Deleted {}", description));
+            }
+            out.event(
+                "clean",
+                serde_json::json!({ "path": file.path, "bytes": file.bytes, "days": days, "deleted": !dry_run }),
+            );
+            count += 1;
+            freed += file.bytes;
+        }
+    }
+    let verb = if dry_run { "Would free" } else { "Freed" };
+    out.info(format!("{} {} in {} part file(s)", verb, indicatif::HumanBytes(freed), count));
+    Ok(())
+}
+
+fn run_preset_action(
+    action: &PresetAction,
+    path: &Path,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match action {
+        PresetAction::Add { name, flags } => {
+            if name.contains('.') {
+                return Err(format!("Preset names cannot contain '.': {}", name).into());
+            }
+            if flags.iter().any(|f| f == "--preset" || f.starts_with("--preset=")) {
+                return Err("A preset cannot include another preset".into());
+            }
+            // Catch typos now rather than on first use
+            let mut trial = vec!["gator".to_string()];
+            trial.extend(flags.iter().cloned());
+            trial.push("https://example.com/file".to_string());
+            if let Err(e) = Args::try_parse_from(&trial) {
+                let message = e.to_string();
+                let reason = message.lines().next().unwrap_or("").trim_start_matches("error: ");
+                return Err(format!("Invalid flags for preset '{}': {}", name, reason).into());
+            }
+
+            let value: toml_edit::Array = flags.iter().map(String::as_str).collect();
+            let content = read_config_file(path)?;
+            let updated = config::set_item(path, &content, &format!("presets.{}", name), value.into())?;
+            write_config_file(path, &updated)?;
+            out.info(format!("Saved preset '{}'", name));
+        }
+        PresetAction::List => {
+            let config = config::validate(path, &read_config_file(path)?)?;
+            if out.json() {
+                out.event("presets", serde_json::json!({ "presets": config.presets }));
+            } else {
+                for (name, flags) in &config.presets {
+                    println!("{}  {}", name, flags.join(" "));
+                }
+            }
+        }
+        PresetAction::Remove { name } => {
+            let content = read_config_file(path)?;
+            match config::remove_key(&content, &format!("presets.{}", name))? {
+                Some(updated) => write_config_file(path, &updated)?,
+                None => return Err(format!("Preset '{}' does not exist", name).into()),
+            }
+        }
+    }
+    Ok(())
+}
 
-    while let Some(chunk) = response.chunk().await? {
-        file.write_all(&chunk).await?;
-        let chunk_len = chunk.len() as u64;
-        bytes_downloaded.fetch_add(chunk_len, Ordering::Relaxed);
-        pb.inc(chunk_len);
+#[allow(clippy::too_many_arguments)]
+async fn self_update(
+    client: &Client,
+    registry: &Registry,
+    channel: update::Channel,
+    check: bool,
+    force: bool,
+    release_url: &str,
+    args: &Args,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current_version = env!("CARGO_PKG_VERSION");
+    let release = update::latest(client, release_url, channel).await?;
+    let available = release.version() != current_version;
+    out.event(
+        "update_check",
+        serde_json::json!({
+            "current": current_version,
+            "latest": release.version(),
+            "available": available,
+        }),
+    );
+    if !available && !force {
+        out.info(format!("gator {} is up to date", current_version));
+        return Ok(());
+    }
+    out.info(format!(
+        "Update available: {} -> {}",
+        current_version,
+        release.version()
+    ));
+    if check {
+        return Ok(());
     }
 
-    pb.finish_with_message("Download complete!");
+    let name = update::asset_name();
+    let binary = release.asset(&name).ok_or_else(|| {
+        format!(
+            "Release {} has no binary for this platform ({})",
+            release.tag_name, name
+        )
+    })?;
+    let checksum = release
+        .asset(&format!("{}.sha256", name))
+        .ok_or_else(|| format!("Release {} has no checksum for {}", release.tag_name, name))?;
+
+    let checksum_file = client
+        .get(&checksum.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let expected = update::parse_checksum(&checksum_file, &name)?;
+
+    let current = std::env::current_exe()?;
+    let staged = update::staging_path(&current);
+    // Never resume into a leftover from an interrupted update
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_file(paths::part_path_in(&staged, args.tmp_dir.as_deref()));
+    download_file(registry, &binary.browser_download_url, &staged, args, out).await?;
+
+    let (digest, _) = dedup::hash_file(&staged)?;
+    if digest != expected {
+        let _ = fs::remove_file(&staged);
+        return Err(format!(
+            "Checksum mismatch for {}; the current binary was left untouched",
+            name
+        )
+        .into());
+    }
+    update::replace_executable(&staged, &current)?;
+    out.info(format!("Updated gator to {}", release.version()));
+    out.event("updated", serde_json::json!({ "version": release.version() }));
     Ok(())
 }
 
-fn create_progress_bar(
-    quiet: bool,
-    msg: &str,
-    length: Option<u64>,
-    _num_chunks: Option<u64>,
-    _bytes_downloaded: Arc<AtomicU64>,
-) -> ProgressBar {
-    let bar = match quiet {
-        true => ProgressBar::hidden(),
-        false => match length {
-            Some(len) => ProgressBar::new(len),
-            None => ProgressBar::new_spinner(),
-        },
-    };
+// Scan `folder` for job files, run each one and move it into `done/` or
+// `failed/`. Runs until interrupted.
+async fn watch_folder(
+    registry: &Registry,
+    folder: &Path,
+    base_dir: &Path,
+    interval: std::time::Duration,
+    args: &Args,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    out.info(format!(
+        "Watching {} for job files (Ctrl+C to stop)...",
+        folder.display()
+    ));
 
-    bar.set_message(msg.to_string());
-
-    match length.is_some() {
-        true => {
-            bar.set_style(ProgressStyle::default_bar()
-                .template("{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {speed} {bytes}/{total_bytes} eta: {eta}")
-                .unwrap()
-                .with_key("speed", |state: &ProgressState, w: &mut dyn std::fmt::Write| {
-                    let bytes_per_sec = state.per_sec();
-                    let mb_per_sec = bytes_per_sec / (1024.0 * 1024.0);
-                    write!(w, "{:.2} MB/s", mb_per_sec).unwrap();
-                })
-                .progress_chars("=> "));
+    let mut waiting_for_quota = false;
+    loop {
+        // Jobs wait in the folder until the quota starts over the next day
+        if let Some(quota) = QUOTA.get().filter(|q| q.exhausted()) {
+            if !waiting_for_quota {
+                out.info(format!(
+                    "Download quota of {} reached for today; new jobs wait until midnight UTC",
+                    indicatif::HumanBytes(quota.limit())
+                ));
+            }
+            waiting_for_quota = true;
+            tokio::time::sleep(interval).await;
+            continue;
         }
-        false => {
-            bar.set_style(ProgressStyle::default_spinner());
+        waiting_for_quota = false;
+        let mut jobs: Vec<PathBuf> = fs::read_dir(folder)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && jobs::is_job_file(path))
+            // A file modified within the last scan may still be being written
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.elapsed().ok())
+                    .is_some_and(|age| age >= interval)
+            })
+            .collect();
+        jobs.sort();
+
+        for job in jobs {
+            out.info(format!("Running job {}...", job.display()));
+            let result = match jobs::parse(&job) {
+                Ok(items) => run_job(registry, items, base_dir, args, out).await,
+                Err(e) => Err(e),
+            };
+            let subdir = match result {
+                Ok(()) => jobs::DONE_DIR,
+                Err(e) => {
+                    out.error(format!("{}: {}", job.display(), e));
+                    jobs::FAILED_DIR
+                }
+            };
+            jobs::move_to(&job, subdir)?;
         }
-    };
 
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Download every item of a job, continuing past failures
+async fn run_job(
+    registry: &Registry,
+    items: Vec<jobs::JobItem>,
+    base_dir: &Path,
+    args: &Args,
+    out: Output,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let total = items.len();
+    let item_out = if args.jobs() > 1 { out.batch_item() } else { out };
+    let mut results = futures::stream::iter(items)
+        .map(|item| async move {
+            let result = match registry.resolve_url(&item.url) {
+                Ok(url) => {
+                    let file_name = item.file_name.unwrap_or_else(|| urls::file_name(&url));
+                    let dir = base_dir.join(&item.dir);
+                    match fs::create_dir_all(&dir) {
+                        Ok(()) => {
+                            download_file(registry, &url, &dir.join(file_name), args, item_out).await
+                        }
+                        Err(e) => Err(e.into()),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            (item.url, result)
+        })
+        .buffer_unordered(args.jobs());
+
+    let (mut failed, mut skipped) = (0, 0);
+    while let Some((url, result)) = results.next().await {
+        match result {
+            Ok(path) if !item_out.details() => out.info(format!("Saved {}", path.display())),
+            Ok(_) => {}
+            Err(e) if e.is::<quota::QuotaExceeded>() => {
+                out.detail(format!("Skipped {}", url));
+                out.event("skipped", serde_json::json!({ "url": url, "reason": "quota" }));
+                skipped += 1;
+            }
+            Err(e) => {
+                out.error(format!("{}: {}", url, e));
+                failed += 1;
+            }
+        }
+    }
+    if skipped > 0 {
+        return Err(format!("{} of {} downloads skipped: the download quota was reached", skipped, total).into());
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} downloads failed", failed, total).into());
+    }
+    Ok(())
+}
+
+async fn confirm_download(url: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let prompt = format!("Download {}? [y/N] ", url);
+    let answer = tokio::task::spawn_blocking(move || {
+        use std::io::Write;
+        eprint!("{}", prompt);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok::<_, std::io::Error>(answer)
+    })
+    .await??;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+
+// Files done out of files queued, above the bars of the files in flight
+fn create_batch_progress_bar(out: Output, files: u64) -> ProgressBar {
+    if !out.show_progress() || out.plain() {
+        return ProgressBar::hidden();
+    }
+    let bar = output::bars().add(ProgressBar::new(files));
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("Total [{elapsed_precise}] [{wide_bar:.green/blue}] {pos}/{len} files")
+            .unwrap()
+            .progress_chars("=> "),
+    );
     bar
 }
+
@@ -0,0 +1,90 @@
+// Whether the system says the connection is metered (billed or capped by the
+// byte), or None where that cannot be told. GATOR_METERED=yes/no overrides
+// the detection. This runs a helper program, so call it from a blocking task.
+pub fn is_metered() -> Option<bool> {
+    if let Ok(value) = std::env::var("GATOR_METERED") {
+        return match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => None,
+        };
+    }
+    detect()
+}
+
+// NetworkManager's overall Metered property, over D-Bus
+#[cfg(target_os = "linux")]
+fn detect() -> Option<bool> {
+    let output = std::process::Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    parse_networkmanager(&String::from_utf8_lossy(&output.stdout))
+}
+
+// The cost of the internet connection profile, through PowerShell since the
+// API is only exposed to WinRT
+#[cfg(windows)]
+fn detect() -> Option<bool> {
+    const SCRIPT: &str = "$p = [Windows.Networking.Connectivity.NetworkInformation,Windows.Networking.Connectivity,ContentType=WindowsRuntime]::GetInternetConnectionProfile(); \
+        if ($p) { $c = $p.GetConnectionCost(); \"$($c.NetworkCostType) $($c.Roaming) $($c.OverDataLimit)\" }";
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", SCRIPT])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    parse_windows(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn detect() -> Option<bool> {
+    None
+}
+
+// `busctl` prints the NMMetered value as e.g. "u 4": 1 and 3 are metered (for
+// certain and by guess), 2 and 4 are not, 0 is unknown
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_networkmanager(output: &str) -> Option<bool> {
+    match output.trim().strip_prefix("u ")?.trim() {
+        "1" | "3" => Some(true),
+        "2" | "4" => Some(false),
+        _ => None,
+    }
+}
+
+// "<NetworkCostType> <Roaming> <OverDataLimit>", e.g. "Unrestricted False False"
+#[cfg_attr(not(windows), allow(dead_code))]
+fn parse_windows(output: &str) -> Option<bool> {
+    let mut fields = output.split_whitespace();
+    let cost = fields.next()?;
+    let capped = fields.any(|f| f.eq_ignore_ascii_case("true"));
+    match cost {
+        "Fixed" | "Variable" => Some(true),
+        "Unrestricted" => Some(capped),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_answers_are_read() {
+        assert_eq!(parse_networkmanager("u 1\n"), Some(true));
+        assert_eq!(parse_networkmanager("u 3"), Some(true));
+        assert_eq!(parse_networkmanager("u 4"), Some(false));
+        assert_eq!(parse_networkmanager("u 0"), None);
+        assert_eq!(parse_windows("Variable False False\r\n"), Some(true));
+        assert_eq!(parse_windows("Unrestricted False False"), Some(false));
+        assert_eq!(parse_windows("Unrestricted True False"), Some(true));
+        assert_eq!(parse_windows("Unknown False False"), None);
+    }
+}
@@ -0,0 +1,71 @@
+// File extensions for common media types, for `--auto-extension`. Types that
+// say nothing about the content (application/octet-stream) have none.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/x-zip-compressed", "zip"),
+    ("application/gzip", "gz"),
+    ("application/x-gzip", "gz"),
+    ("application/x-tar", "tar"),
+    ("application/x-xz", "xz"),
+    ("application/x-bzip2", "bz2"),
+    ("application/zstd", "zst"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/vnd.rar", "rar"),
+    ("application/x-iso9660-image", "iso"),
+    ("application/vnd.debian.binary-package", "deb"),
+    ("application/x-rpm", "rpm"),
+    ("application/wasm", "wasm"),
+    ("application/json", "json"),
+    ("application/x-ndjson", "ndjson"),
+    ("application/xml", "xml"),
+    ("application/javascript", "js"),
+    ("application/rtf", "rtf"),
+    ("application/epub+zip", "epub"),
+    ("application/msword", "doc"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx"),
+    ("application/vnd.oasis.opendocument.text", "odt"),
+    ("application/vnd.apache.parquet", "parquet"),
+    ("text/html", "html"),
+    ("application/xhtml+xml", "html"),
+    ("text/plain", "txt"),
+    ("text/csv", "csv"),
+    ("text/tab-separated-values", "tsv"),
+    ("text/css", "css"),
+    ("text/javascript", "js"),
+    ("text/markdown", "md"),
+    ("text/xml", "xml"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/avif", "avif"),
+    ("image/svg+xml", "svg"),
+    ("image/tiff", "tif"),
+    ("image/x-icon", "ico"),
+    ("image/vnd.microsoft.icon", "ico"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "ogg"),
+    ("audio/wav", "wav"),
+    ("audio/flac", "flac"),
+    ("audio/mp4", "m4a"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("video/x-matroska", "mkv"),
+    ("video/quicktime", "mov"),
+    ("font/woff2", "woff2"),
+    ("font/woff", "woff"),
+];
+
+// The extension for a Content-Type header value, ignoring parameters such as charset
+pub fn extension_for(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim();
+    EXTENSIONS
+        .iter()
+        .find(|(m, _)| m.eq_ignore_ascii_case(mime))
+        .map(|(_, extension)| *extension)
+}
@@ -0,0 +1,251 @@
+use flate2::write::GzEncoder;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Bodies are written in pieces of this size, so `slow` and `reset_after` have
+// something to act between
+const CHUNK: usize = 64 * 1024;
+
+// Separates the parts of a multipart/byteranges body
+const BOUNDARY: &str = "gator-mock-boundary";
+
+// Ways the server misbehaves; the default is a well-behaved server with ranges
+#[derive(Debug, Clone, Default)]
+pub struct Behavior {
+    // Ignore Range headers and answer with the whole file, without Accept-Ranges
+    pub no_ranges: bool,
+    // Drop the connection after this many body bytes, on the first GET only
+    pub reset_after: Option<usize>,
+    // Pause before every chunk of a GET body
+    pub slow: Option<Duration>,
+    // Answer ranges with a Content-Range one byte past the bytes sent
+    pub bad_content_range: bool,
+    // When the client accepts gzip, compress every GET body on its own (so
+    // compressed ranges do not concatenate into the compressed file). HEAD
+    // describes the file as stored.
+    pub compress: bool,
+    // Answer every request with a 302 to this location, like a captive portal
+    pub redirect: Option<String>,
+    // Answer a request for several ranges with the whole file, as servers
+    // without multipart/byteranges support do
+    pub no_multi_range: bool,
+    // Answer the first N requests for a range with 503 Service Unavailable,
+    // like an overloaded server
+    pub unavailable: usize,
+    // Send no ETag, so a partial file cannot be told to be of this version
+    pub no_etag: bool,
+}
+
+// A local HTTP/1.1 server that serves the same generated content at every
+// path, for `gator self-test` and the tests of the download paths
+pub struct MockServer {
+    addr: SocketAddr,
+    data: Arc<Vec<u8>>,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    // Listen on a free loopback port until the runtime shuts down
+    pub async fn start(size: usize, behavior: Behavior) -> std::io::Result<Self> {
+        Self::serve(content(size), behavior).await
+    }
+
+    // Serve `data` instead of generated content, e.g. an archive
+    pub async fn serve(data: Vec<u8>, behavior: Behavior) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server = Self {
+            addr: listener.local_addr()?,
+            data: Arc::new(data),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        };
+        let state = Arc::new(State {
+            data: server.data.clone(),
+            requests: server.requests.clone(),
+            reset_pending: AtomicBool::new(behavior.reset_after.is_some()),
+            unavailable: AtomicUsize::new(behavior.unavailable),
+            behavior,
+        });
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(state.clone().serve(socket));
+            }
+        });
+        Ok(server)
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+
+    // What every path serves
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // The head (request line and headers) of every request so far, lowercased
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Bytes that do not repeat at any power-of-two period, so a chunk written at
+// the wrong offset cannot go unnoticed
+fn content(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i.wrapping_mul(7) % 251) as u8).collect()
+}
+
+struct State {
+    behavior: Behavior,
+    data: Arc<Vec<u8>>,
+    requests: Arc<Mutex<Vec<String>>>,
+    reset_pending: AtomicBool,
+    unavailable: AtomicUsize,
+}
+
+impl State {
+    // One request per connection, answered with `Connection: close`
+    async fn serve(self: Arc<Self>, mut socket: TcpStream) {
+        let mut head = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            match socket.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => head.extend_from_slice(&buf[..n]),
+            }
+        }
+        let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+        self.requests.lock().unwrap().push(head.clone());
+
+        let get = head.starts_with("get ");
+        let header = |name: &str| {
+            head.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::trim)
+        };
+        if let Some(location) = &self.behavior.redirect {
+            let response = format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                location
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+            return;
+        }
+        let overloaded = header("range").is_some()
+            && self.unavailable.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok();
+        if overloaded {
+            let response = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            let _ = socket.write_all(response.as_bytes()).await;
+            return;
+        }
+        let len = self.data.len();
+        let range = header("range")
+            .filter(|_| !self.behavior.no_ranges)
+            .and_then(|r| parse_range(r, len));
+
+        // Stands for the file's version, which never changes here
+        let mut headers = String::new();
+        if !self.behavior.no_etag {
+            headers.push_str(&format!("ETag: \"{:x}\"\r\n", len));
+        }
+        if !self.behavior.no_ranges {
+            headers.push_str("Accept-Ranges: bytes\r\n");
+        }
+        // Several ranges come back as multipart/byteranges, or as the whole file
+        if let Some(ranges) = header("range").filter(|r| r.contains(',') && !self.behavior.no_ranges) {
+            let ranges: Vec<(usize, usize)> = ranges
+                .trim_start_matches("bytes=")
+                .split(',')
+                .filter_map(|r| parse_range(&format!("bytes={}", r.trim()), len))
+                .collect();
+            if !self.behavior.no_multi_range && !ranges.is_empty() {
+                let mut body = Vec::new();
+                for (first, last) in ranges {
+                    body.extend_from_slice(
+                        format!("\r\n--{}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", BOUNDARY, first, last, len).as_bytes(),
+                    );
+                    body.extend_from_slice(&self.data[first..=last]);
+                }
+                body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+                let response = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Type: multipart/byteranges; boundary={}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+                    BOUNDARY,
+                    body.len(),
+                    headers
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+                return;
+            }
+        }
+        let (status, mut body) = match range {
+            Some((first, last)) => {
+                let shift = usize::from(self.behavior.bad_content_range);
+                headers.push_str(&format!(
+                    "Content-Range: bytes {}-{}/{}\r\n",
+                    first + shift,
+                    last + shift,
+                    len
+                ));
+                ("206 Partial Content", self.data[first..=last].to_vec())
+            }
+            None if header("range").is_some_and(|r| !r.contains(',')) && !self.behavior.no_ranges => {
+                let response = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    len
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                return;
+            }
+            None => ("200 OK", self.data.to_vec()),
+        };
+        let gzip = header("accept-encoding").is_some_and(|e| e.contains("gzip"));
+        if get && gzip && self.behavior.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&body).unwrap();
+            body = encoder.finish().unwrap();
+            headers.push_str("Content-Encoding: gzip\r\n");
+        }
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            status,
+            body.len(),
+            headers
+        );
+        if socket.write_all(response.as_bytes()).await.is_err() || !get {
+            return;
+        }
+
+        let mut limit = body.len();
+        if let Some(after) = self.behavior.reset_after {
+            if self.reset_pending.swap(false, Ordering::SeqCst) {
+                limit = limit.min(after);
+            }
+        }
+        for chunk in body[..limit].chunks(CHUNK) {
+            if let Some(delay) = self.behavior.slow {
+                tokio::time::sleep(delay).await;
+            }
+            if socket.write_all(chunk).await.is_err() {
+                return;
+            }
+        }
+        // Dropping the socket closes the connection, cutting a reset body short
+    }
+}
+
+// First and last byte of a single `bytes=first-[last]` range within `len`
+fn parse_range(range: &str, len: usize) -> Option<(usize, usize)> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let first: usize = first.trim().parse().ok()?;
+    let last = match last.trim() {
+        "" => len.checked_sub(1)?,
+        last => last.parse::<usize>().ok()?.min(len.checked_sub(1)?),
+    };
+    (first <= last).then_some((first, last))
+}
@@ -0,0 +1,81 @@
+use crate::config::{SmtpConfig, SmtpSecurity};
+use lettre::message::{header::ContentType, Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use std::error::Error;
+
+// What an unattended run reports when it finishes
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub command: String,
+    pub started: String,
+    pub finished: String,
+    pub elapsed_secs: f64,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+impl Summary {
+    fn subject(&self) -> String {
+        let outcome = if self.success { "finished" } else { "FAILED" };
+        format!("gator: {} ({})", outcome, self.command)
+    }
+
+    fn body(&self) -> String {
+        let mut body = format!(
+            "Command:  {}\nStarted:  {} UTC\nFinished: {} UTC\nElapsed:  {:.1}s\nResult:   {}\n",
+            self.command,
+            self.started,
+            self.finished,
+            self.elapsed_secs,
+            if self.success { "success" } else { "failure" }
+        );
+        if let Some(error) = &self.error {
+            body.push_str(&format!("\nError: {}\n", error));
+        }
+        body
+    }
+}
+
+// Mail `summary` to `to`, with the stats attached as JSON for scripts
+pub async fn send(
+    smtp: &SmtpConfig,
+    to: &str,
+    summary: &Summary,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let from: Mailbox = smtp
+        .from
+        .parse()
+        .map_err(|e| format!("Invalid smtp.from address '{}': {}", smtp.from, e))?;
+    let to: Mailbox = to
+        .parse()
+        .map_err(|e| format!("Invalid email address '{}': {}", to, e))?;
+
+    let stats = Attachment::new("gator-summary.json".to_string())
+        .body(serde_json::to_string_pretty(summary)?, ContentType::parse("application/json")?);
+    let message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(summary.subject())
+        .multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(summary.body()))
+                .singlepart(stats),
+        )?;
+
+    let mut transport = match smtp.security {
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+        SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?,
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+    };
+    if let Some(port) = smtp.port {
+        transport = transport.port(port);
+    }
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    transport.build().send(message).await?;
+    Ok(())
+}
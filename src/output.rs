@@ -0,0 +1,174 @@
+use indicatif::MultiProgress;
+use std::fmt::Display;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Every progress bar is drawn through this, so concurrent downloads share the
+// terminal and messages are printed above the bars instead of through them
+pub fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(MultiProgress::new)
+}
+
+// How much the CLI is allowed to print. Ordered so that `level >= Level::Quiet`
+// reads as "at least errors are shown".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Silent,
+    Quiet,
+    Normal,
+}
+
+// Single place that decides what reaches the terminal, shared by both download paths
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    level: Level,
+    progress: bool,
+    plain: bool,
+    json: bool,
+    // Per-file chatter; off for files downloaded side by side in a batch,
+    // where it would interleave into nonsense
+    details: bool,
+}
+
+impl Output {
+    pub fn new(level: Level, progress: bool, plain: bool, json: bool) -> Self {
+        Self {
+            level,
+            // A progress bar is never drawn below the normal level
+            progress: progress && level == Level::Normal,
+            plain,
+            json,
+            details: true,
+        }
+    }
+
+    // The output for one of several files downloading at once
+    pub fn batch_item(self) -> Self {
+        Self {
+            details: false,
+            ..self
+        }
+    }
+
+    pub fn details(&self) -> bool {
+        self.details
+    }
+
+    // Step-by-step messages about a single download
+    pub fn detail(&self, msg: impl Display) {
+        if self.details {
+            self.info(msg);
+        }
+    }
+
+    // Informational messages: only at the normal level. With --json, stdout is
+    // reserved for JSON so they move to stderr.
+    pub fn info(&self, msg: impl Display) {
+        if self.level >= Level::Normal {
+            bars().suspend(|| {
+                if self.json {
+                    eprintln!("{}", msg);
+                } else {
+                    println!("{}", msg);
+                }
+            });
+        }
+    }
+
+    // Errors: shown unless running silent, and always reported as an event
+    // with --json
+    pub fn error(&self, msg: impl Display) {
+        if self.json {
+            self.event("error", serde_json::json!({ "message": msg.to_string() }));
+        }
+        if self.level >= Level::Quiet {
+            bars().suspend(|| eprintln!("Error: {}", msg));
+        }
+    }
+
+    // A machine-readable record on stdout, one JSON object per line, tagged
+    // with `"event": kind`. Does nothing without --json.
+    pub fn event(&self, kind: &str, fields: serde_json::Value) {
+        if !self.json {
+            return;
+        }
+        let mut record = serde_json::Map::new();
+        record.insert("event".to_string(), kind.into());
+        if let serde_json::Value::Object(fields) = fields {
+            record.extend(fields);
+        }
+        bars().suspend(|| println!("{}", serde_json::Value::Object(record)));
+    }
+
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    pub fn show_progress(&self) -> bool {
+        self.progress
+    }
+
+    // Plain mode swaps the animated bar for periodic timestamped lines
+    pub fn plain(&self) -> bool {
+        self.plain
+    }
+}
+
+// Decide whether output should be plain and whether colors are allowed, and
+// apply the color choice globally. Returns true when plain output is wanted.
+pub fn configure_terminal(plain_flag: bool) -> bool {
+    let dumb = std::env::var("TERM").map(|t| t == "dumb").unwrap_or(false);
+    let plain = plain_flag || dumb;
+
+    // https://no-color.org and https://bixense.com/clicolors
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let clicolor_off = std::env::var("CLICOLOR").map(|v| v == "0").unwrap_or(false);
+    let clicolor_force = std::env::var("CLICOLOR_FORCE")
+        .map(|v| !v.is_empty() && v != "0")
+        .unwrap_or(false);
+
+    let colors = if plain || no_color {
+        false
+    } else if clicolor_force {
+        true
+    } else if clicolor_off {
+        false
+    } else {
+        console::colors_enabled_stderr()
+    };
+    console::set_colors_enabled(colors);
+    console::set_colors_enabled_stderr(colors);
+
+    plain
+}
+
+// UTC wall clock time as `YYYY-MM-DD HH:MM:SS` for plain progress lines
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,310 @@
+use crate::hooks::Hook;
+use crate::listing::Entry;
+use crate::protocol::{BoxError, ByteStream, Probe, ProtocolHandler, Trailers};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use reqwest::Url;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+// clap value parser for `--wait`: a pause like "2s", or a range like
+// "500ms-3s" to pick a random pause from before each request
+pub fn parse_wait(spec: &str) -> Result<(Duration, Duration), String> {
+    let (min, max) = match spec.split_once('-') {
+        Some((min, max)) => (crate::chaos::parse_duration(min)?, crate::chaos::parse_duration(max)?),
+        None => {
+            let wait = crate::chaos::parse_duration(spec)?;
+            (wait, wait)
+        }
+    };
+    if min > max {
+        return Err(format!("'{}' ends before it starts", spec));
+    }
+    Ok((min, max))
+}
+
+// clap value parser for `--max-requests-per-second`: a rate such as 5 or 0.5
+pub fn parse_rate(spec: &str) -> Result<f64, String> {
+    match spec.trim().parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(format!("'{}' is not a positive number of requests per second", spec)),
+    }
+}
+
+// Keeps a run polite to the hosts it mirrors, and lets it look like more than
+// one client: User-Agents to rotate among, a random pause between requests to
+// the same host, and caps on requests per minute and per second to each host.
+// Requests to different hosts do not wait for each other.
+pub struct Pacer {
+    user_agents: Vec<String>,
+    wait: Option<(Duration, Duration)>,
+    per_minute: Option<usize>,
+    // Requests a second, and how many may go out back to back after a quiet spell
+    bucket: Option<(f64, u32)>,
+    hosts: Mutex<HashMap<String, HostSlots>>,
+    next_agent: AtomicUsize,
+    random: RandomState,
+    draws: AtomicU64,
+}
+
+// When the next request to a host may go out, and when the last ones did
+#[derive(Default)]
+struct HostSlots {
+    next: Option<Instant>,
+    recent: VecDeque<Instant>,
+    // Requests the token bucket held at `filled`, the time of its last booking
+    tokens: f64,
+    filled: Option<Instant>,
+}
+
+impl HostSlots {
+    // The first time at or after `at` the token bucket has a request to spare,
+    // which it gives up. It starts full and refills at `rate` a second.
+    fn take_token(&mut self, at: Instant, rate: f64, burst: u32) -> Instant {
+        let burst = f64::from(burst);
+        let (mut at, mut tokens) = match self.filled {
+            Some(filled) => {
+                let at = at.max(filled);
+                (at, (self.tokens + (at - filled).as_secs_f64() * rate).min(burst))
+            }
+            None => (at, burst),
+        };
+        if tokens < 1.0 {
+            at += Duration::from_secs_f64((1.0 - tokens) / rate);
+            tokens = 1.0;
+        }
+        self.tokens = tokens - 1.0;
+        self.filled = Some(at);
+        at
+    }
+}
+
+impl Pacer {
+    pub fn new(user_agents: Vec<String>, wait: Option<(Duration, Duration)>, per_minute: Option<usize>) -> Self {
+        let random = RandomState::new();
+        // Each run starts at a different agent
+        let next_agent = AtomicUsize::new(random.hash_one(0u64) as usize);
+        Self {
+            user_agents,
+            wait,
+            per_minute,
+            bucket: None,
+            hosts: Mutex::new(HashMap::new()),
+            next_agent,
+            random,
+            draws: AtomicU64::new(1),
+        }
+    }
+
+    // At most `rate` requests a second to each host, of which up to `burst`
+    // may go out at once
+    pub fn with_request_rate(self, rate: f64, burst: u32) -> Self {
+        Self {
+            bucket: Some((rate, burst.max(1))),
+            ..self
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.user_agents.is_empty() && !self.paces()
+    }
+
+    fn paces(&self) -> bool {
+        self.wait.is_some() || self.per_minute.is_some() || self.bucket.is_some()
+    }
+
+    // A pause drawn from the --wait range
+    fn pause(&self) -> Duration {
+        let Some((min, max)) = self.wait else {
+            return Duration::ZERO;
+        };
+        let draw = self.random.hash_one(self.draws.fetch_add(1, Ordering::Relaxed)) % 10_000;
+        min + (max - min).mul_f64(draw as f64 / 10_000.0)
+    }
+
+    // Book the earliest time a request to `host` may go out at or after `now`.
+    // Booking under the lock makes concurrent workers queue behind each other
+    // instead of all waking at once.
+    fn reserve(&self, host: &str, now: Instant) -> Instant {
+        let mut hosts = self.hosts.lock().unwrap();
+        let slots = hosts.entry(host.to_string()).or_default();
+        let mut at = slots.next.map_or(now, |next| next.max(now));
+        if let Some(limit) = self.per_minute {
+            if slots.recent.len() >= limit {
+                at = at.max(slots.recent[slots.recent.len() - limit] + MINUTE);
+            }
+        }
+        if let Some((rate, burst)) = self.bucket {
+            at = slots.take_token(at, rate, burst);
+        }
+        if let Some(limit) = self.per_minute {
+            slots.recent.push_back(at);
+            while slots.recent.len() > limit {
+                slots.recent.pop_front();
+            }
+        }
+        slots.next = Some(at + self.pause());
+        at
+    }
+
+    // Wait for the turn of a request to `url`
+    pub async fn pace(&self, url: &str) {
+        if !self.paces() {
+            return;
+        }
+        // Inline data has no host to be polite to
+        let Some(host) = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_ascii_lowercase)) else {
+            return;
+        };
+        let at = self.reserve(&host, Instant::now());
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+impl Hook for Pacer {
+    fn request_headers(&self, _url: &str) -> Result<Vec<(String, String)>, BoxError> {
+        if self.user_agents.is_empty() {
+            return Ok(Vec::new());
+        }
+        let next = self.next_agent.fetch_add(1, Ordering::Relaxed) % self.user_agents.len();
+        Ok(vec![("User-Agent".to_string(), self.user_agents[next].clone())])
+    }
+}
+
+// Wraps a handler so each of its requests waits for its turn with the pacer
+pub struct PacedHandler {
+    inner: Arc<dyn ProtocolHandler>,
+    pacer: Arc<Pacer>,
+}
+
+impl PacedHandler {
+    pub fn wrap(pacer: &Arc<Pacer>, inner: Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler> {
+        Arc::new(Self {
+            inner,
+            pacer: pacer.clone(),
+        })
+    }
+}
+
+impl ProtocolHandler for PacedHandler {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Probe, BoxError>> {
+        Box::pin(async move {
+            self.pacer.pace(url).await;
+            self.inner.probe(url).await
+        })
+    }
+
+    fn open<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        self.open_with_trailers(url, start, end, Trailers::default())
+    }
+
+    fn open_with_trailers<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        trailers: Trailers,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(async move {
+            self.pacer.pace(url).await;
+            self.inner.open_with_trailers(url, start, end, trailers).await
+        })
+    }
+
+    fn open_prioritized<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        urgency: u8,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(async move {
+            self.pacer.pace(url).await;
+            self.inner.open_prioritized(url, start, end, urgency).await
+        })
+    }
+
+    fn fetch_ranges<'a>(
+        &'a self,
+        url: &'a str,
+        ranges: &'a [(u64, u64)],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, BoxError>> {
+        Box::pin(async move {
+            self.pacer.pace(url).await;
+            self.inner.fetch_ranges(url, ranges).await
+        })
+    }
+
+    fn list<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<Entry>, BoxError>> {
+        Box::pin(async move {
+            self.pacer.pace(url).await;
+            self.inner.list(url).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_are_parsed() {
+        assert_eq!(parse_wait("2s").unwrap(), (Duration::from_secs(2), Duration::from_secs(2)));
+        assert_eq!(parse_wait("500ms-3s").unwrap(), (Duration::from_millis(500), Duration::from_secs(3)));
+        assert!(parse_wait("3s-1s").is_err());
+        assert!(parse_wait("soon").is_err());
+    }
+
+    #[test]
+    fn requests_to_a_host_are_spaced_and_capped() {
+        let now = Instant::now();
+        let pacer = Pacer::new(Vec::new(), Some((Duration::from_secs(1), Duration::from_secs(2))), Some(3));
+        let first = pacer.reserve("a.example", now);
+        let second = pacer.reserve("a.example", now);
+        let third = pacer.reserve("a.example", now);
+        assert_eq!(first, now);
+        assert!(second >= first + Duration::from_secs(1) && second <= first + Duration::from_secs(2));
+        assert!(third >= second + Duration::from_secs(1));
+        // The fourth would be the fourth within a minute of the first
+        assert_eq!(pacer.reserve("a.example", now), first + MINUTE);
+        assert_eq!(pacer.reserve("b.example", now), now);
+    }
+
+    #[test]
+    fn request_rates_allow_a_burst_then_space_requests_out() {
+        let now = Instant::now();
+        let pacer = Pacer::new(Vec::new(), None, None).with_request_rate(2.0, 3);
+        let booked: Vec<Duration> = (0..5).map(|_| pacer.reserve("a.example", now) - now).collect();
+        let ms = Duration::from_millis;
+        assert_eq!(booked, [ms(0), ms(0), ms(0), ms(500), ms(1000)]);
+        // A quiet spell refills the bucket, up to the burst
+        let later = now + Duration::from_secs(10);
+        let booked: Vec<Duration> = (0..4).map(|_| pacer.reserve("a.example", later) - later).collect();
+        assert_eq!(booked, [ms(0), ms(0), ms(0), ms(500)]);
+        assert_eq!(pacer.reserve("b.example", now), now);
+        assert!(parse_rate("0.5").is_ok() && parse_rate("0").is_err() && parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn user_agents_rotate() {
+        let agents = vec!["A/1".to_string(), "B/2".to_string()];
+        let pacer = Pacer::new(agents, None, None);
+        let agent = |pacer: &Pacer| pacer.request_headers("https://example.com/").unwrap()[0].1.clone();
+        let first = agent(&pacer);
+        let second = agent(&pacer);
+        assert_ne!(first, second);
+        assert_eq!(agent(&pacer), first);
+    }
+}
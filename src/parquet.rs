@@ -0,0 +1,546 @@
+use crate::protocol::BoxError;
+use crate::reader::ParallelHttpReader;
+use serde::Serialize;
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const MAGIC: &[u8; 4] = b"PAR1";
+
+// What the footer of a Parquet file says about it
+#[derive(Debug, Clone, Serialize)]
+pub struct Schema {
+    pub num_rows: i64,
+    pub row_groups: usize,
+    pub created_by: Option<String>,
+    pub columns: Vec<Column>,
+}
+
+// A leaf of the schema; nested fields are named by their path, e.g. "address.city"
+#[derive(Debug, Clone, Serialize)]
+pub struct Column {
+    pub name: String,
+    // e.g. "INT64", "BYTE_ARRAY"
+    pub physical_type: String,
+    // e.g. "STRING", "TIMESTAMP(MICROS)", "DECIMAL(10,2)"
+    pub logical_type: Option<String>,
+    // "required", "optional" or "repeated"
+    pub repetition: String,
+}
+
+// Read the schema from the footer: the metadata length and magic in the last
+// 8 bytes, then the metadata before them
+pub async fn read_schema(reader: &mut ParallelHttpReader) -> Result<Schema, BoxError> {
+    let len = reader.len();
+    if len < 12 {
+        return Err("not a Parquet file (too short)".into());
+    }
+    let mut tail = [0u8; 8];
+    reader.seek(SeekFrom::Start(len - 8)).await?;
+    reader.read_exact(&mut tail).await?;
+    if &tail[4..] != MAGIC {
+        return Err("not a Parquet file (no PAR1 footer)".into());
+    }
+    let metadata_len = u64::from(u32::from_le_bytes(tail[..4].try_into().unwrap()));
+    if metadata_len > len - 12 {
+        return Err("corrupt Parquet footer (metadata longer than the file)".into());
+    }
+    let mut metadata = vec![0u8; metadata_len as usize];
+    reader.seek(SeekFrom::Start(len - 8 - metadata_len)).await?;
+    reader.read_exact(&mut metadata).await?;
+    parse_metadata(&metadata)
+}
+
+// FileMetaData: 2 schema, 3 num_rows, 4 row_groups, 6 created_by
+fn parse_metadata(data: &[u8]) -> Result<Schema, BoxError> {
+    let mut input = Compact { data, pos: 0 };
+    let mut elements = Vec::new();
+    let (mut num_rows, mut row_groups, mut created_by) = (0, 0, None);
+    input.read_struct(|input, id, kind| {
+        match (id, kind) {
+            (2, LIST) => {
+                let (count, _) = input.list_header()?;
+                for _ in 0..count {
+                    elements.push(input.schema_element()?);
+                }
+            }
+            (3, I64) => num_rows = input.varint_signed()?,
+            (4, LIST) => {
+                let (count, element) = input.list_header()?;
+                row_groups = count;
+                for _ in 0..count {
+                    input.skip(element)?;
+                }
+            }
+            (6, BINARY) => created_by = Some(input.string()?),
+            _ => input.skip(kind)?,
+        }
+        Ok(())
+    })?;
+
+    // The elements are the schema tree in depth-first order; the first is the root
+    let mut columns = Vec::new();
+    let mut pos = 1;
+    let root_children = elements.first().ok_or("corrupt Parquet schema (empty)")?.num_children;
+    for _ in 0..root_children {
+        flatten(&elements, &mut pos, "", &mut columns)?;
+    }
+    Ok(Schema {
+        num_rows,
+        row_groups,
+        created_by,
+        columns,
+    })
+}
+
+fn flatten(elements: &[Element], pos: &mut usize, parent: &str, columns: &mut Vec<Column>) -> Result<(), BoxError> {
+    let element = elements.get(*pos).ok_or("corrupt Parquet schema (missing children)")?;
+    *pos += 1;
+    let name = match parent {
+        "" => element.name.clone(),
+        _ => format!("{}.{}", parent, element.name),
+    };
+    if element.num_children > 0 {
+        for _ in 0..element.num_children {
+            flatten(elements, pos, &name, columns)?;
+        }
+        return Ok(());
+    }
+    columns.push(Column {
+        name,
+        physical_type: element.physical_type.clone().unwrap_or_default(),
+        logical_type: element.logical_type.clone(),
+        repetition: element.repetition.clone(),
+    });
+    Ok(())
+}
+
+// A SchemaElement, as much of it as is shown
+struct Element {
+    name: String,
+    physical_type: Option<String>,
+    logical_type: Option<String>,
+    repetition: String,
+    num_children: i64,
+}
+
+const PHYSICAL_TYPES: &[&str] = &[
+    "BOOLEAN",
+    "INT32",
+    "INT64",
+    "INT96",
+    "FLOAT",
+    "DOUBLE",
+    "BYTE_ARRAY",
+    "FIXED_LEN_BYTE_ARRAY",
+];
+
+// The older ConvertedType annotations, by value
+const CONVERTED_TYPES: &[&str] = &[
+    "UTF8",
+    "MAP",
+    "MAP_KEY_VALUE",
+    "LIST",
+    "ENUM",
+    "DECIMAL",
+    "DATE",
+    "TIME_MILLIS",
+    "TIME_MICROS",
+    "TIMESTAMP_MILLIS",
+    "TIMESTAMP_MICROS",
+    "UINT_8",
+    "UINT_16",
+    "UINT_32",
+    "UINT_64",
+    "INT_8",
+    "INT_16",
+    "INT_32",
+    "INT_64",
+    "JSON",
+    "BSON",
+    "INTERVAL",
+];
+
+// Thrift compact protocol field types
+const TRUE: u8 = 1;
+const FALSE: u8 = 2;
+const BYTE: u8 = 3;
+const I16: u8 = 4;
+const I32: u8 = 5;
+const I64: u8 = 6;
+const DOUBLE: u8 = 7;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const SET: u8 = 10;
+const MAP: u8 = 11;
+const STRUCT: u8 = 12;
+
+// A reader for the Thrift compact protocol, which Parquet metadata is written in
+struct Compact<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Compact<'_> {
+    fn byte(&mut self) -> Result<u8, BoxError> {
+        let byte = *self.data.get(self.pos).ok_or("corrupt Parquet metadata (truncated)")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, BoxError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("corrupt Parquet metadata (varint too long)".into())
+    }
+
+    // Zigzag encoded
+    fn varint_signed(&mut self) -> Result<i64, BoxError> {
+        let value = self.varint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn bytes(&mut self) -> Result<&[u8], BoxError> {
+        let len = self.varint()? as usize;
+        let end = self.pos.checked_add(len).filter(|&e| e <= self.data.len());
+        let end = end.ok_or("corrupt Parquet metadata (truncated)")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, BoxError> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    // Element count and type
+    fn list_header(&mut self) -> Result<(usize, u8), BoxError> {
+        let header = self.byte()?;
+        let count = match header >> 4 {
+            15 => self.varint()? as usize,
+            count => usize::from(count),
+        };
+        Ok((count, header & 0x0f))
+    }
+
+    // Call `field` with the id and type of each field; it must consume the value
+    fn read_struct(
+        &mut self,
+        mut field: impl FnMut(&mut Self, i16, u8) -> Result<(), BoxError>,
+    ) -> Result<(), BoxError> {
+        let mut id = 0i16;
+        loop {
+            let header = self.byte()?;
+            if header == 0 {
+                return Ok(());
+            }
+            id = match header >> 4 {
+                0 => self.varint_signed()? as i16,
+                delta => id + i16::from(delta),
+            };
+            field(self, id, header & 0x0f)?;
+        }
+    }
+
+    fn skip(&mut self, kind: u8) -> Result<(), BoxError> {
+        match kind {
+            // Booleans are in the field header, except in lists
+            TRUE | FALSE => {}
+            BYTE => self.pos += 1,
+            I16 | I32 | I64 => {
+                self.varint()?;
+            }
+            DOUBLE => self.pos += 8,
+            BINARY => {
+                self.bytes()?;
+            }
+            LIST | SET => {
+                let (count, element) = self.list_header()?;
+                for _ in 0..count {
+                    match element {
+                        TRUE | FALSE => self.pos += 1,
+                        _ => self.skip(element)?,
+                    }
+                }
+            }
+            MAP => {
+                let count = self.varint()?;
+                if count > 0 {
+                    let types = self.byte()?;
+                    for _ in 0..count {
+                        self.skip(types >> 4)?;
+                        self.skip(types & 0x0f)?;
+                    }
+                }
+            }
+            STRUCT => self.read_struct(|input, _, kind| input.skip(kind))?,
+            _ => return Err(format!("corrupt Parquet metadata (unknown type {})", kind).into()),
+        }
+        if self.pos > self.data.len() {
+            return Err("corrupt Parquet metadata (truncated)".into());
+        }
+        Ok(())
+    }
+
+    // SchemaElement: 1 type, 3 repetition_type, 4 name, 5 num_children,
+    // 6 converted_type, 7 scale, 8 precision, 10 logicalType
+    fn schema_element(&mut self) -> Result<Element, BoxError> {
+        let mut element = Element {
+            name: String::new(),
+            physical_type: None,
+            logical_type: None,
+            repetition: "required".to_string(),
+            num_children: 0,
+        };
+        let (mut converted, mut logical) = (None, None);
+        let (mut scale, mut precision) = (0, 0);
+        self.read_struct(|input, id, kind| {
+            match (id, kind) {
+                (1, I32) => element.physical_type = Some(name_of(PHYSICAL_TYPES, input.varint_signed()?)),
+                (3, I32) => {
+                    element.repetition = match input.varint_signed()? {
+                        1 => "optional",
+                        2 => "repeated",
+                        _ => "required",
+                    }
+                    .to_string()
+                }
+                (4, BINARY) => element.name = input.string()?,
+                (5, I32) => element.num_children = input.varint_signed()?,
+                (6, I32) => converted = Some(input.varint_signed()?),
+                (7, I32) => scale = input.varint_signed()?,
+                (8, I32) => precision = input.varint_signed()?,
+                (10, STRUCT) => logical = input.logical_type()?,
+                _ => input.skip(kind)?,
+            }
+            Ok(())
+        })?;
+        element.logical_type = logical.or_else(|| {
+            converted.map(|c| match name_of(CONVERTED_TYPES, c).as_str() {
+                "DECIMAL" => format!("DECIMAL({},{})", precision, scale),
+                name => name.to_string(),
+            })
+        });
+        Ok(element)
+    }
+
+    // LogicalType is a union: the id of its one field says which type it is
+    fn logical_type(&mut self) -> Result<Option<String>, BoxError> {
+        let mut name = None;
+        self.read_struct(|input, id, kind| {
+            let mut fields = Vec::new();
+            if kind == STRUCT {
+                input.read_struct(|input, id, kind| {
+                    match (id, kind) {
+                        (_, TRUE) => fields.push((id, 1)),
+                        (_, FALSE) => fields.push((id, 0)),
+                        (_, BYTE) => fields.push((id, i64::from(input.byte()? as i8))),
+                        (_, I32) => fields.push((id, input.varint_signed()?)),
+                        // TIME and TIMESTAMP units are themselves unions
+                        (2, STRUCT) => {
+                            let mut unit = 0;
+                            input.read_struct(|input, id, kind| {
+                                unit = i64::from(id);
+                                input.skip(kind)
+                            })?;
+                            fields.push((id, unit));
+                        }
+                        _ => input.skip(kind)?,
+                    }
+                    Ok(())
+                })?;
+            } else {
+                input.skip(kind)?;
+            }
+            let field = |n: i16| fields.iter().find(|(id, _)| *id == n).map(|(_, v)| *v).unwrap_or(0);
+            let unit = || ["", "MILLIS", "MICROS", "NANOS"].get(field(2) as usize).copied().unwrap_or("");
+            name = Some(match id {
+                1 => "STRING".to_string(),
+                2 => "MAP".to_string(),
+                3 => "LIST".to_string(),
+                4 => "ENUM".to_string(),
+                5 => format!("DECIMAL({},{})", field(2), field(1)),
+                6 => "DATE".to_string(),
+                7 => format!("TIME({})", unit()),
+                8 => format!("TIMESTAMP({})", unit()),
+                10 => format!("{}INT{}", if field(2) == 1 { "" } else { "U" }, field(1)),
+                11 => "NULL".to_string(),
+                12 => "JSON".to_string(),
+                13 => "BSON".to_string(),
+                14 => "UUID".to_string(),
+                15 => "FLOAT16".to_string(),
+                other => format!("UNKNOWN({})", other),
+            });
+            Ok(())
+        })?;
+        Ok(name)
+    }
+}
+
+fn name_of(names: &[&str], value: i64) -> String {
+    usize::try_from(value)
+        .ok()
+        .and_then(|i| names.get(i))
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| format!("UNKNOWN({})", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use crate::protocol::Registry;
+    use reqwest::Client;
+    use std::sync::Arc;
+
+    // A minimal compact protocol writer, for footers to read back
+    #[derive(Default)]
+    struct Writer {
+        out: Vec<u8>,
+        last_id: Vec<i16>,
+    }
+
+    impl Writer {
+        fn varint(&mut self, mut value: u64) {
+            while value >= 0x80 {
+                self.out.push(value as u8 | 0x80);
+                value >>= 7;
+            }
+            self.out.push(value as u8);
+        }
+
+        fn field(&mut self, id: i16, kind: u8) {
+            let last = self.last_id.last_mut().unwrap();
+            self.out.push((((id - *last) as u8) << 4) | kind);
+            *last = id;
+        }
+
+        fn int(&mut self, id: i16, kind: u8, value: i64) {
+            self.field(id, kind);
+            self.varint(((value << 1) ^ (value >> 63)) as u64);
+        }
+
+        fn string(&mut self, id: i16, value: &str) {
+            self.field(id, BINARY);
+            self.varint(value.len() as u64);
+            self.out.extend_from_slice(value.as_bytes());
+        }
+
+        fn begin(&mut self) {
+            self.last_id.push(0);
+        }
+
+        fn end(&mut self) {
+            self.out.push(0);
+            self.last_id.pop();
+        }
+    }
+
+    // (name, physical type, repetition, converted type, children)
+    fn element(w: &mut Writer, name: &str, physical: Option<i64>, repetition: i64, converted: Option<i64>, children: i64) {
+        w.begin();
+        if let Some(physical) = physical {
+            w.int(1, I32, physical);
+        }
+        w.int(3, I32, repetition);
+        w.string(4, name);
+        if children > 0 {
+            w.int(5, I32, children);
+        }
+        if let Some(converted) = converted {
+            w.int(6, I32, converted);
+        }
+        w.end();
+    }
+
+    fn build_parquet() -> Vec<u8> {
+        let mut w = Writer::default();
+        w.begin();
+        w.int(1, I32, 2);
+        w.field(2, LIST);
+        w.out.push((6 << 4) | STRUCT);
+        element(&mut w, "schema", None, 0, None, 4);
+        element(&mut w, "id", Some(2), 0, None, 0);
+        element(&mut w, "name", Some(6), 1, Some(0), 0);
+        element(&mut w, "address", None, 1, None, 1);
+        element(&mut w, "city", Some(6), 1, Some(0), 0);
+        // A timestamp with a logical type: TIMESTAMP { isAdjustedToUTC, unit: MICROS }
+        w.begin();
+        w.int(1, I32, 2);
+        w.int(3, I32, 1);
+        w.string(4, "seen");
+        w.field(10, STRUCT);
+        w.begin();
+        w.field(8, STRUCT);
+        w.begin();
+        w.field(1, TRUE);
+        w.field(2, STRUCT);
+        w.begin();
+        w.field(2, STRUCT);
+        w.begin();
+        w.end();
+        w.end();
+        w.end();
+        w.end();
+        w.end();
+        w.int(3, I64, 1_000_000);
+        // Two row groups, whose contents are skipped
+        w.field(4, LIST);
+        w.out.push((2 << 4) | STRUCT);
+        for _ in 0..2 {
+            w.begin();
+            w.int(2, I64, 12345);
+            w.end();
+        }
+        w.string(6, "gator test");
+        w.end();
+
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&[0; 5000]);
+        let metadata_len = w.out.len() as u32;
+        file.extend_from_slice(&w.out);
+        file.extend_from_slice(&metadata_len.to_le_bytes());
+        file.extend_from_slice(MAGIC);
+        file
+    }
+
+    #[tokio::test]
+    async fn the_schema_is_read_from_the_footer() {
+        let server = MockServer::serve(build_parquet(), Behavior::default()).await.unwrap();
+        let client = Client::builder().no_proxy().build().unwrap();
+        let registry = Registry::with_defaults(Arc::new(client), Vec::new());
+        let mut reader = ParallelHttpReader::open(&registry, &server.url("data.parquet")).await.unwrap();
+
+        let schema = read_schema(&mut reader).await.unwrap();
+        assert_eq!((schema.num_rows, schema.row_groups), (1_000_000, 2));
+        assert_eq!(schema.created_by.as_deref(), Some("gator test"));
+        let columns: Vec<(&str, &str, Option<&str>, &str)> = schema
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.physical_type.as_str(), c.logical_type.as_deref(), c.repetition.as_str()))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                ("id", "INT64", None, "required"),
+                ("name", "BYTE_ARRAY", Some("UTF8"), "optional"),
+                ("address.city", "BYTE_ARRAY", Some("UTF8"), "optional"),
+                ("seen", "INT64", Some("TIMESTAMP(MICROS)"), "optional"),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_metadata_is_an_error() {
+        let file = build_parquet();
+        let metadata = &file[4 + 5000..file.len() - 8];
+        assert!(parse_metadata(metadata).is_ok());
+        let error = parse_metadata(&metadata[..metadata.len() / 2]).err().unwrap();
+        assert!(error.to_string().contains("corrupt"), "{}", error);
+    }
+}
@@ -0,0 +1,350 @@
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+// Windows refuses these as file names, with or without an extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Paths at or beyond this length need the `\\?\` prefix on Windows
+const MAX_PATH: usize = 260;
+
+const FALLBACK_NAME: &str = "downloaded_file";
+
+// Whether names differing only in case are one file on this platform's usual
+// file systems (NTFS, APFS)
+pub const CASE_INSENSITIVE: bool = cfg!(any(windows, target_os = "macos"));
+
+// Turn a name taken from a URL into something safe to create on this platform
+pub fn sanitize_file_name(name: &str) -> String {
+    if cfg!(windows) {
+        sanitize_windows(name)
+    } else {
+        sanitize_unix(name)
+    }
+}
+
+fn sanitize_unix(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if c == '/' || c == '\0' { '_' } else { c })
+        .collect();
+
+    match cleaned.as_str() {
+        "" | "." | ".." => FALLBACK_NAME.to_string(),
+        _ => cleaned,
+    }
+}
+
+pub fn sanitize_windows(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Explorer and the Win32 API silently drop trailing dots and spaces
+    let trimmed = cleaned.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        return FALLBACK_NAME.to_string();
+    }
+
+    // `CON`, `con.txt` and `Con .tar.gz` all refer to the console device
+    let stem = trimmed.split('.').next().unwrap_or(trimmed).trim_end();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        return format!("_{}", trimmed);
+    }
+
+    trimmed.to_string()
+}
+
+// In-progress downloads live next to their destination so the final rename
+// stays on one filesystem and is atomic
+pub fn part_path(final_path: &Path) -> PathBuf {
+    let mut name = final_path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| FALLBACK_NAME.into());
+    name.push(".part");
+    final_path.with_file_name(name)
+}
+
+// With a temporary directory (`--tmp-dir`), the part file lives there instead.
+// Files of the same name bound for different directories must not share a
+// part file, so the name carries a hash of the destination.
+pub fn part_path_in(final_path: &Path, tmp_dir: Option<&Path>) -> PathBuf {
+    let Some(tmp_dir) = tmp_dir else {
+        return part_path(final_path);
+    };
+    let absolute = std::path::absolute(final_path).unwrap_or_else(|_| final_path.to_path_buf());
+    let digest = Sha256::digest(absolute.as_os_str().as_encoded_bytes());
+    let mut name = final_path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| FALLBACK_NAME.into());
+    name.push(format!(".{:016x}.part", u64::from_be_bytes(digest[..8].try_into().unwrap())));
+    tmp_dir.join(name)
+}
+
+// The control file of a segmented download, next to its part file:
+// `file.iso.part` has `file.iso.gator-ctl`, a suffix of its own so it is
+// never mistaken for a `.gator` job file
+pub fn control_path(part_path: &Path) -> PathBuf {
+    let name = part_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let stem = name.strip_suffix(".part").unwrap_or(&name);
+    part_path.with_file_name(format!("{}.gator-ctl", stem))
+}
+
+// `name` + `.ext`, keeping any dots already in the name
+pub fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| FALLBACK_NAME.into());
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+// `--write-metadata` sidecar: `<file>.meta.json` next to the download
+pub fn metadata_path(final_path: &Path) -> PathBuf {
+    let mut name = final_path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| FALLBACK_NAME.into());
+    name.push(".meta.json");
+    final_path.with_file_name(name)
+}
+
+// Create (or truncate) a part file that only the current user can read until
+// it is finished, regardless of the process umask
+pub fn create_private(path: &Path) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // An existing part file keeps its old mode on open, so tighten it explicitly
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    options.open(path)
+}
+
+// Give a finished part file the permissions a freshly created file would get
+// and atomically move it over the destination
+pub fn finalize(part: &Path, final_path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = 0o666 & !current_umask();
+        std::fs::set_permissions(part, std::fs::Permissions::from_mode(mode))?;
+    }
+    match std::fs::rename(part, final_path) {
+        // A part file in a temporary directory on another filesystem is copied
+        // next to the destination first, so the file still appears atomically
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let staged = part_path(final_path);
+            std::fs::copy(part, &staged)?;
+            std::fs::rename(&staged, final_path)?;
+            std::fs::remove_file(part)
+        }
+        result => result,
+    }
+}
+
+// Rename, or copy and delete when the two are on different filesystems
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+#[cfg(unix)]
+static UMASK: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+
+// Record the process umask for `finalize`. Call it before any other thread
+// starts: outside Linux, reading the umask means setting it and restoring it,
+// which would race with files other threads create meanwhile.
+pub fn save_umask() {
+    #[cfg(unix)]
+    UMASK.get_or_init(|| {
+        proc_umask().unwrap_or_else(|| {
+            // SAFETY: umask cannot fail; the original value is restored
+            // immediately, and no other thread is running yet
+            unsafe {
+                let umask = libc::umask(0o022);
+                libc::umask(umask);
+                umask as u32
+            }
+        })
+    });
+}
+
+// The umask saved at startup, else as Linux reports it without changing it,
+// else the usual 022
+#[cfg(unix)]
+fn current_umask() -> u32 {
+    UMASK.get().copied().or_else(proc_umask).unwrap_or(0o022)
+}
+
+#[cfg(unix)]
+fn proc_umask() -> Option<u32> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .and_then(|v| u32::from_str_radix(v.trim(), 8).ok())
+}
+
+// Prefix long absolute paths with `\\?\` on Windows so they bypass MAX_PATH.
+// Elsewhere the path is returned unchanged.
+pub fn long_path(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        // A verbatim path is used as is, so `.` and `..` are resolved first
+        let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        windows_long_path(&absolute.to_string_lossy())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| path.to_path_buf())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+// Returns the `\\?\` form of an absolute Windows path when it is too long, or
+// None when the path is short enough or already prefixed. The limit counts
+// UTF-16 units, as Windows does.
+pub fn windows_long_path(path: &str) -> Option<String> {
+    if path.encode_utf16().count() < MAX_PATH || path.starts_with(r"\\?\") {
+        return None;
+    }
+
+    // The verbatim form does no normalization, so separators must be backslashes
+    let path = path.replace('/', "\\");
+    match path.strip_prefix(r"\\") {
+        Some(unc) => Some(format!(r"\\?\UNC\{}", unc)),
+        None => Some(format!(r"\\?\{}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_files_in_a_temporary_directory_are_unique_per_destination() {
+        let tmp = Path::new("/scratch");
+        let a = part_path_in(Path::new("/data/a/file.bin"), Some(tmp));
+        let b = part_path_in(Path::new("/data/b/file.bin"), Some(tmp));
+        assert_ne!(a, b);
+        assert_eq!(a.parent(), Some(tmp));
+        assert!(a.file_name().unwrap().to_string_lossy().starts_with("file.bin."));
+        assert_eq!(a, part_path_in(Path::new("/data/a/file.bin"), Some(tmp)));
+        assert_eq!(part_path_in(Path::new("/data/a/file.bin"), None), Path::new("/data/a/file.bin.part"));
+    }
+
+    #[test]
+    fn unix_names_only_lose_separators() {
+        assert_eq!(sanitize_unix("file.zip"), "file.zip");
+        assert_eq!(sanitize_unix("a:b*c?.txt"), "a:b*c?.txt");
+        assert_eq!(sanitize_unix(""), FALLBACK_NAME);
+        assert_eq!(sanitize_unix(".."), FALLBACK_NAME);
+    }
+
+    #[test]
+    fn windows_invalid_characters_are_replaced() {
+        assert_eq!(sanitize_windows("a:b*c?.txt"), "a_b_c_.txt");
+        assert_eq!(sanitize_windows("report<1>|\"final\".pdf"), "report_1___final_.pdf");
+        assert_eq!(sanitize_windows("tab\there"), "tab_here");
+    }
+
+    #[test]
+    fn windows_trailing_dots_and_spaces_are_trimmed() {
+        assert_eq!(sanitize_windows("name. . "), "name");
+        assert_eq!(sanitize_windows("archive.tar.gz."), "archive.tar.gz");
+        assert_eq!(sanitize_windows(" . "), FALLBACK_NAME);
+    }
+
+    #[test]
+    fn windows_reserved_names_are_escaped() {
+        assert_eq!(sanitize_windows("CON"), "_CON");
+        assert_eq!(sanitize_windows("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_windows("Com1.tar.gz"), "_Com1.tar.gz");
+        assert_eq!(sanitize_windows("lpt9 .log"), "_lpt9 .log");
+        assert_eq!(sanitize_windows("console.txt"), "console.txt");
+        assert_eq!(sanitize_windows("COM10"), "COM10");
+    }
+
+    #[test]
+    fn short_paths_are_left_alone() {
+        assert_eq!(windows_long_path(r"C:\Downloads\file.zip"), None);
+    }
+
+    #[test]
+    fn long_drive_paths_get_verbatim_prefix() {
+        let long = format!(r"C:\data\{}\file.bin", "x".repeat(300));
+        let prefixed = windows_long_path(&long).unwrap();
+        assert!(prefixed.starts_with(r"\\?\C:\data\"));
+        assert!(prefixed.ends_with(r"\file.bin"));
+    }
+
+    #[test]
+    fn long_unc_paths_use_unc_prefix() {
+        let long = format!(r"\\server\share\{}", "y".repeat(300));
+        let prefixed = windows_long_path(&long).unwrap();
+        assert!(prefixed.starts_with(r"\\?\UNC\server\share\"));
+    }
+
+    #[test]
+    fn forward_slashes_are_converted_in_verbatim_paths() {
+        let long = format!("C:/data/{}/file.bin", "z".repeat(300));
+        let prefixed = windows_long_path(&long).unwrap();
+        assert!(!prefixed.contains('/'));
+    }
+
+    #[test]
+    fn the_limit_counts_utf16_units_not_bytes() {
+        // 408 bytes of UTF-8, but 208 UTF-16 units
+        let accented = format!(r"C:\data\{}", "é".repeat(200));
+        assert_eq!(windows_long_path(&accented), None);
+        let long = format!(r"C:\data\{}", "é".repeat(260));
+        assert!(windows_long_path(&long).is_some());
+    }
+
+    #[test]
+    fn already_prefixed_paths_are_unchanged() {
+        let long = format!(r"\\?\C:\{}", "w".repeat(300));
+        assert_eq!(windows_long_path(&long), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finished_files_get_the_mode_the_umask_allows() {
+        use std::os::unix::fs::PermissionsExt;
+        save_umask();
+        if cfg!(target_os = "linux") {
+            assert_eq!(Some(current_umask()), proc_umask());
+        }
+
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-umask", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part = dir.join("file.bin.part");
+        drop(create_private(&part).unwrap());
+        assert_eq!(std::fs::metadata(&part).unwrap().permissions().mode() & 0o777, 0o600);
+        finalize(&part, &dir.join("file.bin")).unwrap();
+        let mode = std::fs::metadata(dir.join("file.bin")).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o666 & !current_umask());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::IpAddr;
+
+// Shell-style wildcard matching for `--accept`/`--reject` style filters:
+// `*` matches any run of characters, `?` matches exactly one
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text index it was tried against
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and retry
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+pub fn matches_any(patterns: &[String], text: &str) -> bool {
+    patterns.iter().any(|p| matches(p, text))
+}
+
+// A host pattern as NO_PROXY writes them, or with wildcards:
+// `example.com` and `.example.com` cover the domain and its subdomains,
+// `*.example.com` only subdomains, `10.0.0.0/8` a network, `*` everything
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    if let Some((network, bits)) = pattern.split_once('/') {
+        return match (network.parse::<IpAddr>(), bits.parse::<u32>(), host.parse::<IpAddr>()) {
+            (Ok(network), Ok(bits), Ok(ip)) => in_network(ip, network, bits),
+            _ => false,
+        };
+    }
+    if pattern.contains(['*', '?']) {
+        return matches(pattern, host);
+    }
+    let domain = pattern.trim_start_matches('.');
+    host == domain || host.strip_suffix(domain).is_some_and(|rest| rest.ends_with('.'))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, bits: u32) -> bool {
+    let (ip, network, width) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => (u32::from(ip) as u128, u32::from(network) as u128, 32),
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+        _ => return false,
+    };
+    if bits > width {
+        return false;
+    }
+    let shift = width - bits;
+    shift == width || ip >> shift == network >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_match_domains_wildcards_and_networks() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(host_matches("example.com", "dl.example.com"));
+        assert!(!host_matches("example.com", "badexample.com"));
+        assert!(host_matches(".example.com", "example.com"));
+        assert!(host_matches("*.example.com", "dl.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(host_matches("10.0.0.0/8", "10.20.30.40"));
+        assert!(!host_matches("10.0.0.0/8", "11.0.0.1"));
+        assert!(host_matches("fd00::/8", "fd12::1"));
+        assert!(host_matches("0.0.0.0/0", "192.0.2.1"));
+        assert!(host_matches("*", "anything.example"));
+    }
+}
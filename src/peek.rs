@@ -0,0 +1,127 @@
+use crate::parquet::{self, Schema};
+use crate::protocol::{BoxError, Registry};
+use crate::reader::ParallelHttpReader;
+use crate::zip::{self, Member};
+use futures::StreamExt;
+use serde::Serialize;
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio_util::io::StreamReader;
+
+// A line longer than this is cut and ends the peek, so a file without
+// newlines is not read whole
+const MAX_LINE: usize = 64 * 1024;
+
+// What the start or end of a remote file shows about its contents
+#[derive(Debug, Serialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum Peek {
+    Parquet(Schema),
+    Zip { members: Vec<Member> },
+    Text { lines: Vec<String> },
+}
+
+// Look at `url` without downloading it: the schema in a Parquet footer, the
+// listing in a ZIP central directory, or else the first `lines` lines of a
+// text file such as a CSV. The format is taken from the name, or from the
+// first bytes when the name does not tell.
+pub async fn peek(registry: &Registry, url: &str, lines: usize) -> Result<Peek, BoxError> {
+    let path = url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase();
+    if [".parquet", ".parq", ".pq"].iter().any(|e| path.ends_with(e)) {
+        return parquet(registry, url).await;
+    }
+    if path.ends_with(".zip") {
+        return zip(registry, url).await;
+    }
+
+    let mut text = open_text(registry, url).await?;
+    let start = text.fill_buf().await?;
+    if start.starts_with(b"PAR1") {
+        return parquet(registry, url).await;
+    }
+    if start.starts_with(b"PK\x03\x04") {
+        return zip(registry, url).await;
+    }
+    if start[..start.len().min(8192)].contains(&0) {
+        return Err(format!("'{}' is not text, and not a Parquet or ZIP file", url).into());
+    }
+    let mut head = Vec::new();
+    while head.len() < lines {
+        let mut line = Vec::new();
+        if (&mut text).take(MAX_LINE as u64).read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        let cut = line.len() == MAX_LINE && line.last() != Some(&b'\n');
+        while matches!(line.last(), Some(b'\n' | b'\r')) {
+            line.pop();
+        }
+        if cut {
+            head.push(format!("{}...", String::from_utf8_lossy(&line)));
+            break;
+        }
+        head.push(String::from_utf8_lossy(&line).into_owned());
+    }
+    Ok(Peek::Text { lines: head })
+}
+
+async fn parquet(registry: &Registry, url: &str) -> Result<Peek, BoxError> {
+    let mut reader = ParallelHttpReader::open(registry, url).await?.with_block_size(256 * 1024);
+    Ok(Peek::Parquet(parquet::read_schema(&mut reader).await?))
+}
+
+async fn zip(registry: &Registry, url: &str) -> Result<Peek, BoxError> {
+    let mut reader = ParallelHttpReader::open(registry, url).await?.with_block_size(256 * 1024);
+    Ok(Peek::Zip {
+        members: zip::list(&mut reader).await?,
+    })
+}
+
+// The start of the file in small ranges when the server allows, otherwise a
+// response that is dropped once enough has been read
+async fn open_text(registry: &Registry, url: &str) -> Result<Box<dyn AsyncBufRead + Unpin + Send>, BoxError> {
+    let probe = registry.probe(url).await?;
+    if probe.accepts_ranges && probe.length.is_some() {
+        let reader = ParallelHttpReader::open(registry, url)
+            .await?
+            .with_block_size(64 * 1024)
+            .with_read_ahead(0);
+        return Ok(Box::new(BufReader::new(reader)));
+    }
+    let body = registry.handler_for(url)?.open(url, 0, None).await?;
+    Ok(Box::new(StreamReader::new(body.map(|chunk| chunk.map_err(io::Error::other)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use reqwest::Client;
+    use std::sync::Arc;
+
+    fn registry() -> Registry {
+        let client = Client::builder().no_proxy().build().unwrap();
+        Registry::with_defaults(Arc::new(client), Vec::new())
+    }
+
+    #[tokio::test]
+    async fn text_files_show_their_first_lines() {
+        let mut csv = b"id,name\r\n".to_vec();
+        for i in 0..200_000 {
+            csv.extend_from_slice(format!("{},row {}\r\n", i, i).as_bytes());
+        }
+        for behavior in [Behavior::default(), Behavior { no_ranges: true, ..Behavior::default() }] {
+            let server = MockServer::serve(csv.clone(), behavior).await.unwrap();
+            let Peek::Text { lines } = peek(&registry(), &server.url("export?id=42"), 3).await.unwrap() else {
+                panic!("not read as text");
+            };
+            assert_eq!(lines, ["id,name", "0,row 0", "1,row 1"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_files_of_other_formats_are_refused() {
+        let server = MockServer::serve(vec![0, 1, 2, 3, 0, 0, 7], Behavior::default()).await.unwrap();
+        let error = peek(&registry(), &server.url("blob"), 10).await.unwrap_err();
+        assert!(error.to_string().contains("is not text"), "{}", error);
+    }
+}
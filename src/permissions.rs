@@ -0,0 +1,170 @@
+use std::error::Error;
+use std::path::Path;
+
+// clap value parser for `--chmod`: an octal mode such as 644 or 0o600
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.trim_start_matches("0o");
+    let mode = u32::from_str_radix(digits, 8)
+        .map_err(|_| format!("'{}' is not an octal file mode", s))?;
+    if mode > 0o7777 {
+        return Err(format!("'{}' is out of range for a file mode", s));
+    }
+    Ok(mode)
+}
+
+// The user and group ids of `--chown`, resolved once while the arguments are
+// parsed: getpwnam and getgrnam are not safe to call from the threads that
+// finish downloads concurrently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+// clap value parser for `--chown`: `user`, `user:group` or `:group`, with
+// numeric ids allowed for either part
+pub fn parse_owner(spec: &str) -> Result<Owner, String> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, group),
+        None => (spec, ""),
+    };
+    if user.is_empty() && group.is_empty() {
+        return Err("Expected USER, USER:GROUP or :GROUP".to_string());
+    }
+    Ok(Owner {
+        uid: (!user.is_empty()).then(|| lookup_user(user)).transpose()?,
+        gid: (!group.is_empty()).then(|| lookup_group(group)).transpose()?,
+    })
+}
+
+// Apply `--chown` and `--chmod` to a finished download. The owner comes first:
+// changing it clears the setuid and setgid bits the mode may set.
+pub fn apply(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<Owner>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(owner) = owner {
+        set_owner(path, owner)?;
+    }
+    if let Some(mode) = mode {
+        set_mode(path, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+    Err("--chmod is only supported on Unix".into())
+}
+
+#[cfg(unix)]
+fn set_owner(path: &Path, owner: Owner) -> Result<(), Box<dyn Error + Send + Sync>> {
+    std::os::unix::fs::chown(path, owner.uid, owner.gid)
+        .map_err(|e| format!("Failed to chown '{}': {}", path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _owner: Owner) -> Result<(), Box<dyn Error + Send + Sync>> {
+    Err("--chown is only supported on Unix".into())
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<u32, String> {
+    if let Ok(uid) = name.parse::<u32>() {
+        return Ok(uid);
+    }
+    let c_name = std::ffi::CString::new(name).map_err(|_| format!("Unknown user '{}'", name))?;
+    // SAFETY: getpwnam returns a pointer into static storage or null; we only
+    // read pw_uid before any other call that could overwrite it
+    let entry = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("Unknown user '{}'", name));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<u32, String> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid);
+    }
+    let c_name = std::ffi::CString::new(name).map_err(|_| format!("Unknown group '{}'", name))?;
+    // SAFETY: see lookup_user
+    let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("Unknown group '{}'", name));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_name: &str) -> Result<u32, String> {
+    Err("--chown is only supported on Unix".to_string())
+}
+
+#[cfg(not(unix))]
+fn lookup_group(_name: &str) -> Result<u32, String> {
+    Err("--chown is only supported on Unix".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_are_octal() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0o4755").unwrap(), 0o4755);
+        assert!(parse_mode("0o17777").is_err());
+        assert!(parse_mode("678").is_err());
+        assert!(parse_mode("rw-r--r--").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn owners_resolve_names_and_numbers() {
+        let owner = |uid, gid| Owner { uid, gid };
+        assert_eq!(parse_owner("1000").unwrap(), owner(Some(1000), None));
+        assert_eq!(parse_owner("1000:50").unwrap(), owner(Some(1000), Some(50)));
+        assert_eq!(parse_owner(":50").unwrap(), owner(None, Some(50)));
+        assert_eq!(parse_owner("1000:").unwrap(), owner(Some(1000), None));
+        // Every Unix system has a root user with uid 0
+        assert_eq!(parse_owner("root").unwrap(), owner(Some(0), None));
+        assert!(parse_owner(":").is_err());
+        assert!(parse_owner("").is_err());
+        let error = parse_owner("no-such-user-gator:0").unwrap_err();
+        assert_eq!(error, "Unknown user 'no-such-user-gator'");
+        assert!(parse_owner("0:no-such-group-gator").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn setuid_bits_survive_a_chown() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-permissions", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tool");
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+
+        // Our own ids, which any user may chown to
+        let metadata = std::fs::metadata(&path).unwrap();
+        let owner = Owner {
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+        };
+        apply(&path, Some(0o2755), Some(owner)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o2755);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::hooks::Hook;
+use crate::protocol::{BoxError, Probe};
+use std::path::Path;
+use std::sync::Mutex;
+use wasmi::{Config, Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+// Upper bound on the work a single hook call may do before it is aborted
+const FUEL_PER_CALL: u64 = 50_000_000;
+
+// A sandboxed WASM module implementing any of the hook exports. Modules get no
+// imports at all, so they can only compute on the strings they are given.
+//
+// ABI: the module exports `memory` and `gator_alloc(len: i32) -> i32`. Each hook
+// export takes `(ptr: i32, len: i32)` of a UTF-8 input written into a buffer from
+// `gator_alloc`, and returns an i64 packing `(ptr << 32) | len` of its UTF-8
+// output, or 0 for "no change".
+//   gator_resolve_url      input: URL        output: replacement URL
+//   gator_request_headers  input: URL        output: `Name: value` lines
+//   gator_probe            input: probe JSON output: probe JSON (fields to override)
+pub struct WasmPlugin {
+    name: String,
+    state: Mutex<State>,
+}
+
+struct State {
+    store: Store<()>,
+    instance: Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+}
+
+impl WasmPlugin {
+    pub fn load(path: &Path) -> Result<Self, BoxError> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Failed to read plugin '{}': {}", path.display(), e))?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &bytes[..])
+            .map_err(|e| format!("Invalid plugin '{}': {}", path.display(), e))?;
+
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| format!("Failed to start plugin '{}': {}", path.display(), e))?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| format!("Plugin '{}' does not export `memory`", path.display()))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "gator_alloc")
+            .map_err(|_| format!("Plugin '{}' does not export `gator_alloc`", path.display()))?;
+
+        Ok(Self {
+            name: path.display().to_string(),
+            state: Mutex::new(State {
+                store,
+                instance,
+                memory,
+                alloc,
+            }),
+        })
+    }
+
+    // Call `export` with `input`; None when the export is missing or returns 0
+    fn call(&self, export: &str, input: &str) -> Result<Option<String>, BoxError> {
+        let mut state = self.state.lock().map_err(|_| "plugin state poisoned")?;
+        let State {
+            store,
+            instance,
+            memory,
+            alloc,
+        } = &mut *state;
+
+        let Ok(func) = instance.get_typed_func::<(i32, i32), i64>(&*store, export) else {
+            return Ok(None);
+        };
+        store.set_fuel(FUEL_PER_CALL)?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc
+            .call(&mut *store, len)
+            .map_err(|e| format!("Plugin '{}' failed in gator_alloc: {}", self.name, e))?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, input.as_bytes())
+            .map_err(|e| format!("Plugin '{}' returned a bad buffer: {}", self.name, e))?;
+
+        let packed = func
+            .call(&mut *store, (ptr, len))
+            .map_err(|e| format!("Plugin '{}' failed in {}: {}", self.name, export, e))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (out_ptr, out_len) = ((packed as u64 >> 32) as usize, (packed as u64 & 0xffff_ffff) as usize);
+        let mut buffer = vec![0u8; out_len];
+        memory
+            .read(&*store, out_ptr, &mut buffer)
+            .map_err(|e| format!("Plugin '{}' returned a bad buffer: {}", self.name, e))?;
+        let output = String::from_utf8(buffer)
+            .map_err(|_| format!("Plugin '{}' returned invalid UTF-8 from {}", self.name, export))?;
+        Ok(Some(output))
+    }
+}
+
+impl Hook for WasmPlugin {
+    fn resolve_url(&self, url: &str) -> Result<Option<String>, BoxError> {
+        Ok(self
+            .call("gator_resolve_url", url)?
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty()))
+    }
+
+    fn request_headers(&self, url: &str) -> Result<Vec<(String, String)>, BoxError> {
+        let Some(output) = self.call("gator_request_headers", url)? else {
+            return Ok(Vec::new());
+        };
+        Ok(output
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .filter(|(name, _)| !name.is_empty())
+            .collect())
+    }
+
+    fn inspect_probe(&self, _url: &str, probe: &mut Probe) -> Result<(), BoxError> {
+        let input = serde_json::to_string(probe)?;
+        let Some(output) = self.call("gator_probe", &input)? else {
+            return Ok(());
+        };
+        // Start from the current values so a plugin only needs to send what it changes
+        let mut merged = serde_json::to_value(&*probe)?;
+        let changes: serde_json::Value = serde_json::from_str(&output)
+            .map_err(|e| format!("Plugin '{}' returned invalid probe JSON: {}", self.name, e))?;
+        if let (Some(merged), Some(changes)) = (merged.as_object_mut(), changes.as_object()) {
+            for (key, value) in changes {
+                merged.insert(key.clone(), value.clone());
+            }
+        }
+        *probe = serde_json::from_value(merged)?;
+        Ok(())
+    }
+}
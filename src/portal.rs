@@ -0,0 +1,130 @@
+use crate::protocol::Probe;
+use reqwest::header::LOCATION;
+use reqwest::{redirect, Client, StatusCode};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+// Answers 204 with nothing on an open connection. A captive portal (hotel,
+// airport or café Wi-Fi that wants a sign-in first) redirects it to its login
+// page or serves the login page in its place, like every other request.
+pub const CHECK_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+
+// Names of pages a server is expected to answer with HTML
+const PAGE_EXTENSIONS: &[&str] = &["html", "htm", "xhtml", "shtml", "php", "asp", "aspx", "jsp"];
+
+// The URL to check for a captive portal: GATOR_PORTAL_CHECK replaces the
+// default (e.g. on a network that cannot reach it), and "off" turns it off
+pub fn check_url() -> Option<String> {
+    match std::env::var("GATOR_PORTAL_CHECK") {
+        Ok(value) if value.is_empty() || value.eq_ignore_ascii_case("off") => None,
+        Ok(value) => Some(value),
+        Err(_) => Some(CHECK_URL.to_string()),
+    }
+}
+
+// The network holds every request until the user signs in
+#[derive(Debug)]
+pub struct CaptivePortal {
+    // Where the portal sent the check, when it redirected
+    pub login: String,
+}
+
+impl fmt::Display for CaptivePortal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The network is asking you to sign in (captive portal at {}); open it in a browser and authenticate",
+            self.login
+        )
+    }
+}
+
+impl Error for CaptivePortal {}
+
+// Whether a response may be a portal's page rather than the file: HTML under
+// a name that is not a web page, or HTML where the rest of a partial file
+// should be
+pub fn suspect(final_path: &Path, probe: &Probe, resuming: bool) -> bool {
+    let html = probe.content_type.as_deref().is_some_and(crate::sniff::is_html_type);
+    let page = final_path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| PAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+    html && (resuming || !page)
+}
+
+// Ask `check_url` whether the connection is open. A redirect or a page in
+// place of the empty answer means a portal; errors and other statuses say
+// nothing either way, so they count as no portal.
+pub async fn detect(check_url: &str) -> Option<CaptivePortal> {
+    let client = Client::builder()
+        .redirect(redirect::Policy::none())
+        .timeout(Duration::from_secs(10))
+        .build()
+        .ok()?;
+    let response = client.get(check_url).send().await.ok()?;
+    let status = response.status();
+    if status.is_redirection() {
+        let location = response.headers().get(LOCATION).and_then(|l| l.to_str().ok());
+        let login = location
+            .and_then(|l| response.url().join(l).ok())
+            .map(|u| u.to_string())
+            .unwrap_or_else(|| check_url.to_string());
+        return Some(CaptivePortal { login });
+    }
+    if status == StatusCode::NO_CONTENT || !status.is_success() {
+        return None;
+    }
+    let body = response.bytes().await.ok()?;
+    (!body.is_empty()).then(|| CaptivePortal {
+        login: check_url.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+
+    fn probe(content_type: &str) -> Probe {
+        Probe {
+            status: "200 OK".to_string(),
+            length: Some(2048),
+            content_type: Some(content_type.to_string()),
+            content_encoding: None,
+            accepts_ranges: false,
+            headers: Vec::new(),
+            public_key_pin: None,
+        }
+    }
+
+    #[test]
+    fn html_in_place_of_a_file_is_suspect() {
+        let html = probe("text/html; charset=utf-8");
+        assert!(suspect(Path::new("ubuntu.iso"), &html, false));
+        assert!(suspect(Path::new("download"), &html, false));
+        assert!(!suspect(Path::new("index.html"), &html, false));
+        assert!(suspect(Path::new("index.html"), &html, true));
+        assert!(!suspect(Path::new("ubuntu.iso"), &probe("application/octet-stream"), true));
+    }
+
+    #[tokio::test]
+    async fn redirects_and_pages_mean_a_portal() {
+        let open = MockServer::serve(Vec::new(), Behavior::default()).await.unwrap();
+        assert!(detect(&open.url("generate_204")).await.is_none());
+
+        let page = MockServer::serve(b"<html>Sign in</html>".to_vec(), Behavior::default()).await.unwrap();
+        let portal = detect(&page.url("generate_204")).await.unwrap();
+        assert_eq!(portal.login, page.url("generate_204"));
+
+        let redirect = Behavior {
+            redirect: Some("/login?next=1".to_string()),
+            ..Behavior::default()
+        };
+        let gateway = MockServer::serve(Vec::new(), redirect).await.unwrap();
+        let portal = detect(&gateway.url("generate_204")).await.unwrap();
+        assert_eq!(portal.login, gateway.url("login?next=1"));
+    }
+}
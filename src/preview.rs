@@ -0,0 +1,300 @@
+use crate::progress::{Event, ProgressObserver, Snapshot, WorkerState};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+// Bytes read from the file and sent at a time
+const CHUNK: u64 = 64 * 1024;
+
+// Serves the file being downloaded over local HTTP while it downloads, for
+// `--serve`: a player can seek to any part already on disk, and a request for
+// bytes that have not arrived waits for them. Follows a download as one of
+// its progress observers.
+#[derive(Default)]
+pub struct Preview {
+    written: Mutex<Written>,
+    // Rung whenever more of the file is on disk, or the download ends
+    changed: Notify,
+}
+
+// What is known to be on disk of the file being downloaded
+#[derive(Default)]
+struct Written {
+    // The part file, then the finished file
+    path: Option<PathBuf>,
+    total: Option<u64>,
+    content_type: Option<String>,
+    // Sorted, disjoint `start..end` ranges of bytes on disk
+    ranges: Vec<(u64, u64)>,
+    // The segment each worker is fetching, and the bytes of it the previous
+    // snapshot reported. Writes are only certain to have reached the file by
+    // the next snapshot, so the newest count is held back until then.
+    segments: HashMap<usize, (u64, Option<u64>, u64)>,
+    complete: bool,
+    // The download failed or was cancelled
+    stopped: bool,
+}
+
+impl Written {
+    fn add(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    // Where the bytes on disk from `pos` on end, if `pos` is on disk
+    fn available_from(&self, pos: u64) -> Option<u64> {
+        if self.complete {
+            return self.total.filter(|&total| pos < total);
+        }
+        self.ranges.iter().find(|(start, end)| *start <= pos && pos < *end).map(|(_, end)| *end)
+    }
+}
+
+impl Preview {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The file, its size and type once the download has started
+    async fn started(&self) -> Option<(PathBuf, Option<u64>, Option<String>)> {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let written = self.written.lock().unwrap();
+                if let Some(path) = &written.path {
+                    return Some((path.clone(), written.total, written.content_type.clone()));
+                }
+                if written.stopped {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    // Wait until the byte at `pos` is on disk and return where the bytes on
+    // disk from there end; None once it never will be (past the end of the
+    // file, or the download stopped)
+    async fn wait_for(&self, pos: u64) -> Option<u64> {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let written = self.written.lock().unwrap();
+                if let Some(end) = written.available_from(pos) {
+                    return Some(end);
+                }
+                if written.complete || written.stopped {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Written)) {
+        f(&mut self.written.lock().unwrap());
+        self.changed.notify_waiters();
+    }
+}
+
+impl ProgressObserver for Preview {
+    fn on_progress(&self, snapshot: &Snapshot) {
+        self.update(|written| {
+            for (worker, state) in snapshot.workers.iter().enumerate() {
+                let WorkerState::Fetching { start, received, .. } = state else {
+                    continue;
+                };
+                if let Some((segment_start, _, reported)) = written.segments.get_mut(&worker) {
+                    if *segment_start == *start {
+                        let certain = std::mem::replace(reported, *received);
+                        written.add(*start, *start + certain);
+                    }
+                }
+            }
+        });
+    }
+
+    fn on_event(&self, event: &Event) {
+        self.update(|written| match event {
+            Event::Probed { content_type, .. } => written.content_type = content_type.clone(),
+            Event::Started {
+                path,
+                total,
+                resumed_from,
+                ..
+            } => {
+                written.path = Some(path.clone());
+                written.total = *total;
+                written.ranges.clear();
+                written.add(0, *resumed_from);
+            }
+            Event::SegmentStarted { worker, start, end, .. } => {
+                written.segments.insert(*worker, (*start, *end, 0));
+            }
+            // A segment's writes are in the file before it is reported finished
+            Event::SegmentFinished { worker, .. } => {
+                if let Some((start, end, _)) = written.segments.remove(worker) {
+                    let end = end.map(|end| end + 1).or(written.total).unwrap_or(u64::MAX);
+                    written.add(start, end);
+                }
+            }
+            Event::Finished { path, .. } => {
+                written.path = Some(path.clone());
+                written.total = written.total.or_else(|| std::fs::metadata(path).ok().map(|m| m.len()));
+                written.complete = true;
+            }
+            Event::Failed { .. } | Event::Cancelled { .. } => written.stopped = true,
+            _ => {}
+        });
+    }
+}
+
+// Listen on `addr` and serve the download there until the runtime shuts down.
+// Fails right away if the address cannot be bound.
+pub async fn listen(addr: SocketAddr, preview: Arc<Preview>) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let preview = preview.clone();
+            tokio::spawn(async move {
+                let _ = serve(&preview, socket).await;
+            });
+        }
+    });
+    Ok(local)
+}
+
+// One request per connection, answered with `Connection: close`
+async fn serve(preview: &Preview, mut socket: TcpStream) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match socket.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => head.extend_from_slice(&buf[..n]),
+        }
+        if head.len() > 64 * 1024 {
+            return Ok(());
+        }
+    }
+    let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+    let get = head.starts_with("get ");
+    if !get && !head.starts_with("head ") {
+        let response = "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        return socket.write_all(response.as_bytes()).await;
+    }
+    let range = head
+        .lines()
+        .find_map(|l| l.strip_prefix("range")?.strip_prefix(':'))
+        .map(str::trim);
+
+    let Some((path, total, content_type)) = preview.started().await else {
+        let response = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        return socket.write_all(response.as_bytes()).await;
+    };
+    let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+    let (status, first, last, mut headers) = match (range, total) {
+        (Some(range), Some(total)) => match parse_range(range, total) {
+            Some((first, last)) => (
+                "206 Partial Content",
+                first,
+                Some(last),
+                format!("Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n", first, last, total, last - first + 1),
+            ),
+            None => {
+                let response = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    total
+                );
+                return socket.write_all(response.as_bytes()).await;
+            }
+        },
+        (_, Some(total)) => ("200 OK", 0, total.checked_sub(1), format!("Content-Length: {}\r\n", total)),
+        // Without a size the body runs until the download ends
+        (_, None) => ("200 OK", 0, None, String::new()),
+    };
+    if total.is_some() {
+        headers.push_str("Accept-Ranges: bytes\r\n");
+    }
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\n{}Connection: close\r\n\r\n",
+        status, content_type, headers
+    );
+    socket.write_all(response.as_bytes()).await?;
+    if !get || (total == Some(0)) {
+        return Ok(());
+    }
+
+    // A handle opened on the part file keeps working after it is renamed
+    let mut file = File::open(&path).await?;
+    let mut pos = first;
+    let mut chunk = Vec::new();
+    while last.is_none_or(|last| pos <= last) {
+        let Some(available) = preview.wait_for(pos).await else {
+            break;
+        };
+        let end = last.map_or(available, |last| available.min(last + 1)).min(pos + CHUNK);
+        chunk.resize((end - pos) as usize, 0);
+        file.seek(std::io::SeekFrom::Start(pos)).await?;
+        file.read_exact(&mut chunk).await?;
+        socket.write_all(&chunk).await?;
+        pos = end;
+    }
+    Ok(())
+}
+
+// First and last byte of a single `bytes=first-[last]` or `bytes=-suffix`
+// range within `len`
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let end = len.checked_sub(1)?;
+    let (first, last) = match (first.trim(), last.trim()) {
+        ("", suffix) => (len.saturating_sub(suffix.parse().ok()?), end),
+        (first, "") => (first.parse().ok()?, end),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.min(end)),
+    };
+    (first <= last).then_some((first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_on_disk_are_merged() {
+        let mut written = Written::default();
+        written.add(0, 10);
+        written.add(20, 30);
+        assert_eq!(written.available_from(5), Some(10));
+        assert_eq!(written.available_from(10), None);
+        written.add(10, 20);
+        assert_eq!(written.ranges, vec![(0, 30)]);
+        assert_eq!(written.available_from(29), Some(30));
+
+        assert_eq!(parse_range("bytes=5-", 100), Some((5, 99)));
+        assert_eq!(parse_range("bytes=-10", 100), Some((90, 99)));
+        assert_eq!(parse_range("bytes=50-500", 100), Some((50, 99)));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::protocol::Probe;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// One cached probe, as a line of the cache file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    url: String,
+    // Seconds since the Unix epoch
+    probed_at: u64,
+    probe: Probe,
+    // Probe skips its headers when serialized, but digests and --expect-header
+    // need them. Only STORED_HEADERS are written to the cache file.
+    headers: Vec<(String, String)>,
+}
+
+// The headers a cache file keeps: validators, the type, and the digests a
+// download is checked against. Anything else, like Set-Cookie or a token a
+// server echoes back, never reaches the disk.
+pub const STORED_HEADERS: &[&str] = &[
+    "ETag",
+    "Last-Modified",
+    "Content-Type",
+    "Content-MD5",
+    "Digest",
+    "Repr-Digest",
+    "Content-Digest",
+];
+
+pub fn is_stored_header(name: &str) -> bool {
+    STORED_HEADERS.iter().any(|stored| stored.eq_ignore_ascii_case(name))
+}
+
+fn stored_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers.iter().filter(|(name, _)| is_stored_header(name)).cloned().collect()
+}
+
+// Probe results keyed by URL, so a batch that is run again (or ordered by size
+// and then downloaded) does not send the same HEAD twice. Backed by a JSON
+// lines file that is appended to as probes come in, so an interrupted run
+// keeps what it learned.
+pub struct ProbeCache {
+    file: Option<PathBuf>,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl ProbeCache {
+    // A cache for this run only
+    pub fn in_memory() -> Self {
+        Self {
+            file: None,
+            ttl: Duration::MAX,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Load the entries of `file` younger than `ttl`, rewriting it without the
+    // expired ones. A missing or unreadable file is an empty cache.
+    pub fn open(file: &Path, ttl: Duration) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut entries = HashMap::new();
+        if let Ok(content) = std::fs::read_to_string(file) {
+            for line in content.lines() {
+                // A line cut short by an interrupted run is skipped
+                if let Ok(mut entry) = serde_json::from_str::<Entry>(line) {
+                    if !expired(&entry, ttl) {
+                        // Files from before the headers were filtered are scrubbed
+                        entry.headers = stored_headers(&entry.headers);
+                        entries.insert(entry.url.clone(), entry);
+                    }
+                }
+            }
+        }
+
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut compacted = String::new();
+        for entry in entries.values() {
+            compacted.push_str(&serde_json::to_string(entry)?);
+            compacted.push('\n');
+        }
+        std::fs::write(file, compacted)
+            .map_err(|e| format!("Failed to write probe cache '{}': {}", file.display(), e))?;
+
+        Ok(Self {
+            file: Some(file.to_path_buf()),
+            ttl,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> Option<Probe> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(url).filter(|e| !expired(e, self.ttl))?;
+        let mut probe = entry.probe.clone();
+        probe.headers = entry.headers.clone();
+        Some(probe)
+    }
+
+    pub fn insert(&self, url: &str, probe: &Probe) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // A cache for this run only keeps every header, like a fresh probe
+        let headers = match self.file {
+            Some(_) => stored_headers(&probe.headers),
+            None => probe.headers.clone(),
+        };
+        let entry = Entry {
+            url: url.to_string(),
+            probed_at: now(),
+            probe: probe.clone(),
+            headers,
+        };
+        // Held while appending so lines from concurrent probes do not interleave
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_string(&entry)?;
+            line.push('\n');
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)?
+                .write_all(line.as_bytes())?;
+        }
+        entries.insert(url.to_string(), entry);
+        Ok(())
+    }
+}
+
+fn expired(entry: &Entry, ttl: Duration) -> bool {
+    Duration::from_secs(now().saturating_sub(entry.probed_at)) > ttl
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(length: u64) -> Probe {
+        Probe {
+            status: "200 OK".to_string(),
+            length: Some(length),
+            headers: vec![
+                ("ETag".to_string(), "\"v1\"".to_string()),
+                ("Set-Cookie".to_string(), "session=secret".to_string()),
+                ("repr-digest".to_string(), "sha-256=:AAAA:".to_string()),
+            ],
+            ..Probe::default()
+        }
+    }
+
+    #[test]
+    fn probes_are_kept_by_exact_url_across_runs() {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-probe-cache", std::process::id()));
+        let file = dir.join("probes.jsonl");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let cache = ProbeCache::open(&file, Duration::from_secs(60)).unwrap();
+        cache.insert("https://example.com/a.iso", &probe(100)).unwrap();
+        cache.insert("https://example.com/a.iso", &probe(200)).unwrap();
+        let cached = cache.get("https://example.com/a.iso").unwrap();
+        assert_eq!(cached.length, Some(200));
+        let names: Vec<&str> = cached.headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["ETag", "repr-digest"]);
+        assert!(!std::fs::read_to_string(&file).unwrap().contains("secret"));
+        assert!(cache.get("https://example.com/a.iso?").is_none());
+        assert!(cache.get("https://example.com/A.iso").is_none());
+
+        // The next run reads the latest probe back, and compacts the file
+        let cache = ProbeCache::open(&file, Duration::from_secs(60)).unwrap();
+        let cached = cache.get("https://example.com/a.iso").unwrap();
+        assert_eq!(cached.length, Some(200));
+        assert_eq!(cached.headers.len(), 2);
+        assert_eq!(std::fs::read_to_string(&file).unwrap().lines().count(), 1);
+
+        // Nothing is written for a cache of this run, so it keeps every header
+        let memory = ProbeCache::in_memory();
+        memory.insert("https://example.com/b", &probe(1)).unwrap();
+        let cached = memory.get("https://example.com/b").unwrap();
+        assert_eq!(cached.length, Some(1));
+        assert_eq!(cached.headers, probe(1).headers);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn expired_and_broken_entries_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-probe-cache-ttl", std::process::id()));
+        let file = dir.join("probes.jsonl");
+        std::fs::create_dir_all(&dir).unwrap();
+        let line = |url: &str, age: u64| {
+            serde_json::to_string(&Entry {
+                url: url.to_string(),
+                probed_at: now() - age,
+                probe: probe(1),
+                headers: Vec::new(),
+            })
+            .unwrap()
+        };
+        let content = format!(
+            "{}\n{}\n{{\"url\":\"https://example.com/cut",
+            line("https://example.com/old", 3600),
+            line("https://example.com/new", 10)
+        );
+        std::fs::write(&file, content).unwrap();
+
+        let cache = ProbeCache::open(&file, Duration::from_secs(60)).unwrap();
+        assert!(cache.get("https://example.com/old").is_none());
+        assert!(cache.get("https://example.com/new").is_some());
+        assert!(cache.get("https://example.com/cut").is_none());
+        let kept = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(kept.lines().count(), 1);
+        assert!(kept.contains("https://example.com/new"));
+
+        // A shorter TTL on the next run expires the rest
+        let cache = ProbeCache::open(&file, Duration::from_secs(5)).unwrap();
+        assert!(cache.get("https://example.com/new").is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
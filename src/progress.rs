@@ -0,0 +1,264 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+
+// How often observers and `watch` subscribers get a new snapshot while bytes
+// are flowing. Events are delivered as they happen.
+const PUBLISH_INTERVAL: Duration = Duration::from_millis(100);
+
+// Weight of the newest measurement in the smoothed speed
+const SPEED_SMOOTHING: f64 = 0.3;
+
+// The state of one download at a point in time
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Snapshot {
+    // Bytes transferred so far, counting any resumed from (the encoded bytes
+    // when decoding)
+    pub bytes: u64,
+    // Size of the file, when the server said
+    pub total: Option<u64>,
+    // Smoothed transfer rate in bytes per second
+    pub speed: f64,
+    pub segments_done: usize,
+    pub segments_total: usize,
+    pub workers: Vec<WorkerState>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WorkerState {
+    // Waiting for a segment, or not started
+    #[default]
+    Idle,
+    // Fetching bytes `start..=end` (to the end of the file when `end` is
+    // None), of which `received` have been written
+    Fetching { segment: usize, start: u64, end: Option<u64>, received: u64 },
+    // No segments left
+    Done,
+}
+
+// Milestones of a download, in the order they happen
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Probed {
+        url: String,
+        status: String,
+        length: Option<u64>,
+        content_type: Option<String>,
+        accepts_ranges: bool,
+    },
+    // `path` is the part file the bytes go to until the download succeeds
+    Started {
+        path: PathBuf,
+        total: Option<u64>,
+        resumed_from: u64,
+        segments: usize,
+        workers: usize,
+    },
+    SegmentStarted {
+        worker: usize,
+        segment: usize,
+        start: u64,
+        end: Option<u64>,
+    },
+    SegmentFinished {
+        worker: usize,
+        segment: usize,
+    },
+    // The file does not look like what its name promises (see `check_type`)
+    Warning {
+        message: String,
+    },
+    Finished {
+        path: PathBuf,
+        bytes: u64,
+    },
+    Failed {
+        error: String,
+    },
+    // The cancellation token fired; `path` keeps the first `kept` bytes
+    Cancelled {
+        path: Option<PathBuf>,
+        kept: u64,
+    },
+}
+
+// Callbacks for programs that draw their own progress. Both are called from
+// the download's tasks, so they should return quickly.
+pub trait ProgressObserver: Send + Sync {
+    // At most every 100 ms while bytes arrive, and once more at the end
+    fn on_progress(&self, _snapshot: &Snapshot) {}
+
+    fn on_event(&self, _event: &Event) {}
+}
+
+// Where a download reports its progress: to observers, to `watch` subscribers
+// of the latest snapshot, and to subscribers of the event stream. Cloning
+// gives another handle to the same reporter.
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    observers: Mutex<Vec<Arc<dyn ProgressObserver>>>,
+    snapshots: watch::Sender<Snapshot>,
+    events: broadcast::Sender<Event>,
+    state: Mutex<State>,
+}
+
+struct State {
+    snapshot: Snapshot,
+    published_at: Instant,
+    published_bytes: u64,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                observers: Mutex::new(Vec::new()),
+                snapshots: watch::channel(Snapshot::default()).0,
+                events: broadcast::channel(1024).0,
+                state: Mutex::new(State {
+                    snapshot: Snapshot::default(),
+                    published_at: Instant::now(),
+                    published_bytes: 0,
+                }),
+            }),
+        }
+    }
+
+    pub fn observe(&self, observer: Arc<dyn ProgressObserver>) {
+        self.inner.observers.lock().unwrap().push(observer);
+    }
+
+    // The latest snapshot, updated as the download runs
+    pub fn watch(&self) -> watch::Receiver<Snapshot> {
+        self.inner.snapshots.subscribe()
+    }
+
+    // Every event from now on. A subscriber that falls more than 1024 events
+    // behind misses the oldest (see `broadcast::error::RecvError::Lagged`).
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    pub(crate) fn event(&self, event: Event) {
+        for observer in self.inner.observers.lock().unwrap().iter() {
+            observer.on_event(&event);
+        }
+        // Fails only when nobody subscribed
+        let _ = self.inner.events.send(event);
+    }
+
+    // A transfer into `path` of `segments` pieces by `workers` workers
+    // begins, with `resumed_from` bytes already on disk
+    pub(crate) fn start(
+        &self,
+        path: &Path,
+        total: Option<u64>,
+        resumed_from: u64,
+        segments: usize,
+        workers: usize,
+    ) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.snapshot = Snapshot {
+                bytes: resumed_from,
+                total,
+                speed: 0.0,
+                segments_done: 0,
+                segments_total: segments,
+                workers: vec![WorkerState::Idle; workers],
+            };
+            state.published_at = Instant::now();
+            state.published_bytes = resumed_from;
+        }
+        self.event(Event::Started {
+            path: path.to_path_buf(),
+            total,
+            resumed_from,
+            segments,
+            workers,
+        });
+    }
+
+    // `len` more bytes written by `worker`
+    pub(crate) fn advance(&self, worker: usize, len: u64) {
+        let snapshot = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.snapshot.bytes += len;
+            if let Some(WorkerState::Fetching { received, .. }) = state.snapshot.workers.get_mut(worker) {
+                *received += len;
+            }
+            (state.published_at.elapsed() >= PUBLISH_INTERVAL).then(|| take_snapshot(&mut state))
+        };
+        if let Some(snapshot) = snapshot {
+            self.publish(snapshot);
+        }
+    }
+
+    pub(crate) fn segment_started(&self, worker: usize, segment: usize, start: u64, end: Option<u64>) {
+        if let Some(slot) = self.inner.state.lock().unwrap().snapshot.workers.get_mut(worker) {
+            *slot = WorkerState::Fetching { segment, start, end, received: 0 };
+        }
+        self.event(Event::SegmentStarted { worker, segment, start, end });
+    }
+
+    pub(crate) fn segment_finished(&self, worker: usize, segment: usize) {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            state.snapshot.segments_done += 1;
+            if let Some(slot) = state.snapshot.workers.get_mut(worker) {
+                *slot = WorkerState::Idle;
+            }
+        }
+        self.event(Event::SegmentFinished { worker, segment });
+    }
+
+    pub(crate) fn worker_done(&self, worker: usize) {
+        if let Some(slot) = self.inner.state.lock().unwrap().snapshot.workers.get_mut(worker) {
+            *slot = WorkerState::Done;
+        }
+    }
+
+    // Publish the final state whatever the interval
+    pub(crate) fn finish(&self) {
+        let snapshot = take_snapshot(&mut self.inner.state.lock().unwrap());
+        self.publish(snapshot);
+    }
+
+    fn publish(&self, snapshot: Snapshot) {
+        for observer in self.inner.observers.lock().unwrap().iter() {
+            observer.on_progress(&snapshot);
+        }
+        self.inner.snapshots.send_replace(snapshot);
+    }
+}
+
+// Update the speed from the bytes since the last snapshot and return a copy
+fn take_snapshot(state: &mut State) -> Snapshot {
+    let elapsed = state.published_at.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        let rate = (state.snapshot.bytes - state.published_bytes) as f64 / elapsed;
+        let speed = state.snapshot.speed;
+        state.snapshot.speed = if speed == 0.0 {
+            rate
+        } else {
+            speed + SPEED_SMOOTHING * (rate - speed)
+        };
+    }
+    state.published_at = Instant::now();
+    state.published_bytes = state.snapshot.bytes;
+    state.snapshot.clone()
+}
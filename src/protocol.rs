@@ -0,0 +1,752 @@
+use crate::byteranges;
+use crate::data_url;
+use crate::hooks::Hook;
+use crate::ip_family::Families;
+use crate::listing::{self, Entry};
+use crate::pattern;
+use crate::probe_cache::ProbeCache;
+use crate::tls::{self, Pins};
+use crate::urls;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::Stream;
+use http_body_util::BodyExt;
+use reqwest::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RANGE};
+use reqwest::{Client, Method, Request, RequestBuilder, StatusCode};
+use std::collections::HashMap;
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send>>;
+// Fields a server sends after the body, e.g. a checksum it could only compute
+// while streaming. Filled in once the stream has ended.
+pub type Trailers = Arc<Mutex<Vec<(String, String)>>>;
+
+// What the scheduler needs to know about a resource before downloading it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Probe {
+    // Protocol-specific status to show the user, e.g. "200 OK"
+    pub status: String,
+    pub length: Option<u64>,
+    pub content_type: Option<String>,
+    // e.g. "gzip": the body (and `length`) is encoded, not the file itself
+    pub content_encoding: Option<String>,
+    pub accepts_ranges: bool,
+    // All response headers, for `--expect-header`; not shown to plugins
+    #[serde(skip)]
+    pub headers: Vec<(String, String)>,
+    // "sha256//<base64>" of the server certificate's public key, over HTTPS
+    #[serde(skip)]
+    pub public_key_pin: Option<String>,
+}
+
+impl Probe {
+    // What identifies this version of the resource, "etag: ..." or else
+    // "last-modified: ...", if the server sends either
+    pub fn validator(&self) -> Option<String> {
+        ["etag", "last-modified"].iter().find_map(|name| {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(n, v)| format!("{}: {}", n.to_ascii_lowercase(), v))
+        })
+    }
+}
+
+// The server answered, but not with success
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: String,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server returned error: {}", self.status)
+    }
+}
+
+impl Error for StatusError {}
+
+// A 416 to a range request: the range starts at or past the end of the file,
+// whose length (from `Content-Range: bytes */N`) the server may tell
+#[derive(Debug)]
+pub struct RangeNotSatisfiable {
+    pub length: Option<u64>,
+}
+
+impl std::fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.length {
+            Some(length) => write!(f, "Server cannot send that range of a {} byte file", length),
+            None => write!(f, "Server cannot send that range of the file"),
+        }
+    }
+}
+
+impl Error for RangeNotSatisfiable {}
+
+// An answer to a range request that does not carry the bytes asked for: the
+// whole file, or another range. Asking again gets the same answer.
+#[derive(Debug)]
+pub struct WrongRange(pub String);
+
+impl std::fmt::Display for WrongRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for WrongRange {}
+
+// A backend that can fetch (and optionally list) resources for one or more URL
+// schemes. The download scheduler only talks to this trait, so new protocols
+// plug in through the registry without touching it.
+pub trait ProtocolHandler: Send + Sync {
+    // Size, type and range support of `url`
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Probe, BoxError>>;
+
+    // Stream bytes `start..=end` of `url`, or everything from `start` when
+    // `end` is None. Must fail rather than return bytes from another offset.
+    fn open<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>>;
+
+    // Like `open`, also collecting any trailer fields into `trailers`
+    fn open_with_trailers<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        trailers: Trailers,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        let _ = trailers;
+        self.open(url, start, end)
+    }
+
+    // Like `open`, for one of several ranges of a file being fetched at once.
+    // `urgency` follows RFC 9218: 0 is needed soonest, 7 can wait. Servers that
+    // multiplex the ranges over one HTTP/2 connection may send the urgent
+    // ones first.
+    fn open_prioritized<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        urgency: u8,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        let _ = urgency;
+        self.open(url, start, end)
+    }
+
+    // Several ranges of `url` (first and last byte of each) at once, in the
+    // order asked and held in memory. The default fetches them one by one;
+    // HTTP asks for all of them in one request.
+    fn fetch_ranges<'a>(
+        &'a self,
+        url: &'a str,
+        ranges: &'a [(u64, u64)],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, BoxError>> {
+        Box::pin(async move {
+            let mut fetched = Vec::new();
+            for &(first, last) in ranges {
+                let mut stream = self.open(url, first, Some(last)).await?;
+                let mut data = Vec::new();
+                while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+                    data.extend_from_slice(&chunk?);
+                }
+                if Some(data.len() as u64) != range_len(first, last) {
+                    return Err(format!("Server sent {} bytes for range {}-{}", data.len(), first, last).into());
+                }
+                fetched.push(Bytes::from(data));
+            }
+            Ok(fetched)
+        })
+    }
+
+    // Entries below `url` for listable sources
+    fn list<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<Entry>, BoxError>> {
+        Box::pin(async move { Err(format!("Listing is not supported for {}", url).into()) })
+    }
+}
+
+// Protocol handlers keyed by URL scheme, plus the hooks that apply to all of them
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<String, Arc<dyn ProtocolHandler>>,
+    hooks: Arc<Vec<Arc<dyn Hook>>>,
+    probe_cache: Option<ProbeCache>,
+}
+
+impl Registry {
+    // The built-in handlers
+    pub fn with_defaults(client: Arc<Client>, hooks: Vec<Arc<dyn Hook>>) -> Self {
+        Self::with_clients(HostClients::new(client), hooks)
+    }
+
+    // The built-in handlers, with their own client for some hosts
+    pub fn with_clients(clients: HostClients, hooks: Vec<Arc<dyn Hook>>) -> Self {
+        let hooks = Arc::new(hooks);
+        let mut registry = Self {
+            handlers: HashMap::new(),
+            hooks: hooks.clone(),
+            probe_cache: None,
+        };
+        let http = Arc::new(HttpHandler { clients, hooks });
+        registry.register("http", http.clone());
+        registry.register("https", http);
+        registry.register("data", Arc::new(data_url::DataHandler));
+        registry
+    }
+
+    // Run `url` through every URL-resolving hook in order, then normalize it
+    pub fn resolve_url(&self, url: &str) -> Result<String, BoxError> {
+        let mut url = url.to_string();
+        for hook in self.hooks.iter() {
+            if let Some(resolved) = hook.resolve_url(&url)? {
+                url = resolved;
+            }
+        }
+        Ok(urls::normalize(&url))
+    }
+
+    pub fn set_probe_cache(&mut self, cache: ProbeCache) {
+        self.probe_cache = Some(cache);
+    }
+
+    // Probe `url` with its handler, or answer from the probe cache
+    pub async fn probe(&self, url: &str) -> Result<Probe, BoxError> {
+        if let Some(probe) = self.probe_cache.as_ref().and_then(|c| c.get(url)) {
+            return Ok(probe);
+        }
+        let probe = self.handler_for(url)?.probe(url).await?;
+        if let Some(cache) = &self.probe_cache {
+            cache.insert(url, &probe)?;
+        }
+        Ok(probe)
+    }
+
+    pub fn inspect_probe(&self, url: &str, probe: &mut Probe) -> Result<(), BoxError> {
+        for hook in self.hooks.iter() {
+            hook.inspect_probe(url, probe)?;
+        }
+        Ok(())
+    }
+
+    pub fn register(&mut self, scheme: &str, handler: Arc<dyn ProtocolHandler>) {
+        self.handlers.insert(scheme.to_ascii_lowercase(), handler);
+    }
+
+    // Replace every registered handler with `wrap(handler)`, e.g. for fault injection
+    pub fn wrap_handlers(&mut self, wrap: impl Fn(Arc<dyn ProtocolHandler>) -> Arc<dyn ProtocolHandler>) {
+        for handler in self.handlers.values_mut() {
+            *handler = wrap(handler.clone());
+        }
+    }
+
+    pub fn handler_for(&self, url: &str) -> Result<Arc<dyn ProtocolHandler>, BoxError> {
+        let scheme = url
+            .split_once(':')
+            .map(|(scheme, _)| scheme.to_ascii_lowercase())
+            .ok_or_else(|| format!("'{}' is not a URL", url))?;
+        self.handlers
+            .get(&scheme)
+            .cloned()
+            .ok_or_else(|| format!("Unsupported URL scheme '{}'", scheme).into())
+    }
+}
+
+// A client per host pattern, for hosts that need something the default
+// client lacks, such as a TLS client certificate
+#[derive(Clone)]
+pub struct HostClients {
+    default: Arc<Client>,
+    hosts: Vec<(String, Arc<Client>)>,
+    pins: Pins,
+    families: Option<Families>,
+}
+
+impl HostClients {
+    pub fn new(default: Arc<Client>) -> Self {
+        Self {
+            default,
+            hosts: Vec::new(),
+            pins: Pins::default(),
+            families: None,
+        }
+    }
+
+    // Retry requests whose connection failed or stalled over the other IP
+    // family. The clients must resolve names through `families`.
+    pub fn fall_back_across(&mut self, families: Families) {
+        self.families = Some(families);
+    }
+
+    // Only accept responses from servers whose certificate has one of these
+    // public keys. The clients must be built with `tls_info(true)`.
+    pub fn pin(&mut self, pins: Pins) {
+        self.pins = pins;
+    }
+
+    // Use `client` for hosts matching `pattern` (see `pattern::host_matches`)
+    pub fn add(&mut self, pattern: &str, client: Arc<Client>) {
+        self.hosts.push((pattern.to_ascii_lowercase(), client));
+    }
+
+    // The client of the most specific (longest) pattern that matches the
+    // host of `url`, or the default one
+    pub fn for_url(&self, url: &str) -> &Arc<Client> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()));
+        let Some(host) = host else {
+            return &self.default;
+        };
+        self.hosts
+            .iter()
+            .filter(|(pattern, _)| pattern::host_matches(pattern, &host))
+            .max_by_key(|(pattern, _)| pattern.trim_matches('*').len())
+            .map_or(&self.default, |(_, client)| client)
+    }
+
+    // Send `request` with the client for `url`. When connecting to a host
+    // with both IPv4 and IPv6 addresses fails or times out, send it once more
+    // over the other family, which the host then sticks to.
+    async fn execute(&self, url: &str, request: Request) -> reqwest::Result<reqwest::Response> {
+        let client = self.for_url(url);
+        let host = request.url().host_str().map(str::to_ascii_lowercase);
+        let (Some(families), Some(host)) = (&self.families, host) else {
+            return client.execute(request).await;
+        };
+        let pinned = families.pinned(&host);
+        let retry = request.try_clone();
+        match client.execute(request).await {
+            Err(e) if e.is_connect() || e.is_timeout() => match retry {
+                Some(retry) if families.fall_back(&host, pinned) => client.execute(retry).await,
+                _ => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    fn check_pins(&self, url: &str, response: &reqwest::Response) -> Result<(), BoxError> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+        match peer_certificate(response) {
+            Some(cert) => self.pins.check(cert),
+            None => Err(format!("'{}' is not HTTPS, so it cannot be checked against the pinned keys", url).into()),
+        }
+    }
+}
+
+fn peer_certificate(response: &reqwest::Response) -> Option<&[u8]> {
+    response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()?
+        .peer_certificate()
+}
+
+pub struct HttpHandler {
+    clients: HostClients,
+    hooks: Arc<Vec<Arc<dyn Hook>>>,
+}
+
+impl HttpHandler {
+    fn request(&self, method: Method, url: &str) -> Result<RequestBuilder, BoxError> {
+        let mut request = self.clients.for_url(url).request(method, url);
+        for hook in self.hooks.iter() {
+            for (name, value) in hook.request_headers(url)? {
+                request = request.header(name, value);
+            }
+        }
+        Ok(request)
+    }
+
+    // GET bytes `start..=end` of `url`, collecting trailers into `trailers`
+    async fn fetch(
+        &self,
+        url: &str,
+        start: u64,
+        end: Option<u64>,
+        trailers: Trailers,
+        urgency: Option<u8>,
+    ) -> Result<ByteStream, BoxError> {
+        let ranged = start > 0 || end.is_some();
+        let mut request = self.request(Method::GET, url)?.build()?;
+        if let Some(urgency) = urgency {
+            request.headers_mut().insert("priority", HeaderValue::from_str(&format!("u={}", urgency))?);
+        }
+        if ranged {
+            let range = match end {
+                Some(end) => format!("bytes={}-{}", start, end),
+                None => format!("bytes={}-", start),
+            };
+            request.headers_mut().insert(RANGE, HeaderValue::from_str(&range)?);
+            // Servers that compress on the fly may compress each range on its
+            // own, so the pieces would not add up to the file. This replaces
+            // any Accept-Encoding a hook asked for; whole-file requests keep it.
+            request
+                .headers_mut()
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+        }
+
+        let response = self.clients.execute(url, request).await?;
+        self.clients.check_pins(url, &response)?;
+        if ranged && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            let length = response
+                .headers()
+                .get("content-range")
+                .and_then(|h| h.to_str().ok())
+                .and_then(unsatisfied_length);
+            return Err(RangeNotSatisfiable { length }.into());
+        }
+        if !response.status().is_success() {
+            return Err(StatusError {
+                status: response.status().to_string(),
+            }
+            .into());
+        }
+        // A 200 to a range request is the whole file, which would land at the wrong offset
+        if ranged && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(WrongRange(format!(
+                "Server ignored the range request (returned {})",
+                response.status()
+            ))
+            .into());
+        }
+
+        // The body must start exactly where asked, or it would be written at the wrong offset
+        if ranged {
+            let header = response
+                .headers()
+                .get("content-range")
+                .and_then(|h| h.to_str().ok())
+                .ok_or_else(|| WrongRange("Partial response without a Content-Range header".to_string()))?;
+            let (first, last) = parse_content_range(header)
+                .ok_or_else(|| WrongRange(format!("Malformed Content-Range: {}", header)))?;
+            if first != start || end.is_some_and(|end| last != end) {
+                let requested = match end {
+                    Some(end) => format!("{}-{}", start, end),
+                    None => format!("{}-", start),
+                };
+                return Err(WrongRange(format!(
+                    "Server returned bytes {}-{} for requested range {}",
+                    first, last, requested
+                ))
+                .into());
+            }
+        }
+
+        // Read frame by frame rather than with bytes_stream(), which drops trailers
+        let body = http::Response::from(response).into_body();
+        let stream = futures::stream::unfold(body, move |mut body| {
+            let trailers = trailers.clone();
+            async move {
+                loop {
+                    let frame = match body.frame().await? {
+                        Ok(frame) => frame,
+                        Err(e) => return Some((Err(Box::new(e) as BoxError), body)),
+                    };
+                    match frame.into_data() {
+                        Ok(data) => return Some((Ok(data), body)),
+                        Err(frame) => {
+                            if let Ok(fields) = frame.into_trailers() {
+                                let mut trailers = trailers.lock().unwrap();
+                                trailers.extend(header_pairs(&fields));
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Box::pin(stream) as ByteStream)
+    }
+}
+
+impl HttpHandler {
+    // Every range in one `Range: bytes=a-b,c-d` request. Servers answer with a
+    // multipart/byteranges body, or with one range when they merge them.
+    async fn fetch_multipart(&self, url: &str, ranges: &[(u64, u64)]) -> Result<Vec<Bytes>, BoxError> {
+        let spec: Vec<String> = ranges.iter().map(|(first, last)| format!("{}-{}", first, last)).collect();
+        let mut request = self.request(Method::GET, url)?.build()?;
+        request
+            .headers_mut()
+            .insert(RANGE, HeaderValue::from_str(&format!("bytes={}", spec.join(",")))?);
+        request
+            .headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+
+        let response = self.clients.execute(url, request).await?;
+        self.clients.check_pins(url, &response)?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_range = header("content-range");
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            let length = content_range.as_deref().and_then(unsatisfied_length);
+            return Err(RangeNotSatisfiable { length }.into());
+        }
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(format!(
+                "Server did not answer a request for several ranges (returned {})",
+                response.status()
+            )
+            .into());
+        }
+        let boundary = header(CONTENT_TYPE.as_str()).and_then(|t| byteranges::boundary(&t).map(str::to_string));
+        let body = response.bytes().await?;
+        let parts = match boundary {
+            Some(boundary) => byteranges::parse(&body, &boundary)?,
+            None => {
+                let range = content_range.ok_or("Partial response without a Content-Range header")?;
+                let (first, last) =
+                    parse_content_range(&range).ok_or_else(|| format!("Malformed Content-Range: {}", range))?;
+                if Some(body.len() as u64) != range_len(first, last) {
+                    return Err(format!("Server sent {} bytes for range {}-{}", body.len(), first, last).into());
+                }
+                vec![byteranges::Part { start: first, data: body }]
+            }
+        };
+        byteranges::slice(&parts, ranges)
+    }
+}
+
+impl ProtocolHandler for HttpHandler {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Probe, BoxError>> {
+        Box::pin(async move {
+            let request = self.request(Method::HEAD, url)?.build()?;
+            let response = self.clients.execute(url, request).await?;
+            self.clients.check_pins(url, &response)?;
+            if !response.status().is_success() {
+                return Err(StatusError {
+                    status: response.status().to_string(),
+                }
+                .into());
+            }
+
+            let headers = response.headers();
+            Ok(Probe {
+                status: response.status().to_string(),
+                length: headers
+                    .get(CONTENT_LENGTH)
+                    .and_then(|ct_len| ct_len.to_str().ok())
+                    .and_then(|ct_len| ct_len.parse::<u64>().ok()),
+                content_type: headers
+                    .get("content-type")
+                    .and_then(|ct| ct.to_str().ok())
+                    .map(str::to_string),
+                content_encoding: headers
+                    .get("content-encoding")
+                    .and_then(|ce| ce.to_str().ok())
+                    .map(str::to_string),
+                accepts_ranges: headers
+                    .get("accept-ranges")
+                    .and_then(|h| h.to_str().ok())
+                    .map(|s| s == "bytes")
+                    .unwrap_or(false),
+                headers: header_pairs(headers),
+                public_key_pin: peer_certificate(&response).and_then(tls::public_key_pin),
+            })
+        })
+    }
+
+    fn open<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        self.open_with_trailers(url, start, end, Trailers::default())
+    }
+
+    fn open_with_trailers<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        trailers: Trailers,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(self.fetch(url, start, end, trailers, None))
+    }
+
+    fn open_prioritized<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        urgency: u8,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(self.fetch(url, start, end, Trailers::default(), Some(urgency)))
+    }
+
+    fn fetch_ranges<'a>(
+        &'a self,
+        url: &'a str,
+        ranges: &'a [(u64, u64)],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, BoxError>> {
+        Box::pin(self.fetch_multipart(url, ranges))
+    }
+
+    fn list<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<Entry>, BoxError>> {
+        Box::pin(listing::list(self.clients.for_url(url), url, false))
+    }
+}
+
+fn header_pairs(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect()
+}
+
+// First and last byte of `bytes <first>-<last>/<total or *>`
+pub(crate) fn parse_content_range(header: &str) -> Option<(u64, u64)> {
+    let range = header.trim().strip_prefix("bytes")?.trim_start();
+    let (span, _total) = range.split_once('/')?;
+    let (first, last) = span.split_once('-')?;
+    let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    (first <= last).then_some((first, last))
+}
+
+// Bytes from `first` to `last` inclusive, None when that cannot be counted
+// (a range the server made up may end at u64::MAX)
+pub(crate) fn range_len(first: u64, last: u64) -> Option<u64> {
+    last.checked_sub(first)?.checked_add(1)
+}
+
+// The length in `bytes */<total>`, which comes with a 416
+fn unsatisfied_length(header: &str) -> Option<u64> {
+    let range = header.trim().strip_prefix("bytes")?.trim_start();
+    range.strip_prefix('*')?.trim_start().strip_prefix('/')?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::StaticHeaders;
+    use crate::mock_server::{Behavior, MockServer};
+
+    // A server that, when the client accepts gzip, compresses whatever slice it
+    // was asked for, so gzipped ranges do not concatenate into the gzipped file
+    async fn compressing_server() -> MockServer {
+        let behavior = Behavior {
+            compress: true,
+            ..Behavior::default()
+        };
+        MockServer::start(4096, behavior).await.unwrap()
+    }
+
+    // A handler whose requests accept gzip, as a tag policy or plugin can arrange
+    fn gzip_handler() -> HttpHandler {
+        let hooks: Vec<Arc<dyn Hook>> = vec![Arc::new(StaticHeaders(vec![(
+            "Accept-Encoding".to_string(),
+            "gzip".to_string(),
+        )]))];
+        HttpHandler {
+            clients: HostClients::new(Arc::new(Client::builder().no_proxy().build().unwrap())),
+            hooks: Arc::new(hooks),
+        }
+    }
+
+    async fn read_all(handler: &HttpHandler, url: &str, start: u64, end: Option<u64>) -> Vec<u8> {
+        let mut stream = handler.open(url, start, end).await.unwrap();
+        let mut body = Vec::new();
+        while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        body
+    }
+
+    fn accept_encoding(head: &str) -> Vec<&str> {
+        head.lines()
+            .filter_map(|l| l.strip_prefix("accept-encoding:"))
+            .map(str::trim)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn segment_requests_ask_for_identity_encoding() {
+        let server = compressing_server().await;
+        let handler = gzip_handler();
+
+        let mut file = Vec::new();
+        for (start, end) in [(0, 1023), (1024, 2047), (2048, 4095)] {
+            file.extend(read_all(&handler, &server.url("file.bin"), start, Some(end)).await);
+        }
+
+        assert_eq!(file, server.data());
+        for head in server.requests() {
+            assert_eq!(accept_encoding(&head), ["identity"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn resumed_requests_ask_for_identity_encoding() {
+        let server = compressing_server().await;
+
+        let tail = read_all(&gzip_handler(), &server.url("file.bin"), 3000, None).await;
+
+        assert_eq!(tail, server.data()[3000..]);
+        assert_eq!(accept_encoding(&server.requests()[0]), ["identity"]);
+    }
+
+    #[tokio::test]
+    async fn whole_file_requests_keep_negotiated_compression() {
+        let server = compressing_server().await;
+
+        let body = read_all(&gzip_handler(), &server.url("file.bin"), 0, None).await;
+
+        assert_eq!(&body[..2], b"\x1f\x8b");
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, server.data());
+        assert_eq!(accept_encoding(&server.requests()[0]), ["gzip"]);
+    }
+
+    #[test]
+    fn range_lengths_from_the_server_do_not_overflow() {
+        assert_eq!(range_len(0, 0), Some(1));
+        assert_eq!(range_len(10, 19), Some(10));
+        assert_eq!(range_len(5, 4), None);
+        assert_eq!(range_len(0, u64::MAX), None);
+    }
+
+    #[tokio::test]
+    async fn ranges_past_the_end_report_the_length() {
+        let server = compressing_server().await;
+
+        let error = match gzip_handler().open(&server.url("file.bin"), 4096, None).await {
+            Err(e) => e,
+            Ok(_) => panic!("a range past the end was served"),
+        };
+
+        let unsatisfiable = error.downcast_ref::<RangeNotSatisfiable>().unwrap();
+        assert_eq!(unsatisfiable.length, Some(4096));
+        assert_eq!(unsatisfied_length("bytes */0"), Some(0));
+        assert_eq!(unsatisfied_length("bytes 0-1/2"), None);
+    }
+
+    #[test]
+    fn hosts_get_the_client_of_their_most_specific_pattern() {
+        let client = || Arc::new(Client::new());
+        let (default, internal, billing) = (client(), client(), client());
+        let mut clients = HostClients::new(default.clone());
+        clients.add("*.internal.example", internal.clone());
+        clients.add("billing.internal.example", billing.clone());
+        assert!(Arc::ptr_eq(clients.for_url("https://db.internal.example/dump"), &internal));
+        assert!(Arc::ptr_eq(clients.for_url("https://billing.internal.example/x"), &billing));
+        assert!(Arc::ptr_eq(clients.for_url("https://example.org/"), &default));
+    }
+}
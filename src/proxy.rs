@@ -0,0 +1,113 @@
+use crate::pattern::host_matches;
+use crate::protocol::BoxError;
+use crate::system_proxy::SystemProxy;
+use reqwest::Url;
+use std::collections::BTreeMap;
+
+// Where the requests to one host go
+enum Route {
+    Direct,
+    // Whatever the system's PAC file, WPAD or settings pick
+    System,
+    Via(Url),
+}
+
+// Per-host proxy choices from the config file: hosts reached directly, and
+// host patterns mapped to proxies. Hosts neither covers use the proxy from
+// HTTPS_PROXY / HTTP_PROXY / ALL_PROXY unless NO_PROXY lists them, since
+// a client with its own proxy rules no longer looks at those by itself.
+pub struct ProxyRules {
+    direct: Vec<String>,
+    routes: Vec<(String, Route)>,
+    env_https: Option<Url>,
+    env_http: Option<Url>,
+    env_direct: Vec<String>,
+    system: SystemProxy,
+}
+
+impl ProxyRules {
+    // `routes` values are proxy URLs, "direct" or "auto"
+    pub fn new(direct: &[String], routes: &BTreeMap<String, String>) -> Result<Self, BoxError> {
+        let routes = routes
+            .iter()
+            .map(|(hosts, proxy)| {
+                let route = match proxy.as_str() {
+                    "direct" => Route::Direct,
+                    "auto" => Route::System,
+                    url => Route::Via(
+                        Url::parse(url).map_err(|e| format!("Invalid proxy '{}' for '{}': {}", url, hosts, e))?,
+                    ),
+                };
+                Ok((hosts.to_ascii_lowercase(), route))
+            })
+            .collect::<Result<Vec<_>, BoxError>>()?;
+        let env = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+                .and_then(|v| Url::parse(&v).ok())
+        };
+        let env_all = env(&["all_proxy", "ALL_PROXY"]);
+        Ok(Self {
+            direct: direct.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            routes,
+            env_https: env(&["https_proxy", "HTTPS_PROXY"]).or_else(|| env_all.clone()),
+            env_http: env(&["http_proxy", "HTTP_PROXY"]).or(env_all),
+            env_direct: no_proxy_list(std::env::var("no_proxy").or_else(|_| std::env::var("NO_PROXY")).ok()),
+            system: SystemProxy::new(),
+        })
+    }
+
+    // The proxy for `url`, or None to connect directly. The direct list wins;
+    // among the patterns that match the host, the most specific (longest) does.
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        if self.direct.iter().any(|p| host_matches(p, &host)) {
+            return None;
+        }
+        let route = self
+            .routes
+            .iter()
+            .filter(|(pattern, _)| host_matches(pattern, &host))
+            .max_by_key(|(pattern, _)| pattern.trim_matches('*').len());
+        match route {
+            Some((_, Route::Direct)) => None,
+            Some((_, Route::System)) => self.system.proxy_for(url),
+            Some((_, Route::Via(proxy))) => Some(proxy.clone()),
+            None if self.env_direct.iter().any(|p| host_matches(p, &host)) => None,
+            None if url.scheme() == "https" => self.env_https.clone(),
+            None => self.env_http.clone(),
+        }
+    }
+}
+
+fn no_proxy_list(value: Option<String>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_most_specific_rule_wins() {
+        let routes = BTreeMap::from([
+            ("*".to_string(), "http://proxy.corp:3128".to_string()),
+            ("*.partner.example".to_string(), "http://partner-gw:8080".to_string()),
+            ("mirror.partner.example".to_string(), "direct".to_string()),
+        ]);
+        let rules = ProxyRules::new(&["*.corp.example".to_string(), "10.0.0.0/8".to_string()], &routes).unwrap();
+        let proxy = |url: &str| rules.proxy_for(&Url::parse(url).unwrap()).map(|u| u.to_string());
+        assert_eq!(proxy("https://example.org/f"), Some("http://proxy.corp:3128/".to_string()));
+        assert_eq!(proxy("https://dl.partner.example/f"), Some("http://partner-gw:8080/".to_string()));
+        assert_eq!(proxy("https://mirror.partner.example/f"), None);
+        assert_eq!(proxy("http://build.corp.example/f"), None);
+        assert_eq!(proxy("http://10.1.2.3/f"), None);
+        assert!(ProxyRules::new(&[], &BTreeMap::from([("*".to_string(), "not a url".to_string())])).is_err());
+    }
+}
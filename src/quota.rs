@@ -0,0 +1,155 @@
+use crate::progress::{Event, ProgressObserver, Snapshot};
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A limit on the bytes transferred by all the downloads of a run. Once it is
+// reached no new download should start; downloads in flight finish.
+#[derive(Clone)]
+pub struct Quota {
+    limit: u64,
+    daily: bool,
+    usage: Arc<Mutex<Usage>>,
+}
+
+struct Usage {
+    day: u64,
+    used: u64,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            daily: false,
+            usage: Arc::new(Mutex::new(Usage { day: today(), used: 0 })),
+        }
+    }
+
+    // For runs that never end: the count starts over at midnight UTC
+    pub fn daily(limit: u64) -> Self {
+        Self {
+            daily: true,
+            ..Self::new(limit)
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn used(&self) -> u64 {
+        self.usage().used
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+
+    // An observer for one download's `Progress` that counts its bytes against
+    // the quota as they arrive. Bytes a download resumed from are not counted.
+    pub fn meter(&self) -> Arc<dyn ProgressObserver> {
+        Arc::new(Meter {
+            quota: self.clone(),
+            counted: Mutex::new((0, 0)),
+        })
+    }
+
+    fn usage(&self) -> std::sync::MutexGuard<'_, Usage> {
+        let mut usage = self.usage.lock().unwrap();
+        if self.daily && usage.day != today() {
+            *usage = Usage { day: today(), used: 0 };
+        }
+        usage
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400
+}
+
+struct Meter {
+    quota: Quota,
+    // Bytes resumed from, and bytes counted so far
+    counted: Mutex<(u64, u64)>,
+}
+
+impl ProgressObserver for Meter {
+    fn on_progress(&self, snapshot: &Snapshot) {
+        let mut counted = self.counted.lock().unwrap();
+        let transferred = snapshot.bytes.saturating_sub(counted.0);
+        if transferred > counted.1 {
+            self.quota.usage().used += transferred - counted.1;
+            counted.1 = transferred;
+        }
+    }
+
+    fn on_event(&self, event: &Event) {
+        if let Event::Started { resumed_from, .. } = event {
+            *self.counted.lock().unwrap() = (*resumed_from, 0);
+        }
+    }
+}
+
+// A download was not started because the quota was used up
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub limit: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download quota of {} reached; not started", indicatif::HumanBytes(self.limit))
+    }
+}
+
+impl Error for QuotaExceeded {}
+
+// Bytes, or with a K, M, G or T suffix (binary units; a trailing B or iB is allowed)
+pub fn parse(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let number = trimmed.trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let (number, multiplier) = match number.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('M') => (&number[..number.len() - 1], 1 << 20),
+        Some('G') => (&number[..number.len() - 1], 1 << 30),
+        Some('T') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .ok()
+        .filter(|n: &f64| n.is_finite() && *n > 0.0)
+        .ok_or_else(|| format!("'{}' is not a size like 20G or 500M", value))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Progress;
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse("20G"), Ok(20 << 30));
+        assert_eq!(parse("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse("500m"), Ok(500 << 20));
+        assert_eq!(parse("4096"), Ok(4096));
+        assert!(parse("lots").is_err());
+        assert!(parse("0").is_err());
+    }
+
+    #[test]
+    fn only_transferred_bytes_count() {
+        let quota = Quota::new(1000);
+        let progress = Progress::new();
+        progress.observe(quota.meter());
+        progress.start("file.part".as_ref(), Some(2000), 1500, 1, 1);
+        progress.advance(0, 300);
+        progress.finish();
+        assert_eq!(quota.used(), 300);
+        assert!(!quota.exhausted());
+    }
+}
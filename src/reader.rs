@@ -0,0 +1,274 @@
+use crate::protocol::{BoxError, ProtocolHandler, Registry};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::task::JoinHandle;
+
+// Random access to a remote file through ranged requests, e.g. to read a zip's
+// central directory or a parquet footer without downloading the rest. The file
+// is fetched in blocks; the blocks after the one being read are fetched in
+// parallel ahead of time, and recently read blocks are kept for re-reads.
+pub struct ParallelHttpReader {
+    handler: Arc<dyn ProtocolHandler>,
+    url: String,
+    len: u64,
+    block_size: u64,
+    read_ahead: u64,
+    position: u64,
+    cache: BlockCache,
+    pending: HashMap<u64, JoinHandle<Result<Bytes, BoxError>>>,
+}
+
+impl ParallelHttpReader {
+    // Probe `url`, which must report its length and accept ranges. Starts with
+    // 1 MiB blocks, 4 blocks of read-ahead and 16 cached blocks.
+    pub async fn open(registry: &Registry, url: &str) -> Result<Self, BoxError> {
+        let handler = registry.handler_for(url)?;
+        let probe = registry.probe(url).await?;
+        let len = match (probe.length, probe.accepts_ranges) {
+            (Some(len), true) => len,
+            (None, _) => return Err(format!("Cannot read '{}' in ranges: the server did not send its length", url).into()),
+            (_, false) => return Err(format!("Cannot read '{}' in ranges: the server does not accept ranges", url).into()),
+        };
+        Ok(Self {
+            handler,
+            url: url.to_string(),
+            len,
+            block_size: 1024 * 1024,
+            read_ahead: 4,
+            position: 0,
+            cache: BlockCache::new(16),
+            pending: HashMap::new(),
+        })
+    }
+
+    // Bytes per request; smaller blocks waste less on scattered small reads
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    // Blocks fetched ahead of the one being read (0 disables read-ahead)
+    pub fn with_read_ahead(mut self, blocks: u64) -> Self {
+        self.read_ahead = blocks;
+        self
+    }
+
+    // Blocks kept after they were read
+    pub fn with_cache_blocks(mut self, blocks: usize) -> Self {
+        self.cache = BlockCache::new(blocks.max(1));
+        self
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Start fetching `block` and the read-ahead after it, and abandon fetches
+    // outside that window (left over from before a seek)
+    fn prefetch(&mut self, block: u64) {
+        let last = (self.len - 1) / self.block_size;
+        let window = block..=last.min(block + self.read_ahead);
+        self.pending.retain(|b, fetch| {
+            let keep = window.contains(b);
+            if !keep {
+                fetch.abort();
+            }
+            keep
+        });
+        for b in window {
+            if self.cache.contains(b) || self.pending.contains_key(&b) {
+                continue;
+            }
+            let start = b * self.block_size;
+            let end = (start + self.block_size).min(self.len) - 1;
+            let fetch = fetch(self.handler.clone(), self.url.clone(), start, end);
+            self.pending.insert(b, tokio::spawn(fetch));
+        }
+    }
+}
+
+impl Drop for ParallelHttpReader {
+    fn drop(&mut self) {
+        for fetch in self.pending.values() {
+            fetch.abort();
+        }
+    }
+}
+
+async fn fetch(handler: Arc<dyn ProtocolHandler>, url: String, start: u64, end: u64) -> Result<Bytes, BoxError> {
+    let expected = end - start + 1;
+    let mut stream = handler.open(&url, start, Some(end)).await?;
+    let mut data = BytesMut::with_capacity(expected as usize);
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    if data.len() as u64 != expected {
+        return Err(format!("Received {} of {} bytes for range {}-{}", data.len(), expected, start, end).into());
+    }
+    Ok(data.freeze())
+}
+
+impl AsyncRead for ParallelHttpReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position >= this.len || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let block = this.position / this.block_size;
+        this.prefetch(block);
+        let data = match this.cache.get(block) {
+            Some(data) => data,
+            None => {
+                let fetch = this.pending.get_mut(&block).expect("prefetch starts the block being read");
+                let result = ready!(Pin::new(fetch).poll(cx));
+                this.pending.remove(&block);
+                let data = result.map_err(io::Error::other)?.map_err(io::Error::other)?;
+                this.cache.insert(block, data.clone());
+                data
+            }
+        };
+        let offset = (this.position - block * this.block_size) as usize;
+        let n = buf.remaining().min(data.len() - offset);
+        buf.put_slice(&data[offset..offset + n]);
+        this.position += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for ParallelHttpReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        // Past the end is allowed, as with files; reads there return nothing
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = target.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+// The most recently read blocks
+struct BlockCache {
+    capacity: usize,
+    blocks: HashMap<u64, Bytes>,
+    // Least recently used first
+    order: VecDeque<u64>,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, block: u64) -> bool {
+        self.blocks.contains_key(&block)
+    }
+
+    fn get(&mut self, block: u64) -> Option<Bytes> {
+        let data = self.blocks.get(&block)?.clone();
+        self.touch(block);
+        Some(data)
+    }
+
+    fn insert(&mut self, block: u64, data: Bytes) {
+        self.blocks.insert(block, data);
+        self.touch(block);
+        while self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.blocks.remove(&evicted);
+            }
+        }
+    }
+
+    fn touch(&mut self, block: u64) {
+        self.order.retain(|&b| b != block);
+        self.order.push_back(block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use reqwest::Client;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    fn registry() -> Registry {
+        let client = Client::builder().no_proxy().build().unwrap();
+        Registry::with_defaults(Arc::new(client), Vec::new())
+    }
+
+    #[tokio::test]
+    async fn reads_the_whole_file_in_blocks() {
+        let server = MockServer::start(1_000_000, Behavior::default()).await.unwrap();
+        let mut reader = ParallelHttpReader::open(&registry(), &server.url("file.bin"))
+            .await
+            .unwrap()
+            .with_block_size(64 * 1024)
+            .with_cache_blocks(2);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, server.data());
+        // HEAD and one GET per block
+        assert_eq!(server.requests().len(), 1 + 1_000_000usize.div_ceil(64 * 1024));
+    }
+
+    #[tokio::test]
+    async fn seeks_fetch_only_the_blocks_read() {
+        let server = MockServer::start(12 * 1024 * 1024, Behavior::default()).await.unwrap();
+        let mut reader = ParallelHttpReader::open(&registry(), &server.url("file.bin"))
+            .await
+            .unwrap()
+            .with_block_size(64 * 1024);
+
+        // A footer, then a record in the middle
+        let mut footer = [0u8; 100];
+        assert_eq!(reader.seek(SeekFrom::End(-100)).await.unwrap(), 12 * 1024 * 1024 - 100);
+        reader.read_exact(&mut footer).await.unwrap();
+        assert_eq!(footer[..], server.data()[server.data().len() - 100..]);
+        let mut record = [0u8; 1000];
+        reader.seek(SeekFrom::Start(5_000_000)).await.unwrap();
+        reader.read_exact(&mut record).await.unwrap();
+        assert_eq!(record[..], server.data()[5_000_000..5_001_000]);
+        // Reading the footer again is served from the cache
+        reader.seek(SeekFrom::End(-100)).await.unwrap();
+        reader.read_exact(&mut footer).await.unwrap();
+
+        let gets = server.requests().iter().filter(|r| r.starts_with("get ")).count();
+        assert!(gets <= 1 + 5, "{} requests", gets);
+        assert!(reader.seek(SeekFrom::Current(-20_000_000)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn servers_without_ranges_are_refused() {
+        let behavior = Behavior {
+            no_ranges: true,
+            ..Behavior::default()
+        };
+        let server = MockServer::start(1000, behavior).await.unwrap();
+        let error = ParallelHttpReader::open(&registry(), &server.url("file.bin")).await.err().unwrap();
+        assert!(error.to_string().contains("does not accept ranges"), "{}", error);
+    }
+}
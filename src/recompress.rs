@@ -0,0 +1,124 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+// How a downloaded file is compressed again with `--recompress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    // gzip's compression level, 1 to 9
+    pub level: u32,
+}
+
+// clap value parser for `--recompress FORMAT[:LEVEL]`
+pub fn parse(spec: &str) -> Result<Target, String> {
+    let (format, level) = match spec.split_once(':') {
+        Some((format, level)) => (format, Some(level)),
+        None => (spec, None),
+    };
+    match format.to_ascii_lowercase().as_str() {
+        "gzip" | "gz" => {}
+        "zstd" => return Err("zstd is not available in this build of Gator; use gzip[:LEVEL]".to_string()),
+        other => return Err(format!("Unknown format '{}'; use gzip[:LEVEL]", other)),
+    }
+    let level = match level {
+        None => flate2::Compression::default().level(),
+        Some(level) => match level.parse() {
+            Ok(level @ 1..=9) => level,
+            _ => return Err(format!("'{}' is not a gzip level from 1 to 9", level)),
+        },
+    };
+    Ok(Target { level })
+}
+
+// What recompressing one file did
+#[derive(Debug, Clone)]
+pub struct Recompressed {
+    pub path: PathBuf,
+    pub before: u64,
+    pub after: u64,
+}
+
+// Compress the file at `path` to `target` in a single streaming pass,
+// decoding it first when it is gzip already, and replace it with
+// `<name>.gz`. The original stays until the new file is complete. This
+// blocks.
+pub fn recompress(path: &Path, target: Target) -> io::Result<Recompressed> {
+    let before = fs::metadata(path)?.len();
+    let mut input = BufReader::new(fs::File::open(path)?);
+    let gzipped = input.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    if gzipped {
+        if let Some(stem) = name.to_str().and_then(|n| n.strip_suffix(".gz")) {
+            name = stem.into();
+        }
+    }
+    name.push(".gz");
+    let dest = path.with_file_name(&name);
+    name.push(".gator-recompress");
+    let tmp = path.with_file_name(name);
+
+    let result = (|| {
+        let mut decoded: Box<dyn Read> = match gzipped {
+            true => Box::new(MultiGzDecoder::new(input)),
+            false => Box::new(input),
+        };
+        let file = BufWriter::new(fs::File::create(&tmp)?);
+        let mut encoder = GzEncoder::new(file, flate2::Compression::new(target.level));
+        io::copy(&mut decoded, &mut encoder)?;
+        let file = encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &dest)
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if dest != path {
+        fs::remove_file(path)?;
+    }
+    Ok(Recompressed {
+        after: fs::metadata(&dest)?.len(),
+        path: dest,
+        before,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn files_are_compressed_again_as_gzip() {
+        assert_eq!(parse("gzip:9"), Ok(Target { level: 9 }));
+        assert_eq!(parse("gzip").map(|t| t.level), Ok(6));
+        assert!(parse("gzip:12").is_err());
+        assert!(parse("zstd:19").is_err());
+
+        let dir = std::env::temp_dir().join(format!("gator-recompress-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text = "a line of a large text dataset\n".repeat(1000);
+
+        // A plain file gains a .gz
+        let plain = dir.join("data.csv");
+        fs::write(&plain, &text).unwrap();
+        let done = recompress(&plain, Target { level: 9 }).unwrap();
+        assert_eq!(done.path, dir.join("data.csv.gz"));
+        assert!(!plain.exists());
+        assert!(done.after < done.before);
+
+        // A gzip file is decoded and compressed again under the same name
+        let mut fast = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        fast.write_all(text.as_bytes()).unwrap();
+        let gz = dir.join("other.csv.gz");
+        fs::write(&gz, fast.finish().unwrap()).unwrap();
+        let done = recompress(&gz, Target { level: 9 }).unwrap();
+        assert_eq!(done.path, gz);
+        let mut decoded = String::new();
+        MultiGzDecoder::new(fs::File::open(&gz).unwrap()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use crate::hooks::Hook;
+use crate::protocol::BoxError;
+
+// A rule from the config's `[[rewrite]]` tables: the first match of a
+// regular expression in the URL is replaced, with $1 (or ${1}) standing for
+// the text of the first group, $0 for the whole match and $$ for a dollar
+pub struct Rule {
+    regex: Regex,
+    replace: Vec<Piece>,
+}
+
+enum Piece {
+    Text(String),
+    Group(usize),
+}
+
+impl Rule {
+    pub fn new(pattern: &str, replace: &str) -> Result<Self, BoxError> {
+        let invalid = |e: String| format!("Invalid rewrite rule '{}': {}", pattern, e);
+        let regex = Regex::new(pattern).map_err(invalid)?;
+        let replace = parse_replace(replace, regex.groups).map_err(invalid)?;
+        Ok(Self { regex, replace })
+    }
+
+    // `url` with the rule applied, or None if it does not match
+    pub fn apply(&self, url: &str) -> Option<String> {
+        let text: Vec<char> = url.chars().collect();
+        let captures = self.regex.captures(&text)?;
+        let (start, end) = captures[0]?;
+        let mut rewritten: String = text[..start].iter().collect();
+        for piece in &self.replace {
+            match piece {
+                Piece::Text(t) => rewritten.push_str(t),
+                Piece::Group(i) => {
+                    if let Some((s, e)) = captures[*i] {
+                        rewritten.extend(&text[s..e]);
+                    }
+                }
+            }
+        }
+        rewritten.extend(&text[end..]);
+        Some(rewritten)
+    }
+}
+
+fn parse_replace(replace: &str, groups: usize) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = replace.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' || chars.next_if_eq(&'$').is_some() {
+            text.push(c);
+            continue;
+        }
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut digits = String::new();
+        while let Some(d) = chars.next_if(char::is_ascii_digit) {
+            digits.push(d);
+        }
+        if braced && chars.next_if_eq(&'}').is_none() {
+            return Err("unclosed '${' in the replacement".to_string());
+        }
+        let group = digits
+            .parse()
+            .map_err(|_| "'$' must be followed by a group number; write $$ for a dollar sign".to_string())?;
+        if group > groups {
+            return Err(format!("the replacement uses ${}, but there are only {} groups", group, groups));
+        }
+        pieces.push(Piece::Text(std::mem::take(&mut text)));
+        pieces.push(Piece::Group(group));
+    }
+    pieces.push(Piece::Text(text));
+    Ok(pieces)
+}
+
+// The config's rewrite rules, each applied in turn to what the ones before
+// it produced
+pub struct Rewrites(pub Vec<Rule>);
+
+impl Hook for Rewrites {
+    fn resolve_url(&self, url: &str) -> Result<Option<String>, BoxError> {
+        let mut rewritten: Option<String> = None;
+        for rule in &self.0 {
+            if let Some(next) = rule.apply(rewritten.as_deref().unwrap_or(url)) {
+                rewritten = Some(next);
+            }
+        }
+        Ok(rewritten)
+    }
+}
+
+// Just enough regular expression syntax for rules written by hand: literals,
+// `.`, classes such as `[a-z0-9_-]` and `[^/]`, `\d` `\w` `\s` (and their
+// negations), `^` and `$`, groups (capturing, or not with `(?:...)`),
+// alternation, and `*` `+` `?` `{n,m}`, greedy or lazy with a trailing `?`.
+// Matching backtracks, which is fine for strings the length of a URL.
+struct Regex {
+    alternatives: Vec<Vec<Node>>,
+    groups: usize,
+}
+
+enum Node {
+    Char(char),
+    Any,
+    // Inclusive ranges, and whether the class is negated
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    // The alternatives inside, and the group's number if it captures
+    Group(Vec<Vec<Node>>, Option<usize>),
+    Repeat(Box<Repeat>),
+}
+
+struct Repeat {
+    node: Node,
+    min: usize,
+    max: Option<usize>,
+    greedy: bool,
+}
+
+impl Node {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Node::Char(expected) => c == *expected,
+            Node::Any => true,
+            Node::Class(ranges, negated) => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated,
+            _ => false,
+        }
+    }
+}
+
+// Start and end of each group, in chars; group 0 is the whole match
+type Captures = Vec<Option<(usize, usize)>>;
+
+// What to do with the position reached so far; true ends the search
+type Next<'a> = &'a mut dyn FnMut(usize, &mut Captures) -> bool;
+
+impl Regex {
+    fn new(pattern: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+        };
+        let alternatives = parser.alternatives()?;
+        if parser.pos < parser.chars.len() {
+            return Err("unmatched ')'".to_string());
+        }
+        Ok(Self {
+            alternatives,
+            groups: parser.groups,
+        })
+    }
+
+    // The leftmost match
+    fn captures(&self, text: &[char]) -> Option<Captures> {
+        for start in 0..=text.len() {
+            let mut captures = vec![None; self.groups + 1];
+            let mut end = None;
+            let found = alternatives(&self.alternatives, text, start, &mut captures, &mut |pos, _| {
+                end = Some(pos);
+                true
+            });
+            if found {
+                captures[0] = Some((start, end?));
+                return Some(captures);
+            }
+        }
+        None
+    }
+}
+
+fn alternatives(alternatives: &[Vec<Node>], text: &[char], pos: usize, captures: &mut Captures, next: Next) -> bool {
+    alternatives.iter().any(|nodes| sequence(nodes, text, pos, captures, next))
+}
+
+fn sequence(nodes: &[Node], text: &[char], pos: usize, captures: &mut Captures, next: Next) -> bool {
+    let Some((node, rest)) = nodes.split_first() else {
+        return next(pos, captures);
+    };
+    match node {
+        Node::Start => pos == 0 && sequence(rest, text, pos, captures, next),
+        Node::End => pos == text.len() && sequence(rest, text, pos, captures, next),
+        Node::Group(inner, index) => alternatives(inner, text, pos, captures, &mut |end, captures| {
+            let Some(index) = *index else {
+                return sequence(rest, text, end, captures, next);
+            };
+            let saved = captures[index];
+            captures[index] = Some((pos, end));
+            if sequence(rest, text, end, captures, next) {
+                return true;
+            }
+            captures[index] = saved;
+            false
+        }),
+        Node::Repeat(repeat) => repetitions(repeat, 0, rest, text, pos, captures, next),
+        single => text.get(pos).is_some_and(|&c| single.matches(c)) && sequence(rest, text, pos + 1, captures, next),
+    }
+}
+
+// Match `repeat` after `count` repetitions so far, then `rest`
+fn repetitions(
+    repeat: &Repeat,
+    count: usize,
+    rest: &[Node],
+    text: &[char],
+    pos: usize,
+    captures: &mut Captures,
+    next: Next,
+) -> bool {
+    let again = |captures: &mut Captures, next: Next| {
+        repeat.max != Some(count)
+            && sequence(std::slice::from_ref(&repeat.node), text, pos, captures, &mut |end, captures| {
+                // Repeating an empty match would never end
+                (end != pos || count < repeat.min) && repetitions(repeat, count + 1, rest, text, end, captures, next)
+            })
+    };
+    let done = count >= repeat.min;
+    match repeat.greedy {
+        true => again(captures, next) || (done && sequence(rest, text, pos, captures, next)),
+        false => (done && sequence(rest, text, pos, captures, next)) || again(captures, next),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += usize::from(c.is_some());
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        self.pos += usize::from(found);
+        found
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.eat('|') {
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.next().unwrap_or_default() {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let index = match self.chars[self.pos..].starts_with(&['?', ':']) {
+                    true => {
+                        self.pos += 2;
+                        None
+                    }
+                    false => {
+                        self.groups += 1;
+                        Some(self.groups)
+                    }
+                };
+                let inner = self.alternatives()?;
+                if !self.eat(')') {
+                    return Err("unclosed '('".to_string());
+                }
+                Node::Group(inner, index)
+            }
+            '[' => self.class()?,
+            '\\' => {
+                let c = self.next().ok_or("trailing '\\'")?;
+                match shorthand(c) {
+                    Some((ranges, negated)) => Node::Class(ranges, negated),
+                    None => Node::Char(escaped(c)?),
+                }
+            }
+            c @ ('*' | '+' | '?' | '{') => return Err(format!("nothing to repeat before '{}'", c)),
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let mut c = self.next().ok_or("unclosed '['")?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            if c == '\\' {
+                c = self.next().ok_or("trailing '\\'")?;
+                match shorthand(c) {
+                    Some((set, false)) => {
+                        ranges.extend(set);
+                        continue;
+                    }
+                    Some((_, true)) => return Err(format!("'\\{}' cannot be used inside '[...]'", c)),
+                    None => c = escaped(c)?,
+                }
+            }
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|&n| n != ']') {
+                self.pos += 1;
+                let mut end = self.next().unwrap_or_default();
+                if end == '\\' {
+                    end = escaped(self.next().ok_or("trailing '\\'")?)?;
+                }
+                if end < c {
+                    return Err(format!("'{}-{}' is not a range", c, end));
+                }
+                ranges.push((c, end));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = if self.eat('*') {
+            (0, None)
+        } else if self.eat('+') {
+            (1, None)
+        } else if self.eat('?') {
+            (0, Some(1))
+        } else if self.eat('{') {
+            self.counts()?
+        } else {
+            return Ok(atom);
+        };
+        if matches!(atom, Node::Start | Node::End) {
+            return Err("nothing to repeat after an anchor".to_string());
+        }
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat(Box::new(Repeat {
+            node: atom,
+            min,
+            max,
+            greedy,
+        })))
+    }
+
+    // The inside of `{n}`, `{n,}` or `{n,m}`, after the `{`
+    fn counts(&mut self) -> Result<(usize, Option<usize>), String> {
+        let close = self.chars[self.pos..].iter().position(|&c| c == '}').ok_or("unclosed '{'")?;
+        let spec: String = self.chars[self.pos..self.pos + close].iter().collect();
+        self.pos += close + 1;
+        let bad = || format!("'{{{}}}' is not a repetition", spec);
+        let count = |n: &str| n.parse::<usize>().map_err(|_| bad());
+        let (min, max) = match spec.split_once(',') {
+            None => (count(&spec)?, Some(count(&spec)?)),
+            Some((min, "")) => (count(min)?, None),
+            Some((min, max)) => (count(min)?, Some(count(max)?)),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(bad());
+        }
+        Ok((min, max))
+    }
+}
+
+// The ranges `\d`, `\w` and `\s` (and their upper-case negations) stand for
+fn shorthand(c: char) -> Option<(Vec<(char, char)>, bool)> {
+    let ranges = match c.to_ascii_lowercase() {
+        'd' => vec![('0', '9')],
+        'w' => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+        's' => vec![(' ', ' '), ('\t', '\r')],
+        _ => return None,
+    };
+    Some((ranges, c.is_ascii_uppercase()))
+}
+
+// An escaped punctuation character stands for itself; escaped letters are
+// reserved, so a typo such as `\b` is not silently a `b`
+fn escaped(c: char) -> Result<char, String> {
+    match c.is_ascii_alphanumeric() {
+        true => Err(format!("unknown escape '\\{}'", c)),
+        false => Ok(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_rewrite_the_first_match() {
+        let rule = |pattern: &str, replace: &str, url: &str| Rule::new(pattern, replace).unwrap().apply(url);
+        assert_eq!(
+            rule(
+                r"^https://git\.example\.com/(.+?)/-/blob/",
+                "https://git.example.com/$1/-/raw/",
+                "https://git.example.com/team/tools/-/blob/main/a.sh"
+            )
+            .as_deref(),
+            Some("https://git.example.com/team/tools/-/raw/main/a.sh")
+        );
+        assert_eq!(
+            rule(r"([?&])dl=0(&|$)", "${1}dl=1$2", "https://files.example/s/abc/x.zip?dl=0").as_deref(),
+            Some("https://files.example/s/abc/x.zip?dl=1")
+        );
+        assert_eq!(
+            rule(r"/(\d{2,4})-([^/.]+)\.(?:htm|html)$", "/$2-$1.pdf", "https://a.example/docs/2024-report.html").as_deref(),
+            Some("https://a.example/docs/report-2024.pdf")
+        );
+        assert_eq!(rule(r"\w+\$", "$$0", "https://a.example/"), None);
+        assert_eq!(rule("[a-c]+", "<$0>", "https://a.example/").as_deref(), Some("https://<a>.example/"));
+
+        for (pattern, replace) in [("(a", ""), ("a)", ""), ("*a", ""), ("[z-a]", ""), (r"\q", ""), ("(a)", "$2"), ("a", "$x")] {
+            assert!(Rule::new(pattern, replace).is_err(), "{} -> {}", pattern, replace);
+        }
+
+        let rules = Rewrites(vec![Rule::new("^http:", "https:").unwrap(), Rule::new("/old/", "/new/").unwrap()]);
+        assert_eq!(
+            rules.resolve_url("http://a.example/old/f").unwrap().as_deref(),
+            Some("https://a.example/new/f")
+        );
+        assert_eq!(rules.resolve_url("ftp://a.example/f").unwrap(), None);
+    }
+}
@@ -0,0 +1,263 @@
+use crate::download::{self, DownloadOptions};
+use crate::hooks::{Hook, StaticHeaders};
+use crate::mock_server::{Behavior, MockServer};
+use crate::output::{Level, Output};
+use crate::protocol::{BoxError, Registry};
+use reqwest::Client;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// Large enough for the work-stealing scheduler, which starts above 10 MiB
+const SEGMENTED: usize = 12 * 1024 * 1024;
+const SINGLE: usize = 3 * 1024 * 1024;
+
+// One server behavior and what a download from it must end in
+pub struct Scenario {
+    pub name: &'static str,
+    size: usize,
+    behavior: Behavior,
+    // Ask for gzip, as a header policy or plugin can
+    accept_gzip: bool,
+    expect: Expect,
+}
+
+enum Expect {
+    // The saved file is byte for byte what the server has
+    Intact,
+    // The download fails with an error containing this text, and nothing is
+    // left under the final name
+    Fails(&'static str),
+}
+
+pub fn scenarios() -> Vec<Scenario> {
+    let scenario = |name, size, behavior, expect| Scenario {
+        name,
+        size,
+        behavior,
+        accept_gzip: false,
+        expect,
+    };
+    vec![
+        scenario("segmented download", SEGMENTED, Behavior::default(), Expect::Intact),
+        scenario("single connection", SINGLE, Behavior::default(), Expect::Intact),
+        scenario(
+            "no range support",
+            SEGMENTED,
+            Behavior {
+                no_ranges: true,
+                ..Behavior::default()
+            },
+            Expect::Intact,
+        ),
+        scenario(
+            "slow segments",
+            SEGMENTED,
+            Behavior {
+                slow: Some(Duration::from_millis(5)),
+                ..Behavior::default()
+            },
+            Expect::Intact,
+        ),
+        Scenario {
+            accept_gzip: true,
+            ..scenario(
+                "compressed ranges",
+                SEGMENTED,
+                Behavior {
+                    compress: true,
+                    ..Behavior::default()
+                },
+                Expect::Intact,
+            )
+        },
+        scenario(
+            "connection reset",
+            SINGLE,
+            Behavior {
+                reset_after: Some(SINGLE / 3),
+                ..Behavior::default()
+            },
+            Expect::Fails("body error"),
+        ),
+        scenario(
+            "segment reset",
+            SEGMENTED,
+            Behavior {
+                reset_after: Some(SEGMENTED / 100),
+                ..Behavior::default()
+            },
+            Expect::Intact,
+        ),
+        scenario(
+            "overloaded server",
+            SEGMENTED,
+            Behavior {
+                unavailable: 4,
+                ..Behavior::default()
+            },
+            Expect::Intact,
+        ),
+        scenario(
+            "bad Content-Range",
+            SEGMENTED,
+            Behavior {
+                bad_content_range: true,
+                ..Behavior::default()
+            },
+            Expect::Fails("for requested range"),
+        ),
+    ]
+}
+
+// Download from a fresh mock server into `dir` and check the outcome
+pub async fn run_scenario(scenario: &Scenario, dir: &Path) -> Result<(), BoxError> {
+    let server = MockServer::start(scenario.size, scenario.behavior.clone()).await?;
+    let url = server.url("file.bin");
+    let path = dir.join("file.bin");
+
+    let mut hooks: Vec<Arc<dyn Hook>> = Vec::new();
+    if scenario.accept_gzip {
+        hooks.push(Arc::new(StaticHeaders(vec![(
+            "Accept-Encoding".to_string(),
+            "gzip".to_string(),
+        )])));
+    }
+    // A proxy from the environment could not reach the loopback server
+    let client = Arc::new(Client::builder().no_proxy().build()?);
+    let registry = Registry::with_defaults(client, hooks);
+    let out = Output::new(Level::Silent, false, true, false);
+
+    let options = DownloadOptions::default();
+    let result = download::download_file(&registry, &url, &path, &options, &CancellationToken::new(), out).await;
+    match (&scenario.expect, result) {
+        (Expect::Intact, Ok(saved)) => {
+            let saved = std::fs::read(saved)?;
+            if saved == server.data() {
+                return Ok(());
+            }
+            let offset = saved
+                .iter()
+                .zip(server.data())
+                .position(|(a, b)| a != b)
+                .unwrap_or(saved.len().min(server.data().len()));
+            Err(format!(
+                "the saved file differs from the server's from byte {} ({} of {} bytes)",
+                offset,
+                saved.len(),
+                server.data().len()
+            )
+            .into())
+        }
+        (Expect::Intact, Err(e)) => Err(format!("the download failed: {}", e).into()),
+        (Expect::Fails(_), Ok(_)) => Err("the download succeeded".into()),
+        (Expect::Fails(message), Err(e)) => {
+            if !e.to_string().contains(message) {
+                Err(format!("failed with '{}', expected '{}'", e, message).into())
+            } else if path.exists() {
+                Err("a failed download was saved under the final name".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+// `gator self-test`: run every scenario and report each one
+pub async fn run(out: Output) -> Result<(), BoxError> {
+    let root = std::env::temp_dir().join(format!("gator-self-test-{}", std::process::id()));
+    let scenarios = scenarios();
+    let mut failed = 0;
+    for (i, scenario) in scenarios.iter().enumerate() {
+        let dir = root.join(i.to_string());
+        std::fs::create_dir_all(&dir)?;
+        match run_scenario(scenario, &dir).await {
+            Ok(()) => out.info(format!("ok    {}", scenario.name)),
+            Err(e) => {
+                failed += 1;
+                out.info(format!("FAIL  {}: {}", scenario.name, e));
+            }
+        }
+    }
+    let _ = std::fs::remove_dir_all(&root);
+
+    if failed > 0 {
+        return Err(format!("{} of {} self-test scenarios failed", failed, scenarios.len()).into());
+    }
+    out.info(format!("All {} self-test scenarios passed", scenarios.len()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check(name: &str) {
+        let scenario = scenarios().into_iter().find(|s| s.name == name).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "gator-test-{}-{}",
+            std::process::id(),
+            name.replace(' ', "-")
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let result = run_scenario(&scenario, &dir).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        if let Err(e) = result {
+            panic!("{}: {}", name, e);
+        }
+    }
+
+    #[tokio::test]
+    async fn segmented_download() {
+        check("segmented download").await;
+    }
+
+    #[tokio::test]
+    async fn single_connection() {
+        check("single connection").await;
+    }
+
+    #[tokio::test]
+    async fn no_range_support() {
+        check("no range support").await;
+    }
+
+    #[tokio::test]
+    async fn slow_segments() {
+        check("slow segments").await;
+    }
+
+    #[tokio::test]
+    async fn compressed_ranges() {
+        check("compressed ranges").await;
+    }
+
+    #[tokio::test]
+    async fn connection_reset() {
+        check("connection reset").await;
+    }
+
+    #[tokio::test]
+    async fn segment_reset() {
+        check("segment reset").await;
+    }
+
+    #[tokio::test]
+    async fn overloaded_server() {
+        check("overloaded server").await;
+    }
+
+    #[tokio::test]
+    async fn bad_content_range() {
+        check("bad Content-Range").await;
+    }
+
+    #[test]
+    fn scenario_names_are_unique() {
+        let scenarios = scenarios();
+        for (i, scenario) in scenarios.iter().enumerate() {
+            assert!(scenarios[..i].iter().all(|s| s.name != scenario.name), "{}", scenario.name);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::hooks::Hook;
+use crate::protocol::{BoxError, Probe};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::Url;
+
+const DRIVE_DOWNLOAD: &str = "https://drive.usercontent.google.com/download";
+
+// Turns the share links of Google Drive, OneDrive and Dropbox, and files
+// viewed on code hosts, which lead to a page about the file, into links to
+// the file itself
+pub struct ShareLinks;
+
+impl Hook for ShareLinks {
+    fn resolve_url(&self, url: &str) -> Result<Option<String>, BoxError> {
+        let Ok(parsed) = Url::parse(url.trim()) else {
+            return Ok(None);
+        };
+        Ok(drive(&parsed)
+            .or_else(|| onedrive(&parsed))
+            .or_else(|| dropbox(&parsed))
+            .or_else(|| code_host(&parsed)))
+    }
+
+    // A page from Drive's download endpoint means the file could not be
+    // handed out, and would otherwise be saved as if it were the file
+    fn inspect_probe(&self, url: &str, probe: &mut Probe) -> Result<(), BoxError> {
+        let html = probe.content_type.as_deref().is_some_and(|t| t.starts_with("text/html"));
+        if html && url.starts_with(DRIVE_DOWNLOAD) {
+            return Err("Google Drive sent a page instead of the file; it may not be shared publicly, or too many people have downloaded it recently".into());
+        }
+        Ok(())
+    }
+}
+
+// drive.google.com/file/d/ID/view, /open?id=ID and /uc?id=ID. `confirm=t`
+// answers the "can't scan this file for viruses" page that large files get
+// before it is shown.
+fn drive(url: &Url) -> Option<String> {
+    if url.host_str() != Some("drive.google.com") {
+        return None;
+    }
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    let id = match segments.as_slice() {
+        ["file", "d", id, ..] => id.to_string(),
+        ["open" | "uc"] => url.query_pairs().find(|(k, _)| k == "id")?.1.into_owned(),
+        _ => return None,
+    };
+    let mut direct = Url::parse(DRIVE_DOWNLOAD).ok()?;
+    direct
+        .query_pairs_mut()
+        .append_pair("id", &id)
+        .append_pair("export", "download")
+        .append_pair("confirm", "t");
+    Some(direct.to_string())
+}
+
+// Personal OneDrive links go through the shares API, which redirects to the
+// file; SharePoint (OneDrive for work) links only need `download=1`
+fn onedrive(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    if host == "1drv.ms" || host == "onedrive.live.com" {
+        let encoded = URL_SAFE_NO_PAD.encode(url.as_str());
+        return Some(format!("https://api.onedrive.com/v1.0/shares/u!{}/root/content", encoded));
+    }
+    // Sharing links look like /:x:/g/personal/... or /:u:/r/sites/...
+    let sharing = url.path().starts_with("/:") && url.path()[2..].starts_with(|c: char| c.is_ascii_alphabetic());
+    if host.ends_with(".sharepoint.com") && sharing && !url.query_pairs().any(|(k, _)| k == "download") {
+        let mut direct = url.clone();
+        direct.query_pairs_mut().append_pair("download", "1");
+        return Some(direct.to_string());
+    }
+    None
+}
+
+// dropbox.com/s/..., /sh/... and /scl/fi/... links show a preview unless
+// they ask for `dl=1`, which sends the file (or a folder as a zip)
+fn dropbox(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    let shared = ["/s/", "/sh/", "/scl/fi/", "/scl/fo/"].iter().any(|p| url.path().starts_with(p));
+    if !matches!(host, "dropbox.com" | "www.dropbox.com") || !shared {
+        return None;
+    }
+    if url.query_pairs().any(|(k, v)| (k == "dl" || k == "raw") && v == "1") {
+        return None;
+    }
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| k != "dl")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    let mut direct = url.clone();
+    direct.query_pairs_mut().clear().extend_pairs(pairs).append_pair("dl", "1");
+    Some(direct.to_string())
+}
+
+// Files viewed on GitHub, GitLab or Hugging Face, rather than the raw file
+fn code_host(url: &Url) -> Option<String> {
+    let mut direct = url.clone();
+    match url.host_str()? {
+        "github.com" => {
+            let segments: Vec<&str> = url.path_segments()?.collect();
+            let [owner, repo, "blob", rest @ ..] = segments.as_slice() else {
+                return None;
+            };
+            if rest.len() < 2 {
+                return None;
+            }
+            return Some(format!("https://raw.githubusercontent.com/{}/{}/{}", owner, repo, rest.join("/")));
+        }
+        "gitlab.com" => direct.set_path(&url.path().replacen("/-/blob/", "/-/raw/", 1)),
+        "huggingface.co" => direct.set_path(&url.path().replacen("/blob/", "/resolve/", 1)),
+        _ => return None,
+    }
+    (direct != *url).then(|| direct.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_links_lead_to_the_file() {
+        let resolve = |url: &str| ShareLinks.resolve_url(url).unwrap();
+        let direct = "https://drive.usercontent.google.com/download?id=1AbC_d-9&export=download&confirm=t";
+        assert_eq!(resolve("https://drive.google.com/file/d/1AbC_d-9/view?usp=sharing").as_deref(), Some(direct));
+        assert_eq!(resolve("https://drive.google.com/open?id=1AbC_d-9").as_deref(), Some(direct));
+        assert_eq!(resolve("https://drive.google.com/uc?id=1AbC_d-9&export=download").as_deref(), Some(direct));
+        assert_eq!(resolve("https://drive.google.com/drive/folders/1AbC"), None);
+
+        assert_eq!(
+            resolve("https://1drv.ms/u/s!AkD8").as_deref(),
+            Some("https://api.onedrive.com/v1.0/shares/u!aHR0cHM6Ly8xZHJ2Lm1zL3UvcyFBa0Q4/root/content")
+        );
+        assert_eq!(
+            resolve("https://contoso.sharepoint.com/:x:/g/personal/me/EaBc?e=x1").as_deref(),
+            Some("https://contoso.sharepoint.com/:x:/g/personal/me/EaBc?e=x1&download=1")
+        );
+        assert_eq!(resolve("https://contoso.sharepoint.com/sites/team/report.xlsx"), None);
+
+        assert_eq!(
+            resolve("https://www.dropbox.com/scl/fi/abc/data.zip?rlkey=k1&dl=0").as_deref(),
+            Some("https://www.dropbox.com/scl/fi/abc/data.zip?rlkey=k1&dl=1")
+        );
+        assert_eq!(
+            resolve("https://www.dropbox.com/s/abc/data.zip").as_deref(),
+            Some("https://www.dropbox.com/s/abc/data.zip?dl=1")
+        );
+        assert_eq!(resolve("https://www.dropbox.com/s/abc/data.zip?dl=1"), None);
+        assert_eq!(
+            resolve("https://github.com/me/tool/blob/v1.2/dist/tool.tar.gz").as_deref(),
+            Some("https://raw.githubusercontent.com/me/tool/v1.2/dist/tool.tar.gz")
+        );
+        assert_eq!(resolve("https://github.com/me/tool/releases/download/v1.2/tool.tar.gz"), None);
+        assert_eq!(
+            resolve("https://huggingface.co/datasets/org/set/blob/main/train.parquet").as_deref(),
+            Some("https://huggingface.co/datasets/org/set/resolve/main/train.parquet")
+        );
+
+        let mut page = Probe {
+            status: "200 OK".to_string(),
+            length: None,
+            content_type: Some("text/html; charset=utf-8".to_string()),
+            content_encoding: None,
+            accepts_ranges: false,
+            headers: Vec::new(),
+            public_key_pin: None,
+        };
+        assert!(ShareLinks.inspect_probe(direct, &mut page).is_err());
+        assert!(ShareLinks.inspect_probe("https://example.com/", &mut page).is_ok());
+    }
+}
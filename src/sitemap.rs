@@ -0,0 +1,199 @@
+use crate::listing::{xml_blocks, xml_text};
+use flate2::read::GzDecoder;
+use reqwest::Client;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::io::Read;
+
+#[derive(Debug, Clone)]
+pub struct SitemapEntry {
+    pub url: String,
+    pub lastmod: Option<String>,
+}
+
+// Fetch a sitemap and return every page it lists, following sitemap indexes
+// (and gzipped sitemaps) recursively
+pub async fn collect(
+    client: &Client,
+    url: &str,
+) -> Result<Vec<SitemapEntry>, Box<dyn Error + Send + Sync>> {
+    let mut entries = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([url.to_string()]);
+
+    while let Some(url) = queue.pop_front() {
+        if !visited.insert(url.clone()) {
+            continue;
+        }
+
+        let xml = fetch(client, &url).await?;
+        if xml.contains("<sitemapindex") {
+            for block in xml_blocks(&xml, "sitemap") {
+                if let Some(loc) = xml_text(block, "loc") {
+                    queue.push_back(loc);
+                }
+            }
+        } else {
+            for block in xml_blocks(&xml, "url") {
+                if let Some(loc) = xml_text(block, "loc") {
+                    entries.push(SitemapEntry {
+                        url: loc,
+                        lastmod: xml_text(block, "lastmod"),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// clap value parser for `--since`: a date as YYYY-MM-DD, which is compared
+// with lastmod as text, so it must have exactly that shape
+pub fn parse_date(s: &str) -> Result<String, String> {
+    let invalid = || format!("'{}' is not a date as YYYY-MM-DD", s);
+    let parts: Vec<&str> = s.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let digits = |part: &str, len: usize| part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(year, 4) || !digits(month, 2) || !digits(day, 2) {
+        return Err(invalid());
+    }
+    let (month, day): (u32, u32) = (month.parse().unwrap(), day.parse().unwrap());
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    Ok(s.to_string())
+}
+
+// True when the entry was modified on or after `since` (YYYY-MM-DD). Entries
+// without a lastmod are kept, since there is nothing to compare.
+pub fn modified_since(entry: &SitemapEntry, since: &str) -> bool {
+    match &entry.lastmod {
+        // W3C datetimes compare correctly as strings once cut to the date
+        Some(lastmod) => lastmod.get(..10).unwrap_or(lastmod) >= since,
+        None => true,
+    }
+}
+
+async fn fetch(client: &Client, url: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let response = client.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch sitemap {}: {}", url, response.status()).into());
+    }
+    let body = response.bytes().await?;
+
+    // `sitemap.xml.gz` is served as a plain gzip file, not with Content-Encoding
+    if body.starts_with(&[0x1f, 0x8b]) {
+        let mut xml = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut xml)?;
+        Ok(xml)
+    } else {
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Serves a different body per path, built once the address is known, and
+    // counts the requests for each
+    async fn serve(
+        pages: impl FnOnce(&str) -> HashMap<String, Vec<u8>>,
+    ) -> (String, Arc<Mutex<HashMap<String, usize>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let pages = Arc::new(pages(&base));
+        let hits = Arc::new(Mutex::new(HashMap::new()));
+        let counted = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut head = vec![0u8; 4096];
+                let n = socket.read(&mut head).await.unwrap_or(0);
+                let head = String::from_utf8_lossy(&head[..n]);
+                let path = head.split_whitespace().nth(1).unwrap_or("/").to_string();
+                *counted.lock().unwrap().entry(path.clone()).or_insert(0) += 1;
+                let response = match pages.get(&path) {
+                    Some(body) => {
+                        let mut response =
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+                                .into_bytes();
+                        response.extend_from_slice(body);
+                        response
+                    }
+                    None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                };
+                let _ = socket.write_all(&response).await;
+            }
+        });
+        (base, hits)
+    }
+
+    fn index(locs: &[String]) -> Vec<u8> {
+        let sitemaps: String = locs.iter().map(|loc| format!("<sitemap><loc>{}</loc></sitemap>", loc)).collect();
+        format!("<?xml version=\"1.0\"?><sitemapindex>{}</sitemapindex>", sitemaps).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn indexes_are_followed_once_each_including_gzipped_sitemaps() {
+        let (base, hits) = serve(|base| {
+            let mut gzipped = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gzipped
+                .write_all(b"<urlset><url><loc>https://example.com/b?x=1&amp;y=2</loc></url></urlset>")
+                .unwrap();
+            HashMap::from([
+                // The index lists itself, and another index that lists it back
+                ("/index.xml".to_string(), index(&[format!("{}/index.xml", base), format!("{}/more.xml", base)])),
+                ("/more.xml".to_string(), index(&[format!("{}/index.xml", base), format!("{}/pages.xml.gz", base)])),
+                ("/pages.xml.gz".to_string(), gzipped.finish().unwrap()),
+                (
+                    "/other.xml".to_string(),
+                    b"<urlset><url><loc>https://example.com/a</loc><lastmod>2024-03-01T10:00:00Z</lastmod></url></urlset>"
+                        .to_vec(),
+                ),
+            ])
+        })
+        .await;
+
+        let entries = collect(&Client::new(), &format!("{}/index.xml", base)).await.unwrap();
+        let urls: Vec<&str> = entries.iter().map(|e| e.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/b?x=1&y=2"]);
+        let hits = hits.lock().unwrap().clone();
+        assert_eq!(hits.get("/index.xml"), Some(&1));
+        assert_eq!(hits.get("/more.xml"), Some(&1));
+        assert_eq!(hits.get("/pages.xml.gz"), Some(&1));
+
+        let entries = collect(&Client::new(), &format!("{}/other.xml", base)).await.unwrap();
+        assert_eq!(entries[0].lastmod.as_deref(), Some("2024-03-01T10:00:00Z"));
+        assert!(collect(&Client::new(), &format!("{}/missing.xml", base)).await.is_err());
+    }
+
+    #[test]
+    fn entries_are_kept_from_the_since_date_on() {
+        let entry = |lastmod: Option<&str>| SitemapEntry {
+            url: "https://example.com/".to_string(),
+            lastmod: lastmod.map(str::to_string),
+        };
+        assert!(modified_since(&entry(Some("2024-03-01T10:00:00+02:00")), "2024-03-01"));
+        assert!(modified_since(&entry(Some("2024-12-31")), "2024-03-01"));
+        assert!(!modified_since(&entry(Some("2024-02-29T23:59:59Z")), "2024-03-01"));
+        assert!(!modified_since(&entry(Some("2023")), "2024-03-01"));
+        assert!(modified_since(&entry(None), "2024-03-01"));
+    }
+
+    #[test]
+    fn since_dates_must_be_yyyy_mm_dd() {
+        assert_eq!(parse_date("2024-03-01").unwrap(), "2024-03-01");
+        for date in ["2024-3-1", "2024/03/01", "24-03-01", "2024-13-01", "2024-00-10", "2024-03-32", "yesterday", ""] {
+            assert!(parse_date(date).is_err(), "{}", date);
+        }
+    }
+}
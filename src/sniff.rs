@@ -0,0 +1,217 @@
+use std::io::Read;
+use std::path::Path;
+
+// A binary format that can be recognized by the bytes at a fixed offset
+struct Signature {
+    name: &'static str,
+    extensions: &'static [&'static str],
+    offset: usize,
+    magic: &'static [u8],
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        name: "gzip",
+        extensions: &["gz", "tgz"],
+        offset: 0,
+        magic: b"\x1f\x8b",
+    },
+    Signature {
+        name: "xz",
+        extensions: &["xz", "txz"],
+        offset: 0,
+        magic: b"\xfd7zXZ\x00",
+    },
+    Signature {
+        name: "bzip2",
+        extensions: &["bz2", "tbz2"],
+        offset: 0,
+        magic: b"BZh",
+    },
+    Signature {
+        name: "zstd",
+        extensions: &["zst"],
+        offset: 0,
+        magic: b"\x28\xb5\x2f\xfd",
+    },
+    Signature {
+        name: "7-Zip",
+        extensions: &["7z"],
+        offset: 0,
+        magic: b"7z\xbc\xaf\x27\x1c",
+    },
+    Signature {
+        name: "ZIP",
+        extensions: &[
+            "zip", "jar", "apk", "whl", "docx", "xlsx", "pptx", "odt", "epub",
+        ],
+        offset: 0,
+        magic: b"PK",
+    },
+    Signature {
+        name: "tar",
+        extensions: &["tar"],
+        offset: 257,
+        magic: b"ustar",
+    },
+    Signature {
+        name: "ISO 9660",
+        extensions: &["iso"],
+        offset: 32769,
+        magic: b"CD001",
+    },
+    Signature {
+        name: "PDF",
+        extensions: &["pdf"],
+        offset: 0,
+        magic: b"%PDF-",
+    },
+    Signature {
+        name: "PNG",
+        extensions: &["png"],
+        offset: 0,
+        magic: b"\x89PNG",
+    },
+    Signature {
+        name: "JPEG",
+        extensions: &["jpg", "jpeg"],
+        offset: 0,
+        magic: b"\xff\xd8\xff",
+    },
+    Signature {
+        name: "GIF",
+        extensions: &["gif"],
+        offset: 0,
+        magic: b"GIF8",
+    },
+    Signature {
+        name: "Debian package",
+        extensions: &["deb"],
+        offset: 0,
+        magic: b"!<arch>",
+    },
+    Signature {
+        name: "RPM",
+        extensions: &["rpm"],
+        offset: 0,
+        magic: b"\xed\xab\xee\xdb",
+    },
+    Signature {
+        name: "WebAssembly",
+        extensions: &["wasm"],
+        offset: 0,
+        magic: b"\x00asm",
+    },
+    Signature {
+        name: "Windows executable",
+        extensions: &["exe", "dll"],
+        offset: 0,
+        magic: b"MZ",
+    },
+];
+
+fn signature_for(path: &Path) -> Option<&'static Signature> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    SIGNATURES
+        .iter()
+        .find(|s| s.extensions.contains(&extension.as_str()))
+}
+
+pub fn is_html_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.eq_ignore_ascii_case("text/html") || mime.eq_ignore_ascii_case("application/xhtml+xml")
+}
+
+// An HTML page served under a name that promises a binary format, judged by
+// the Content-Type alone so it can be caught before anything is downloaded
+pub fn check_content_type(path: &Path, content_type: Option<&str>) -> Option<String> {
+    let signature = signature_for(path)?;
+    let content_type = content_type.filter(|ct| is_html_type(ct))?;
+    Some(format!(
+        "'{}' is served as {}, not as a {} file (an error or login page?)",
+        file_name(path),
+        content_type,
+        signature.name
+    ))
+}
+
+// Compare the start of the downloaded file at `data` with the format its
+// destination `path` promises. Empty files are left alone.
+pub fn check_file(path: &Path, data: &Path) -> std::io::Result<Option<String>> {
+    let Some(signature) = signature_for(path) else {
+        return Ok(None);
+    };
+    let mut head = Vec::new();
+    std::fs::File::open(data)?
+        .take((signature.offset + signature.magic.len()).max(1024) as u64)
+        .read_to_end(&mut head)?;
+    if head.is_empty()
+        || head
+            .get(signature.offset..)
+            .is_some_and(|h| h.starts_with(signature.magic))
+    {
+        return Ok(None);
+    }
+    let mut problem = format!(
+        "'{}' does not start like a {} file",
+        file_name(path),
+        signature.name
+    );
+    if crate::html::looks_like_html(Path::new(""), &head) {
+        problem.push_str("; it looks like an HTML page (an error or login page?)");
+    }
+    Ok(Some(problem))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_served_for_a_binary_name_is_caught() {
+        let archive = Path::new("dir/dataset.tar.gz");
+        assert_eq!(
+            check_content_type(archive, Some("Text/HTML; charset=utf-8")).as_deref(),
+            Some("'dataset.tar.gz' is served as Text/HTML; charset=utf-8, not as a gzip file (an error or login page?)")
+        );
+        assert_eq!(check_content_type(archive, Some("application/gzip")), None);
+        assert_eq!(check_content_type(archive, None), None);
+        assert_eq!(check_content_type(Path::new("page.html"), Some("text/html")), None);
+        assert!(check_content_type(Path::new("IMAGE.ISO"), Some("application/xhtml+xml")).is_some());
+    }
+
+    #[test]
+    fn files_are_checked_against_their_magic_bytes() {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-sniff", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let check = |name: &str, data: &[u8]| {
+            let saved = dir.join("saved");
+            std::fs::write(&saved, data).unwrap();
+            check_file(Path::new(name), &saved).unwrap()
+        };
+
+        assert_eq!(check("dataset.tar.gz", b"\x1f\x8b\x08\x00rest"), None);
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(check("backup.tar", &tar), None);
+        assert_eq!(check("notes.txt", b"anything"), None);
+        assert_eq!(check("empty.zip", b""), None);
+
+        let login = b"<!DOCTYPE html>\n<html><head><title>Sign in</title></head><body><form>...</form></body></html>";
+        assert_eq!(
+            check("dataset.tar.gz", login).as_deref(),
+            Some("'dataset.tar.gz' does not start like a gzip file; it looks like an HTML page (an error or login page?)")
+        );
+        assert_eq!(
+            check("report.pdf", b"PK\x03\x04").as_deref(),
+            Some("'report.pdf' does not start like a PDF file")
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Samples closer together than this are merged into the next one, so a burst
+// of redraws does not turn one large chunk into a spike
+const MIN_SAMPLE: Duration = Duration::from_millis(50);
+
+// clap value parser for `--speed-window`
+pub fn parse_window(spec: &str) -> Result<Duration, String> {
+    let window = crate::chaos::parse_duration(spec)?;
+    if window.is_zero() {
+        return Err("The speed window must be longer than zero".to_string());
+    }
+    Ok(window)
+}
+
+// Transfer speed of one download, for its progress display. The current speed
+// is a moving average weighted towards roughly the last `window`, so it
+// follows a change in speed without jumping with every chunk; the average
+// covers the whole session. Positions are in the whole file: the bytes
+// resumed from were not transferred this session and count for neither speed.
+pub struct SpeedMeter {
+    window: Duration,
+    resumed: u64,
+    sample: Mutex<Sample>,
+}
+
+struct Sample {
+    started: Instant,
+    at: Instant,
+    // Position in the file as of `at`
+    pos: u64,
+    speed: Option<f64>,
+}
+
+impl SpeedMeter {
+    pub fn new(window: Duration, resumed: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            window,
+            resumed,
+            sample: Mutex::new(Sample {
+                started: now,
+                at: now,
+                pos: resumed,
+                speed: None,
+            }),
+        }
+    }
+
+    // Bytes that were on disk before this session
+    pub fn resumed(&self) -> u64 {
+        self.resumed
+    }
+
+    // Note that the file is written up to `pos` by `now` and return the
+    // current speed in bytes per second
+    pub fn update(&self, pos: u64, now: Instant) -> f64 {
+        let mut sample = self.sample.lock().unwrap();
+        let elapsed = now.saturating_duration_since(sample.at);
+        if elapsed < MIN_SAMPLE {
+            return sample.speed.unwrap_or(0.0);
+        }
+        let rate = pos.saturating_sub(sample.pos) as f64 / elapsed.as_secs_f64();
+        // A sample weighs as much as the share of the window it covers, however
+        // often the display is redrawn
+        let weight = 1.0 - (-elapsed.as_secs_f64() / self.window.as_secs_f64()).exp();
+        let speed = match sample.speed {
+            Some(speed) => speed + weight * (rate - speed),
+            None => rate,
+        };
+        *sample = Sample {
+            at: now,
+            pos,
+            speed: Some(speed),
+            ..*sample
+        };
+        speed
+    }
+
+    // The current speed as of the last update
+    pub fn current(&self) -> f64 {
+        self.sample.lock().unwrap().speed.unwrap_or(0.0)
+    }
+
+    // Bytes per second over the whole session
+    pub fn average(&self, pos: u64, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.sample.lock().unwrap().started);
+        match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => pos.saturating_sub(self.resumed) as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    // Time left to reach `total` at the current speed, once there is one
+    pub fn eta(&self, pos: u64, total: u64) -> Option<Duration> {
+        let speed = self.current();
+        match total.saturating_sub(pos) {
+            0 => Some(Duration::ZERO),
+            left => (speed > 0.0).then(|| Duration::from_secs_f64(left as f64 / speed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_follows_changes_within_the_window() {
+        // Resumed at 1000 bytes
+        let meter = SpeedMeter::new(Duration::from_secs(2), 1000);
+        let start = meter.sample.lock().unwrap().started;
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        assert_eq!(meter.update(1100, at(100)), 1000.0);
+        // Redraws in quick succession do not count as samples
+        assert_eq!(meter.update(1150, at(120)), 1000.0);
+
+        // The speed doubles: a moment later the average has not caught up, a
+        // few windows later it has
+        let mut pos = 1100;
+        for ms in (200..=10_000).step_by(100) {
+            pos += 200;
+            let speed = meter.update(pos, at(ms));
+            if ms == 200 {
+                assert!(speed > 1000.0 && speed < 1200.0, "{}", speed);
+            }
+        }
+        assert!((meter.current() - 2000.0).abs() < 50.0, "{}", meter.current());
+        let average = meter.average(pos, at(10_000));
+        assert!(average > 1900.0 && average < 2000.0, "{}", average);
+
+        let eta = meter.eta(pos, 41_800).unwrap();
+        assert!(eta > Duration::from_secs(9) && eta < Duration::from_secs(12), "{:?}", eta);
+        assert_eq!(parse_window("5s").unwrap(), Duration::from_secs(5));
+        assert!(parse_window("0s").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// The proxy the system picks for each URL, for `--proxy auto`: the one a
+// proxy auto-config (PAC) file or WPAD names, or the one set in the network
+// settings. Answers are kept per scheme, host and port, since asking runs a
+// helper program; that is also all of the URL a connection gets to see.
+#[derive(Default)]
+pub struct SystemProxy {
+    answers: Mutex<HashMap<String, Option<Url>>>,
+}
+
+impl SystemProxy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The proxy for `url`, or None to connect directly (also when the system
+    // cannot tell). This blocks the first time a host is seen, while the lock
+    // keeps other requests from asking the same question at once.
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let key = format!(
+            "{}://{}:{}",
+            url.scheme(),
+            url.host_str().unwrap_or(""),
+            url.port_or_known_default().unwrap_or(0)
+        );
+        let mut answers = self.answers.lock().unwrap();
+        answers
+            .entry(key)
+            .or_insert_with(|| lookup(url.as_str()).as_deref().and_then(first_usable))
+            .clone()
+    }
+}
+
+// libproxy's `proxy` tool reads URLs on stdin and answers each with a list of
+// proxies; it follows the desktop's settings, PAC files and WPAD
+#[cfg(not(windows))]
+fn lookup(url: &str) -> Option<String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    let mut child = Command::new("proxy")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    child.stdin.take()?.write_all(format!("{}\n", url).as_bytes()).ok()?;
+    let output = child.wait_with_output().ok().filter(|o| o.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).lines().next()?.to_string())
+}
+
+// WinINet's proxy for the URL, which runs the PAC file or WPAD when Windows
+// is set to. The URL itself comes back when there is no proxy.
+#[cfg(windows)]
+fn lookup(url: &str) -> Option<String> {
+    let script = format!(
+        "[System.Net.WebRequest]::GetSystemWebProxy().GetProxy([Uri]'{}').AbsoluteUri",
+        url.replace('\'', "''")
+    );
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let proxy = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let direct = Url::parse(url).ok().map(|u| u.to_string());
+    Some(if Some(&proxy) == direct.as_ref() {
+        "direct://".to_string()
+    } else {
+        proxy
+    })
+}
+
+// The first HTTP(S) proxy of a list like "http://proxy:3128, direct://", in
+// the order the system prefers them. A list that puts a direct connection
+// first, or offers only kinds of proxy Gator cannot use, means none.
+fn first_usable(proxies: &str) -> Option<Url> {
+    proxies
+        .split([',', ' ', ';'])
+        .filter(|p| !p.is_empty())
+        .map_while(|p| Url::parse(p).ok().filter(|u| u.scheme() != "direct"))
+        .find(|u| matches!(u.scheme(), "http" | "https"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_http_proxy_is_used() {
+        let proxy = |list| first_usable(list).map(|u| u.to_string());
+        assert_eq!(proxy("http://proxy.corp:3128"), Some("http://proxy.corp:3128/".to_string()));
+        assert_eq!(
+            proxy("socks5://socks.corp:1080 https://proxy.corp:8443 direct://"),
+            Some("https://proxy.corp:8443/".to_string())
+        );
+        assert_eq!(proxy("direct://"), None);
+        assert_eq!(proxy("direct://, http://proxy.corp:3128"), None);
+        assert_eq!(proxy(""), None);
+    }
+}
@@ -0,0 +1,588 @@
+use crate::paths;
+use crate::protocol::{BoxError, Probe, Registry};
+use crate::reader::ParallelHttpReader;
+use flate2::read::GzDecoder;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+const BLOCK: u64 = 512;
+
+// An entry of a tar archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub name: String,
+    pub size: u64,
+    pub kind: Kind,
+    pub mode: u32,
+    // "YYYY-MM-DD HH:MM", in UTC
+    pub modified: String,
+    // Where the member's data starts in the (uncompressed) archive
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    // From the file name, which is all there is before the first byte arrives
+    pub fn for_url(url: &str) -> Result<Self, BoxError> {
+        let path = url.split(['?', '#']).next().unwrap_or(url).to_ascii_lowercase();
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Ok(Compression::Gzip)
+        } else if [".tar.zst", ".tzst", ".tar.xz", ".txz", ".tar.bz2", ".tbz2"]
+            .iter()
+            .any(|e| path.ends_with(e))
+        {
+            Err("Only uncompressed and gzip-compressed tar archives are supported".into())
+        } else {
+            Ok(Compression::None)
+        }
+    }
+}
+
+// The member list of one version of an archive, so later runs can skip the
+// pass over the archive that listing it takes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Index {
+    pub url: String,
+    pub compression: Compression,
+    // What identifies this version of the file: its length and ETag or Last-Modified
+    length: Option<u64>,
+    validator: Option<String>,
+    pub members: Vec<Member>,
+}
+
+impl Index {
+    fn new(url: &str, compression: Compression, probe: &Probe, members: Vec<Member>) -> Self {
+        Self {
+            url: url.to_string(),
+            compression,
+            length: probe.length,
+            validator: probe.validator(),
+            members,
+        }
+    }
+
+    // The index at `path`, if it describes the archive as the server has it now
+    pub fn load(path: &Path, url: &str, probe: &Probe) -> Option<Self> {
+        let index: Self = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+        let current = index.url == url && index.length == probe.length && index.validator == probe.validator();
+        // Without a validator a same-sized new version cannot be told apart
+        (current && index.validator.is_some()).then_some(index)
+    }
+
+    // Where the index of `url` is kept below `dir`
+    pub fn file_in(dir: &Path, url: &str) -> PathBuf {
+        let digest = Sha256::digest(url.as_bytes());
+        let name: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        dir.join(format!("{}.json", name))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), BoxError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let part = paths::part_path(path);
+        std::fs::write(&part, serde_json::to_vec(self)?)?;
+        std::fs::rename(&part, path)?;
+        Ok(())
+    }
+}
+
+// List an archive: from `cached` when it is still current, by reading only
+// the member headers through ranges when the archive is uncompressed, or
+// else by streaming through all of it
+pub async fn index(registry: &Registry, url: &str, cached: Option<&Path>) -> Result<Index, BoxError> {
+    let compression = Compression::for_url(url)?;
+    let probe = registry.probe(url).await?;
+    if let Some(index) = cached.and_then(|path| Index::load(path, url, &probe)) {
+        return Ok(index);
+    }
+    let members = if compression == Compression::None && probe.accepts_ranges && probe.length.is_some() {
+        // Small blocks without read-ahead: only the headers are wanted
+        let reader = ParallelHttpReader::open(registry, url)
+            .await?
+            .with_block_size(64 * 1024)
+            .with_read_ahead(0);
+        let mut source = Seeking(SyncIoBridge::new(reader));
+        tokio::task::spawn_blocking(move || walk(&mut source, |_, _| Ok(true))).await??
+    } else {
+        stream(registry, url, compression, |_, _| Ok(true)).await?
+    };
+    let index = Index::new(url, compression, &probe, members);
+    if let Some(path) = cached {
+        index.save(path)?;
+    }
+    Ok(index)
+}
+
+// Extract `files` (members of `index`, each with its destination). An
+// uncompressed archive on a server with ranges is read member by member; a
+// compressed one is read from the start up to the end of the last member
+// wanted. Returns the bytes written for each file.
+pub async fn extract(
+    registry: &Registry,
+    url: &str,
+    index: &Index,
+    files: Vec<(Member, PathBuf)>,
+) -> Result<Vec<(Member, PathBuf, u64)>, BoxError> {
+    let probe = registry.probe(url).await?;
+
+    if index.compression == Compression::None && probe.accepts_ranges {
+        let handler = registry.handler_for(url)?;
+        let mut extracted = Vec::new();
+        for (member, dest) in files {
+            let part = paths::part_path(&dest);
+            let mut file = tokio::fs::File::from_std(paths::create_private(&part)?);
+            let mut written = 0;
+            if member.size > 0 {
+                let mut data = handler.open(url, member.offset, Some(member.offset + member.size - 1)).await?;
+                while let Some(chunk) = data.next().await {
+                    let chunk = chunk?;
+                    file.write_all(&chunk).await?;
+                    written += chunk.len() as u64;
+                }
+            }
+            file.flush().await?;
+            drop(file);
+            finish(&part, &dest, &member, written)?;
+            extracted.push((member, dest, written));
+        }
+        return Ok(extracted);
+    }
+
+    let Some(last) = files.iter().map(|(m, _)| m.offset + m.size).max() else {
+        return Ok(Vec::new());
+    };
+    let extracted = Arc::new(Mutex::new(Vec::new()));
+    let saved = extracted.clone();
+    stream(registry, url, index.compression, move |member, data| {
+        if let Some((_, dest)) = files.iter().find(|(m, _)| m.offset == member.offset) {
+            let written = save(member, data, dest)?;
+            saved.lock().unwrap().push((member.clone(), dest.clone(), written));
+        }
+        Ok(member.offset + member.size < last)
+    })
+    .await?;
+    let extracted = std::mem::take(&mut *extracted.lock().unwrap());
+    Ok(extracted)
+}
+
+// Download the archive from the start through a decoder and walk it, calling
+// `visit` as in `walk`
+async fn stream<F>(registry: &Registry, url: &str, compression: Compression, visit: F) -> Result<Vec<Member>, BoxError>
+where
+    F: FnMut(&Member, &mut dyn Read) -> Result<bool, BoxError> + Send + 'static,
+{
+    let body = registry.handler_for(url)?.open(url, 0, None).await?;
+    let body = SyncIoBridge::new(StreamReader::new(body.map(|chunk| chunk.map_err(io::Error::other))));
+    tokio::task::spawn_blocking(move || match compression {
+        Compression::None => walk(&mut Streaming(body), visit),
+        Compression::Gzip => walk(&mut Streaming(GzDecoder::new(body)), visit),
+    })
+    .await?
+}
+
+// A member's data into `dest`, through a part file
+fn save(member: &Member, data: &mut dyn Read, dest: &Path) -> Result<u64, BoxError> {
+    let part = paths::part_path(dest);
+    let mut file = paths::create_private(&part)?;
+    let written = io::copy(data, &mut file);
+    drop(file);
+    match written {
+        Ok(written) => finish(&part, dest, member, written).map(|_| written),
+        Err(e) => {
+            let _ = std::fs::remove_file(&part);
+            Err(e.into())
+        }
+    }
+}
+
+fn finish(part: &Path, dest: &Path, member: &Member, written: u64) -> Result<(), BoxError> {
+    if written != member.size {
+        let _ = std::fs::remove_file(part);
+        return Err(format!("'{}': received {} of {} bytes", member.name, written, member.size).into());
+    }
+    paths::finalize(part, dest)?;
+    Ok(())
+}
+
+// Where a member is saved below the output directory: its path in the
+// archive, without anything that could climb out of the directory
+pub fn relative_path(member: &Member) -> PathBuf {
+    member
+        .name
+        .split('/')
+        .filter(|s| !matches!(*s, "" | "." | ".."))
+        .map(paths::sanitize_file_name)
+        .collect()
+}
+
+// How the archive's bytes are read: a ranged reader seeks over member data,
+// a stream has to read through it
+trait Source: Read {
+    fn skip(&mut self, n: u64) -> io::Result<()>;
+}
+
+struct Seeking<R>(R);
+
+impl<R: Read> Read for Seeking<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read + Seek> Source for Seeking<R> {
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        self.0.seek(SeekFrom::Current(n as i64)).map(|_| ())
+    }
+}
+
+struct Streaming<R>(R);
+
+impl<R: Read> Read for Streaming<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read> Source for Streaming<R> {
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        io::copy(&mut (&mut self.0).take(n), &mut io::sink()).map(|_| ())
+    }
+}
+
+// Read the archive's headers in order, calling `visit` with every member and
+// a reader over its data, until `visit` returns false or the archive ends.
+// Returns the members seen.
+fn walk<S: Source>(
+    source: &mut S,
+    mut visit: impl FnMut(&Member, &mut dyn Read) -> Result<bool, BoxError>,
+) -> Result<Vec<Member>, BoxError> {
+    let mut members = Vec::new();
+    let mut offset = 0;
+    // Set by GNU long name ('L') and pax ('x') entries for the next member
+    let mut long_name: Option<String> = None;
+    let mut pax_size: Option<u64> = None;
+    loop {
+        let mut block = [0u8; BLOCK as usize];
+        match source.read_exact(&mut block) {
+            Ok(()) => {}
+            // Some writers leave out the two zero blocks at the end
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !members.is_empty() => break,
+            Err(e) => return Err(e.into()),
+        }
+        offset += BLOCK;
+        if block.iter().all(|&b| b == 0) {
+            break;
+        }
+        let header = Header::parse(&block)?;
+        let size = pax_size.take().unwrap_or(header.size);
+        let padded = size.div_ceil(BLOCK) * BLOCK;
+
+        match header.typeflag {
+            b'L' | b'x' => {
+                let mut data = Vec::new();
+                (&mut *source).take(size).read_to_end(&mut data)?;
+                source.skip(padded - size)?;
+                offset += padded;
+                if header.typeflag == b'L' {
+                    long_name = Some(String::from_utf8_lossy(&data).trim_end_matches('\0').to_string());
+                } else {
+                    for (key, value) in pax_records(&data) {
+                        match key {
+                            "path" => long_name = Some(value.to_string()),
+                            "size" => pax_size = value.parse().ok(),
+                            _ => {}
+                        }
+                    }
+                }
+                continue;
+            }
+            // Global pax headers and long link names
+            b'g' | b'K' => {
+                source.skip(padded)?;
+                offset += padded;
+                continue;
+            }
+            _ => {}
+        }
+
+        let member = Member {
+            name: long_name.take().unwrap_or(header.name),
+            size,
+            kind: match header.typeflag {
+                b'0' | b'\0' | b'7' => Kind::File,
+                b'5' => Kind::Dir,
+                b'2' => Kind::Symlink,
+                b'1' => Kind::Hardlink,
+                _ => Kind::Other,
+            },
+            mode: header.mode,
+            modified: utc_time(header.modified),
+            offset,
+        };
+        // Links and directories carry no data even when a size is given
+        let data_len = if matches!(member.kind, Kind::File | Kind::Other) { size } else { 0 };
+        let mut data = (&mut *source).take(data_len);
+        let more = visit(&member, &mut data)?;
+        let unread = data.limit();
+        members.push(member);
+        if !more {
+            break;
+        }
+        source.skip(unread + data_len.div_ceil(BLOCK) * BLOCK - data_len)?;
+        offset += data_len.div_ceil(BLOCK) * BLOCK;
+    }
+    Ok(members)
+}
+
+struct Header {
+    name: String,
+    mode: u32,
+    size: u64,
+    modified: u64,
+    typeflag: u8,
+}
+
+impl Header {
+    fn parse(block: &[u8; 512]) -> Result<Self, BoxError> {
+        // The checksum is computed with its own field read as spaces
+        let sum: u64 = block
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+            .sum();
+        if octal(&block[148..156]) != Some(sum) {
+            return Err("Not a tar archive (bad header checksum)".into());
+        }
+        let mut name = text(&block[0..100]);
+        // ustar splits long paths into a prefix and a name
+        if &block[257..262] == b"ustar" {
+            let prefix = text(&block[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+        Ok(Self {
+            name,
+            mode: octal(&block[100..108]).unwrap_or(0) as u32,
+            size: number(&block[124..136]).ok_or("Not a tar archive (bad size field)")?,
+            modified: number(&block[136..148]).unwrap_or(0),
+            typeflag: block[156],
+        })
+    }
+}
+
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = text(field);
+    let digits = digits.trim_matches([' ', '\0']);
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+// Octal, or big-endian binary (GNU) for values too large for the field
+fn number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            value = value.checked_mul(256)? + u64::from(b);
+        }
+        return Some(value);
+    }
+    octal(field)
+}
+
+fn utc_time(secs: u64) -> String {
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let (days, rest) = ((secs / 86400) as i64 + 719_468, secs % 86400);
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, rest / 3600, rest % 3600 / 60)
+}
+
+// "LEN KEY=VALUE\n" records
+fn pax_records(data: &[u8]) -> Vec<(&str, &str)> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space]).ok().and_then(|l| l.parse::<usize>().ok()) else {
+            break;
+        };
+        if len <= space + 1 || len > rest.len() {
+            break;
+        }
+        if let Ok(record) = std::str::from_utf8(&rest[space + 1..len - 1]) {
+            if let Some((key, value)) = record.split_once('=') {
+                records.push((key, value));
+            }
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use flate2::write::GzEncoder;
+    use reqwest::Client;
+    use std::io::Write;
+
+    fn header(name: &str, size: usize, typeflag: u8) -> [u8; 512] {
+        let mut block = [0u8; 512];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        block[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        // 2024-05-06 12:34:00 UTC
+        block[136..147].copy_from_slice(format!("{:011o}", 1_714_998_840).as_bytes());
+        block[156] = typeflag;
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+        block[148..156].fill(b' ');
+        let sum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+        block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        block
+    }
+
+    fn push_entry(tar: &mut Vec<u8>, name: &str, content: &[u8], typeflag: u8) {
+        tar.extend_from_slice(&header(name, content.len(), typeflag));
+        tar.extend_from_slice(content);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    // A minimal archive writer: (name, content), directories ending in '/',
+    // names over 100 bytes with a GNU long name entry
+    fn build_tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, content) in files {
+            let typeflag = if name.ends_with('/') { b'5' } else { b'0' };
+            if name.len() > 100 {
+                push_entry(&mut tar, "././@LongLink", name.as_bytes(), b'L');
+                push_entry(&mut tar, &name[..100], content, typeflag);
+            } else {
+                push_entry(&mut tar, name, content, typeflag);
+            }
+        }
+        tar.extend_from_slice(&[0; 1024]);
+        tar
+    }
+
+    fn registry() -> Registry {
+        let client = Client::builder().no_proxy().build().unwrap();
+        Registry::with_defaults(Arc::new(client), Vec::new())
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn gets(server: &MockServer) -> usize {
+        server.requests().iter().filter(|r| r.starts_with("get ")).count()
+    }
+
+    #[tokio::test]
+    async fn uncompressed_archives_are_read_through_ranges() {
+        let big: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+        let long_name = format!("data/{}/small.csv", "nested".repeat(20));
+        let small = b"id,value\n1,2\n".repeat(50);
+        let tar = build_tar(&[("data/", b""), ("data/big.bin", &big), (&long_name, &small)]);
+        let server = MockServer::serve(tar, Behavior::default()).await.unwrap();
+        let registry = registry();
+        let url = server.url("archive.tar");
+
+        let index = index(&registry, &url, None).await.unwrap();
+        let names: Vec<&str> = index.members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["data/", "data/big.bin", long_name.as_str()]);
+        assert_eq!(index.members[0].kind, Kind::Dir);
+        assert_eq!((index.members[1].size, index.members[1].mode), (big.len() as u64, 0o644));
+        assert_eq!(index.members[2].modified, "2024-05-06 12:34");
+
+        let dir = temp_dir("tar-ranges");
+        let dest = dir.join(relative_path(&index.members[2]));
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        let extracted = extract(&registry, &url, &index, vec![(index.members[2].clone(), dest.clone())])
+            .await
+            .unwrap();
+        assert_eq!(extracted[0].2, small.len() as u64);
+        assert_eq!(std::fs::read(&dest).unwrap(), small);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Two header blocks and the member; the 4 MB member alone would take 62 blocks
+        assert!(gets(&server) <= 3, "{} requests", gets(&server));
+    }
+
+    #[tokio::test]
+    async fn gzip_archives_are_streamed_and_indexed_once() {
+        let files: Vec<(String, Vec<u8>)> = (0..5).map(|i| (format!("part-{}.txt", i), vec![b'a' + i; 3000])).collect();
+        let entries: Vec<(&str, &[u8])> = files.iter().map(|(n, c)| (n.as_str(), c.as_slice())).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&build_tar(&entries)).unwrap();
+        let server = MockServer::serve(encoder.finish().unwrap(), Behavior::default()).await.unwrap();
+        let registry = registry();
+        let url = server.url("archive.tar.gz");
+        let dir = temp_dir("tar-gzip");
+        let cached = Index::file_in(&dir.join("index"), &url);
+
+        let index = index(&registry, &url, Some(&cached)).await.unwrap();
+        assert_eq!(index.members.len(), 5);
+        assert_eq!(gets(&server), 1);
+        // A second listing comes from the cache
+        let again = super::index(&registry, &url, Some(&cached)).await.unwrap();
+        assert_eq!(again.members.len(), 5);
+        assert_eq!(gets(&server), 1);
+
+        let dest = dir.join("part-1.txt");
+        let extracted = extract(&registry, &url, &index, vec![(index.members[1].clone(), dest.clone())])
+            .await
+            .unwrap();
+        assert_eq!(extracted.len(), 1);
+        assert_eq!(std::fs::read(&dest).unwrap(), files[1].1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unsupported_compression_is_refused_by_name() {
+        assert_eq!(Compression::for_url("https://x/a.tgz?sig=1").unwrap(), Compression::Gzip);
+        assert_eq!(Compression::for_url("https://x/a.tar").unwrap(), Compression::None);
+        assert!(Compression::for_url("https://x/a.tar.zst").is_err());
+        assert_eq!(utc_time(0), "1970-01-01 00:00");
+        assert_eq!(utc_time(951_825_600), "2000-02-29 12:00");
+    }
+}
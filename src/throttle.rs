@@ -0,0 +1,180 @@
+use crate::listing::Entry;
+use crate::protocol::{BoxError, ByteStream, Probe, ProtocolHandler, Trailers};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Keeps downloads under a number of bytes a second, so a run leaves room on a
+// shared link. Bytes are paid for as they arrive; a response that is not read
+// fills the socket buffers and TCP slows the server down.
+pub struct Bucket {
+    rate: f64,
+    burst: f64,
+    // Bytes in the bucket at an instant, below zero while arrivals are paid off
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    // `rate` bytes a second, of which a tenth of a second's worth may arrive
+    // at once
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            burst: rate / 10.0,
+            state: Mutex::new((rate / 10.0, Instant::now())),
+        }
+    }
+
+    // When `len` bytes that arrived at `now` are paid for. A chunk larger than
+    // the bucket goes through and is paid off by the next ones waiting longer.
+    fn reserve(&self, len: usize, now: Instant) -> Instant {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, filled) = *state;
+        let now = now.max(filled);
+        let tokens = (tokens + (now - filled).as_secs_f64() * self.rate).min(self.burst) - len as f64;
+        *state = (tokens, now);
+        if tokens >= 0.0 {
+            now
+        } else {
+            now + Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+
+    pub async fn take(&self, len: usize) {
+        let at = self.reserve(len, Instant::now());
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+// Wraps a handler so the bodies of its responses arrive no faster than a rate
+// shared by every request, and optionally one for each response on its own.
+// Probes and listings are small and pass through.
+pub struct ThrottledHandler {
+    inner: Arc<dyn ProtocolHandler>,
+    shared: Option<Arc<Bucket>>,
+    per_connection: Option<u64>,
+}
+
+impl ThrottledHandler {
+    pub fn wrap(
+        shared: Option<Arc<Bucket>>,
+        per_connection: Option<u64>,
+        inner: Arc<dyn ProtocolHandler>,
+    ) -> Arc<dyn ProtocolHandler> {
+        Arc::new(Self {
+            inner,
+            shared,
+            per_connection,
+        })
+    }
+
+    // Pay for each chunk before the next one is read
+    fn throttle(&self, stream: ByteStream) -> ByteStream {
+        let shared = self.shared.clone();
+        let own = self.per_connection.map(|rate| Arc::new(Bucket::new(rate)));
+        Box::pin(stream.then(move |chunk| {
+            let shared = shared.clone();
+            let own = own.clone();
+            async move {
+                if let Ok(data) = &chunk {
+                    if let Some(bucket) = &shared {
+                        bucket.take(data.len()).await;
+                    }
+                    if let Some(bucket) = &own {
+                        bucket.take(data.len()).await;
+                    }
+                }
+                chunk
+            }
+        }))
+    }
+}
+
+impl ProtocolHandler for ThrottledHandler {
+    fn probe<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Probe, BoxError>> {
+        self.inner.probe(url)
+    }
+
+    fn open<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        self.open_with_trailers(url, start, end, Trailers::default())
+    }
+
+    fn open_with_trailers<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        trailers: Trailers,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(async move {
+            let stream = self.inner.open_with_trailers(url, start, end, trailers).await?;
+            Ok(self.throttle(stream))
+        })
+    }
+
+    fn open_prioritized<'a>(
+        &'a self,
+        url: &'a str,
+        start: u64,
+        end: Option<u64>,
+        urgency: u8,
+    ) -> BoxFuture<'a, Result<ByteStream, BoxError>> {
+        Box::pin(async move {
+            let stream = self.inner.open_prioritized(url, start, end, urgency).await?;
+            Ok(self.throttle(stream))
+        })
+    }
+
+    // A multipart response is read whole, so it is paid for once it is in
+    fn fetch_ranges<'a>(
+        &'a self,
+        url: &'a str,
+        ranges: &'a [(u64, u64)],
+    ) -> BoxFuture<'a, Result<Vec<Bytes>, BoxError>> {
+        Box::pin(async move {
+            let fetched = self.inner.fetch_ranges(url, ranges).await?;
+            let len = fetched.iter().map(Bytes::len).sum();
+            if let Some(bucket) = &self.shared {
+                bucket.take(len).await;
+            }
+            if let Some(rate) = self.per_connection {
+                Bucket::new(rate).take(len).await;
+            }
+            Ok(fetched)
+        })
+    }
+
+    fn list<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<Vec<Entry>, BoxError>> {
+        self.inner.list(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_past_the_burst_wait_for_the_rate() {
+        let bucket = Bucket::new(1000);
+        let now = Instant::now() + Duration::from_secs(1);
+        let ms = Duration::from_millis;
+        // A full bucket holds 100 bytes
+        assert_eq!(bucket.reserve(100, now) - now, ms(0));
+        assert_eq!(bucket.reserve(100, now) - now, ms(100));
+        // The 100ms since have paid off that debt, but not 50 more bytes
+        assert_eq!(bucket.reserve(50, now + ms(100)) - now, ms(150));
+        // A quiet spell refills the bucket no further than the burst
+        let later = now + ms(10_000);
+        assert_eq!(bucket.reserve(100, later), later);
+        assert_eq!(bucket.reserve(1, later) - later, ms(1));
+    }
+}
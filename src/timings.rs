@@ -0,0 +1,205 @@
+use crate::progress::{Event, ProgressObserver};
+use crate::protocol::BoxError;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+// How long each phase of a request to a server takes, like curl's time_*
+// variables but per phase rather than since the start
+#[derive(Debug, Clone)]
+pub struct Phases {
+    pub dns: Duration,
+    pub connect: Duration,
+    // None for plain HTTP
+    pub tls: Option<Duration>,
+    // From sending the request to the first byte of the response
+    pub first_byte: Duration,
+}
+
+impl Phases {
+    // Time from the start of the lookup to the first byte of the response
+    pub fn total(&self) -> Duration {
+        self.dns + self.connect + self.tls.unwrap_or_default() + self.first_byte
+    }
+}
+
+// Time a HEAD request for `url` on a connection of its own, made directly to
+// the server (not through a proxy), so each phase can be told apart. This
+// blocks.
+pub fn measure(url: &str) -> Result<Phases, BoxError> {
+    let parsed = reqwest::Url::parse(url)?;
+    let https = match parsed.scheme() {
+        "https" => true,
+        "http" => false,
+        scheme => return Err(format!("Cannot time the phases of a {}: request", scheme).into()),
+    };
+    let host = parsed.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let port = parsed.port_or_known_default().unwrap_or(443);
+
+    let start = Instant::now();
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    let dns = start.elapsed();
+
+    // Like the download's own connections, on to the next address when one
+    // refuses, so the time includes any failed attempts
+    let start = Instant::now();
+    let mut failure = None;
+    let stream = addrs
+        .iter()
+        .find_map(|addr| {
+            TcpStream::connect_timeout(addr, TIMEOUT)
+                .map_err(|e| failure = Some(e))
+                .ok()
+        })
+        .ok_or_else(|| match failure {
+            Some(e) => format!("Cannot connect to {}: {}", host, e),
+            None => format!("Cannot resolve '{}'", host),
+        })?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let connect = start.elapsed();
+
+    let mut path = parsed.path().to_string();
+    if let Some(query) = parsed.query() {
+        path = format!("{}?{}", path, query);
+    }
+    let authority = match parsed.port() {
+        Some(port) => format!("{}:{}", parsed.host_str().unwrap_or_default(), port),
+        None => parsed.host_str().unwrap_or_default().to_string(),
+    };
+    let request = format!(
+        "HEAD {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: gator/{}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        env!("CARGO_PKG_VERSION")
+    );
+    if !https {
+        let first_byte = first_byte(stream, &request)?;
+        return Ok(Phases {
+            dns,
+            connect,
+            tls: None,
+            first_byte,
+        });
+    }
+    let start = Instant::now();
+    let stream = native_tls::TlsConnector::new()?
+        .connect(host, stream)
+        .map_err(|e| format!("TLS handshake with {} failed: {}", host, e))?;
+    let tls = start.elapsed();
+    let first_byte = first_byte(stream, &request)?;
+    Ok(Phases {
+        dns,
+        connect,
+        tls: Some(tls),
+        first_byte,
+    })
+}
+
+// Send `request` and wait for the first byte of the response
+fn first_byte(mut stream: impl Read + Write, request: &str) -> Result<Duration, BoxError> {
+    let start = Instant::now();
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+    let mut byte = [0u8; 1];
+    if stream.read(&mut byte)? == 0 {
+        return Err("The server closed the connection without responding".into());
+    }
+    Ok(start.elapsed())
+}
+
+// When the milestones of a download were reached, to split its time into
+// the probe and the transfer of the body
+#[derive(Default)]
+pub struct Stopwatch {
+    marks: Mutex<Marks>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Marks {
+    began: Option<Instant>,
+    probed: Option<Instant>,
+    started: Option<Instant>,
+    resumed_from: u64,
+    finished: Option<(Instant, u64)>,
+}
+
+// The time a download took, split at the probe
+#[derive(Debug, Clone)]
+pub struct Split {
+    pub probe: Duration,
+    pub transfer: Duration,
+    pub total: Duration,
+    // Bytes transferred, not counting any resumed from
+    pub bytes: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        let stopwatch = Self::default();
+        stopwatch.marks.lock().unwrap().began = Some(Instant::now());
+        stopwatch
+    }
+
+    // None unless the download got to the end
+    pub fn split(&self) -> Option<Split> {
+        let marks = *self.marks.lock().unwrap();
+        let began = marks.began?;
+        let probed = marks.probed?;
+        let (finished, bytes) = marks.finished?;
+        Some(Split {
+            probe: probed - began,
+            transfer: finished - marks.started.unwrap_or(probed),
+            total: finished - began,
+            bytes: bytes.saturating_sub(marks.resumed_from),
+        })
+    }
+}
+
+impl ProgressObserver for Stopwatch {
+    fn on_event(&self, event: &Event) {
+        let mut marks = self.marks.lock().unwrap();
+        let now = Instant::now();
+        match event {
+            // A retried download probes again
+            Event::Probed { .. } => marks.probed = Some(now),
+            Event::Started { resumed_from, .. } => {
+                marks.started = Some(now);
+                marks.resumed_from = *resumed_from;
+            }
+            Event::Finished { bytes, .. } => marks.finished = Some((now, *bytes)),
+            _ => {}
+        }
+    }
+}
+
+// A duration the way the timing report shows it: milliseconds up to a few
+// seconds, then seconds
+pub fn format(duration: Duration) -> String {
+    match duration.as_secs_f64() {
+        secs if secs < 10.0 => format!("{:.1} ms", secs * 1000.0),
+        secs => format!("{:.2} s", secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+
+    #[tokio::test]
+    async fn phases_of_a_plain_request_are_timed() {
+        let server = MockServer::start(1024, Behavior::default()).await.unwrap();
+        let url = server.url("file.bin");
+        let phases = tokio::task::spawn_blocking(move || measure(&url)).await.unwrap().unwrap();
+        assert!(phases.tls.is_none());
+        assert!(phases.total() >= phases.first_byte);
+        assert!(server.requests().iter().any(|r| r.starts_with("head /file.bin ")));
+        assert!(measure("ftp://example.com/file").is_err());
+        assert_eq!(format(Duration::from_micros(250_400)), "250.4 ms");
+        assert_eq!(format(Duration::from_secs(12)), "12.00 s");
+    }
+}
@@ -0,0 +1,280 @@
+use crate::protocol::BoxError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// SHA-256 hashes of the public keys (SubjectPublicKeyInfo) a server may
+// present, written like curl's --pinnedpubkey: "sha256//<base64>", several
+// joined with ';'
+#[derive(Debug, Clone, Default)]
+pub struct Pins(Vec<[u8; 32]>);
+
+impl Pins {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let pins = value
+            .split(';')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|pin| {
+                let hash = pin
+                    .strip_prefix("sha256//")
+                    .ok_or_else(|| format!("'{}' is not a pin like sha256//<base64>", pin))?;
+                STANDARD
+                    .decode(hash)
+                    .ok()
+                    .and_then(|h| <[u8; 32]>::try_from(h).ok())
+                    .ok_or_else(|| format!("'{}' is not a base64 SHA-256 hash", hash))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if pins.is_empty() {
+            return Err("No pins given".to_string());
+        }
+        Ok(Self(pins))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Fail unless the public key of the DER certificate is one of the pins
+    pub fn check(&self, cert: &[u8]) -> Result<(), BoxError> {
+        let key = public_key_info(cert).ok_or("Cannot read the public key of the server's certificate")?;
+        let hash: [u8; 32] = Sha256::digest(key).into();
+        if self.0.contains(&hash) {
+            return Ok(());
+        }
+        Err(format!(
+            "The server's public key (sha256//{}) matches none of the pinned keys",
+            STANDARD.encode(hash)
+        )
+        .into())
+    }
+}
+
+// The pin of the public key in a DER certificate, "sha256//<base64>"
+pub fn public_key_pin(cert: &[u8]) -> Option<String> {
+    let key = public_key_info(cert)?;
+    Some(format!("sha256//{}", STANDARD.encode(Sha256::digest(key))))
+}
+
+// The whole SubjectPublicKeyInfo element: the seventh field of the
+// TBSCertificate, or the sixth when the optional version is left out
+fn public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = element(cert)?;
+    let (_, tbs, _) = element(certificate)?;
+    let mut fields = tbs;
+    let (tag, _, _) = element(fields)?;
+    if tag == 0xa0 {
+        fields = element(fields)?.2;
+    }
+    // serial, signature algorithm, issuer, validity, subject
+    for _ in 0..5 {
+        fields = element(fields)?.2;
+    }
+    let (tag, _, rest) = element(fields)?;
+    (tag == 0x30).then(|| &fields[..fields.len() - rest.len()])
+}
+
+// The first DER element of `data`: its tag, its contents, and what follows it
+fn element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, data) = data.split_first()?;
+    let (len, data) = if first < 0x80 {
+        (first as usize, data)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < count {
+            return None;
+        }
+        let len = data[..count].iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        (len, &data[count..])
+    };
+    (data.len() >= len).then(|| (tag, &data[..len], &data[len..]))
+}
+
+// One certificate of the chain a server presents
+#[derive(Debug, Clone, Serialize)]
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    // Subject alternative names: DNS names and IP addresses
+    pub names: Vec<String>,
+}
+
+// What a stapled OCSP response says about the server's certificate
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stapled {
+    Good,
+    Revoked,
+    Unknown,
+    // Not signed by the issuer, out of date, or not about this certificate
+    Invalid,
+}
+
+impl std::fmt::Display for Stapled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Stapled::Good => "good",
+            Stapled::Revoked => "revoked",
+            Stapled::Unknown => "unknown",
+            Stapled::Invalid => "invalid",
+        })
+    }
+}
+
+// What a TLS handshake with a server shows
+#[derive(Debug, Clone, Serialize)]
+pub struct Handshake {
+    // The server's certificate first
+    pub chain: Vec<Certificate>,
+    // Why the system's CA store does not trust the chain for this host
+    pub untrusted: Option<String>,
+    // None when the server stapled no OCSP response
+    pub ocsp: Option<Stapled>,
+}
+
+// Connect to `host` directly (not through a proxy) and look at its
+// certificates and stapled OCSP response. Trust is reported, not enforced, so
+// an intercepted connection can be examined too. This blocks.
+#[cfg(not(any(windows, target_vendor = "apple")))]
+pub fn handshake(host: &str, port: u16) -> Result<Handshake, BoxError> {
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode, StatusType};
+    use openssl::x509::X509VerifyResult;
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::time::Duration;
+
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve '{}'", host))?;
+    let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(15))?;
+    stream.set_read_timeout(Some(Duration::from_secs(15)))?;
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let mut config = builder.build().configure()?;
+    config.set_status_type(StatusType::OCSP)?;
+    let stream = config
+        .connect(host, stream)
+        .map_err(|e| format!("TLS handshake with {} failed: {}", host, e))?;
+    let ssl = stream.ssl();
+    let chain = ssl.peer_cert_chain().ok_or("The server presented no certificates")?;
+    let verified = ssl.verify_result();
+    Ok(Handshake {
+        chain: chain.iter().map(describe).collect(),
+        untrusted: (verified != X509VerifyResult::OK).then(|| verified.error_string().to_string()),
+        ocsp: ssl.ocsp_status().map(|response| stapled(response, chain)),
+    })
+}
+
+#[cfg(any(windows, target_vendor = "apple"))]
+pub fn handshake(_host: &str, _port: u16) -> Result<Handshake, BoxError> {
+    Err("Certificate chains and OCSP responses cannot be read on this platform".into())
+}
+
+#[cfg(not(any(windows, target_vendor = "apple")))]
+fn describe(cert: &openssl::x509::X509Ref) -> Certificate {
+    let name = |name: &openssl::x509::X509NameRef| {
+        name.entries()
+            .map(|entry| {
+                let key = entry.object().nid().short_name().unwrap_or("?");
+                let value = entry.data().as_utf8().map(|v| v.to_string()).unwrap_or_default();
+                format!("{}={}", key, value)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let names = cert
+        .subject_alt_names()
+        .into_iter()
+        .flatten()
+        .filter_map(|n| {
+            n.dnsname().map(str::to_string).or_else(|| match n.ipaddress()? {
+                &[a, b, c, d] => Some(std::net::Ipv4Addr::new(a, b, c, d).to_string()),
+                bytes => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).to_string()),
+            })
+        })
+        .collect();
+    Certificate {
+        subject: name(cert.subject_name()),
+        issuer: name(cert.issuer_name()),
+        not_before: cert.not_before().to_string(),
+        not_after: cert.not_after().to_string(),
+        names,
+    }
+}
+
+// Check the stapled response's signature against the chain and the system's
+// CA store, then read the status of the server's certificate
+#[cfg(not(any(windows, target_vendor = "apple")))]
+fn stapled(response: &[u8], chain: &openssl::stack::StackRef<openssl::x509::X509>) -> Stapled {
+    use openssl::hash::MessageDigest;
+    use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspResponse, OcspResponseStatus};
+    use openssl::x509::store::X509StoreBuilder;
+
+    let status = || -> Option<Stapled> {
+        let response = OcspResponse::from_der(response).ok()?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return None;
+        }
+        let basic = response.basic().ok()?;
+        let mut store = X509StoreBuilder::new().ok()?;
+        store.set_default_paths().ok()?;
+        basic.verify(chain, &store.build(), OcspFlag::empty()).ok()?;
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), chain.get(0)?, chain.get(1)?).ok()?;
+        let status = basic.find_status(&id)?;
+        // Allow for clocks that are a few minutes apart
+        status.check_validity(300, None).ok()?;
+        Some(match status.status {
+            OcspCertStatus::GOOD => Stapled::Good,
+            OcspCertStatus::REVOKED => Stapled::Revoked,
+            _ => Stapled::Unknown,
+        })
+    };
+    status().unwrap_or(Stapled::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A self-signed P-256 certificate and the pin `openssl` computes for it
+    const CERT: &str = "MIIBfTCCASOgAwIBAgIUaKnosI1fCLv2pmC4rDRAc/cjh5EwCgYIKoZIzj0EAwIw\
+        EzERMA8GA1UEAwwIcGluLnRlc3QwIBcNMjYxMDE1MTEzNDUyWhgPMjEyNjA5MjEx\
+        MTM0NTJaMBMxETAPBgNVBAMMCHBpbi50ZXN0MFkwEwYHKoZIzj0CAQYIKoZIzj0D\
+        AQcDQgAEwNIPtbK6ITy5yOVDWJihUZuxKLagAuj/LscRNS9tQkrWComICY1spZwe\
+        brEvEByQnExyyY3AzuD9/9Fq38AqL6NTMFEwHQYDVR0OBBYEFP1qyHaVyQgZEwDV\
+        KZB4aXG/3bCDMB8GA1UdIwQYMBaAFP1qyHaVyQgZEwDVKZB4aXG/3bCDMA8GA1Ud\
+        EwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIgYtdMKtjqHVnaBsBc9s0S1b1X\
+        alRSYcOD9l4zxD7WAmcCIQDVn3eBvIfvUgfDobRv0mOGp9MLU1GyJu2c1DqpvYFP\
+        IA==";
+    const PIN: &str = "sha256//+QZzTt3puZe6h8dxuROBVK7K4ikTKMTikIoRnDJJ2cA=";
+
+    #[test]
+    fn certificates_are_checked_against_any_of_the_pins() {
+        let cert = STANDARD.decode(CERT).unwrap();
+        assert_eq!(public_key_pin(&cert).as_deref(), Some(PIN));
+
+        let other = "sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        assert!(Pins::parse(&format!("{};{}", other, PIN)).unwrap().check(&cert).is_ok());
+        let error = Pins::parse(other).unwrap().check(&cert).unwrap_err();
+        assert!(error.to_string().contains(PIN), "{}", error);
+
+        assert!(Pins::parse("sha256//tooshort").is_err());
+        assert!(Pins::parse("/path/to/key.pem").is_err());
+    }
+
+    #[cfg(not(any(windows, target_vendor = "apple")))]
+    #[test]
+    fn certificates_are_described() {
+        let cert = openssl::x509::X509::from_der(&STANDARD.decode(CERT).unwrap()).unwrap();
+        let described = describe(&cert);
+        assert_eq!(described.subject, "CN=pin.test");
+        assert_eq!(described.issuer, "CN=pin.test");
+        assert_eq!(described.not_after, "Sep 21 11:34:52 2126 GMT");
+        assert!(described.names.is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use clap::ValueEnum;
+use reqwest::Client;
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+// GitHub releases API for the project; `--release-url` points elsewhere for forks and mirrors
+pub const DEFAULT_RELEASE_URL: &str = "https://api.github.com/repos/apexrx/Gator/releases";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Channel {
+    // Latest release that is not marked as a pre-release
+    Stable,
+    // Latest pre-release
+    Nightly,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    pub fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.name == name)
+    }
+}
+
+// The newest release on `channel`. The API lists releases newest first.
+pub async fn latest(
+    client: &Client,
+    url: &str,
+    channel: Channel,
+) -> Result<Release, Box<dyn Error + Send + Sync>> {
+    let response = client
+        .get(url)
+        .header("Accept", "application/vnd.github+json")
+        // GitHub rejects API requests without a User-Agent
+        .header("User-Agent", concat!("gator/", env!("CARGO_PKG_VERSION")))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("Release endpoint returned error: {}", response.status()).into());
+    }
+    let releases: Vec<Release> = serde_json::from_str(&response.text().await?)?;
+    releases
+        .into_iter()
+        .find(|r| r.prerelease == (channel == Channel::Nightly))
+        .ok_or_else(|| format!("No {:?} release found", channel).to_lowercase().into())
+}
+
+// Release binaries are published as `gator-<arch>-<os>` (plus `.exe` on Windows)
+pub fn asset_name() -> String {
+    format!(
+        "gator-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+// The digest of `name` from a `sha256sum` style checksum file: lines of
+// "<hex>  <name>" (or "<hex> *<name>" for binary mode), or just "<hex>"
+pub fn parse_checksum(content: &str, name: &str) -> Result<[u8; 32], Box<dyn Error + Send + Sync>> {
+    let hex = content
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let hex = fields.next()?;
+            match fields.next().map(|file| file.trim_start_matches('*')) {
+                None => Some(hex),
+                Some(file) if file == name => Some(hex),
+                Some(_) => None,
+            }
+        })
+        .ok_or_else(|| format!("Checksum file has no entry for {}", name))?;
+    // Checked byte by byte, so non-ASCII text is rejected before it is sliced
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("Checksum file does not contain a SHA-256 digest".into());
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).expect("two hex digits");
+    }
+    Ok(digest)
+}
+
+// Where the new binary is staged: next to the running one, so the final
+// rename is on the same filesystem
+pub fn staging_path(current: &Path) -> PathBuf {
+    let mut name = current.file_name().unwrap_or_default().to_os_string();
+    name.push(".update");
+    current.with_file_name(name)
+}
+
+// Atomically move the verified binary at `new` over the running executable
+pub fn replace_executable(new: &Path, current: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(new, std::fs::Permissions::from_mode(0o755))?;
+    }
+    // Windows cannot overwrite a running executable, but it can rename it away
+    #[cfg(windows)]
+    {
+        let mut old = current.as_os_str().to_os_string();
+        old.push(".old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(current, &old)?;
+    }
+    std::fs::rename(new, current)
+        .map_err(|e| format!("Failed to replace '{}': {}", current.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn checksum_files_give_the_digest_of_the_named_binary() {
+        let expected = parse_checksum(DIGEST, "gator-x86_64-linux").unwrap();
+        assert_eq!(expected[..4], [0x2c, 0xf2, 0x4d, 0xba]);
+        assert_eq!(parse_checksum(&format!("{}\n", DIGEST.to_uppercase()), "gator").unwrap(), expected);
+
+        let sums = format!(
+            "{}  gator-aarch64-macos\n{} *gator-x86_64-linux\n{}  gator-x86_64-windows.exe\n",
+            "0".repeat(64),
+            DIGEST,
+            "1".repeat(64)
+        );
+        assert_eq!(parse_checksum(&sums, "gator-x86_64-linux").unwrap(), expected);
+        assert_eq!(parse_checksum(&sums, "gator-x86_64-windows.exe").unwrap(), [0x11; 32]);
+
+        let error = parse_checksum(&sums, "gator-riscv64-linux").unwrap_err();
+        assert_eq!(error.to_string(), "Checksum file has no entry for gator-riscv64-linux");
+        assert!(parse_checksum("", "gator").is_err());
+        assert!(parse_checksum("abc123  gator", "gator").is_err());
+        assert!(parse_checksum(&"g".repeat(64), "gator").is_err());
+        // 64 bytes, but a two-byte character would be split by the slicing
+        assert!(parse_checksum(&format!("a{}{}", "é", "0".repeat(61)), "gator").is_err());
+        assert!(parse_checksum(&format!("{}  gator", "é".repeat(32)), "gator").is_err());
+    }
+}
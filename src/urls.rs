@@ -0,0 +1,211 @@
+use crate::data_url;
+use crate::mime;
+use crate::paths;
+use reqwest::Url;
+
+// The canonical form of a URL, so the same resource is always the same string:
+// lowercase scheme and host, internationalized host names in their ASCII
+// (punycode) form, no default port, no `.`/`..` segments, no fragment (it is
+// never sent to the server), and characters that may not appear in a URL
+// percent-encoded. Strings that do not parse are returned as given, for the
+// protocol handler to report.
+pub fn normalize(url: &str) -> String {
+    match Url::parse(url.trim()) {
+        Ok(mut parsed) => {
+            parsed.set_fragment(None);
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+// Sort the query parameters by name, for `--sort-query`. Parameters with the
+// same name keep their order, since some servers read them as a list.
+pub fn sort_query(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    let Some(query) = parsed.query() else {
+        return url.to_string();
+    };
+    let mut params: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+    params.sort_by_key(|p| p.split('=').next().unwrap_or(""));
+    let sorted = params.join("&");
+    parsed.set_query((!sorted.is_empty()).then_some(sorted.as_str()));
+    parsed.to_string()
+}
+
+// The default file name for `url`: its last path segment, percent-decoded and
+// made safe for this platform. The query and fragment are not part of it.
+// Inline data: URLs have no path to take a name from, so they are saved as
+// "data" with the extension of their media type.
+pub fn file_name(url: &str) -> String {
+    if let Ok((media_type, _)) = data_url::parse(url.trim()) {
+        return match mime::extension_for(&media_type) {
+            Some(extension) => format!("data.{}", extension),
+            None => "data".to_string(),
+        };
+    }
+    let segment = match Url::parse(url.trim()) {
+        Ok(parsed) => parsed
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or("")
+            .to_string(),
+        Err(_) => {
+            let path = url.split(['?', '#']).next().unwrap_or("");
+            path.rsplit('/').next().unwrap_or("").to_string()
+        }
+    };
+    paths::sanitize_file_name(&decode_segment(&segment))
+}
+
+// Percent-decode one path segment. `+` stays a plus sign: it only means a
+// space in form-encoded query strings. Malformed escapes are kept literally,
+// bytes that are not UTF-8 are read as Latin-1 (as old servers send them),
+// and decoded control characters become `_`.
+pub fn decode_segment(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    let text = match String::from_utf8(decoded) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    text.chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_spaces_and_punctuation_are_decoded() {
+        assert_eq!(
+            file_name("https://example.com/files/My%20Report%20%281%29.pdf"),
+            "My Report (1).pdf"
+        );
+        assert_eq!(file_name("https://example.com/caf%C3%A9%20menu.txt"), "café menu.txt");
+    }
+
+    #[test]
+    fn plus_signs_in_paths_are_literal() {
+        assert_eq!(file_name("https://example.com/c++/a+b.tar.gz"), "a+b.tar.gz");
+        assert_eq!(file_name("https://example.com/a%2Bb.txt"), "a+b.txt");
+    }
+
+    #[test]
+    fn query_and_fragment_are_not_part_of_the_name() {
+        assert_eq!(
+            file_name("https://cdn.example.com/dl/file.zip?token=a%2Fb&expires=1#section"),
+            "file.zip"
+        );
+        assert_eq!(file_name("https://example.com/download?id=42"), "download");
+    }
+
+    #[test]
+    fn encoded_separators_cannot_escape_the_directory() {
+        assert_eq!(file_name("https://example.com/..%2F..%2Fetc%2Fpasswd"), ".._.._etc_passwd");
+        assert_eq!(file_name("https://example.com/%2E%2E"), "downloaded_file");
+    }
+
+    #[test]
+    fn decoded_control_characters_are_replaced() {
+        assert_eq!(file_name("https://example.com/evil%0Aname%00.txt"), "evil_name_.txt");
+    }
+
+    #[test]
+    fn latin1_escapes_are_decoded() {
+        assert_eq!(file_name("https://example.com/%E9t%E9.txt"), "été.txt");
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(file_name("https://example.com/100%25%zz%.txt"), "100%%zz%.txt");
+    }
+
+    #[test]
+    fn unencoded_unicode_paths_round_trip() {
+        assert_eq!(file_name("https://example.com/文档/日本語 ファイル.pdf"), "日本語 ファイル.pdf");
+    }
+
+    #[test]
+    fn directories_and_bare_hosts_fall_back() {
+        assert_eq!(file_name("https://example.com/docs/"), "downloaded_file");
+        assert_eq!(file_name("https://example.com"), "downloaded_file");
+    }
+
+    #[test]
+    fn data_urls_are_named_after_their_media_type() {
+        assert_eq!(file_name("data:application/json;base64,e30="), "data.json");
+        assert_eq!(file_name("data:application/octet-stream,%00"), "data");
+    }
+
+    #[test]
+    fn unparsable_urls_use_the_last_segment() {
+        assert_eq!(file_name("not a url/with%20space.bin?x=1"), "with space.bin");
+    }
+
+    #[test]
+    fn international_hosts_become_punycode() {
+        assert_eq!(
+            normalize("https://Bücher.example/ä ö.pdf"),
+            "https://xn--bcher-kva.example/%C3%A4%20%C3%B6.pdf"
+        );
+        assert_eq!(file_name("https://bücher.example/ä ö.pdf"), "ä ö.pdf");
+        assert_eq!(
+            normalize("http://例え.テスト/"),
+            "http://xn--r8jz45g.xn--zckzah/"
+        );
+    }
+
+    #[test]
+    fn equivalent_urls_normalize_to_the_same_string() {
+        let canonical = "http://example.com/a/c.txt?x=1";
+        for url in [
+            "http://example.com/a/c.txt?x=1",
+            "HTTP://Example.COM/a/c.txt?x=1",
+            "http://example.com:80/a/c.txt?x=1",
+            "http://example.com/a/b/../c.txt?x=1",
+            "http://example.com/a/./c.txt?x=1#top",
+        ] {
+            assert_eq!(normalize(url), canonical, "{}", url);
+        }
+        assert_eq!(normalize("https://example.com:443/"), "https://example.com/");
+        assert_eq!(normalize("https://example.com:8443/"), "https://example.com:8443/");
+    }
+
+    #[test]
+    fn query_parameters_are_sorted_by_name() {
+        assert_eq!(
+            sort_query("https://example.com/f?b=2&a=1&&c=3"),
+            "https://example.com/f?a=1&b=2&c=3"
+        );
+        assert_eq!(
+            sort_query("https://example.com/f?id=2&format=csv&id=1"),
+            "https://example.com/f?format=csv&id=2&id=1"
+        );
+        assert_eq!(sort_query("https://example.com/f"), "https://example.com/f");
+        assert_eq!(sort_query("https://example.com/f?&"), "https://example.com/f");
+    }
+
+    #[test]
+    fn unparsable_urls_are_not_normalized() {
+        assert_eq!(normalize("example.com/file"), "example.com/file");
+    }
+}
@@ -0,0 +1,405 @@
+use crate::paths;
+use crate::protocol::BoxError;
+use crate::reader::ParallelHttpReader;
+use flate2::write::DeflateDecoder;
+use flate2::CrcWriter;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_LOCATOR: u32 = 0x0706_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const CENTRAL_FILE_HEADER: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+// The end record is 22 bytes plus a comment of up to 64 KiB
+const MAX_END_RECORD: u64 = 22 + 0xffff;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+// A file or directory in an archive, from its central directory entry
+#[derive(Debug, Clone, Serialize)]
+pub struct Member {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    pub method: u16,
+    pub crc32: u32,
+    // "YYYY-MM-DD HH:MM", in the archive's (unspecified) time zone
+    pub modified: String,
+    pub is_dir: bool,
+    pub encrypted: bool,
+    #[serde(skip)]
+    header_offset: u64,
+}
+
+// Read the central directory through ranged requests: the end record at the
+// tail of the file, then the directory it points to
+pub async fn list(reader: &mut ParallelHttpReader) -> Result<Vec<Member>, BoxError> {
+    let len = reader.len();
+    let tail_len = len.min(MAX_END_RECORD);
+    let tail = read_at(reader, len - tail_len, tail_len as usize).await?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(&tail, i) == END_OF_CENTRAL_DIRECTORY)
+        .ok_or("not a ZIP archive (no end of central directory record)")?;
+
+    let mut entries = u64::from(u16_at(&tail, end + 10));
+    let mut directory_size = u64::from(u32_at(&tail, end + 12));
+    let mut directory_offset = u64::from(u32_at(&tail, end + 16));
+    // Archives past 4 GiB or 65535 entries keep the real values in a ZIP64 record
+    if end >= 20 && u32_at(&tail, end - 20) == ZIP64_END_LOCATOR {
+        let record = read_at(reader, u64_at(&tail, end - 20 + 8), 56).await?;
+        if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY {
+            return Err("corrupt ZIP64 end of central directory record".into());
+        }
+        entries = u64_at(&record, 32);
+        directory_size = u64_at(&record, 40);
+        directory_offset = u64_at(&record, 48);
+    }
+    if directory_offset.checked_add(directory_size).is_none_or(|e| e > len) {
+        return Err("corrupt ZIP archive (central directory past the end of the file)".into());
+    }
+
+    let directory = read_at(reader, directory_offset, directory_size as usize).await?;
+    let mut members = Vec::new();
+    let mut pos = 0;
+    for _ in 0..entries {
+        if pos + 46 > directory.len() || u32_at(&directory, pos) != CENTRAL_FILE_HEADER {
+            return Err("corrupt ZIP central directory".into());
+        }
+        let name_len = usize::from(u16_at(&directory, pos + 28));
+        let extra_len = usize::from(u16_at(&directory, pos + 30));
+        let comment_len = usize::from(u16_at(&directory, pos + 32));
+        let next = pos + 46 + name_len + extra_len + comment_len;
+        if next > directory.len() {
+            return Err("corrupt ZIP central directory".into());
+        }
+        let name = String::from_utf8_lossy(&directory[pos + 46..pos + 46 + name_len]).into_owned();
+        let mut member = Member {
+            is_dir: name.ends_with('/'),
+            name,
+            size: u64::from(u32_at(&directory, pos + 24)),
+            compressed_size: u64::from(u32_at(&directory, pos + 20)),
+            method: u16_at(&directory, pos + 10),
+            crc32: u32_at(&directory, pos + 16),
+            modified: dos_time(u16_at(&directory, pos + 14), u16_at(&directory, pos + 12)),
+            encrypted: u16_at(&directory, pos + 8) & 1 != 0,
+            header_offset: u64::from(u32_at(&directory, pos + 42)),
+        };
+        apply_zip64_extra(&mut member, &directory[pos + 46 + name_len..pos + 46 + name_len + extra_len]);
+        members.push(member);
+        pos = next;
+    }
+    Ok(members)
+}
+
+// Fields too large for the central directory entry are 0xffffffff there and
+// follow in the ZIP64 extra field, in this order
+fn apply_zip64_extra(member: &mut Member, mut extra: &[u8]) {
+    while extra.len() >= 4 {
+        let (id, len) = (u16_at(extra, 0), usize::from(u16_at(extra, 2)));
+        let Some(data) = extra.get(4..4 + len) else {
+            return;
+        };
+        if id == 0x0001 {
+            let mut values = data.chunks_exact(8).map(|c| u64_at(c, 0));
+            for field in [&mut member.size, &mut member.compressed_size, &mut member.header_offset] {
+                if *field == 0xffff_ffff {
+                    match values.next() {
+                        Some(value) => *field = value,
+                        None => return,
+                    }
+                }
+            }
+            return;
+        }
+        extra = &extra[4 + len..];
+    }
+}
+
+// Where a member is saved below the output directory: its path in the
+// archive, without anything that could climb out of the directory
+pub fn relative_path(member: &Member) -> PathBuf {
+    member
+        .name
+        .split(['/', '\\'])
+        .filter(|s| !matches!(*s, "" | "." | ".."))
+        .map(paths::sanitize_file_name)
+        .collect()
+}
+
+// Fetch a member's compressed bytes and write them, decompressed, to `dest`
+// through a part file. Returns the bytes written.
+pub async fn extract(reader: &mut ParallelHttpReader, member: &Member, dest: &Path) -> Result<u64, BoxError> {
+    if member.encrypted {
+        return Err(format!("'{}' is encrypted", member.name).into());
+    }
+    if member.method != STORED && member.method != DEFLATED {
+        return Err(format!(
+            "'{}' uses compression method {}; only stored and deflated files can be extracted",
+            member.name, member.method
+        )
+        .into());
+    }
+
+    // The local header repeats the name and has its own extra field, so the
+    // data starts at an offset only it tells
+    let header = read_at(reader, member.header_offset, 30).await?;
+    if u32_at(&header, 0) != LOCAL_FILE_HEADER {
+        return Err(format!("corrupt ZIP archive (no local header for '{}')", member.name).into());
+    }
+    let data_offset =
+        member.header_offset + 30 + u64::from(u16_at(&header, 26)) + u64::from(u16_at(&header, 28));
+
+    let part_path = paths::part_path(dest);
+    let file = CrcWriter::new(paths::create_private(&part_path)?);
+    let mut sink = match member.method {
+        STORED => Sink::Stored(file),
+        _ => Sink::Deflated(DeflateDecoder::new(file)),
+    };
+    reader.seek(SeekFrom::Start(data_offset)).await?;
+    let mut remaining = member.compressed_size;
+    let mut buf = vec![0u8; 64 * 1024];
+    let result: Result<CrcWriter<File>, BoxError> = async {
+        while remaining > 0 {
+            let n = buf.len().min(remaining as usize);
+            reader.read_exact(&mut buf[..n]).await?;
+            sink.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(sink.finish()?)
+    }
+    .await;
+    let check = result.and_then(|file| {
+        let written = file.get_ref().metadata()?.len();
+        if written != member.size {
+            return Err(format!("'{}' extracted to {} bytes, expected {}", member.name, written, member.size).into());
+        }
+        if file.crc().sum() != member.crc32 {
+            return Err(format!("'{}' failed its CRC-32 check", member.name).into());
+        }
+        Ok(written)
+    });
+    match check {
+        Ok(written) => {
+            paths::finalize(&part_path, dest)?;
+            Ok(written)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&part_path);
+            Err(e)
+        }
+    }
+}
+
+enum Sink {
+    Stored(CrcWriter<File>),
+    Deflated(DeflateDecoder<CrcWriter<File>>),
+}
+
+impl Sink {
+    fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::Stored(file) => file.write_all(data),
+            Sink::Deflated(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<CrcWriter<File>> {
+        let mut file = match self {
+            Sink::Stored(file) => file,
+            Sink::Deflated(decoder) => decoder.finish()?,
+        };
+        file.flush()?;
+        Ok(file)
+    }
+}
+
+async fn read_at(reader: &mut ParallelHttpReader, offset: u64, len: usize) -> Result<Vec<u8>, BoxError> {
+    reader.seek(SeekFrom::Start(offset)).await?;
+    let mut data = vec![0u8; len];
+    reader
+        .read_exact(&mut data)
+        .await
+        .map_err(|e| format!("Failed to read {} bytes at {}: {}", len, offset, e))?;
+    Ok(data)
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn dos_time(date: u16, time: u16) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        1980 + (date >> 9),
+        (date >> 5) & 0xf,
+        date & 0x1f,
+        time >> 11,
+        (time >> 5) & 0x3f
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{Behavior, MockServer};
+    use crate::protocol::Registry;
+    use flate2::write::DeflateEncoder;
+    use flate2::Crc;
+    use reqwest::Client;
+    use std::sync::Arc;
+
+    // 2024-05-06 12:34
+    const DATE: u16 = (44 << 9) | (5 << 5) | 6;
+    const TIME: u16 = (12 << 11) | (34 << 5);
+
+    // A minimal archive writer: (name, content, deflate)
+    fn build_zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut directory = Vec::new();
+        for (name, content, deflate) in files {
+            let mut crc = Crc::new();
+            crc.update(content);
+            let (method, data) = match deflate {
+                true => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                    encoder.write_all(content).unwrap();
+                    (DEFLATED, encoder.finish().unwrap())
+                }
+                false => (STORED, content.to_vec()),
+            };
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&TIME.to_le_bytes());
+            fields.extend_from_slice(&DATE.to_le_bytes());
+            fields.extend_from_slice(&crc.sum().to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+
+            directory.extend_from_slice(&CENTRAL_FILE_HEADER.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            zip.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+            zip.extend_from_slice(&[20, 0, 0, 0]);
+            zip.extend_from_slice(&fields);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(&data);
+        }
+        let offset = zip.len() as u32;
+        zip.extend_from_slice(&directory);
+        zip.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&offset.to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+        zip
+    }
+
+    async fn open(server: &MockServer) -> ParallelHttpReader {
+        let client = Client::builder().no_proxy().build().unwrap();
+        let registry = Registry::with_defaults(Arc::new(client), Vec::new());
+        ParallelHttpReader::open(&registry, &server.url("archive.zip"))
+            .await
+            .unwrap()
+            .with_block_size(64 * 1024)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gator-test-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn members_are_listed_from_the_central_directory() {
+        let text = b"hello, hello, hello, hello".repeat(100);
+        let zip = build_zip(&[("docs/", b"", false), ("docs/readme.txt", &text, true), ("raw.bin", b"\x00\x01", false)]);
+        let server = MockServer::serve(zip, Behavior::default()).await.unwrap();
+
+        let members = list(&mut open(&server).await).await.unwrap();
+        let names: Vec<&str> = members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["docs/", "docs/readme.txt", "raw.bin"]);
+        assert!(members[0].is_dir);
+        assert_eq!((members[1].size, members[1].method), (text.len() as u64, DEFLATED));
+        assert!(members[1].compressed_size < members[1].size);
+        assert_eq!(members[2].modified, "2024-05-06 12:34");
+    }
+
+    #[tokio::test]
+    async fn members_are_extracted_without_fetching_the_rest() {
+        let big: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+        let small = b"id,value\n1,2\n".repeat(50);
+        let zip = build_zip(&[("big.bin", &big, false), ("data/small.csv", &small, true)]);
+        let server = MockServer::serve(zip, Behavior::default()).await.unwrap();
+        let mut reader = open(&server).await;
+        let members = list(&mut reader).await.unwrap();
+
+        let dir = temp_dir("zip-extract");
+        let dest = dir.join(relative_path(&members[1]));
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        assert_eq!(extract(&mut reader, &members[1], &dest).await.unwrap(), small.len() as u64);
+        assert_eq!(std::fs::read(&dest).unwrap(), small);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // The 4 MB member alone would take 62 blocks
+        let gets = server.requests().iter().filter(|r| r.starts_with("get ")).count();
+        assert!(gets <= 4, "{} requests", gets);
+    }
+
+    #[tokio::test]
+    async fn corrupt_members_are_not_saved() {
+        let mut zip = build_zip(&[("file.txt", b"some text", false)]);
+        // Flip a byte of the stored data, after the 30 byte header and name
+        zip[30 + 8] ^= 0xff;
+        let server = MockServer::serve(zip, Behavior::default()).await.unwrap();
+        let mut reader = open(&server).await;
+        let members = list(&mut reader).await.unwrap();
+
+        let dir = temp_dir("zip-corrupt");
+        let dest = dir.join("file.txt");
+        let error = extract(&mut reader, &members[0], &dest).await.unwrap_err();
+        assert!(error.to_string().contains("CRC-32"), "{}", error);
+        assert!(!dest.exists() && !paths::part_path(&dest).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn member_paths_stay_inside_the_output_directory() {
+        let member = |name: &str| Member {
+            name: name.to_string(),
+            size: 0,
+            compressed_size: 0,
+            method: STORED,
+            crc32: 0,
+            modified: String::new(),
+            is_dir: false,
+            encrypted: false,
+            header_offset: 0,
+        };
+        assert_eq!(relative_path(&member("../../etc/passwd")), Path::new("etc/passwd"));
+        assert_eq!(relative_path(&member("/abs/./file")), Path::new("abs/file"));
+        assert_eq!(relative_path(&member("dir\\file.txt")), Path::new("dir/file.txt"));
+    }
+}